@@ -1,7 +1,10 @@
+use std::fmt;
 use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
 use std::time::Duration;
 
 use divan::{counter, Bencher};
+use rand::rngs::SmallRng;
 use rand::{Rng, SeedableRng};
 
 use peanutbutter::*;
@@ -13,16 +16,18 @@ fn main() {
 #[divan::bench(min_time = 0.5, threads = [0, 1, 4], args = [1 << 10, 1 << 15, 1 << 20])]
 fn fibonacci(bencher: Bencher, projects: u64) {
     let allowed_budget = 1_000.;
-    let mut service = Service::new();
-    service.add_config(
-        "test",
-        BudgetingConfig::new(
-            Duration::from_millis(10),
-            Duration::from_millis(5),
-            Duration::from_micros(500),
-            allowed_budget,
-        ),
-    );
+    let service = Service::new();
+    service
+        .try_add_config(
+            "test",
+            BudgetingConfig::new(
+                Duration::from_millis(10),
+                Duration::from_millis(5),
+                Duration::from_micros(500),
+                allowed_budget,
+            ),
+        )
+        .unwrap();
 
     let seed = AtomicU64::new(0);
     let num_ops: u32 = 10_000;
@@ -46,3 +51,205 @@ fn fibonacci(bencher: Bencher, projects: u64) {
             }
         });
 }
+
+/// Checks a small set of hot, already existing projects from many threads at once.
+#[divan::bench(min_time = 0.5, threads = [0, 1, 4], args = [1, 16, 256])]
+fn contended_reads(bencher: Bencher, projects: u64) {
+    let service = Service::new();
+    service
+        .try_add_config(
+            "test",
+            BudgetingConfig::new(
+                Duration::from_secs(60),
+                Duration::from_secs(10),
+                Duration::from_secs(1),
+                1_000.,
+            ),
+        )
+        .unwrap();
+    for project_id in 0..projects {
+        service.record_spending("test", project_id, 1.);
+    }
+
+    let num_ops: u32 = 10_000;
+
+    bencher
+        .counter(counter::ItemsCount::new(num_ops))
+        .bench(move || {
+            for i in 0..num_ops {
+                service.exceeds_budget("test", u64::from(i) % projects);
+            }
+        });
+}
+
+/// Checks a single hot project from many concurrent tasks, with and without a [`Coalescer`].
+///
+/// Every `1 / WRITE_EVERY` of the operations records spending instead, which is never coalesced.
+#[divan::bench(min_time = 0.5, args = [false, true], consts = [0, 10, 2])]
+fn coalesced_checks<const WRITE_EVERY: u32>(bencher: Bencher, coalesce: bool) {
+    let service = Arc::new(Service::new());
+    service
+        .try_add_config(
+            "test",
+            BudgetingConfig::new(
+                Duration::from_secs(60),
+                Duration::from_secs(10),
+                Duration::from_secs(1),
+                1_000.,
+            ),
+        )
+        .unwrap();
+    service.record_spending("test", 0, 1.);
+    let coalescer = Arc::new(Coalescer::default());
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(4)
+        .build()
+        .unwrap();
+
+    let (num_tasks, num_ops): (u32, u32) = (64, 1_000);
+
+    bencher
+        .counter(counter::ItemsCount::new(num_tasks * num_ops))
+        .bench(|| {
+            runtime.block_on(async {
+                let tasks: Vec<_> = (0..num_tasks)
+                    .map(|_| {
+                        let (service, coalescer) = (service.clone(), coalescer.clone());
+                        tokio::spawn(async move {
+                            for i in 0..num_ops {
+                                if WRITE_EVERY > 0 && i % WRITE_EVERY == 0 {
+                                    service.record_spending("test", 0, 1.);
+                                    continue;
+                                }
+                                let _ = match coalesce {
+                                    true => coalescer.try_exceeds_budget(&service, "test", 0).await,
+                                    false => service.try_exceeds_budget_with_reason("test", 0),
+                                };
+                            }
+                        })
+                    })
+                    .collect();
+                for task in tasks {
+                    task.await.unwrap();
+                }
+            })
+        });
+}
+
+/// How the project ids of requests are distributed.
+#[derive(Clone, Copy, Debug)]
+enum Distribution {
+    /// All requests go to a single hot project, for maximum contention.
+    Hot,
+    /// Requests are spread uniformly across all projects.
+    Uniform,
+    /// A few projects receive most of the requests, following Zipf's law.
+    Zipf,
+}
+
+/// A realistic workload, made up of a [`Distribution`] and the share of writes.
+#[derive(Clone, Copy, Debug)]
+struct Scenario {
+    distribution: Distribution,
+    /// The percentage of requests recording spending, all others only check the budget.
+    write_percent: u32,
+}
+
+impl fmt::Display for Scenario {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let distribution = match self.distribution {
+            Distribution::Hot => "hot",
+            Distribution::Uniform => "uniform",
+            Distribution::Zipf => "zipf",
+        };
+        write!(f, "{distribution}/{}% writes", self.write_percent)
+    }
+}
+
+const SCENARIOS: &[Scenario] = &{
+    const fn scenario(distribution: Distribution, write_percent: u32) -> Scenario {
+        Scenario {
+            distribution,
+            write_percent,
+        }
+    }
+    [
+        scenario(Distribution::Hot, 10),
+        scenario(Distribution::Hot, 90),
+        scenario(Distribution::Uniform, 10),
+        scenario(Distribution::Uniform, 90),
+        scenario(Distribution::Zipf, 10),
+        scenario(Distribution::Zipf, 90),
+    ]
+};
+
+/// The number of distinct projects in the [`SCENARIOS`].
+const SCENARIO_PROJECTS: u64 = 1 << 16;
+
+/// Samples project ids following Zipf's law with an exponent of `1`.
+struct Zipf {
+    /// The cumulative distribution of all the project ids.
+    cdf: Vec<f64>,
+}
+
+impl Zipf {
+    fn new(projects: u64) -> Self {
+        let mut total = 0.;
+        let mut cdf: Vec<_> = (1..=projects)
+            .map(|rank| {
+                total += 1. / rank as f64;
+                total
+            })
+            .collect();
+        for p in &mut cdf {
+            *p /= total;
+        }
+        Self { cdf }
+    }
+
+    fn sample(&self, rng: &mut SmallRng) -> u64 {
+        let p: f64 = rng.gen();
+        self.cdf.partition_point(|&c| c < p) as u64
+    }
+}
+
+/// Runs a mix of reads and writes, with project ids following the [`Distribution`] of the [`Scenario`].
+#[divan::bench(min_time = 0.5, threads = [0, 1, 4], args = SCENARIOS)]
+fn scenarios(bencher: Bencher, scenario: Scenario) {
+    let allowed_budget = 1_000.;
+    let service = Service::new();
+    service
+        .try_add_config(
+            "test",
+            BudgetingConfig::new(
+                Duration::from_millis(10),
+                Duration::from_millis(5),
+                Duration::from_micros(500),
+                allowed_budget,
+            ),
+        )
+        .unwrap();
+    let zipf = Zipf::new(SCENARIO_PROJECTS);
+
+    let seed = AtomicU64::new(0);
+    let num_ops: u32 = 10_000;
+
+    bencher
+        .counter(counter::ItemsCount::new(num_ops))
+        .bench(move || {
+            let mut rng =
+                SmallRng::seed_from_u64(seed.fetch_add(1, std::sync::atomic::Ordering::Relaxed));
+            for _ in 0..num_ops {
+                let project_id = match scenario.distribution {
+                    Distribution::Hot => 0,
+                    Distribution::Uniform => rng.gen_range(0..SCENARIO_PROJECTS),
+                    Distribution::Zipf => zipf.sample(&mut rng),
+                };
+                if rng.gen_range(0..100) < scenario.write_percent {
+                    service.record_spending("test", project_id, rng.gen_range(0.0..allowed_budget));
+                } else {
+                    service.exceeds_budget("test", project_id);
+                }
+            }
+        });
+}
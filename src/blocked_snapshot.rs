@@ -0,0 +1,262 @@
+//! A read-only snapshot of the blocked projects in a shared memory file, for co-located clients.
+//!
+//! The file consists of native-endian 64-bit words. The header is made up of the `PBBLOCK1` magic,
+//! a sequence number which is odd while the snapshot is being updated, the capacity of entries,
+//! the number of entries, the total number of blocked projects (which might exceed the capacity),
+//! and the time of the last update in milliseconds since the unix epoch.
+//! It is followed by `capacity` entries, each of which is the [`config_hash`] of the config name
+//! and the project id. The first `count` entries are sorted, so that they can be binary searched.
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{fence, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+/// The magic number at the start of the snapshot file.
+const MAGIC: u64 = u64::from_le_bytes(*b"PBBLOCK1");
+
+/// The number of words of the header, which is followed by the entries.
+const HEADER_WORDS: usize = 6;
+
+const SEQUENCE: usize = 1;
+const CAPACITY: usize = 2;
+const COUNT: usize = 3;
+const TOTAL: usize = 4;
+const UPDATED_AT: usize = 5;
+
+/// How often reading a consistent snapshot is attempted while it is being updated, before giving up.
+const MAX_READ_ATTEMPTS: usize = 1_000;
+
+/// Returns the 64-bit FNV-1a hash of a config name, which identifies the config within a snapshot.
+pub fn config_hash(config: &str) -> u64 {
+    (config.bytes()).fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// A file mapped into memory, shared with other processes.
+#[derive(Debug)]
+struct Mapping {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// The mapping is only ever accessed through atomics.
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    fn new(file: &File, len: usize, writable: bool) -> io::Result<Self> {
+        let prot = match writable {
+            true => libc::PROT_READ | libc::PROT_WRITE,
+            false => libc::PROT_READ,
+        };
+        let fd = file.as_raw_fd();
+        let ptr = unsafe { libc::mmap(std::ptr::null_mut(), len, prot, libc::MAP_SHARED, fd, 0) };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { ptr, len })
+    }
+
+    fn words(&self) -> &[AtomicU64] {
+        // `mmap` returns page-aligned memory, and `AtomicU64` has the same layout as `u64`.
+        unsafe { std::slice::from_raw_parts(self.ptr as *const AtomicU64, self.len / 8) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+/// Writes the blocked projects to the snapshot file, see [`Service::share_blocked_projects`].
+///
+/// [`Service::share_blocked_projects`]: crate::Service::share_blocked_projects
+#[derive(Debug)]
+pub(crate) struct BlockedSnapshotWriter {
+    mapping: Mapping,
+    capacity: usize,
+}
+
+impl BlockedSnapshotWriter {
+    /// Creates the snapshot file at `path`, with room for `capacity` blocked projects.
+    ///
+    /// An existing file is replaced atomically, so that readers never see a partially initialized one.
+    pub fn create(path: &Path, capacity: usize) -> io::Result<Self> {
+        let mut tmp_path = PathBuf::from(path).into_os_string();
+        tmp_path.push(".tmp");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        let len = (HEADER_WORDS + 2 * capacity) * 8;
+        file.set_len(len as u64)?;
+
+        let mapping = Mapping::new(&file, len, true)?;
+        let words = mapping.words();
+        words[CAPACITY].store(capacity as u64, Ordering::Relaxed);
+        words[0].store(MAGIC, Ordering::Release);
+        fs::rename(&tmp_path, path)?;
+        Ok(Self { mapping, capacity })
+    }
+
+    /// Replaces the snapshot with the given `(config_hash, project_id)` pairs of blocked projects.
+    ///
+    /// Only the first `capacity` projects in sort order are written if there are more.
+    pub fn write(&mut self, blocked: &mut [(u64, u64)], now: SystemTime) {
+        blocked.sort_unstable();
+        let count = blocked.len().min(self.capacity);
+        let updated_at = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64);
+
+        let words = self.mapping.words();
+        let sequence = words[SEQUENCE].load(Ordering::Relaxed);
+        words[SEQUENCE].store(sequence + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        for (idx, &(config, project_id)) in blocked[..count].iter().enumerate() {
+            words[HEADER_WORDS + 2 * idx].store(config, Ordering::Relaxed);
+            words[HEADER_WORDS + 2 * idx + 1].store(project_id, Ordering::Relaxed);
+        }
+        words[COUNT].store(count as u64, Ordering::Relaxed);
+        words[TOTAL].store(blocked.len() as u64, Ordering::Relaxed);
+        words[UPDATED_AT].store(updated_at, Ordering::Relaxed);
+        words[SEQUENCE].store(sequence + 2, Ordering::Release);
+    }
+}
+
+/// A read-only view of the blocked projects shared by a [`Service`](crate::Service) on the same host.
+///
+/// Checking the state of a project is a binary search within shared memory, without any IPC.
+/// The snapshot is updated on every maintenance pass, so it lags behind the actual decisions
+/// by up to a second. Reads fail open, treating projects as not blocked while no consistent
+/// snapshot can be read.
+#[derive(Debug)]
+pub struct BlockedSnapshot {
+    mapping: Mapping,
+}
+
+impl BlockedSnapshot {
+    /// Opens the snapshot file at `path`, as written by [`Service::share_blocked_projects`].
+    ///
+    /// Returns an [`io::ErrorKind::InvalidData`] error if the file is not a snapshot.
+    ///
+    /// [`Service::share_blocked_projects`]: crate::Service::share_blocked_projects
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len() as usize;
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "not a blocked snapshot");
+        if len < HEADER_WORDS * 8 {
+            return Err(invalid());
+        }
+
+        let mapping = Mapping::new(&file, len, false)?;
+        let words = mapping.words();
+        let capacity = words[CAPACITY].load(Ordering::Relaxed) as usize;
+        if words[0].load(Ordering::Acquire) != MAGIC || capacity > (words.len() - HEADER_WORDS) / 2
+        {
+            return Err(invalid());
+        }
+        Ok(Self { mapping })
+    }
+
+    /// Returns whether the project of the given config was blocked as of the last update.
+    pub fn is_blocked(&self, config: &str, project_id: u64) -> bool {
+        let key = (config_hash(config), project_id);
+        self.read(|words, count| {
+            let entry = |idx: usize| {
+                let word =
+                    |offset: usize| words[HEADER_WORDS + 2 * idx + offset].load(Ordering::Relaxed);
+                (word(0), word(1))
+            };
+            let (mut low, mut high) = (0, count);
+            while low < high {
+                let mid = low + (high - low) / 2;
+                match entry(mid).cmp(&key) {
+                    std::cmp::Ordering::Less => low = mid + 1,
+                    std::cmp::Ordering::Greater => high = mid,
+                    std::cmp::Ordering::Equal => return true,
+                }
+            }
+            false
+        })
+        .unwrap_or(false)
+    }
+
+    /// Returns the total number of blocked projects as of the last update,
+    /// which might exceed the number of projects within the snapshot.
+    pub fn blocked_projects(&self) -> usize {
+        (self.read(|words, _count| words[TOTAL].load(Ordering::Relaxed) as usize)).unwrap_or(0)
+    }
+
+    /// Returns the time of the last update, which tells whether the snapshot is still being updated.
+    pub fn updated_at(&self) -> SystemTime {
+        let updated_at = self.read(|words, _count| words[UPDATED_AT].load(Ordering::Relaxed));
+        SystemTime::UNIX_EPOCH + Duration::from_millis(updated_at.unwrap_or(0))
+    }
+
+    /// Calls `f` with the words of the snapshot and the number of its entries,
+    /// retrying until it saw a consistent snapshot.
+    fn read<R>(&self, f: impl Fn(&[AtomicU64], usize) -> R) -> Option<R> {
+        let words = self.mapping.words();
+        let capacity = (words.len() - HEADER_WORDS) / 2;
+        for _ in 0..MAX_READ_ATTEMPTS {
+            let sequence = words[SEQUENCE].load(Ordering::Acquire);
+            if sequence.is_multiple_of(2) {
+                let count = (words[COUNT].load(Ordering::Relaxed) as usize).min(capacity);
+                let result = f(words, count);
+                fence(Ordering::Acquire);
+                if words[SEQUENCE].load(Ordering::Relaxed) == sequence {
+                    return Some(result);
+                }
+            }
+            std::thread::yield_now();
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocked_snapshot() {
+        let path = std::env::temp_dir().join(format!("pb-snapshot-{}", std::process::id()));
+        let mut writer = BlockedSnapshotWriter::create(&path, 2).unwrap();
+        let reader = BlockedSnapshot::open(&path).unwrap();
+        assert!(!reader.is_blocked("a", 1));
+        assert_eq!(reader.updated_at(), SystemTime::UNIX_EPOCH);
+
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let (a, b) = (config_hash("a"), config_hash("b"));
+        writer.write(&mut [(b, 1), (a, 2)], now);
+        assert!(reader.is_blocked("a", 2));
+        assert!(reader.is_blocked("b", 1));
+        assert!(!reader.is_blocked("a", 1));
+        assert_eq!(reader.updated_at(), now);
+
+        // only the capacity is written, but all the blocked projects are counted
+        writer.write(&mut [(a, 3), (a, 2), (a, 1)], now);
+        assert!(reader.is_blocked("a", 1) && reader.is_blocked("a", 2));
+        assert!(!reader.is_blocked("a", 3) && !reader.is_blocked("b", 1));
+        assert_eq!(reader.blocked_projects(), 3);
+
+        // readers keep the mapping of a replaced file
+        let replaced = BlockedSnapshotWriter::create(&path, 1).unwrap();
+        assert!(reader.is_blocked("a", 1));
+        drop(replaced);
+        assert!(!BlockedSnapshot::open(&path).unwrap().is_blocked("a", 1));
+
+        std::fs::write(&path, [0; 64]).unwrap();
+        let err = BlockedSnapshot::open(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,173 @@
+//! Compares the decisions of this service against a secondary instance, for a sample of projects.
+//!
+//! This validates changes to the budgeting logic, like new accounting strategies, against a reference
+//! instance (usually running the previous version) before cutting over. All the requests of sampled
+//! projects are forwarded, so that the secondary instance sees their full spending.
+//! Requests are forwarded in the background once the local decision has been made,
+//! so the canary never adds latency, nor does it affect any decisions.
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use peanutbutter::{write_metric, MetricKind, MetricsWriter};
+use serde::{Deserialize, Serialize};
+
+use crate::http_source::HttpSource;
+use crate::request_id::RequestId;
+use crate::sampling::ProjectSampler;
+
+/// The maximum number of requests that are forwarded concurrently.
+///
+/// Requests beyond this are skipped, so that a slow secondary instance can not pile up tasks.
+const MAX_IN_FLIGHT: usize = 256;
+
+/// The response of the secondary instance.
+#[derive(Deserialize)]
+struct CanaryResponse {
+    exceeds_budget: bool,
+}
+
+/// A secondary instance to which the requests of sampled projects are forwarded.
+#[derive(Debug)]
+pub struct Canary {
+    /// The base URL of the secondary instance.
+    source: HttpSource,
+    /// Decides which projects are forwarded.
+    sampler: ProjectSampler,
+    /// The number of requests that are currently being forwarded.
+    in_flight: AtomicUsize,
+    /// The number of decisions that were compared.
+    comparisons: AtomicU64,
+    /// The number of decisions of the secondary instance that differed from the local ones.
+    divergences: AtomicU64,
+    /// The number of requests that failed, or were skipped because of [`MAX_IN_FLIGHT`].
+    failures: AtomicU64,
+}
+
+impl Canary {
+    /// Creates a [`Canary`] forwarding the given fraction of projects to the `url`.
+    pub fn new(url: &str, sample_rate: f64) -> Result<Self, String> {
+        Ok(Self {
+            source: HttpSource::new(url)?,
+            sampler: ProjectSampler::new(sample_rate)?,
+            in_flight: AtomicUsize::new(0),
+            comparisons: AtomicU64::new(0),
+            divergences: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+        })
+    }
+
+    /// Forwards the `request` of a sampled project to the `path` of the secondary instance,
+    /// and compares its decision with the `local` one in the background.
+    ///
+    /// Divergences are logged along with the [`RequestId`] of the local request.
+    pub fn compare(
+        self: &Arc<Self>,
+        path: &'static str,
+        project_id: u64,
+        request: &impl Serialize,
+        request_id: &RequestId,
+        local: bool,
+    ) {
+        if !self.sampler.is_sampled(project_id) {
+            return;
+        }
+        if self.in_flight.fetch_add(1, Ordering::Relaxed) >= MAX_IN_FLIGHT {
+            self.in_flight.fetch_sub(1, Ordering::Relaxed);
+            self.failures.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let Ok(body) = serde_json::to_string(request) else {
+            self.in_flight.fetch_sub(1, Ordering::Relaxed);
+            return;
+        };
+
+        let canary = self.clone();
+        let request_id = request_id.clone();
+        tokio::spawn(async move {
+            let result = canary.source.post::<CanaryResponse>(path, &body).await;
+            canary.in_flight.fetch_sub(1, Ordering::Relaxed);
+            match result {
+                Ok(response) => {
+                    canary.comparisons.fetch_add(1, Ordering::Relaxed);
+                    if response.exceeds_budget != local {
+                        canary.divergences.fetch_add(1, Ordering::Relaxed);
+                        println!(
+                            "Canary diverged on `{path}` {body} (request `{request_id}`): \
+                             local decision {local}, canary decision {}",
+                            response.exceeds_budget
+                        );
+                    }
+                }
+                Err(_) => {
+                    canary.failures.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        });
+    }
+
+    /// Writes the metrics of the canary comparison to `out`.
+    pub fn write_metrics(&self, out: &mut impl MetricsWriter) {
+        write_metric(
+            out,
+            "peanutbutter_canary_comparisons_total",
+            MetricKind::Counter,
+            "Number of decisions compared against the canary instance.",
+            self.comparisons.load(Ordering::Relaxed),
+        );
+        write_metric(
+            out,
+            "peanutbutter_canary_divergences_total",
+            MetricKind::Counter,
+            "Number of decisions of the canary instance that differed from the local ones.",
+            self.divergences.load(Ordering::Relaxed),
+        );
+        write_metric(
+            out,
+            "peanutbutter_canary_failures_total",
+            MetricKind::Counter,
+            "Number of requests to the canary instance that failed or were skipped.",
+            self.failures.load(Ordering::Relaxed),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_compare() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _addr) = listener.accept().await.unwrap();
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).await.unwrap();
+                let response = "HTTP/1.0 200 OK\r\n\r\n{\"exceeds_budget\": true}";
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        let canary = Arc::new(Canary::new(&url, 1.).unwrap());
+        let request_id = RequestId::generate();
+        canary.compare("/exceeds_budget", 1, &[1], &request_id, true);
+        canary.compare("/exceeds_budget", 2, &[2], &request_id, false);
+
+        for _ in 0..100 {
+            if canary.comparisons.load(Ordering::Relaxed) == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(canary.comparisons.load(Ordering::Relaxed), 2);
+        assert_eq!(canary.divergences.load(Ordering::Relaxed), 1);
+        assert_eq!(canary.failures.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,165 @@
+//! Captures the requests of a sample of projects to a file, so that they can be [replayed](crate::replay).
+//!
+//! Each line of the file is a JSON [`CapturedEntry`], with the wall-clock time of the request
+//! and the decision that was made. The file is written by a background thread, and entries are
+//! dropped instead of blocking requests when it falls behind.
+
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::time::SystemTime;
+
+use peanutbutter::{write_metric, DecisionReason, MetricKind, MetricsWriter};
+use serde::{Deserialize, Serialize};
+
+use crate::request_id::RequestId;
+use crate::sampling::ProjectSampler;
+use crate::{ExceedsBudgetRequest, RecordSpendingRequest};
+
+/// The maximum number of entries waiting to be written.
+const MAX_PENDING_ENTRIES: usize = 16 * 1024;
+
+/// A request that can be captured, tagged by its path.
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "path")]
+pub enum CapturedRequest {
+    #[serde(rename = "/record_spending")]
+    RecordSpending(RecordSpendingRequest),
+    #[serde(rename = "/exceeds_budget")]
+    ExceedsBudget(ExceedsBudgetRequest),
+}
+
+impl CapturedRequest {
+    /// Returns the name of the config of the request.
+    pub fn config_name(&self) -> &str {
+        match self {
+            Self::RecordSpending(request) => &request.config_name,
+            Self::ExceedsBudget(request) => &request.config_name,
+        }
+    }
+
+    /// Returns the id of the project of the request.
+    pub fn project_id(&self) -> u64 {
+        match self {
+            Self::RecordSpending(request) => request.project_id,
+            Self::ExceedsBudget(request) => request.project_id,
+        }
+    }
+}
+
+/// A single line of the capture file.
+#[derive(Deserialize, Serialize)]
+pub struct CapturedEntry {
+    /// The wall-clock time of the request, in milliseconds since the UNIX epoch.
+    pub timestamp_ms: u64,
+    #[serde(flatten)]
+    pub request: CapturedRequest,
+    /// The decision that was made for the request.
+    pub exceeds_budget: bool,
+    /// The [`RequestId`] of the request, which is missing in files captured by older versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// The reason of the decision, which is missing for unknown configs and in older files.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<DecisionReason>,
+}
+
+/// Writes the requests of sampled projects to a capture file.
+#[derive(Debug)]
+pub struct Capture {
+    /// Decides which projects are captured.
+    sampler: ProjectSampler,
+    /// The queue of serialized entries, written by the background thread.
+    sender: SyncSender<String>,
+    /// The number of captured entries.
+    captured: AtomicU64,
+    /// The number of entries that were dropped because the background thread fell behind.
+    dropped: AtomicU64,
+}
+
+impl Capture {
+    /// Creates a [`Capture`] appending the given fraction of projects to the file at `path`.
+    pub fn new(path: &Path, sample_rate: f64) -> Result<Self, String> {
+        let sampler = ProjectSampler::new(sample_rate)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| format!("failed to open capture file `{}`: {err}", path.display()))?;
+
+        let (sender, receiver) = mpsc::sync_channel::<String>(MAX_PENDING_ENTRIES);
+        std::thread::spawn(move || {
+            let mut file = BufWriter::new(file);
+            while let Ok(line) = receiver.recv() {
+                let mut result = writeln!(file, "{line}");
+                // Batch all the entries that are already waiting, and flush once the queue is empty.
+                while let (Ok(()), Ok(line)) = (&result, receiver.try_recv()) {
+                    result = writeln!(file, "{line}");
+                }
+                if let Err(err) = result.and_then(|()| file.flush()) {
+                    println!("Failed to write capture file: {err}");
+                }
+            }
+        });
+
+        Ok(Self {
+            sampler,
+            sender,
+            captured: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// Captures the `request` along with its decision and id, if its project is sampled.
+    pub fn record(
+        &self,
+        request: CapturedRequest,
+        request_id: &RequestId,
+        exceeds_budget: bool,
+        reason: Option<DecisionReason>,
+    ) {
+        if !self.sampler.is_sampled(request.project_id()) {
+            return;
+        }
+        let timestamp_ms = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64);
+        let entry = CapturedEntry {
+            timestamp_ms,
+            request,
+            exceeds_budget,
+            request_id: Some(request_id.to_string()),
+            reason,
+        };
+        let Ok(line) = serde_json::to_string(&entry) else {
+            return;
+        };
+
+        match self.sender.try_send(line) {
+            Ok(()) => self.captured.fetch_add(1, Ordering::Relaxed),
+            Err(TrySendError::Full(_) | TrySendError::Disconnected(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed)
+            }
+        };
+    }
+
+    /// Writes the metrics of the capture to `out`.
+    pub fn write_metrics(&self, out: &mut impl MetricsWriter) {
+        write_metric(
+            out,
+            "peanutbutter_capture_entries_total",
+            MetricKind::Counter,
+            "Number of requests written to the capture file.",
+            self.captured.load(Ordering::Relaxed),
+        );
+        write_metric(
+            out,
+            "peanutbutter_capture_dropped_total",
+            MetricKind::Counter,
+            "Number of requests not captured because writing the capture file fell behind.",
+            self.dropped.load(Ordering::Relaxed),
+        );
+    }
+}
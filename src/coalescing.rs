@@ -0,0 +1,132 @@
+//! Coalesces concurrent identical `exceeds_budget` checks into a single call to the [`Service`].
+//!
+//! Under thundering-herd patterns, many checks of the same project arrive at once, all contending
+//! for the lock of that single project. With coalescing, only the first of these checks calls into the
+//! [`Service`], while the others wait for and share its decision.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use tokio::sync::watch;
+
+use crate::{config_hash, write_metric, ConfigError, Decision, MetricKind, MetricsWriter, Service};
+
+/// The decision of a check that is in flight, which is shared with all the coalesced checks.
+type Pending = watch::Sender<Option<Result<Decision, ConfigError>>>;
+
+/// Deduplicates concurrent checks of the same config and project.
+///
+/// Waiting checks yield to the async runtime until the decision is made, instead of blocking their thread.
+#[derive(Debug, Default)]
+pub struct Coalescer {
+    /// The checks that are currently in flight, keyed by [`config_hash`] and project id.
+    ///
+    /// Just like in a [`BlockedSnapshot`](crate::BlockedSnapshot), configs are identified by the hash
+    /// of their name, so that checks do not need to allocate a key.
+    in_flight: DashMap<(u64, u64), Arc<Pending>>,
+    /// The number of checks that shared the decision of another one.
+    coalesced: AtomicU64,
+}
+
+impl Coalescer {
+    /// Checks whether the project exceeds its budget, see [`Service::try_exceeds_budget_with_reason`].
+    ///
+    /// If the same check is already in flight, this waits for its decision instead.
+    pub async fn try_exceeds_budget(
+        &self,
+        service: &Service,
+        config: &str,
+        project_id: u64,
+    ) -> Result<Decision, ConfigError> {
+        let key = (config_hash(config), project_id);
+        // Most checks of a herd join the one in flight, which only needs a read lock.
+        let joined = self.in_flight.get(&key).map(|pending| pending.subscribe());
+        let mut receiver = match joined {
+            Some(receiver) => receiver,
+            None => match self.in_flight.entry(key) {
+                Entry::Occupied(entry) => entry.get().subscribe(),
+                Entry::Vacant(entry) => {
+                    let pending = entry.insert(Arc::new(watch::Sender::new(None))).clone();
+                    let decision =
+                        (service.try_exceeds_budget_by_key_async(config, project_id.into())).await;
+                    // Checks arriving from now on make their own decision.
+                    self.in_flight
+                        .remove_if(&key, |_key, other| Arc::ptr_eq(other, &pending));
+                    pending.send_replace(Some(decision.clone()));
+                    return decision;
+                }
+            },
+        };
+
+        self.coalesced.fetch_add(1, Ordering::Relaxed);
+        let decision = match receiver.wait_for(Option::is_some).await {
+            Ok(decision) => decision.clone(),
+            // The check in flight was abandoned without a decision.
+            Err(_) => None,
+        };
+        match decision {
+            Some(decision) => decision,
+            None => (service.try_exceeds_budget_by_key_async(config, project_id.into())).await,
+        }
+    }
+
+    /// Writes the coalescing metrics to `out`.
+    pub fn write_metrics(&self, out: &mut impl MetricsWriter) {
+        write_metric(
+            out,
+            "peanutbutter_coalesced_checks_total",
+            MetricKind::Counter,
+            "Number of exceeds_budget checks that shared the decision of a concurrent identical check.",
+            self.coalesced.load(Ordering::Relaxed),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::BudgetingConfig;
+
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_coalescing() {
+        let service = Arc::new(Service::new());
+        let config = BudgetingConfig::new(
+            Duration::from_secs(60),
+            Duration::from_secs(10),
+            Duration::from_secs(1),
+            10.,
+        );
+        service.try_add_config("test", config).unwrap();
+        service.record_spending("test", 1, 1_000.);
+
+        let coalescer = Arc::new(Coalescer::default());
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let (service, coalescer) = (service.clone(), coalescer.clone());
+                tokio::spawn(async move {
+                    for _ in 0..1_000 {
+                        for (project_id, blocked) in [(1, true), (2, false)] {
+                            let decision =
+                                coalescer.try_exceeds_budget(&service, "test", project_id);
+                            let exceeds_budget = decision.await.map(|d| d.exceeds_budget);
+                            assert_eq!(exceeds_budget, Ok(blocked));
+                        }
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert!(coalescer.in_flight.is_empty());
+        assert_eq!(
+            coalescer.try_exceeds_budget(&service, "unknown", 1).await,
+            Err(ConfigError::Unknown("unknown".into()))
+        );
+    }
+}
@@ -0,0 +1,308 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+
+use quanta::Instant;
+
+use crate::config::{saturating_add, BudgetingConfig};
+use crate::stats::{ProjectReport, RefundError};
+use crate::tracker::{BudgetTracker, SourceLog, TransitionLog};
+
+/// A [`BudgetTracker`] capping the number of concurrently held slots of a project.
+///
+/// Acquired slots are held until they are released again,
+/// and the [`budget`](BudgetingConfig::budget) is the number of slots a project may hold at once.
+/// Slots are usually acquired via [`Service::acquire`](crate::Service::acquire),
+/// which releases them automatically once they expire, so that leaked slots do not block a project forever.
+/// Plain recorded spending can not be released explicitly, so its slots are held for one
+/// [`budgeting_window`](BudgetingConfig::budgeting_window), and released automatically afterwards.
+///
+/// In contrast to [`ProjectStats`](crate::ProjectStats), there is no backoff,
+/// and a project is unblocked as soon as it releases its slots.
+/// The [`budgeting_window`](BudgetingConfig::budgeting_window) is how long a project without any
+/// held slots is kept around.
+#[derive(Debug)]
+pub struct ConcurrencyTracker {
+    /// Configuration that governs the number of slots.
+    config: Arc<BudgetingConfig>,
+
+    /// The number of acquired slots, which are held until they are released.
+    held: f64,
+
+    /// The slots held by recorded spending, along with the time they expire at, oldest first.
+    expiring: VecDeque<(Instant, f64)>,
+
+    /// Whether this project held more slots than allowed when it was last checked.
+    exceeds_budget: bool,
+
+    /// The time at which this project started exceeding its budget, if it currently does.
+    blocked_since: Option<Instant>,
+
+    /// The recent changes of the "exceeded" state.
+    transitions: TransitionLog,
+
+    /// The recent sources of the acquired slots.
+    sources: SourceLog,
+
+    /// The time at which this tracker was created.
+    first_seen: Instant,
+
+    /// The time at which slots were last acquired or released.
+    last_updated: Instant,
+}
+
+impl ConcurrencyTracker {
+    /// Creates a new per-project tracker based on the given [`BudgetingConfig`].
+    pub fn new(config: Arc<BudgetingConfig>) -> Self {
+        let now = config.now();
+        Self {
+            config,
+            held: 0.,
+            expiring: VecDeque::new(),
+            exceeds_budget: false,
+            blocked_since: None,
+            transitions: TransitionLog::default(),
+            sources: SourceLog::default(),
+            first_seen: now,
+            last_updated: now,
+        }
+    }
+
+    /// Returns the number of currently held slots.
+    pub fn held(&self) -> f64 {
+        self.held_at(self.config.now())
+    }
+
+    /// Returns the number of slots held at the given `now`, ignoring the slots of expired spending.
+    fn held_at(&self, now: Instant) -> f64 {
+        let expiring = self
+            .expiring
+            .iter()
+            .filter(|(expires_at, _)| *expires_at > now);
+        self.held + expiring.map(|(_, slots)| slots).sum::<f64>()
+    }
+
+    /// Updates the "exceeded" state according to the currently held slots.
+    ///
+    /// A stale recent `now` does not move the last update back in time.
+    fn update(&mut self, now: Instant) -> bool {
+        self.last_updated = self.last_updated.max(now);
+        self.check()
+    }
+}
+
+impl BudgetTracker for ConcurrencyTracker {
+    /// Recorded spending holds its slots for one budgeting window, as it is never released explicitly.
+    fn record(&mut self, spent: f64) -> bool {
+        let now = self.config.now().max(self.last_updated);
+        // Spending expiring within the same bucket is merged, which bounds the number of entries.
+        let expires_at = self
+            .config
+            .truncated_now(saturating_add(now, self.config.budgeting_window));
+        // `max` also turns `NaN` into `0`.
+        let spent = spent.max(0.);
+        match self.expiring.back_mut() {
+            Some(latest) if latest.0 >= expires_at => latest.1 += spent,
+            _ => self.expiring.push_back((expires_at, spent)),
+        }
+        self.update(now)
+    }
+
+    fn acquire(&mut self, acquired: f64) -> bool {
+        // `max` also turns `NaN` into `0`.
+        self.held += acquired.max(0.);
+        self.update(self.config.now())
+    }
+
+    fn record_source(&mut self, source: &str) {
+        self.sources.record(source);
+    }
+
+    /// Refunds are treated just like releases, if the config allows them.
+    fn refund(&mut self, refunded: f64) -> Result<bool, RefundError> {
+        if !self.config.allow_refunds {
+            return Err(RefundError::NotAllowed);
+        }
+        if !(refunded.is_finite() && refunded >= 0.) {
+            return Err(RefundError::InvalidAmount);
+        }
+        Ok(self.release(refunded, self.config.now()))
+    }
+
+    /// Slots are not bucketed, so they are released regardless of when they were acquired.
+    ///
+    /// Anything beyond the acquired slots is released from the slots of the most recently recorded spending.
+    fn release(&mut self, released: f64, _recorded_at: Instant) -> bool {
+        let mut released = released.max(0.);
+        let from_held = released.min(self.held);
+        self.held -= from_held;
+        released -= from_held;
+        for (_, slots) in self.expiring.iter_mut().rev() {
+            let from_slots = released.min(*slots);
+            *slots -= from_slots;
+            released -= from_slots;
+        }
+        self.update(self.config.now())
+    }
+
+    fn check(&mut self) -> bool {
+        let now = self.config.now().max(self.last_updated);
+        while self
+            .expiring
+            .front()
+            .is_some_and(|(expires_at, _)| *expires_at <= now)
+        {
+            self.expiring.pop_front();
+        }
+        let exceeds_budget = self.held_at(now) > self.config.budget;
+        if self.exceeds_budget != exceeds_budget {
+            self.exceeds_budget = exceeds_budget;
+            self.blocked_since = exceeds_budget.then_some(now);
+            self.transitions.record(now);
+        }
+        exceeds_budget
+    }
+
+    fn cached_check(&self) -> bool {
+        self.exceeds_budget
+    }
+
+    fn would_exceed(&self, spent: f64, now: Instant) -> bool {
+        self.held_at(now) + spent.max(0.) > self.config.budget
+    }
+
+    /// Projects holding acquired slots are never stale, as the slots are released once they expire.
+    ///
+    /// The slots of recorded spending expire within one budgeting window after the last update.
+    fn is_stale(&self, now: Instant) -> bool {
+        self.held == 0.
+            && now.saturating_duration_since(self.last_updated) > self.config.budgeting_window
+    }
+
+    fn report(&self, now: Instant) -> ProjectReport {
+        let held = self.held_at(now);
+        ProjectReport {
+            exceeds_budget: self.exceeds_budget,
+            spent_budget: held,
+            budget: self.config.budget,
+            backoff_remaining: None,
+            remaining_budget: (self.config.budget - held).max(0.),
+            blocked_for: self
+                .blocked_since
+                .map(|since| now.saturating_duration_since(since)),
+            transitions: self.transitions.count(now),
+            last_transition: self.transitions.last(now),
+            first_seen: self.first_seen,
+            last_updated: self.last_updated,
+            recent_sources: self.sources.recent(),
+        }
+    }
+
+    fn skip_time(&mut self, skipped: Duration) {
+        if let Some(since) = &mut self.blocked_since {
+            *since += skipped;
+        }
+        for (expires_at, _) in &mut self.expiring {
+            *expires_at += skipped;
+        }
+        self.last_updated += skipped;
+    }
+
+    fn config(&self) -> &Arc<BudgetingConfig> {
+        &self.config
+    }
+
+    fn set_config(&mut self, config: Arc<BudgetingConfig>) {
+        self.config = config;
+    }
+
+    fn memory_usage(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.expiring.capacity() * std::mem::size_of::<(Instant, f64)>()
+            + self.transitions.memory_usage()
+            + self.sources.memory_usage()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use quanta::Clock;
+
+    use crate::config::Timer;
+
+    use super::*;
+
+    #[test]
+    fn test_concurrency() {
+        let (clock, mock) = Clock::mock();
+        mock.increment(Duration::from_secs(100));
+        let timer = Timer::new(clock);
+
+        let config = BudgetingConfig::new(
+            Duration::from_secs(10),
+            Duration::from_secs(5),
+            Duration::from_secs(1),
+            2.,
+        )
+        .with_timer(timer.clone());
+        let mut tracker = ConcurrencyTracker::new(Arc::new(config));
+
+        assert!(!tracker.acquire(2.));
+        assert!(tracker.would_exceed(1., timer.now()));
+        assert_eq!(tracker.report(timer.now()).remaining_budget, 0.);
+        assert!(tracker.acquire(1.));
+        assert!(tracker.cached_check());
+
+        // there is no backoff, releasing slots unblocks right away
+        mock.increment(Duration::from_secs(1));
+        assert!(!tracker.release(1., timer.now()));
+        assert_eq!(tracker.held(), 2.);
+        assert_eq!(tracker.report(timer.now()).transitions, 2);
+        assert_eq!(tracker.refund(1.), Err(RefundError::NotAllowed));
+
+        // held slots are kept around until they are released
+        mock.increment(Duration::from_secs(10));
+        assert!(!tracker.is_stale(timer.now()));
+        tracker.release(5., timer.now());
+        assert_eq!(tracker.held(), 0.);
+        assert!(!tracker.is_stale(timer.now()));
+        mock.increment(Duration::from_secs(6));
+        assert!(tracker.is_stale(timer.now()));
+    }
+
+    #[test]
+    fn test_recorded_slots_expire() {
+        let (clock, mock) = Clock::mock();
+        mock.increment(Duration::from_secs(100));
+        let timer = Timer::new(clock);
+
+        let config = BudgetingConfig::new(
+            Duration::from_secs(10),
+            Duration::from_secs(5),
+            Duration::from_secs(1),
+            2.,
+        )
+        .with_timer(timer.clone());
+        let mut tracker = ConcurrencyTracker::new(Arc::new(config));
+
+        // plain recorded spending is never released, so its slots expire after the window
+        assert!(!tracker.acquire(1.));
+        assert!(tracker.record(2.));
+        mock.increment(Duration::from_secs(3));
+        assert!(tracker.record(1.));
+        assert_eq!(tracker.held(), 4.);
+
+        mock.increment(Duration::from_secs(2));
+        assert_eq!(tracker.held(), 2.);
+        assert!(!tracker.check());
+        mock.increment(Duration::from_secs(3));
+        assert_eq!(tracker.held(), 1.);
+
+        // only the acquired slots keep the project around
+        tracker.release(1., timer.now());
+        assert!(!tracker.is_stale(timer.now()));
+        mock.increment(Duration::from_secs(6));
+        assert!(tracker.is_stale(timer.now()));
+        assert_eq!(tracker.held(), 0.);
+    }
+}
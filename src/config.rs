@@ -1,11 +1,347 @@
+use std::fmt;
+use std::sync::Arc;
+#[cfg(feature = "service")]
+use std::sync::{Mutex, PoisonError};
 use std::time::Duration;
 
+#[cfg(feature = "service")]
+use arc_swap::{ArcSwap, Guard};
+#[cfg(feature = "service")]
+use dashmap::DashSet;
+#[cfg(feature = "service")]
+use indexmap::IndexMap;
 use quanta::{Clock, Instant};
+use serde::{Deserialize, Serialize};
+
+use crate::concurrency::ConcurrencyTracker;
+#[cfg(feature = "service")]
+use crate::metrics::{Counter, DecisionCounters};
+use crate::stats::ProjectStats;
+use crate::tracker::BudgetTracker;
+#[cfg(feature = "service")]
+use crate::{ProjectKey, StateStore};
+
+/// The interval at which the recent time is updated by the maintenance of a [`Service`](crate::Service).
+///
+/// Configs with buckets smaller than this read the precise time instead, as the recent time
+/// would otherwise sort the spending of several buckets into a single one.
+pub(crate) const RECENT_TIME_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The longest duration of a [`BudgetingConfig`], like its window or backoff, that is accepted when deserializing it.
+///
+/// Longer durations are most likely a mistake, and would overflow the clock when added to it.
+pub const MAX_CONFIG_DURATION: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// Returns `instant + duration`, saturating at the latest representable [`Instant`] instead of panicking.
+pub(crate) fn saturating_add(instant: Instant, duration: Duration) -> Instant {
+    // quanta truncates durations to 64 bits of nanoseconds when adding them, so they are clamped first.
+    let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+    if let Some(sum) = instant.checked_add(Duration::from_nanos(nanos)) {
+        return sum;
+    }
+    // quanta does not expose the latest representable instant, so it is searched for.
+    let (mut low, mut high) = (0, nanos);
+    while low < high {
+        let mid = low + (high - low).div_ceil(2);
+        match instant.checked_add(Duration::from_nanos(mid)) {
+            Some(_) => low = mid,
+            None => high = mid - 1,
+        }
+    }
+    instant + Duration::from_nanos(low)
+}
+
+/// An error that can happen when registering a [`BudgetingConfig`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    /// A config with the given name is already registered.
+    Duplicate(String),
+    /// No config with the given name is registered.
+    Unknown(String),
+    /// A config with the given name was removed, and its project state is still being purged.
+    Draining(String),
+    /// The maximum number of configs is registered already, see [`Service::set_max_configs`].
+    ///
+    /// [`Service::set_max_configs`]: crate::Service::set_max_configs
+    TooMany(usize),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Duplicate(name) => write!(f, "config `{name}` is already registered"),
+            Self::Unknown(name) => write!(f, "config `{name}` is not registered"),
+            Self::Draining(name) => {
+                write!(f, "config `{name}` was removed, and is still being purged")
+            }
+            Self::TooMany(max) => write!(f, "the maximum of {max} configs is registered already"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// What should happen to the existing project state when a config is replaced.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplaceState {
+    /// Existing project state is kept, and evaluated against the new config from now on.
+    ///
+    /// If the `bucket_size` changes, the spending of the recorded buckets is split into buckets of the new size,
+    /// proportionally to how much of each old bucket they cover.
+    Keep,
+    /// All existing project state is dropped, and projects start from scratch.
+    Reset,
+}
+
+/// Whether the decisions of a config are enforced.
+///
+/// This can be changed at runtime via [`Service::set_enforcement`](crate::Service::set_enforcement),
+/// for example to quickly switch off enforcement during incidents.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Enforcement {
+    /// Spending is recorded, and projects exceeding their budget are reported as such.
+    #[default]
+    On,
+    /// Spending is recorded, but projects are never reported as exceeding their budget.
+    ///
+    /// Projects that would have been reported are counted in the metrics instead.
+    DryRun,
+    /// Spending is not recorded at all, and projects never exceed their budget.
+    Off,
+}
+
+/// A stable identifier of a registered config, assigned at registration.
+///
+/// Contrary to the position of a config, this never changes,
+/// and it is not reused when a config is removed and added again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ConfigId(pub u32);
+
+impl fmt::Display for ConfigId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// A [`BudgetingConfig`] as registered within a [`Service`](crate::Service).
+#[cfg(feature = "service")]
+#[derive(Clone)]
+pub struct RegisteredConfig {
+    /// The stable identifier of this config.
+    pub id: ConfigId,
+    /// The config itself.
+    pub config: Arc<BudgetingConfig>,
+    /// Whether the decisions of this config are currently enforced.
+    pub enforcement: Enforcement,
+    /// The stats/budgets of all the projects of this config.
+    ///
+    /// Each config has its own store, so that configs with a large number of projects
+    /// do not affect the lock contention and maintenance of other configs.
+    pub(crate) projects: Arc<dyn StateStore>,
+    /// The projects which exceeded their budget when they were last evaluated.
+    ///
+    /// This mirrors the [cached check](BudgetTracker::cached_check) of the trackers, so that it can be
+    /// answered without locking the [`StateStore`], and is reconciled with it by the maintenance.
+    pub(crate) blocked: Arc<DashSet<ProjectKey>>,
+    /// The answers of the budget checks of this config, which are kept when the config is replaced.
+    pub(crate) decisions: Arc<DecisionCounters>,
+    /// The number of records above the [`max_single_spend`](BudgetingConfig::max_single_spend) of this config.
+    pub(crate) oversized_spends: Arc<Counter>,
+    /// Whether this config was removed, in which case it is kept as a tombstone.
+    pub(crate) removal: Option<Removal>,
+}
+
+/// The registered configs of a [`Service`](crate::Service), keyed by name.
+///
+/// Requests look up configs in an immutable snapshot, which does not take any lock.
+/// Changes to the configs are rare, and publish a changed copy of the snapshot, one change at a time.
+#[cfg(feature = "service")]
+#[derive(Debug, Default)]
+pub(crate) struct ConfigRegistry {
+    /// The current snapshot of the configs.
+    snapshot: ArcSwap<IndexMap<String, RegisteredConfig>>,
+    /// Serializes changes, so that concurrent changes do not overwrite each other.
+    changes: Mutex<()>,
+}
+
+#[cfg(feature = "service")]
+impl ConfigRegistry {
+    /// Returns the current snapshot of the configs, without taking any lock.
+    pub fn load(&self) -> Guard<Arc<IndexMap<String, RegisteredConfig>>> {
+        self.snapshot.load()
+    }
+
+    /// Changes a copy of the configs via `f`, and publishes it as the new snapshot.
+    pub fn update<R>(&self, f: impl FnOnce(&mut IndexMap<String, RegisteredConfig>) -> R) -> R {
+        let _changes = self.changes.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut configs = IndexMap::clone(&self.snapshot.load());
+        let result = f(&mut configs);
+        self.snapshot.store(Arc::new(configs));
+        result
+    }
+}
+
+/// The state of a removed config.
+///
+/// The slot of a removed config is kept until the maintenance thread purged all of its project state,
+/// so that the config index is not reused while state keyed by it still exists.
+#[cfg(feature = "service")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Removal {
+    /// The config was removed, but its project state was not purged yet.
+    Draining,
+    /// All project state was purged, and the slot can be reused by a config of the same name.
+    Purged,
+}
+
+#[cfg(feature = "service")]
+impl RegisteredConfig {
+    /// Returns the [`Enforcement`] that applies to the decisions of this config.
+    ///
+    /// This is [`Enforcement::Off`] while the config is not [`enabled`](BudgetingConfig::enabled).
+    pub fn effective_enforcement(&self) -> Enforcement {
+        if self.config.enabled {
+            self.enforcement
+        } else {
+            Enforcement::Off
+        }
+    }
+
+    /// Applies the [`max_single_spend`](BudgetingConfig::max_single_spend) to a single record of `spent` budget.
+    ///
+    /// Returns the budget to record, or [`None`] if the record is rejected.
+    pub(crate) fn cap_spend(&self, spent: f64) -> Option<f64> {
+        match self.config.max_single_spend {
+            Some(max_single_spend) if spent > max_single_spend => {
+                self.oversized_spends.add(1);
+                (self.config.oversized_spend == OversizedSpend::Clamp).then_some(max_single_spend)
+            }
+            _ => Some(spent),
+        }
+    }
+
+    /// Updates the [blocked](RegisteredConfig::blocked) projects with the cached check of a `tracker`.
+    ///
+    /// This is called while the tracker is locked in the [`StateStore`], so that concurrent updates
+    /// of the same project are applied in order.
+    pub(crate) fn cache_check(&self, project_id: ProjectKey, tracker: &dyn BudgetTracker) {
+        // Only changes take the write lock of the set.
+        let exceeds_budget = tracker.cached_check();
+        if exceeds_budget != self.blocked.contains(&project_id) {
+            match exceeds_budget {
+                true => self.blocked.insert(project_id),
+                false => self.blocked.remove(&project_id).is_some(),
+            };
+        }
+    }
+}
+
+#[cfg(feature = "service")]
+impl fmt::Debug for RegisteredConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RegisteredConfig")
+            .field("id", &self.id)
+            .field("config", &self.config)
+            .field("enforcement", &self.enforcement)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The strategy used to account for the spending of each project.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub enum AccountingStrategy {
+    /// Spending is sorted into time buckets, and averaged over a sliding window.
+    ///
+    /// This is implemented by [`ProjectStats`].
+    #[default]
+    SlidingWindow,
+    /// Acquired slots are held until released, capping the concurrency of each project.
+    ///
+    /// Plain recorded spending holds its slots for one budgeting window instead, as it is never released.
+    ///
+    /// This is implemented by [`ConcurrencyTracker`].
+    Concurrency,
+}
+
+/// How requests for a config are answered when they are shed because the service is overloaded.
+///
+/// This applies to budget checks and recorded spending which are rejected by the transports,
+/// for example because of the request limits per client host.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverloadPolicy {
+    /// The request is rejected with an explicit error.
+    #[default]
+    Reject,
+    /// The request is answered with a decision that the project does not exceed its budget.
+    ///
+    /// The decision comes with the [`DecisionReason::Overloaded`](crate::DecisionReason::Overloaded).
+    Allow,
+}
+
+/// What happens to a single record of spending above the [`BudgetingConfig::max_single_spend`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OversizedSpend {
+    /// The spending is recorded as the `max_single_spend`.
+    #[default]
+    Clamp,
+    /// The spending is discarded, as if it was never recorded.
+    Reject,
+}
+
+/// A second, longer window evaluated in addition to the regular budgeting window.
+///
+/// With a slow-burn window, a project is only blocked if it exceeds the budgets of both windows.
+/// This avoids blocking projects for short spikes, while sustained overspending is still caught.
+/// The window is tracked with the same number of buckets as the regular one,
+/// which are correspondingly larger.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SlowBurnWindow {
+    /// Length of the slow-burn window.
+    pub budgeting_window: Duration,
+
+    /// The budget assigned to each project within the slow-burn window, averaged *per-second*.
+    pub budget: f64,
+}
+
+/// A reduced budget for projects that were only seen recently, which increases linearly to the full budget.
+///
+/// This contains brand-new abusive projects faster, while established projects get the full budget.
+/// A project is new when its tracker is created, which is also the case when a project without traffic
+/// was cleaned up, after a restart, and for [pre-warmed](crate::Service::prewarm_projects) projects.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RampUp {
+    /// The time after which a new project gets its full budget.
+    pub duration: Duration,
+
+    /// The fraction of the budget a project gets when it is first seen, between `0` and `1`.
+    pub initial_fraction: f64,
+}
+
+impl RampUp {
+    /// Returns the fraction of the budget a project gets, `elapsed` after it was first seen.
+    pub fn fraction(&self, elapsed: Duration) -> f64 {
+        if elapsed >= self.duration {
+            return 1.;
+        }
+        let progress = elapsed.as_secs_f64() / self.duration.as_secs_f64();
+        self.initial_fraction + (1. - self.initial_fraction) * progress
+    }
+}
 
 /// The budgeting configuration.
 ///
 /// This determines the window, buckets, and the allowed budget for each project.
-#[derive(Debug)]
+///
+/// When (de)serialized, only the public settings are considered. A deserialized config
+/// uses its own [`Clock`], which is overridden by the [`Service`](crate::Service) once registered.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "ConfigFields")]
 pub struct BudgetingConfig {
     /// The "backoff" duration within which no flip-flopping of the "exceeded" state happens.
     ///
@@ -21,15 +357,180 @@ pub struct BudgetingConfig {
     /// The budget assigned to each project.
     pub budget: f64,
 
+    /// An additional period for which a project that exceeds its budget is kept around.
+    ///
+    /// Projects are cleaned up when they did not receive any traffic within the `budgeting_window`,
+    /// which also unblocks them. This delays the cleanup of blocked projects by this duration.
+    pub grace_period: Duration,
+
+    /// Whether refunds of previously recorded spending are allowed.
+    pub allow_refunds: bool,
+
+    /// How long clients may cache the decision for projects that were never seen.
+    ///
+    /// Projects without any recorded spending never exceed their budget, so clients can avoid
+    /// checking them on every event. This defaults to the `bucket_size`, and zero disables the hint.
+    /// See [`Decision::cache_for`](crate::Decision::cache_for).
+    pub unknown_project_ttl: Duration,
+
+    /// The strategy used to account for the spending of each project.
+    pub strategy: AccountingStrategy,
+
+    /// How requests are answered when they are shed because the service is overloaded.
+    pub overload_policy: OverloadPolicy,
+
+    /// The maximum spending of a single record, if any.
+    ///
+    /// This protects the budget of a project from a single corrupted record, like a bogus
+    /// value of 10^12 seconds, which would block the project for the whole window.
+    /// Records above it are handled according to [`oversized_spend`](Self::oversized_spend).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_single_spend: Option<f64>,
+
+    /// What happens to records above the [`max_single_spend`](Self::max_single_spend).
+    pub oversized_spend: OversizedSpend,
+
+    /// Whether this config is enabled.
+    ///
+    /// Disabled configs still accept requests, but discard all spending and never exceed their budget,
+    /// just like with [`Enforcement::Off`], without clients erroring on an unknown config.
+    pub enabled: bool,
+
+    /// An optional second, longer window which needs to be exceeded as well.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slow_burn: Option<SlowBurnWindow>,
+
+    /// An optional reduced budget for new projects.
+    ///
+    /// This only applies to the [`SlidingWindow`](AccountingStrategy::SlidingWindow) strategy.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ramp_up: Option<RampUp>,
+
+    /// The name under which this config was registered.
+    #[serde(skip)]
+    pub(crate) name: String,
+
     /// The number of time buckets to keep track of.
     ///
     /// This should be at least ⌈budgeting_window/buckt_size⌉.
+    #[serde(skip)]
     pub(crate) num_buckets: usize,
 
     /// The [`Timer`] used to select the proper bucket.
+    #[serde(skip)]
     timer: Timer,
 }
 
+/// Two configs are equal if their settings are, regardless of their [`Clock`].
+impl PartialEq for BudgetingConfig {
+    fn eq(&self, other: &Self) -> bool {
+        self.backoff_duration == other.backoff_duration
+            && self.budgeting_window == other.budgeting_window
+            && self.bucket_size == other.bucket_size
+            && self.budget == other.budget
+            && self.grace_period == other.grace_period
+            && self.allow_refunds == other.allow_refunds
+            && self.unknown_project_ttl == other.unknown_project_ttl
+            && self.strategy == other.strategy
+            && self.overload_policy == other.overload_policy
+            && self.max_single_spend == other.max_single_spend
+            && self.oversized_spend == other.oversized_spend
+            && self.enabled == other.enabled
+            && self.slow_burn == other.slow_burn
+            && self.ramp_up == other.ramp_up
+            && self.name == other.name
+    }
+}
+
+/// The serialized settings of a [`BudgetingConfig`], which the remaining state is rebuilt from.
+#[derive(Deserialize)]
+struct ConfigFields {
+    backoff_duration: Duration,
+    budgeting_window: Duration,
+    bucket_size: Duration,
+    budget: f64,
+    #[serde(default)]
+    grace_period: Duration,
+    #[serde(default)]
+    allow_refunds: bool,
+    #[serde(default)]
+    unknown_project_ttl: Option<Duration>,
+    #[serde(default)]
+    strategy: AccountingStrategy,
+    #[serde(default)]
+    overload_policy: OverloadPolicy,
+    #[serde(default)]
+    max_single_spend: Option<f64>,
+    #[serde(default)]
+    oversized_spend: OversizedSpend,
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+    #[serde(default)]
+    slow_burn: Option<SlowBurnWindow>,
+    #[serde(default)]
+    ramp_up: Option<RampUp>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl TryFrom<ConfigFields> for BudgetingConfig {
+    type Error = &'static str;
+
+    fn try_from(fields: ConfigFields) -> Result<Self, Self::Error> {
+        if fields.bucket_size.as_micros() == 0 {
+            return Err("`bucket_size` needs to be at least one microsecond");
+        }
+        let durations = [
+            Some(fields.backoff_duration),
+            Some(fields.budgeting_window),
+            Some(fields.bucket_size),
+            Some(fields.grace_period),
+            fields.unknown_project_ttl,
+            fields.slow_burn.map(|slow_burn| slow_burn.budgeting_window),
+            fields.ramp_up.map(|ramp_up| ramp_up.duration),
+        ];
+        if durations
+            .into_iter()
+            .flatten()
+            .any(|d| d > MAX_CONFIG_DURATION)
+        {
+            return Err("durations need to be at most a year");
+        }
+        let mut config = Self::new(
+            fields.backoff_duration,
+            fields.budgeting_window,
+            fields.bucket_size,
+            fields.budget,
+        )
+        .with_grace_period(fields.grace_period)
+        .with_allow_refunds(fields.allow_refunds)
+        .with_strategy(fields.strategy)
+        .with_overload_policy(fields.overload_policy)
+        .with_enabled(fields.enabled);
+        if let Some(ttl) = fields.unknown_project_ttl {
+            config = config.with_unknown_project_ttl(ttl);
+        }
+        if let Some(slow_burn) = fields.slow_burn {
+            config = config.with_slow_burn(slow_burn.budgeting_window, slow_burn.budget);
+        }
+        if let Some(ramp_up) = fields.ramp_up {
+            if !(0. ..=1.).contains(&ramp_up.initial_fraction) {
+                return Err("`ramp_up.initial_fraction` needs to be between 0 and 1");
+            }
+            config = config.with_ramp_up(ramp_up.duration, ramp_up.initial_fraction);
+        }
+        if let Some(max_single_spend) = fields.max_single_spend {
+            if !(max_single_spend.is_finite() && max_single_spend > 0.) {
+                return Err("`max_single_spend` needs to be positive and finite");
+            }
+            config = config.with_max_single_spend(max_single_spend, fields.oversized_spend);
+        }
+        Ok(config)
+    }
+}
+
 impl BudgetingConfig {
     /// Creates a new [`BudgetingConfig`] with the provided configuration.
     pub fn new(
@@ -48,10 +549,170 @@ impl BudgetingConfig {
             bucket_size,
             num_buckets,
             budget,
+            grace_period: Duration::ZERO,
+            allow_refunds: false,
+            unknown_project_ttl: bucket_size,
+            strategy: AccountingStrategy::default(),
+            overload_policy: OverloadPolicy::default(),
+            max_single_spend: None,
+            oversized_spend: OversizedSpend::default(),
+            enabled: true,
+            slow_burn: None,
+            ramp_up: None,
+            name: String::new(),
             timer,
         }
     }
 
+    /// Sets the [`grace_period`](Self::grace_period) of blocked projects.
+    pub fn with_grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// Sets whether [refunds](ProjectStats::record_refund) are allowed.
+    pub fn with_allow_refunds(mut self, allow_refunds: bool) -> Self {
+        self.allow_refunds = allow_refunds;
+        self
+    }
+
+    /// Sets the [`unknown_project_ttl`](Self::unknown_project_ttl) hint for projects that were never seen.
+    pub fn with_unknown_project_ttl(mut self, unknown_project_ttl: Duration) -> Self {
+        self.unknown_project_ttl = unknown_project_ttl;
+        self
+    }
+
+    /// Sets the [`AccountingStrategy`] used for each project.
+    pub fn with_strategy(mut self, strategy: AccountingStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Sets the [`OverloadPolicy`] for requests which are shed.
+    pub fn with_overload_policy(mut self, overload_policy: OverloadPolicy) -> Self {
+        self.overload_policy = overload_policy;
+        self
+    }
+
+    /// Sets the [`max_single_spend`](Self::max_single_spend), and what happens to records above it.
+    pub fn with_max_single_spend(
+        mut self,
+        max_single_spend: f64,
+        oversized_spend: OversizedSpend,
+    ) -> Self {
+        self.max_single_spend = Some(max_single_spend);
+        self.oversized_spend = oversized_spend;
+        self
+    }
+
+    /// Sets whether this config is [`enabled`](Self::enabled).
+    pub fn with_enabled(mut self, enabled: bool) -> Self {
+        self.enabled = enabled;
+        self
+    }
+
+    /// Adds a [`SlowBurnWindow`] with the given length and budget.
+    pub fn with_slow_burn(mut self, budgeting_window: Duration, budget: f64) -> Self {
+        self.slow_burn = Some(SlowBurnWindow {
+            budgeting_window,
+            budget,
+        });
+        self
+    }
+
+    /// Adds a [`RampUp`] of the given duration, starting with the given fraction of the budget.
+    pub fn with_ramp_up(mut self, duration: Duration, initial_fraction: f64) -> Self {
+        self.ramp_up = Some(RampUp {
+            duration,
+            initial_fraction,
+        });
+        self
+    }
+
+    /// Creates a new [`BudgetTracker`] for a project, according to the configured [`AccountingStrategy`].
+    pub fn new_tracker(self: &Arc<Self>) -> Box<dyn BudgetTracker> {
+        match self.strategy {
+            AccountingStrategy::SlidingWindow => Box::new(ProjectStats::new(self.clone())),
+            AccountingStrategy::Concurrency => Box::new(ConcurrencyTracker::new(self.clone())),
+        }
+    }
+
+    /// Returns the name under which this config was registered, or an empty name if it was not.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the effective number of buckets that spending is sorted into.
+    ///
+    /// This is `budgeting_window / bucket_size`, rounded down.
+    pub fn num_buckets(&self) -> usize {
+        self.num_buckets
+    }
+
+    /// Returns the ratio of the `budgeting_window` to the `bucket_size`.
+    ///
+    /// This is not a whole number if the `bucket_size` does not divide the window.
+    pub fn window_to_bucket_ratio(&self) -> f64 {
+        self.budgeting_window.as_secs_f64() / self.bucket_size.as_secs_f64()
+    }
+
+    /// Returns the smallest spend rate that is distinguishable from no spending at all.
+    ///
+    /// This is the rate of a single unit of spending within the `budgeting_window`.
+    /// A budget below this rate is exceeded by the first unit of spending.
+    pub fn min_enforceable_rate(&self) -> f64 {
+        1. / self.budgeting_window.as_secs_f64()
+    }
+
+    /// Returns the likely misconfigurations of this config, which are not invalid as such.
+    ///
+    /// These are reported as [`Event::ConfigWarning`](crate::Event::ConfigWarning) whenever the config
+    /// is registered or replaced.
+    pub fn warnings(&self) -> Vec<&'static str> {
+        let mut warnings = vec![];
+        if self.budget == 0. {
+            warnings.push("`budget` is zero, so any spending exceeds it");
+        }
+        if self.strategy != AccountingStrategy::SlidingWindow {
+            return warnings;
+        }
+        let window = self.budgeting_window.as_micros();
+        if !window.is_multiple_of(self.bucket_size.as_micros()) {
+            warnings.push("`bucket_size` does not divide the window, whose remainder is ignored");
+        }
+        if self.budgeting_window < 2 * self.bucket_size {
+            warnings
+                .push("the window spans less than two buckets, so spending expires all at once");
+        }
+        if self.budgeting_window < self.backoff_duration {
+            warnings.push("the window is shorter than the backoff, which outlasts the spending");
+        }
+        // A zero backoff disables it altogether.
+        if !self.backoff_duration.is_zero() && self.backoff_duration < self.bucket_size {
+            warnings
+                .push("the backoff is shorter than a bucket, so it ends before spending expires");
+        }
+        if self.budget > 0. && self.budget < self.min_enforceable_rate() {
+            warnings.push("`budget` is below the minimum enforceable rate of the window");
+        }
+        warnings
+    }
+
+    /// Sets the name under which this config is registered.
+    #[cfg(feature = "service")]
+    pub(crate) fn with_name(mut self, name: &str) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Overrides the [`Clock`] that is being used by this configuration.
+    ///
+    /// This is mostly useful when using [`ProjectStats`] standalone,
+    /// as a [`Service`](crate::Service) will override the clock with its own.
+    pub fn with_clock(self, clock: Clock) -> Self {
+        self.with_timer(Timer::new(clock))
+    }
+
     /// Overrides the [`Timer`] that is being used by this configuration.
     pub(crate) fn with_timer(mut self, timer: Timer) -> Self {
         self.timer = timer;
@@ -59,21 +720,112 @@ impl BudgetingConfig {
     }
 
     /// Returns a [`Instant::recent()`] which can be further truncated.
+    ///
+    /// For buckets smaller than the [`RECENT_TIME_INTERVAL`], this is a precise [`Instant::now()`] instead.
     pub(crate) fn now(&self) -> Instant {
-        self.timer.now()
+        match self.uses_precise_time() {
+            true => self.timer.precise_now(),
+            false => self.timer.now(),
+        }
+    }
+
+    /// Returns whether the buckets are too small for the recent time to be precise enough.
+    fn uses_precise_time(&self) -> bool {
+        self.bucket_size < RECENT_TIME_INTERVAL
     }
 
     /// Returns the recent [`Instant`], as well as one truncated to `bucket_size`.
     pub(crate) fn truncated_now(&self, now: Instant) -> Instant {
         self.timer.truncated(now, self.bucket_size)
     }
+
+    /// Returns the size of the buckets of the [`SlowBurnWindow`], if there is one.
+    pub(crate) fn slow_bucket_size(&self) -> Option<Duration> {
+        let slow_burn = self.slow_burn.as_ref()?;
+        let num_buckets = self.num_buckets.max(1) as u32;
+        Some((slow_burn.budgeting_window / num_buckets).max(Duration::from_micros(1)))
+    }
+
+    /// Returns the given `now` truncated to the given `bucket_size`.
+    pub(crate) fn truncated_to(&self, now: Instant, bucket_size: Duration) -> Instant {
+        self.timer.truncated(now, bucket_size)
+    }
+}
+
+/// The source of time used by a [`Service`](crate::Service).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeSource {
+    /// A [`Clock`], which uses the calibrated CPU counter where available.
+    Quanta,
+    /// The [`std::time::Instant`], used where the calibration of the [`Clock`] is unreliable.
+    Std,
+}
+
+impl TimeSource {
+    /// The time over which a [`Clock`] is compared to the [`std::time::Instant`].
+    #[cfg(feature = "service")]
+    const DETECTION_INTERVAL: Duration = Duration::from_millis(20);
+
+    /// Returns the source as a static string.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Quanta => "quanta",
+            Self::Std => "std",
+        }
+    }
+
+    /// Detects whether the given [`Clock`] can be relied upon.
+    ///
+    /// The [`Clock`] is compared to the [`std::time::Instant`] over a short interval,
+    /// and is rejected if it goes backwards or deviates by more than 10%,
+    /// which happens when the calibration of the CPU counter failed, for example on some virtualized hosts.
+    #[cfg(feature = "service")]
+    pub fn detect(clock: &Clock) -> Self {
+        let (std_start, start) = (std::time::Instant::now(), clock.now());
+        std::thread::sleep(Self::DETECTION_INTERVAL);
+        let (std_elapsed, end) = (std_start.elapsed(), clock.now());
+
+        let Some(elapsed) = end.checked_duration_since(start) else {
+            return Self::Std;
+        };
+        match (0.9..1.1).contains(&(elapsed.as_secs_f64() / std_elapsed.as_secs_f64())) {
+            true => Self::Quanta,
+            false => Self::Std,
+        }
+    }
+
+    /// [Detects](Self::detect) whether the host's [`Clock`] can be relied upon.
+    ///
+    /// The calibration of the CPU counter is shared by the whole process, so it is only detected once,
+    /// and later calls return right away.
+    #[cfg(feature = "service")]
+    pub fn detect_host() -> Self {
+        static DETECTED: std::sync::OnceLock<TimeSource> = std::sync::OnceLock::new();
+        *DETECTED.get_or_init(|| Self::detect(&Clock::new()))
+    }
+}
+
+impl fmt::Display for TimeSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Where a [`Timer`] gets its time from.
+#[derive(Clone, Debug)]
+enum Source {
+    /// A (possibly mocked) [`Clock`].
+    Clock(Clock),
+    /// The [`std::time::Instant`] at which the [`Timer`] was constructed.
+    #[cfg(feature = "service")]
+    Std(std::time::Instant),
 }
 
 /// A [`Timer`] that is mockable and allows us to get a truncated [`Instant`].
 #[derive(Clone, Debug)]
 pub struct Timer {
-    /// The [`Clock`] thats being used for this timer.
-    clock: Clock,
+    /// The source thats being used for this timer.
+    source: Source,
     /// Whenever this [`Timer`] was constructed.
     start_time: Instant,
 }
@@ -82,12 +834,47 @@ impl Timer {
     /// Creates a new [`Timer`]
     pub fn new(clock: Clock) -> Self {
         let start_time = clock.recent();
-        Self { clock, start_time }
+        let source = Source::Clock(clock);
+        Self { source, start_time }
+    }
+
+    /// Creates a new [`Timer`] based on the [`std::time::Instant`], see [`TimeSource::Std`].
+    ///
+    /// The given [`Clock`] is only used to get a starting [`Instant`].
+    #[cfg(feature = "service")]
+    pub fn with_std_time(clock: &Clock) -> Self {
+        let start_time = clock.now();
+        let source = Source::Std(std::time::Instant::now());
+        Self { source, start_time }
+    }
+
+    /// Returns the [`TimeSource`] of this timer.
+    #[cfg(feature = "service")]
+    pub fn source(&self) -> TimeSource {
+        match self.source {
+            Source::Clock(_) => TimeSource::Quanta,
+            Source::Std(_) => TimeSource::Std,
+        }
     }
 
     /// Returns a [`Instant::recent()`] which can be further truncated.
+    ///
+    /// The [`TimeSource::Std`] has no recent time, and always returns the precise time instead.
     pub fn now(&self) -> Instant {
-        self.clock.recent()
+        match &self.source {
+            Source::Clock(clock) => clock.recent(),
+            #[cfg(feature = "service")]
+            Source::Std(_) => self.precise_now(),
+        }
+    }
+
+    /// Returns a precise [`Instant::now()`], which does not depend on the recent time being updated.
+    pub fn precise_now(&self) -> Instant {
+        match &self.source {
+            Source::Clock(clock) => clock.now(),
+            #[cfg(feature = "service")]
+            Source::Std(start) => self.start_time + start.elapsed(),
+        }
     }
 
     /// Returns the `now` truncated to a multiple of the given [`Duration`].
@@ -125,4 +912,155 @@ mod tests {
         assert!(advanced_now > now);
         assert_eq!(advanced_now.duration_since(now), duration);
     }
+
+    #[cfg(feature = "service")]
+    #[test]
+    fn test_std_time() {
+        let (clock, mock) = Clock::mock();
+        mock.increment(Duration::from_secs(100));
+        let timer = Timer::with_std_time(&clock);
+        assert_eq!(timer.source(), TimeSource::Std);
+
+        // the mocked clock is only used for the start time, and does not need to be advanced
+        let start = timer.now();
+        assert!(start >= clock.now());
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(timer.now().duration_since(start) >= Duration::from_millis(10));
+        assert!(timer.precise_now() > start);
+
+        assert_eq!(Timer::new(clock).source(), TimeSource::Quanta);
+    }
+
+    #[cfg(feature = "service")]
+    #[test]
+    fn test_detect_time_source() {
+        // the host clock may or may not be reliable, but it is only detected once
+        assert_eq!(TimeSource::detect_host(), TimeSource::detect_host());
+
+        // a mocked clock that does not advance is unreliable
+        let (clock, _mock) = Clock::mock();
+        assert_eq!(TimeSource::detect(&clock), TimeSource::Std);
+    }
+
+    #[test]
+    fn test_precise_time() {
+        let config = |bucket_size| {
+            BudgetingConfig::new(Duration::ZERO, Duration::from_secs(1), bucket_size, 10.)
+        };
+        assert!(config(Duration::from_micros(500)).uses_precise_time());
+        assert!(!config(RECENT_TIME_INTERVAL).uses_precise_time());
+        assert!(!config(Duration::from_secs(1)).uses_precise_time());
+
+        // the precise time is the same as the recent one for mocked clocks
+        let (clock, mock) = Clock::mock();
+        mock.increment(Duration::from_secs(1));
+        let config = config(Duration::from_millis(1)).with_clock(clock);
+        assert_eq!(config.now(), config.timer.now());
+    }
+
+    #[test]
+    fn test_derived_values() {
+        let config = |window_ms, bucket_ms, budget| {
+            BudgetingConfig::new(
+                Duration::from_secs(10),
+                Duration::from_millis(window_ms),
+                Duration::from_millis(bucket_ms),
+                budget,
+            )
+        };
+        let valid = config(20_000, 500, 10.);
+        assert_eq!(valid.num_buckets(), 40);
+        assert_eq!(valid.window_to_bucket_ratio(), 40.);
+        assert_eq!(valid.min_enforceable_rate(), 0.05);
+        assert!(valid.warnings().is_empty());
+
+        let misconfigured = config(2_500, 1_000, 0.1);
+        assert_eq!(misconfigured.num_buckets(), 2);
+        assert_eq!(misconfigured.window_to_bucket_ratio(), 2.5);
+        assert_eq!(misconfigured.warnings().len(), 3);
+
+        // there is neither a window nor a backoff with slots
+        let concurrency = misconfigured.with_strategy(AccountingStrategy::Concurrency);
+        assert!(concurrency.warnings().is_empty());
+    }
+
+    #[test]
+    fn test_pathological_warnings() {
+        let config = |backoff_ms, window_ms, bucket_ms, budget| {
+            BudgetingConfig::new(
+                Duration::from_millis(backoff_ms),
+                Duration::from_millis(window_ms),
+                Duration::from_millis(bucket_ms),
+                budget,
+            )
+        };
+        let backoff_too_short = config(500, 10_000, 1_000, 10.);
+        assert_eq!(
+            backoff_too_short.warnings(),
+            ["the backoff is shorter than a bucket, so it ends before spending expires"]
+        );
+        // a zero backoff is deliberate
+        assert!(config(0, 10_000, 1_000, 10.).warnings().is_empty());
+
+        let single_bucket = config(1_000, 1_500, 1_000, 10.);
+        assert!(single_bucket
+            .warnings()
+            .contains(&"the window spans less than two buckets, so spending expires all at once"));
+        assert!(config(1_000, 2_000, 1_000, 10.).warnings().is_empty());
+
+        let zero_budget = config(1_000, 10_000, 1_000, 0.);
+        assert_eq!(
+            zero_budget.warnings(),
+            ["`budget` is zero, so any spending exceeds it"]
+        );
+        let zero_budget = zero_budget.with_strategy(AccountingStrategy::Concurrency);
+        assert_eq!(zero_budget.warnings().len(), 1);
+    }
+
+    #[test]
+    fn test_saturating_add() {
+        let (clock, mock) = Clock::mock();
+        mock.increment(Duration::from_secs(100));
+        let now = clock.now();
+        assert_eq!(
+            saturating_add(now, Duration::from_secs(1)),
+            now + Duration::from_secs(1)
+        );
+
+        let latest = saturating_add(now, Duration::MAX);
+        assert!(latest > now + MAX_CONFIG_DURATION);
+        assert_eq!(saturating_add(latest, Duration::from_nanos(1)), latest);
+    }
+
+    #[test]
+    fn test_serde_roundtrip() {
+        let config = BudgetingConfig::new(
+            Duration::from_secs(60),
+            Duration::from_secs(10),
+            Duration::from_millis(500),
+            10.,
+        )
+        .with_allow_refunds(true)
+        .with_overload_policy(OverloadPolicy::Allow)
+        .with_max_single_spend(100., OversizedSpend::Reject)
+        .with_enabled(false);
+        assert_eq!(config.num_buckets(), 20);
+        assert_eq!(config.clone(), config);
+
+        let json = serde_json::to_string(&config).unwrap();
+        let deserialized: BudgetingConfig = serde_json::from_str(&json).unwrap();
+        assert_eq!(deserialized, config);
+        assert_eq!(deserialized.num_buckets(), 20);
+
+        // configs are enabled by default, and reject shed requests
+        let json = r#"{"backoff_duration": {"secs": 60, "nanos": 0}, "budgeting_window": {"secs": 10, "nanos": 0}, "bucket_size": {"secs": 1, "nanos": 0}, "budget": 10}"#;
+        let deserialized = serde_json::from_str::<BudgetingConfig>(json).unwrap();
+        assert!(deserialized.enabled);
+        assert_eq!(deserialized.overload_policy, OverloadPolicy::Reject);
+
+        let json = r#"{"backoff_duration": {"secs": 60, "nanos": 0}, "budgeting_window": {"secs": 10, "nanos": 0}, "bucket_size": {"secs": 0, "nanos": 0}, "budget": 10}"#;
+        assert!(serde_json::from_str::<BudgetingConfig>(json).is_err());
+        let json = r#"{"backoff_duration": {"secs": 18446744000, "nanos": 0}, "budgeting_window": {"secs": 10, "nanos": 0}, "bucket_size": {"secs": 1, "nanos": 0}, "budget": 10}"#;
+        assert!(serde_json::from_str::<BudgetingConfig>(json).is_err());
+    }
 }
@@ -0,0 +1,150 @@
+//! Periodically fetches budgeting configs from a central control plane.
+//!
+//! The control plane is expected to serve a JSON object of [`ConfigSettings`] keyed by config name,
+//! the same as the `configs` in the local config file. Remote configs take precedence over local ones,
+//! and existing project state is kept when a config changes. Remote configs which are no longer served are removed,
+//! or revert to their local version.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use indexmap::IndexMap;
+use peanutbutter::{ConfigError, ReplaceState, Service};
+
+use crate::http_source::HttpSource;
+use crate::settings::ConfigSettings;
+
+type Configs = IndexMap<String, ConfigSettings>;
+
+/// Merges the `remote` configs over the `local` ones.
+fn merge(local: &Configs, remote: Configs) -> Configs {
+    let mut merged = local.clone();
+    merged.extend(remote);
+    merged
+}
+
+/// Applies all the configs that changed compared to `applied` to the [`Service`].
+///
+/// Applied configs which are missing from `configs` are removed from the [`Service`].
+/// Returns the configs that are effectively applied now.
+/// Invalid configs are skipped, keeping the previous version.
+async fn apply(service: &Service, applied: &Configs, configs: Configs) -> Configs {
+    let mut now_applied = applied.clone();
+    for name in applied.keys() {
+        if configs.contains_key(name) {
+            continue;
+        }
+        // The config might have been removed via the admin API already.
+        let _ = service.remove_config(name);
+        now_applied.shift_remove(name);
+        println!("Removed config `{name}`, which is no longer served by the control plane");
+    }
+    for (name, settings) in configs {
+        if applied.get(&name) == Some(&settings) {
+            continue;
+        }
+        if name == crate::healthcheck::CONFIG {
+            println!("Ignoring reserved config `{name}` from control plane");
+            continue;
+        }
+
+        let result = async {
+            let config = settings.to_config()?;
+            let replaced = service.replace_config_async(&name, config, ReplaceState::Keep);
+            match replaced.await {
+                Err(ConfigError::Unknown(_)) => {}
+                result => return result.map_err(|err| err.to_string()),
+            }
+            let config = settings.to_config()?;
+            service
+                .try_add_config(&name, config)
+                .map_err(|err| err.to_string())
+        };
+        let result = result.await;
+
+        match result {
+            Ok(()) => {
+                println!("Applied config `{name}` from control plane");
+                now_applied.insert(name, settings);
+            }
+            Err(err) => println!("Invalid config `{name}` from control plane: {err}"),
+        }
+    }
+    now_applied
+}
+
+/// Fetches configs from the control plane every `interval` forever, and applies them to the [`Service`].
+///
+/// The `local` configs are the ones the service was started with.
+pub async fn run(
+    control_plane: HttpSource,
+    interval: Duration,
+    local: Configs,
+    service: Arc<Service>,
+) {
+    let mut applied = local.clone();
+    loop {
+        // The error is not `Send`, so it can not be held while applying the configs.
+        let fetched = control_plane.fetch().await.map_err(|err| err.to_string());
+        match fetched {
+            Ok(remote) => applied = apply(&service, &applied, merge(&local, remote)).await,
+            Err(err) => println!("Failed to fetch configs from control plane: {err}"),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::http_source::parse_response;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_apply_configs() {
+        let service = Service::new();
+        let config = |budget| ConfigSettings {
+            backoff_secs: 60.,
+            window_secs: 10.,
+            bucket_secs: 1.,
+            budget,
+            grace_period_secs: 0.,
+            allow_refunds: false,
+            unknown_project_ttl_secs: None,
+            strategy: Default::default(),
+            overload_policy: Default::default(),
+            max_single_spend: None,
+            oversized_spend: Default::default(),
+            enabled: true,
+            slow_window_secs: None,
+            slow_budget: None,
+            ramp_up_secs: None,
+            ramp_up_fraction: None,
+        };
+        let local = Configs::from([("local".into(), config(10.))]);
+        service
+            .try_add_config("local", local["local"].to_config().unwrap())
+            .unwrap();
+
+        let response =
+            b"HTTP/1.0 200 OK\r\nContent-Type: application/json\r\n\r\n{\"local\": {\"backoff_secs\": 60, \"window_secs\": 10, \"bucket_secs\": 1, \"budget\": 1}, \"remote\": {\"backoff_secs\": 60, \"window_secs\": 10, \"bucket_secs\": 1, \"budget\": 1}, \"invalid\": {\"backoff_secs\": 60, \"window_secs\": 10, \"bucket_secs\": 0, \"budget\": 1}}";
+        let remote = parse_response(response).unwrap();
+
+        assert!(!service.record_spending("local", 1, 50.));
+        let applied = apply(&service, &local, merge(&local, remote)).await;
+        assert_eq!(
+            applied,
+            Configs::from([("local".into(), config(1.)), ("remote".into(), config(1.))])
+        );
+        // the project state is kept, and is evaluated against the new remote budget
+        assert!(service.exceeds_budget("local", 1));
+        assert!(service.record_spending("remote", 1, 50.));
+        assert!(!service.record_spending("invalid", 1, 50.));
+
+        // configs which are no longer served are removed, or revert to their local version
+        let applied = apply(&service, &applied, merge(&local, Configs::new())).await;
+        assert_eq!(applied, local);
+        assert!(!service.configs().contains_key("remote"));
+        assert_eq!(service.configs()["local"].config.budget, 10.);
+    }
+}
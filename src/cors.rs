@@ -0,0 +1,159 @@
+//! Allows browsers to call the reporting endpoints directly, for example from internal dashboards.
+//!
+//! Only `GET` requests are allowed cross-origin, which covers all the reporting endpoints like
+//! `/configs` or `/spend_summary`, but none of the endpoints that record spending or change state.
+//! Preflight requests for `GET` are answered right away, all other ones are left to the router.
+
+use std::sync::Arc;
+
+use axum::extract::{Request, State};
+use axum::http::{header, HeaderValue, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+
+use crate::request_id;
+
+/// How long browsers may cache the result of a preflight request, in seconds.
+const MAX_AGE_SECS: &str = "3600";
+
+/// The origins that are allowed to call the reporting endpoints.
+#[derive(Debug)]
+pub struct Cors {
+    /// The allowed origins, or [`None`] if any origin is allowed.
+    origins: Option<Vec<HeaderValue>>,
+}
+
+impl Cors {
+    /// Creates a [`Cors`] allowing the given origins, like `https://dashboard.example.com`.
+    ///
+    /// The `*` origin allows any origin.
+    pub fn new(origins: &[String]) -> Result<Self, String> {
+        if origins.iter().any(|origin| origin == "*") {
+            return Ok(Self { origins: None });
+        }
+        let origins = origins.iter().map(|origin| {
+            let origin = origin.trim_end_matches('/');
+            HeaderValue::from_str(origin).map_err(|_| format!("invalid CORS origin `{origin}`"))
+        });
+        Ok(Self {
+            origins: Some(origins.collect::<Result<_, _>>()?),
+        })
+    }
+
+    /// Returns the value of the `Access-Control-Allow-Origin` header for a request from `origin`,
+    /// if that origin is allowed.
+    fn allow_origin(&self, origin: &HeaderValue) -> Option<HeaderValue> {
+        match &self.origins {
+            None => Some(HeaderValue::from_static("*")),
+            Some(origins) => origins.contains(origin).then(|| origin.clone()),
+        }
+    }
+}
+
+/// Adds the CORS headers to cross-origin `GET` requests, and answers their preflight requests.
+pub async fn allow_origins(
+    State(cors): State<Arc<Cors>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let headers = request.headers();
+    let Some(allow_origin) = (headers.get(header::ORIGIN)).and_then(|o| cors.allow_origin(o))
+    else {
+        return next.run(request).await;
+    };
+    let requested_method = headers.get(header::ACCESS_CONTROL_REQUEST_METHOD);
+    let is_preflight = request.method() == Method::OPTIONS
+        && requested_method.is_some_and(|method| method == Method::GET.as_str());
+
+    if !is_preflight && request.method() != Method::GET {
+        return next.run(request).await;
+    }
+
+    let mut response = if is_preflight {
+        let mut response = StatusCode::NO_CONTENT.into_response();
+        let headers = response.headers_mut();
+        let allow_methods = HeaderValue::from_static("GET");
+        headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, allow_methods);
+        let max_age = HeaderValue::from_static(MAX_AGE_SECS);
+        headers.insert(header::ACCESS_CONTROL_MAX_AGE, max_age);
+        response
+    } else {
+        next.run(request).await
+    };
+
+    let headers = response.headers_mut();
+    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+    // Browsers only let scripts read the request id if it is exposed explicitly.
+    let expose_headers = HeaderValue::from(request_id::HEADER);
+    headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, expose_headers);
+    headers.append(header::VARY, HeaderValue::from_static("origin"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::middleware::from_fn_with_state;
+    use axum::routing::{get, post};
+    use axum::Router;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    /// Sends a raw HTTP request with the given `method` and extra headers, and returns the response.
+    async fn send(addr: std::net::SocketAddr, method: &str, path: &str, headers: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request =
+            format!("{method} {path} HTTP/1.1\r\nHost: test\r\nConnection: close\r\n{headers}\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response.to_ascii_lowercase()
+    }
+
+    #[tokio::test]
+    async fn test_cors() {
+        let cors = Cors::new(&["https://dashboard.test/".into()]).unwrap();
+        let allowed = HeaderValue::from_static("https://dashboard.test");
+        assert_eq!(cors.allow_origin(&allowed), Some(allowed));
+        assert!(cors
+            .allow_origin(&HeaderValue::from_static("https://other.test"))
+            .is_none());
+        let any = Cors::new(&["*".into()]).unwrap();
+        assert_eq!(
+            any.allow_origin(&HeaderValue::from_static("https://other.test")),
+            Some(HeaderValue::from_static("*"))
+        );
+
+        let app = Router::new()
+            .route("/configs", get(|| async { "{}" }))
+            .route("/record_spending", post(|| async { "{}" }))
+            .layer(from_fn_with_state(Arc::new(cors), allow_origins));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let origin = "Origin: https://dashboard.test\r\n";
+        let response = send(addr, "GET", "/configs", origin).await;
+        assert!(response.starts_with("http/1.1 200"));
+        assert!(response.contains("access-control-allow-origin: https://dashboard.test"));
+        assert!(response.contains("access-control-expose-headers: x-request-id"));
+
+        let preflight = format!("{origin}Access-Control-Request-Method: GET\r\n");
+        let response = send(addr, "OPTIONS", "/configs", &preflight).await;
+        assert!(response.starts_with("http/1.1 204"));
+        assert!(response.contains("access-control-allow-methods: get"));
+
+        // state-changing endpoints are not allowed cross-origin
+        let preflight = format!("{origin}Access-Control-Request-Method: POST\r\n");
+        let response = send(addr, "OPTIONS", "/record_spending", &preflight).await;
+        assert!(!response.contains("access-control-allow-origin"));
+        let response = send(addr, "POST", "/record_spending", origin).await;
+        assert!(!response.contains("access-control-allow-origin"));
+
+        let origin = "Origin: https://other.test\r\n";
+        let response = send(addr, "GET", "/configs", origin).await;
+        assert!(response.starts_with("http/1.1 200"));
+        assert!(!response.contains("access-control-allow-origin"));
+    }
+}
@@ -0,0 +1,171 @@
+//! Conveniences for running the server as a daemon outside of Kubernetes, for example managed by systemd.
+//!
+//! The server always stays in the foreground, as expected by systemd services with `Type=notify`:
+//! - Readiness is signaled via `sd_notify` once all the listeners are bound, if `NOTIFY_SOCKET` is set.
+//! - A [`PidFile`] holds the id of the process, and is removed again when the server shuts down after a failure.
+//! - A [`LogFile`] receives all the output, and is reopened on `SIGUSR1`, for example after logrotate moved it.
+//! - A `SIGTERM` or `SIGINT` shuts the server down gracefully, see [`wait_for_termination`].
+
+use std::convert::Infallible;
+use std::fs::OpenOptions;
+use std::future::Future;
+use std::io::{self, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// How often a requested reopen of the [`LogFile`] or termination is checked.
+const REOPEN_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Whether a reopen of the [`LogFile`] was requested by a `SIGUSR1`.
+static REOPEN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Whether a graceful shutdown was requested by a `SIGTERM` or `SIGINT`.
+static TERMINATION_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Sends a state like `READY=1` to the service manager, if it listens on `NOTIFY_SOCKET`.
+pub fn notify(state: &str) -> io::Result<()> {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    let socket = UnixDatagram::unbound()?;
+    // Socket names starting with `@` are in the abstract namespace.
+    #[cfg(target_os = "linux")]
+    if let Some(name) = path.as_encoded_bytes().strip_prefix(b"@") {
+        use std::os::linux::net::SocketAddrExt;
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        socket.send_to_addr(state.as_bytes(), &addr)?;
+        return Ok(());
+    }
+    socket.send_to(state.as_bytes(), path)?;
+    Ok(())
+}
+
+extern "C" fn request_termination(_signal: libc::c_int) {
+    TERMINATION_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Handles `SIGTERM` and `SIGINT` by requesting a graceful shutdown instead of exiting right away.
+///
+/// The returned future completes with an error once that was requested, which stops the supervisor.
+pub fn wait_for_termination() -> io::Result<impl Future<Output = Result<(), &'static str>>> {
+    let handler = request_termination as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    for signal in [libc::SIGTERM, libc::SIGINT] {
+        if unsafe { libc::signal(signal, handler) } == libc::SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(async {
+        while !termination_requested() {
+            tokio::time::sleep(REOPEN_INTERVAL).await;
+        }
+        Err("termination was requested")
+    })
+}
+
+/// Returns whether a graceful shutdown was requested, see [`wait_for_termination`].
+pub fn termination_requested() -> bool {
+    TERMINATION_REQUESTED.load(Ordering::Relaxed)
+}
+
+/// A file holding the id of this process, which is removed once dropped.
+#[derive(Debug)]
+pub struct PidFile(PathBuf);
+
+impl PidFile {
+    /// Writes the id of this process to the file at `path`, replacing its contents.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        std::fs::write(path, format!("{}\n", std::process::id()))?;
+        Ok(Self(path.into()))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// A file that receives everything written to stdout and stderr.
+#[derive(Debug)]
+pub struct LogFile(PathBuf);
+
+extern "C" fn request_reopen(_signal: libc::c_int) {
+    REOPEN_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+impl LogFile {
+    /// Redirects stdout and stderr to the file at `path`, appending to it.
+    ///
+    /// From now on, a `SIGUSR1` requests the file to be reopened, see [`LogFile::reopen_on_signal`].
+    pub fn open(path: &Path) -> io::Result<Self> {
+        redirect_output(path)?;
+        // The handler only sets a flag, which is async-signal-safe.
+        let handler = request_reopen as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        if unsafe { libc::signal(libc::SIGUSR1, handler) } == libc::SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self(path.into()))
+    }
+
+    /// Reopens the file whenever it was requested by a `SIGUSR1`, forever.
+    ///
+    /// If reopening fails, the output keeps going to the previous file.
+    pub async fn reopen_on_signal(self) -> Result<(), Infallible> {
+        loop {
+            tokio::time::sleep(REOPEN_INTERVAL).await;
+            if REOPEN_REQUESTED.swap(false, Ordering::Relaxed) {
+                match redirect_output(&self.0) {
+                    Ok(()) => println!("Reopened log file `{}`", self.0.display()),
+                    Err(err) => println!("Failed to reopen log file `{}`: {err}", self.0.display()),
+                }
+            }
+        }
+    }
+}
+
+/// Points stdout and stderr to the file at `path`, which is created if it does not exist.
+fn redirect_output(path: &Path) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    io::stdout().flush()?;
+    for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if unsafe { libc::dup2(file.as_raw_fd(), fd) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notify() {
+        let path = std::env::temp_dir().join("peanutbutter-test-notify.sock");
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixDatagram::bind(&path).unwrap();
+
+        std::env::set_var("NOTIFY_SOCKET", &path);
+        notify("READY=1").unwrap();
+        std::env::remove_var("NOTIFY_SOCKET");
+        notify("STOPPING=1").unwrap();
+
+        let mut buf = [0; 64];
+        let len = listener.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_pid_file() {
+        let path = std::env::temp_dir().join("peanutbutter-test.pid");
+        let pid_file = PidFile::create(&path).unwrap();
+        let pid = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(pid.trim(), std::process::id().to_string());
+        drop(pid_file);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,108 @@
+use std::fmt;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Why a project was, or was not, reported as exceeding its budget.
+///
+/// Several features, like the [backoff](crate::BudgetingConfig::backoff_duration),
+/// the [ramp-up](crate::RampUp) of new projects and the [`Enforcement`](crate::Enforcement)
+/// of a config, can influence a decision. The reason tells them apart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub enum DecisionReason {
+    /// The project spends less than its budget.
+    UnderBudget,
+    /// The project spends more than its budget.
+    OverBudget,
+    /// The project is kept in its current state by the backoff,
+    /// even though its spending alone would lead to the opposite decision.
+    Backoff,
+    /// The project exceeds its budget, but the config is only enforced as a dry run.
+    DryRun,
+    /// The project exceeds the reduced budget of a new project, but not the full budget.
+    WarmingUp,
+    /// The config is not enforced, so the spending of the project is not considered at all.
+    NotEnforced,
+    /// The decision is forced by a [`DecisionOverride`], regardless of the spending of the project.
+    Overridden,
+    /// The request was shed because the service is overloaded, and the config allows it anyway,
+    /// see [`OverloadPolicy::Allow`](crate::OverloadPolicy::Allow).
+    Overloaded,
+}
+
+impl DecisionReason {
+    /// Returns the reason as a static string, as it is serialized.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::UnderBudget => "under-budget",
+            Self::OverBudget => "over-budget",
+            Self::Backoff => "backoff",
+            Self::DryRun => "dry-run",
+            Self::WarmingUp => "warming-up",
+            Self::NotEnforced => "not-enforced",
+            Self::Overridden => "overridden",
+            Self::Overloaded => "overloaded",
+        }
+    }
+}
+
+impl fmt::Display for DecisionReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A decision about a project, along with the reason it was made.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Decision {
+    /// Whether the project is reported as exceeding its budget.
+    pub exceeds_budget: bool,
+    /// Why the decision was made.
+    pub reason: DecisionReason,
+    /// How long clients may cache the decision instead of checking again.
+    ///
+    /// This is only given for projects that were never seen, see
+    /// [`BudgetingConfig::unknown_project_ttl`](crate::BudgetingConfig::unknown_project_ttl).
+    pub cache_for: Option<Duration>,
+}
+
+/// A decision that is forced for a project, for example in an emergency.
+///
+/// Overrides take precedence over the spending and the [`Enforcement`](crate::Enforcement) of the config,
+/// but spending is still recorded as usual.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DecisionOverride {
+    /// The project is always reported as exceeding its budget.
+    ForceBlock,
+    /// The project is never reported as exceeding its budget.
+    ForceAllow,
+}
+
+impl DecisionOverride {
+    /// Returns the decision which is forced by this override.
+    pub fn decision(self) -> Decision {
+        Decision {
+            exceeds_budget: self == Self::ForceBlock,
+            reason: DecisionReason::Overridden,
+            cache_for: None,
+        }
+    }
+}
+
+impl Decision {
+    /// Creates a decision based only on the "exceeded" state.
+    pub fn from_exceeds_budget(exceeds_budget: bool) -> Self {
+        let reason = match exceeds_budget {
+            true => DecisionReason::OverBudget,
+            false => DecisionReason::UnderBudget,
+        };
+        Self {
+            exceeds_budget,
+            reason,
+            cache_for: None,
+        }
+    }
+}
@@ -0,0 +1,287 @@
+//! Negotiates the encoding of HTTP bodies between JSON, MessagePack and CBOR.
+//!
+//! High-volume producers can send their requests as `application/msgpack` or `application/cbor`
+//! to avoid the overhead of JSON, and receive the response in the same encoding.
+//! Bodies are deserialized into and serialized from the same serde types in every encoding.
+
+use axum::async_trait;
+use axum::body::Bytes;
+use axum::extract::{FromRequest, Json, Request};
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// The encoding of a request body, which is also used for its response.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl Encoding {
+    /// Returns the encoding declared by the `Content-Type` of a request.
+    ///
+    /// Anything that is neither MessagePack nor CBOR is treated as JSON, which rejects unexpected content types.
+    pub fn of_request(headers: &HeaderMap) -> Self {
+        let content_type = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok());
+        let mime = content_type
+            .and_then(|v| v.split(';').next())
+            .map(str::trim);
+        match mime {
+            Some("application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack") => {
+                Self::MessagePack
+            }
+            Some("application/cbor") => Self::Cbor,
+            _ => Self::Json,
+        }
+    }
+
+    /// Returns the content type that responses in this encoding are sent with.
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::MessagePack => "application/msgpack",
+            Self::Cbor => "application/cbor",
+        }
+    }
+
+    /// Deserializes a body in this encoding.
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, String> {
+        let mut reader = bytes;
+        let body = match self {
+            Self::Json => return serde_json::from_slice(bytes).map_err(|err| err.to_string()),
+            Self::MessagePack => {
+                rmp_serde::from_read(&mut reader).map_err(|err| err.to_string())?
+            }
+            Self::Cbor => ciborium::from_reader(&mut reader).map_err(|err| err.to_string())?,
+        };
+        match reader.is_empty() {
+            true => Ok(body),
+            false => Err("trailing bytes after the value".into()),
+        }
+    }
+
+    /// Serializes a body in this encoding.
+    ///
+    /// Structs are encoded as maps keyed by field name, just like JSON objects.
+    fn encode<T: Serialize>(&self, body: &T) -> Result<Vec<u8>, String> {
+        match self {
+            Self::Json => serde_json::to_vec(body).map_err(|err| err.to_string()),
+            Self::MessagePack => rmp_serde::to_vec_named(body).map_err(|err| err.to_string()),
+            Self::Cbor => {
+                let mut out = Vec::new();
+                ciborium::into_writer(body, &mut out).map_err(|err| err.to_string())?;
+                Ok(out)
+            }
+        }
+    }
+}
+
+/// A request or response body in the [`Encoding`] negotiated via the `Content-Type` of the request.
+///
+/// JSON bodies are handled by the [`Json`] extractor itself, so they behave exactly the same as before.
+#[derive(Debug)]
+pub struct Negotiated<T>(pub Encoding, pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for Negotiated<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Response> {
+        let encoding = Encoding::of_request(request.headers());
+        if encoding == Encoding::Json {
+            let Json(body) = Json::from_request(request, state)
+                .await
+                .map_err(IntoResponse::into_response)?;
+            return Ok(Self(encoding, body));
+        }
+
+        let bytes = Bytes::from_request(request, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        let invalid = |err: &dyn std::fmt::Display| {
+            let message = format!(
+                "Failed to decode the {} body: {err}",
+                encoding.content_type()
+            );
+            (StatusCode::BAD_REQUEST, message).into_response()
+        };
+        let body = encoding.decode(&bytes).map_err(|err| invalid(&err))?;
+        Ok(Self(encoding, body))
+    }
+}
+
+impl<T: Serialize> IntoResponse for Negotiated<T> {
+    fn into_response(self) -> Response {
+        let Self(encoding, body) = self;
+        if encoding == Encoding::Json {
+            return Json(body).into_response();
+        }
+        match encoding.encode(&body) {
+            Ok(bytes) => {
+                let content_type = HeaderValue::from_static(encoding.content_type());
+                ([(CONTENT_TYPE, content_type)], bytes).into_response()
+            }
+            Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err).into_response(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use axum::body::Body;
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+    struct Sample {
+        config_name: String,
+        project_id: u64,
+        spent: f64,
+        refund: bool,
+        source: Option<String>,
+        large: u64,
+        negative: Vec<i64>,
+        long: String,
+        nested: BTreeMap<String, Vec<u32>>,
+    }
+
+    #[test]
+    fn test_encodings() {
+        let sample = Sample {
+            config_name: "symbolication-native".into(),
+            project_id: 42,
+            spent: 1.5,
+            refund: false,
+            source: None,
+            large: u64::MAX,
+            negative: vec![-1, -100, -40_000, i64::MIN],
+            long: "x".repeat(300),
+            nested: BTreeMap::from([("items".into(), vec![1, 2, 3]), ("empty".into(), vec![])]),
+        };
+        for encoding in [Encoding::Json, Encoding::MessagePack, Encoding::Cbor] {
+            let encoded = encoding.encode(&sample).unwrap();
+            assert_eq!(
+                encoding.decode(&encoded),
+                Ok(sample.clone()),
+                "{encoding:?}"
+            );
+
+            let truncated = &encoded[..encoded.len() - 1];
+            assert!(
+                encoding.decode::<Sample>(truncated).is_err(),
+                "{encoding:?}"
+            );
+        }
+
+        // {"a": 1, "b": [true, null]} as encoded by other implementations
+        #[derive(Clone, Debug, Deserialize, PartialEq)]
+        struct Other {
+            a: u64,
+            b: Vec<Option<bool>>,
+        }
+        let expected = Other {
+            a: 1,
+            b: vec![Some(true), None],
+        };
+        let msgpack = b"\x82\xa1a\x01\xa1b\x92\xc3\xc0";
+        assert_eq!(Encoding::MessagePack.decode(msgpack), Ok(expected.clone()));
+        let cbor = b"\xa2\x61a\x01\x61b\x82\xf5\xf6";
+        assert_eq!(Encoding::Cbor.decode(cbor), Ok(expected.clone()));
+
+        // floats of all widths, and small negative integers
+        assert_eq!(
+            Encoding::MessagePack.decode(b"\xca\x3f\xc0\x00\x00"),
+            Ok(1.5)
+        );
+        assert_eq!(Encoding::MessagePack.decode(b"\xd0\x80"), Ok(-128));
+        assert_eq!(Encoding::Cbor.decode(b"\xf9\x3e\x00"), Ok(1.5));
+        assert_eq!(Encoding::Cbor.decode(b"\x38\x63"), Ok(-100));
+
+        assert!(Encoding::Cbor.decode::<u64>(b"\x01\x01").is_err());
+        assert!(Encoding::MessagePack.decode::<u64>(b"\x01\x01").is_err());
+    }
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct SpendingRequest {
+        project_id: u64,
+        spent: f64,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct SpendingResponse {
+        exceeds_budget: bool,
+    }
+
+    async fn extract(
+        content_type: &str,
+        body: Vec<u8>,
+    ) -> Result<Negotiated<SpendingRequest>, StatusCode> {
+        let request = axum::http::Request::builder()
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from(body))
+            .unwrap();
+        Negotiated::from_request(request, &())
+            .await
+            .map_err(|response| response.status())
+    }
+
+    #[tokio::test]
+    async fn test_negotiated() {
+        let expected = SpendingRequest {
+            project_id: 42,
+            spent: 2.5,
+        };
+        for (content_type, encoding) in [
+            ("application/json", Encoding::Json),
+            ("application/msgpack", Encoding::MessagePack),
+            ("application/x-msgpack", Encoding::MessagePack),
+            ("application/cbor; charset=binary", Encoding::Cbor),
+        ] {
+            let body = encoding.encode(&expected).unwrap();
+            let Negotiated(negotiated, request) = extract(content_type, body).await.unwrap();
+            assert_eq!(negotiated, encoding);
+            assert_eq!(request, expected);
+
+            // responses are encoded just like the request
+            let response = SpendingResponse {
+                exceeds_budget: true,
+            };
+            let response = Negotiated(encoding, response).into_response();
+            assert_eq!(response.headers()[CONTENT_TYPE], encoding.content_type());
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let expected = SpendingResponse {
+                exceeds_budget: true,
+            };
+            assert_eq!(encoding.decode(&body), Ok(expected));
+        }
+
+        #[derive(Serialize)]
+        struct MissingField {
+            project_id: u64,
+        }
+        let missing_field = Encoding::Cbor.encode(&MissingField { project_id: 42 });
+        let status = extract("application/cbor", missing_field.unwrap())
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let invalid = b"\xc1".to_vec();
+        let status = extract("application/msgpack", invalid).await.unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        // other content types are still rejected by the JSON extractor
+        let status = extract("text/plain", b"{}".to_vec()).await.unwrap_err();
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+}
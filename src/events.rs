@@ -0,0 +1,170 @@
+use std::fmt;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use crate::ProjectKey;
+
+/// An event within the [`Service`](crate::Service) that might be of interest to operators.
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum Event {
+    /// A project that was exceeding its budget has been cleaned up by the maintenance thread.
+    ///
+    /// This happens when a blocked project did not receive any traffic for its whole window
+    /// (plus the configured grace period), and it effectively unblocks the project.
+    BlockedProjectCleanedUp {
+        /// The name of the config the project belongs to.
+        config: String,
+        /// The key of the project.
+        project_id: ProjectKey,
+    },
+    /// Requests were made for a config name which is not registered.
+    ///
+    /// This is rate limited per config name, and usually hints at a misconfigured client.
+    UnknownConfig {
+        /// The unknown config name.
+        config: String,
+        /// The number of requests for this config name since the last event.
+        requests: u64,
+    },
+    /// The state exceeded its configured memory limit, and projects were evicted to make room.
+    ///
+    /// Only projects which are not exceeding their budget are evicted, least recently updated first.
+    MemoryLimitExceeded {
+        /// The approximate memory used by the state before evicting, in bytes.
+        used_bytes: usize,
+        /// The configured memory limit, in bytes.
+        limit_bytes: usize,
+        /// The number of evicted projects.
+        evicted: usize,
+    },
+    /// More projects than the configured threshold changed their "exceeded" state within one maintenance pass.
+    ///
+    /// This single event replaces the individual [`Event::BlockedProjectCleanedUp`] events of the pass,
+    /// to avoid alert storms. See [`Service::set_mass_state_change_threshold`](crate::Service::set_mass_state_change_threshold).
+    MassStateChange {
+        /// The total number of projects that changed their state.
+        state_changes: usize,
+        /// The number of projects that changed their state per config, most changes first.
+        configs: Vec<(String, usize)>,
+    },
+    /// The clock jumped forward between two maintenance passes, for example after a suspended VM resumed.
+    ///
+    /// See [`Service::set_skip_clock_jumps`](crate::Service::set_skip_clock_jumps).
+    ClockJumped {
+        /// The time between the two maintenance passes.
+        jumped: Duration,
+        /// The number of projects whose state was shifted forward to skip the jump.
+        skipped_projects: usize,
+    },
+    /// A config was registered while more than [`CONFIG_COUNT_WARNING`] configs are registered.
+    ///
+    /// Every config adds to the work of each maintenance pass, and to the size of the metrics.
+    /// The number of configs can be capped via [`Service::set_max_configs`](crate::Service::set_max_configs).
+    ///
+    /// [`CONFIG_COUNT_WARNING`]: crate::CONFIG_COUNT_WARNING
+    ManyConfigs {
+        /// The number of registered configs.
+        configs: usize,
+    },
+    /// A config was registered or replaced with a likely misconfiguration, see [`BudgetingConfig::warnings`].
+    ///
+    /// The config is in effect nonetheless, but its decisions are probably not what was intended.
+    ///
+    /// [`BudgetingConfig::warnings`]: crate::BudgetingConfig::warnings
+    ConfigWarning {
+        /// The name of the config.
+        config: String,
+        /// The description of the misconfiguration.
+        warning: &'static str,
+    },
+    /// A maintenance pass panicked, and was aborted.
+    ///
+    /// The maintenance continues with the next pass, but this hints at a bug.
+    MaintenancePanicked {
+        /// The message of the panic.
+        message: String,
+    },
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BlockedProjectCleanedUp { config, project_id } => write!(
+                f,
+                "blocked project {project_id} of config `{config}` was cleaned up, unblocking it"
+            ),
+            Self::UnknownConfig { config, requests } => {
+                write!(f, "{requests} requests for unknown config `{config}`")
+            }
+            Self::MemoryLimitExceeded {
+                used_bytes,
+                limit_bytes,
+                evicted,
+            } => write!(
+                f,
+                "state uses {used_bytes} bytes, exceeding the limit of {limit_bytes} bytes, evicted {evicted} projects"
+            ),
+            Self::MassStateChange {
+                state_changes,
+                configs,
+            } => {
+                write!(f, "{state_changes} projects changed their state at once")?;
+                for (idx, (config, state_changes)) in configs.iter().enumerate() {
+                    let separator = if idx == 0 { ": " } else { ", " };
+                    write!(f, "{separator}{state_changes} of config `{config}`")?;
+                }
+                Ok(())
+            }
+            Self::ClockJumped {
+                jumped,
+                skipped_projects,
+            } => write!(
+                f,
+                "clock jumped forward by {jumped:?} between maintenance passes, skipped the jump for {skipped_projects} projects"
+            ),
+            Self::ManyConfigs { configs } => write!(
+                f,
+                "{configs} configs are registered, consider consolidating them or capping their number"
+            ),
+            Self::ConfigWarning { config, warning } => {
+                write!(f, "config `{config}` is likely misconfigured: {warning}")
+            }
+            Self::MaintenancePanicked { message } => {
+                write!(f, "maintenance pass panicked: {message}")
+            }
+        }
+    }
+}
+
+/// A callback that is invoked for every [`Event`].
+type Handler = Box<dyn Fn(&Event) + Send + Sync>;
+
+/// Dispatches [`Event`]s to an optionally registered handler.
+#[derive(Default)]
+pub(crate) struct Events {
+    handler: RwLock<Option<Handler>>,
+}
+
+impl Events {
+    /// Registers the handler that is invoked for all future [`Event`]s.
+    pub fn set_handler(&self, handler: Handler) {
+        *self.handler.write().unwrap() = Some(handler);
+    }
+
+    /// Emits the given [`Event`] to the registered handler, if any.
+    pub fn emit(&self, event: Event) {
+        if let Some(handler) = self.handler.read().unwrap().as_ref() {
+            handler(&event);
+        }
+    }
+}
+
+impl fmt::Debug for Events {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let has_handler = self.handler.read().unwrap().is_some();
+        f.debug_struct("Events")
+            .field("has_handler", &has_handler)
+            .finish()
+    }
+}
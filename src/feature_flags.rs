@@ -0,0 +1,72 @@
+//! Periodically polls the [`Enforcement`] of each config from a feature-flag provider.
+//!
+//! The provider is expected to serve a JSON object mapping config names to their enforcement,
+//! one of `"on"`, `"dry-run"` or `"off"`. Configs missing from the response are enforced.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use indexmap::IndexMap;
+use peanutbutter::{Enforcement, Service};
+
+use crate::http_source::HttpSource;
+
+type Flags = IndexMap<String, Enforcement>;
+
+/// Applies the enforcement `flags` to all the configs of the [`Service`].
+fn apply(service: &Service, flags: &Flags) {
+    for (name, registered) in service.configs() {
+        let enforcement = flags.get(&name).copied().unwrap_or_default();
+        if registered.enforcement != enforcement
+            && service.set_enforcement(&name, enforcement).is_ok()
+        {
+            println!("Changed enforcement of config `{name}` to {enforcement:?}");
+        }
+    }
+}
+
+/// Fetches flags from the feature-flag provider every `interval` forever, and applies them to the [`Service`].
+pub async fn run(provider: HttpSource, interval: Duration, service: Arc<Service>) {
+    loop {
+        match provider.fetch().await {
+            Ok(flags) => apply(&service, &flags),
+            Err(err) => println!("Failed to fetch feature flags: {err}"),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use peanutbutter::BudgetingConfig;
+
+    use super::*;
+
+    #[test]
+    fn test_apply_flags() {
+        let service = Service::new();
+        for name in ["a", "b", "c"] {
+            let config = BudgetingConfig::new(
+                Duration::from_secs(60),
+                Duration::from_secs(10),
+                Duration::from_secs(1),
+                10.,
+            );
+            service.try_add_config(name, config).unwrap();
+        }
+
+        let flags: Flags = serde_json::from_str(r#"{"a": "dry-run", "b": "off"}"#).unwrap();
+        apply(&service, &flags);
+        let enforcement = |name: &str| service.configs()[name].enforcement;
+        assert_eq!(enforcement("a"), Enforcement::DryRun);
+        assert_eq!(enforcement("b"), Enforcement::Off);
+        assert_eq!(enforcement("c"), Enforcement::On);
+
+        // configs missing from the flags are enforced again
+        apply(&service, &Flags::new());
+        assert_eq!(enforcement("a"), Enforcement::On);
+        assert_eq!(enforcement("b"), Enforcement::On);
+    }
+}
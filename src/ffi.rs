@@ -0,0 +1,161 @@
+//! A C ABI for embedding the [`Service`] into non-Rust applications, enabled by the `ffi` feature.
+//!
+//! Build it as a shared library with `cargo rustc --release --lib --features ffi --crate-type cdylib`.
+//! The functions returning a `c_int` return `1` for `true`, `0` for `false`, and `-1` for errors,
+//! which are invalid arguments or unknown config names.
+//! Config names are NUL-terminated UTF-8 strings, which are only borrowed for the duration of the call.
+
+use std::ffi::{c_char, c_int, CStr};
+use std::time::Duration;
+
+use crate::{BudgetingConfig, Service};
+
+/// Creates a new (empty) [`Service`], which has to be freed with [`pb_service_free`].
+#[no_mangle]
+pub extern "C" fn pb_service_new() -> *mut Service {
+    Box::into_raw(Box::new(Service::new()))
+}
+
+/// Frees a [`Service`] created with [`pb_service_new`].
+///
+/// # Safety
+///
+/// `service` must have been returned by [`pb_service_new`], and must not be used afterwards.
+/// It may be null, in which case nothing happens.
+#[no_mangle]
+pub unsafe extern "C" fn pb_service_free(service: *mut Service) {
+    if !service.is_null() {
+        drop(Box::from_raw(service));
+    }
+}
+
+/// Registers a new [`BudgetingConfig`] with the given name, see [`Service::try_add_config`].
+///
+/// All the durations are in seconds, and the `budget` is per-second.
+/// Returns `0` on success, or `-1` if the arguments are invalid or the name is already registered.
+///
+/// # Safety
+///
+/// `service` must be a valid pointer returned by [`pb_service_new`],
+/// and `name` must be a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn pb_add_config(
+    service: *const Service,
+    name: *const c_char,
+    backoff_secs: f64,
+    window_secs: f64,
+    bucket_secs: f64,
+    budget: f64,
+) -> c_int {
+    let (Some(service), Some(name)) = (service.as_ref(), config_name(name)) else {
+        return -1;
+    };
+    let durations = [backoff_secs, window_secs, bucket_secs].map(Duration::try_from_secs_f64);
+    let [Ok(backoff), Ok(window), Ok(bucket_size)] = durations else {
+        return -1;
+    };
+    if bucket_size.is_zero() || bucket_size > window || !budget.is_finite() {
+        return -1;
+    }
+
+    let config = BudgetingConfig::new(backoff, window, bucket_size, budget);
+    match service.try_add_config(name, config) {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// Records spent budget, and returns whether the project exceeds its budget,
+/// see [`Service::try_record_spending`].
+///
+/// # Safety
+///
+/// `service` must be a valid pointer returned by [`pb_service_new`],
+/// and `config` must be a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn pb_record_spending(
+    service: *const Service,
+    config: *const c_char,
+    project_id: u64,
+    spent: f64,
+) -> c_int {
+    let (Some(service), Some(config)) = (service.as_ref(), config_name(config)) else {
+        return -1;
+    };
+    to_c_int(service.try_record_spending(config, project_id, spent))
+}
+
+/// Checks whether the project exceeds its budget, see [`Service::try_exceeds_budget`].
+///
+/// # Safety
+///
+/// `service` must be a valid pointer returned by [`pb_service_new`],
+/// and `config` must be a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn pb_exceeds_budget(
+    service: *const Service,
+    config: *const c_char,
+    project_id: u64,
+) -> c_int {
+    let (Some(service), Some(config)) = (service.as_ref(), config_name(config)) else {
+        return -1;
+    };
+    to_c_int(service.try_exceeds_budget(config, project_id))
+}
+
+/// Borrows a config name, which is [`None`] if it is null or not valid UTF-8.
+///
+/// # Safety
+///
+/// `name` must be null, or a valid NUL-terminated string that outlives the returned `&str`.
+unsafe fn config_name<'a>(name: *const c_char) -> Option<&'a str> {
+    if name.is_null() {
+        return None;
+    }
+    CStr::from_ptr(name).to_str().ok()
+}
+
+/// Converts the outcome of a decision into its C representation.
+fn to_c_int<E>(result: Result<bool, E>) -> c_int {
+    match result {
+        Ok(exceeds_budget) => exceeds_budget.into(),
+        Err(_) => -1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr;
+
+    use super::*;
+
+    #[test]
+    fn test_ffi() {
+        let config = c"test";
+        unsafe {
+            let service = pb_service_new();
+            assert_eq!(pb_add_config(service, config.as_ptr(), 1., 10., 1., 10.), 0);
+            // duplicate names and invalid durations are rejected
+            assert_eq!(
+                pb_add_config(service, config.as_ptr(), 1., 10., 1., 10.),
+                -1
+            );
+            assert_eq!(
+                pb_add_config(service, c"other".as_ptr(), 1., 10., 0., 10.),
+                -1
+            );
+
+            assert_eq!(pb_record_spending(service, config.as_ptr(), 1, 50.), 0);
+            assert_eq!(pb_record_spending(service, config.as_ptr(), 1, 100.), 1);
+            assert_eq!(pb_exceeds_budget(service, config.as_ptr(), 1), 1);
+            assert_eq!(pb_exceeds_budget(service, config.as_ptr(), 2), 0);
+
+            assert_eq!(pb_exceeds_budget(service, c"unknown".as_ptr(), 1), -1);
+            assert_eq!(pb_exceeds_budget(service, ptr::null(), 1), -1);
+            assert_eq!(pb_exceeds_budget(ptr::null(), config.as_ptr(), 1), -1);
+
+            pb_service_free(service);
+            pb_service_free(ptr::null_mut());
+        }
+    }
+}
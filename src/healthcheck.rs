@@ -0,0 +1,134 @@
+//! A round trip against a running instance, usable as a container `HEALTHCHECK`.
+//!
+//! Unlike `/_health`, this exercises the full request path of the budgeting endpoints,
+//! including the JSON (de)serialization and the state of the tracked projects.
+//! Every run spends on a fresh project of the reserved [`CONFIG`], which never exceeds its budget,
+//! and then checks that the spending shows up in its remaining budget.
+
+use std::error::Error;
+use std::time::{Duration, SystemTime};
+
+use peanutbutter::BudgetingConfig;
+use serde::Deserialize;
+use tokio::runtime::Builder;
+
+use crate::http_source::HttpSource;
+
+/// The name of the internal config every server registers for the healthcheck.
+pub const CONFIG: &str = "__healthcheck";
+
+/// The URL of the instance that is checked by default.
+const DEFAULT_URL: &str = "http://127.0.0.1:4433";
+
+/// The amount spent by each healthcheck, which is well within the budget of the [`config`].
+const SPENT: f64 = 1.;
+
+/// The reserved config used by the healthcheck.
+///
+/// The budget of a project is far larger than what a single healthcheck spends, and its state
+/// is cleaned up quickly, so that healthchecks neither block projects nor accumulate state.
+pub fn config() -> BudgetingConfig {
+    let window = Duration::from_secs(10);
+    BudgetingConfig::new(Duration::ZERO, window, Duration::from_secs(1), 10. * SPENT)
+}
+
+#[derive(Deserialize)]
+struct ExceedsBudgetResponse {
+    exceeds_budget: bool,
+}
+
+#[derive(Deserialize)]
+struct RemainingBudgetResponse {
+    remaining_budget: f64,
+}
+
+/// Runs the healthcheck against the instance at the optional URL given in `args`.
+pub fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let url = match args {
+        [] => DEFAULT_URL,
+        [url] => url,
+        _ => return Err("usage: peanutbutter healthcheck [<url>]".into()),
+    };
+    let source = HttpSource::new(url)?;
+    // Every run uses a fresh project, so that concurrent or repeated runs do not interfere.
+    let project_id = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_nanos() as u64;
+
+    let runtime = Builder::new_current_thread().enable_all().build()?;
+    runtime.block_on(round_trip(&source, project_id))
+}
+
+/// Records spending for the project, and checks the decisions and the remaining budget.
+async fn round_trip(source: &HttpSource, project_id: u64) -> Result<(), Box<dyn Error>> {
+    let request = serde_json::json!({"config_name": CONFIG, "project_id": project_id});
+    let mut record = request.clone();
+    record["spent"] = SPENT.into();
+
+    let response: ExceedsBudgetResponse =
+        (source.post("/record_spending", &record.to_string()).await)
+            .map_err(|err| format!("`/record_spending` failed: {err}"))?;
+    if response.exceeds_budget {
+        return Err("`/record_spending` unexpectedly exceeded the budget".into());
+    }
+
+    let response: ExceedsBudgetResponse =
+        (source.post("/exceeds_budget", &request.to_string()).await)
+            .map_err(|err| format!("`/exceeds_budget` failed: {err}"))?;
+    if response.exceeds_budget {
+        return Err("`/exceeds_budget` unexpectedly exceeded the budget".into());
+    }
+
+    let response: RemainingBudgetResponse =
+        (source.post("/remaining_budget", &request.to_string()).await)
+            .map_err(|err| format!("`/remaining_budget` failed: {err}"))?;
+    let config = config();
+    let full_budget = config.budget * config.budgeting_window.as_secs_f64();
+    if response.remaining_budget > full_budget - SPENT {
+        return Err(format!(
+            "the spending was not recorded, {} of {full_budget} remaining",
+            response.remaining_budget
+        )
+        .into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{app, create_service, AppState, ListenerMetrics, Settings, Transport};
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = create_service(&Settings::default()).unwrap();
+        let metrics = ListenerMetrics::new(Transport::Http, addr);
+        let state = AppState {
+            service: Arc::new(service),
+            strict_configs: true,
+            coalescer: None,
+            http_metrics: Default::default(),
+            listeners: Arc::new(vec![metrics.clone()]),
+            canary: None,
+            capture: None,
+            log_requests: false,
+            cors: None,
+            ip_limiter: None,
+        };
+        let app = app(state, 1024, metrics);
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let source = HttpSource::new(&format!("http://{addr}")).unwrap();
+        round_trip(&source, 1).await.unwrap();
+        round_trip(&source, 2).await.unwrap();
+
+        let source = HttpSource::new("http://127.0.0.1:1").unwrap();
+        assert!(round_trip(&source, 1).await.is_err());
+    }
+}
@@ -0,0 +1,349 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use quanta::Instant;
+use serde::Serialize;
+
+use crate::config::ConfigId;
+use crate::summary::SpendSummary;
+
+/// The duration covered by each slot of the [`SpendHistory`].
+const HISTORY_SLOT: Duration = Duration::from_secs(60 * 60);
+
+/// The maximum number of hours of spend rates that are retained per config.
+pub const MAX_HISTORY_HOURS: usize = 24;
+
+/// The duration covered by each [`MinuteAggregate`].
+const TREND_SLOT: Duration = Duration::from_secs(60);
+
+/// The maximum number of minutes of [`MinuteAggregate`]s that are retained per config.
+pub const MAX_TREND_MINUTES: usize = 6 * 60;
+
+/// The exponent of the upper bound of the smallest bucket of a [`SpendRateHistogram`].
+const MIN_EXPONENT: i32 = -10;
+
+/// The number of buckets of a [`SpendRateHistogram`], including the overflow bucket.
+const NUM_BUCKETS: usize = 42;
+
+/// A histogram of per-project spend rates, with power-of-two buckets.
+///
+/// Each bucket counts the spend rates up to its upper bound of `2^(idx - 10)`,
+/// while the last bucket counts all the spend rates exceeding the others.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SpendRateHistogram {
+    buckets: [u64; NUM_BUCKETS],
+}
+
+impl Default for SpendRateHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: [0; NUM_BUCKETS],
+        }
+    }
+}
+
+impl SpendRateHistogram {
+    /// Returns the upper bound of the bucket with the given index.
+    fn upper_bound(idx: usize) -> f64 {
+        2f64.powi(idx as i32 + MIN_EXPONENT)
+    }
+
+    /// Adds a single spend rate to this histogram.
+    pub(crate) fn observe(&mut self, spend_rate: f64) {
+        let idx = (0..NUM_BUCKETS - 1)
+            .find(|idx| spend_rate <= Self::upper_bound(*idx))
+            .unwrap_or(NUM_BUCKETS - 1);
+        self.buckets[idx] += 1;
+    }
+
+    /// Adds all the spend rates of the `other` histogram to this one.
+    pub(crate) fn merge(&mut self, other: &SpendRateHistogram) {
+        for (bucket, other) in self.buckets.iter_mut().zip(other.buckets) {
+            *bucket += other;
+        }
+    }
+
+    /// Returns the total number of spend rates in this histogram.
+    pub fn total(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+}
+
+/// A budget that could be configured, and how it would have affected the projects.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct BudgetCandidate {
+    /// The candidate budget, averaged *per-second*.
+    pub budget: f64,
+    /// The fraction of the observed spend rates exceeding the candidate budget.
+    ///
+    /// As spend rates are sampled on every maintenance pass, this is the fraction of the time
+    /// that the projects would have been blocked, on average.
+    pub blocked_fraction: f64,
+}
+
+/// A report of how the projects of a config would have been affected by different budgets.
+///
+/// See [`Service::budget_report`](crate::Service::budget_report).
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct BudgetReport {
+    /// The number of hours of history the report is based on, including the current, incomplete one.
+    pub hours: usize,
+    /// The number of sampled per-project spend rates.
+    pub samples: u64,
+    /// The currently configured budget.
+    pub budget: f64,
+    /// The candidate budgets, from the smallest to the largest observed spend rate.
+    pub candidates: Vec<BudgetCandidate>,
+}
+
+impl BudgetReport {
+    /// Creates a report from the given `histogram` of spend rates.
+    pub(crate) fn new(hours: usize, budget: f64, histogram: &SpendRateHistogram) -> Self {
+        let samples = histogram.total();
+        let mut candidates = vec![];
+        let mut exceeding = samples;
+        for (idx, count) in histogram.buckets[..NUM_BUCKETS - 1].iter().enumerate() {
+            exceeding -= count;
+            // Candidates below the smallest observed spend rate would block everything.
+            if exceeding == samples {
+                continue;
+            }
+            candidates.push(BudgetCandidate {
+                budget: SpendRateHistogram::upper_bound(idx),
+                blocked_fraction: exceeding as f64 / samples as f64,
+            });
+            if exceeding == 0 {
+                break;
+            }
+        }
+        Self {
+            hours,
+            samples,
+            budget,
+            candidates,
+        }
+    }
+}
+
+/// The spending of a config aggregated over a single minute.
+///
+/// See [`Service::spend_trend`](crate::Service::spend_trend).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MinuteAggregate {
+    /// The time since this minute started.
+    pub age: Duration,
+    /// The total spend rate (per-second) across all the tracked projects, averaged over the minute.
+    pub spend_rate: f64,
+    /// The highest number of projects exceeding their budget at the same time within the minute.
+    pub blocked_projects: usize,
+}
+
+/// The aggregated [`SpendSummary`]s of a single minute of a config.
+#[derive(Clone, Copy, Debug)]
+struct TrendSlot {
+    /// The time at which the slot started.
+    started: Instant,
+    /// The sum of the total spend rates of all the summaries within the slot.
+    spend_rates: f64,
+    /// The number of summaries within the slot.
+    samples: u32,
+    /// The highest number of blocked projects of the summaries within the slot.
+    blocked_projects: usize,
+}
+
+impl TrendSlot {
+    fn new(started: Instant) -> Self {
+        Self {
+            started,
+            spend_rates: 0.,
+            samples: 0,
+            blocked_projects: 0,
+        }
+    }
+
+    fn observe(&mut self, summary: &SpendSummary) {
+        self.spend_rates += summary.spend_rate;
+        self.samples += 1;
+        self.blocked_projects = self.blocked_projects.max(summary.blocked_projects);
+    }
+}
+
+/// The per-config [`SpendRateHistogram`]s of the last [`MAX_HISTORY_HOURS`] hours,
+/// and the per-config [`MinuteAggregate`]s of the last [`MAX_TREND_MINUTES`] minutes.
+///
+/// This is recorded by the maintenance thread from the [`SpendSummary`]s of each pass.
+#[derive(Debug, Default)]
+pub(crate) struct SpendHistory {
+    /// The hourly slots of each config, along with the time at which each slot started, newest first.
+    slots: Mutex<HashMap<ConfigId, VecDeque<(Instant, SpendRateHistogram)>>>,
+    /// The minutely slots of each config, newest first.
+    trend_slots: Mutex<HashMap<ConfigId, VecDeque<TrendSlot>>>,
+}
+
+impl SpendHistory {
+    /// Records the spend rates of the given `summaries`, and forgets configs that have no summary.
+    pub fn record(&self, now: Instant, summaries: &HashMap<ConfigId, SpendSummary>) {
+        self.record_trend(now, summaries);
+        let mut slots = self.slots.lock().unwrap();
+        slots.retain(|id, _slots| summaries.contains_key(id));
+        for (id, summary) in summaries {
+            let slots = slots.entry(*id).or_default();
+            match slots.front_mut() {
+                Some((started, histogram))
+                    if now.saturating_duration_since(*started) < HISTORY_SLOT =>
+                {
+                    histogram.merge(&summary.spend_rates);
+                }
+                _ => {
+                    slots.push_front((now, summary.spend_rates));
+                    slots.truncate(MAX_HISTORY_HOURS);
+                }
+            }
+        }
+    }
+
+    /// Records the [`MinuteAggregate`]s of the given `summaries`, and forgets configs that have no summary.
+    fn record_trend(&self, now: Instant, summaries: &HashMap<ConfigId, SpendSummary>) {
+        let mut trend_slots = self.trend_slots.lock().unwrap();
+        trend_slots.retain(|id, _slots| summaries.contains_key(id));
+        for (id, summary) in summaries {
+            let slots = trend_slots.entry(*id).or_default();
+            match slots.front_mut() {
+                Some(slot) if now.saturating_duration_since(slot.started) < TREND_SLOT => {
+                    slot.observe(summary);
+                }
+                _ => {
+                    let mut slot = TrendSlot::new(now);
+                    slot.observe(summary);
+                    slots.push_front(slot);
+                    slots.truncate(MAX_TREND_MINUTES);
+                }
+            }
+        }
+    }
+
+    /// Returns the [`MinuteAggregate`]s of the given config within the last `minutes`, oldest first.
+    pub fn trend(&self, id: ConfigId, minutes: usize, now: Instant) -> Vec<MinuteAggregate> {
+        let trend_slots = self.trend_slots.lock().unwrap();
+        let slots = trend_slots.get(&id).into_iter().flatten().take(minutes);
+        let mut trend: Vec<_> = slots
+            .map(|slot| MinuteAggregate {
+                age: now.saturating_duration_since(slot.started),
+                spend_rate: slot.spend_rates / slot.samples.max(1) as f64,
+                blocked_projects: slot.blocked_projects,
+            })
+            .collect();
+        trend.reverse();
+        trend
+    }
+
+    /// Returns the merged spend rates of the given config within the last `hours`,
+    /// along with the number of hourly slots these were recorded in.
+    pub fn spend_rates(&self, id: ConfigId, hours: usize) -> (usize, SpendRateHistogram) {
+        let slots = self.slots.lock().unwrap();
+        let mut histogram = SpendRateHistogram::default();
+        let slots = slots.get(&id).into_iter().flatten().take(hours);
+        let mut num_slots = 0;
+        for (_started, slot) in slots {
+            histogram.merge(slot);
+            num_slots += 1;
+        }
+        (num_slots, histogram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use quanta::Clock;
+
+    use super::*;
+
+    #[test]
+    fn test_budget_report() {
+        let (clock, mock) = Clock::mock();
+        mock.increment(Duration::from_secs(100));
+        let history = SpendHistory::default();
+
+        let summary = |spend_rates: &[f64]| {
+            let mut summary = SpendSummary::default();
+            for spend_rate in spend_rates {
+                summary.spend_rates.observe(*spend_rate);
+            }
+            HashMap::from([(ConfigId(1), summary)])
+        };
+        history.record(clock.now(), &summary(&[1., 1.]));
+        mock.increment(HISTORY_SLOT);
+        history.record(clock.now(), &summary(&[1.5, 4.]));
+        mock.increment(Duration::from_secs(60));
+        history.record(clock.now(), &summary(&[3., 1_000_000_000_000.]));
+
+        let (hours, histogram) = history.spend_rates(ConfigId(1), 1);
+        assert_eq!((hours, histogram.total()), (1, 4));
+        let (hours, histogram) = history.spend_rates(ConfigId(1), 24);
+        assert_eq!((hours, histogram.total()), (2, 6));
+
+        let report = BudgetReport::new(hours, 2., &histogram);
+        assert_eq!(report.samples, 6);
+        let candidate = |budget, blocked: f64| BudgetCandidate {
+            budget,
+            blocked_fraction: blocked / 6.,
+        };
+        assert_eq!(report.candidates[0], candidate(1., 4.));
+        assert_eq!(report.candidates[1], candidate(2., 3.));
+        assert_eq!(report.candidates[2], candidate(4., 1.));
+        // the overflowing spend rate is never below a candidate
+        assert_eq!(
+            report.candidates.last(),
+            Some(&candidate(2f64.powi(30), 1.))
+        );
+
+        // removed configs are forgotten
+        history.record(clock.now(), &HashMap::new());
+        assert_eq!(history.spend_rates(ConfigId(1), 24).0, 0);
+    }
+
+    #[test]
+    fn test_trend() {
+        let (clock, mock) = Clock::mock();
+        mock.increment(Duration::from_secs(100));
+        let history = SpendHistory::default();
+
+        let summary = |spend_rate, blocked_projects| {
+            let summary = SpendSummary {
+                spend_rate,
+                blocked_projects,
+                ..Default::default()
+            };
+            HashMap::from([(ConfigId(1), summary)])
+        };
+        history.record(clock.now(), &summary(1., 0));
+        mock.increment(Duration::from_secs(30));
+        history.record(clock.now(), &summary(3., 2));
+        mock.increment(Duration::from_secs(30));
+        history.record(clock.now(), &summary(5., 1));
+        mock.increment(Duration::from_secs(10));
+
+        let trend = history.trend(ConfigId(1), MAX_TREND_MINUTES, clock.now());
+        let aggregate = |age, spend_rate, blocked_projects| MinuteAggregate {
+            age: Duration::from_secs(age),
+            spend_rate,
+            blocked_projects,
+        };
+        assert_eq!(trend, [aggregate(70, 2., 2), aggregate(10, 5., 1)]);
+        assert_eq!(history.trend(ConfigId(1), 1, clock.now()).len(), 1);
+        assert!(history.trend(ConfigId(2), 60, clock.now()).is_empty());
+
+        // only the most recent minutes are retained
+        for _ in 0..MAX_TREND_MINUTES {
+            mock.increment(TREND_SLOT);
+            history.record(clock.now(), &summary(1., 0));
+        }
+        let trend = history.trend(ConfigId(1), usize::MAX, clock.now());
+        assert_eq!(trend.len(), MAX_TREND_MINUTES);
+
+        // removed configs are forgotten
+        history.record(clock.now(), &HashMap::new());
+        assert!(history.trend(ConfigId(1), 60, clock.now()).is_empty());
+    }
+}
@@ -0,0 +1,143 @@
+//! A minimal HTTP client, for periodically polling JSON documents from internal services.
+//!
+//! JSON requests can also be posted to other endpoints of the same service.
+//!
+//! Only plain `http://` URLs are supported, as these sources are expected to be internal services.
+
+use std::error::Error;
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// The maximum time a single request is allowed to take.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The maximum size of a response.
+const MAX_RESPONSE_SIZE: u64 = 1024 * 1024;
+
+/// A JSON document served over HTTP.
+#[derive(Debug, PartialEq)]
+pub struct HttpSource {
+    /// The `host:port` to connect to, also used as `Host` header.
+    authority: String,
+    /// The path of the document, including the query string.
+    path: String,
+}
+
+impl HttpSource {
+    /// Creates a [`HttpSource`] from an `http://` URL.
+    pub fn new(url: &str) -> Result<Self, String> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("URL `{url}` needs to start with `http://`"))?;
+        let (authority, path) = match rest.find('/') {
+            Some(idx) => rest.split_at(idx),
+            None => (rest, "/"),
+        };
+        if authority.is_empty() {
+            return Err(format!("URL `{url}` is missing a host"));
+        }
+
+        let authority = if authority.contains(':') && !authority.ends_with(']') {
+            authority.into()
+        } else {
+            format!("{authority}:80")
+        };
+        Ok(Self {
+            authority,
+            path: path.into(),
+        })
+    }
+
+    /// Fetches and parses the current document.
+    pub async fn fetch<T: DeserializeOwned>(&self) -> Result<T, Box<dyn Error>> {
+        let request = format!(
+            "GET {} HTTP/1.0\r\nHost: {}\r\nAccept: application/json\r\n\r\n",
+            self.path, self.authority
+        );
+        self.send(request).await
+    }
+
+    /// Posts a JSON `body` to the given `path` relative to the document, and parses the response.
+    pub async fn post<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        body: &str,
+    ) -> Result<T, Box<dyn Error>> {
+        let path = format!("{}{path}", self.path.trim_end_matches('/'));
+        let request = format!(
+            "POST {path} HTTP/1.0\r\nHost: {}\r\nAccept: application/json\r\n\
+             Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+            self.authority,
+            body.len()
+        );
+        self.send(request).await
+    }
+
+    async fn send<T: DeserializeOwned>(&self, request: String) -> Result<T, Box<dyn Error>> {
+        tokio::time::timeout(FETCH_TIMEOUT, self.send_inner(request))
+            .await
+            .map_err(|_| "timed out")?
+    }
+
+    async fn send_inner<T: DeserializeOwned>(&self, request: String) -> Result<T, Box<dyn Error>> {
+        let mut stream = TcpStream::connect(&self.authority).await?;
+        // HTTP/1.0 responses are never chunked, and the server closes the connection when done.
+        stream.write_all(request.as_bytes()).await?;
+
+        let mut response = Vec::new();
+        stream
+            .take(MAX_RESPONSE_SIZE)
+            .read_to_end(&mut response)
+            .await?;
+        parse_response(&response)
+    }
+}
+
+/// Parses a raw HTTP `response` with a JSON body.
+pub fn parse_response<T: DeserializeOwned>(response: &[u8]) -> Result<T, Box<dyn Error>> {
+    let header_end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or("incomplete response")?;
+    let (head, body) = response.split_at(header_end + 4);
+
+    let status_line = head.split(|b| *b == b'\r').next().unwrap_or_default();
+    let status_line = String::from_utf8_lossy(status_line);
+    match status_line.split(' ').nth(1) {
+        Some("200") => {}
+        _ => return Err(format!("unexpected response `{status_line}`").into()),
+    }
+
+    Ok(serde_json::from_slice(body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_url() {
+        let source = HttpSource::new("http://control-plane:8080/configs?service=pb").unwrap();
+        assert_eq!(source.authority, "control-plane:8080");
+        assert_eq!(source.path, "/configs?service=pb");
+
+        let source = HttpSource::new("http://control-plane").unwrap();
+        assert_eq!(source.authority, "control-plane:80");
+        assert_eq!(source.path, "/");
+
+        assert!(HttpSource::new("https://control-plane").is_err());
+        assert!(HttpSource::new("http:///configs").is_err());
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let response = b"HTTP/1.0 200 OK\r\nContent-Type: application/json\r\n\r\n[1, 2]";
+        assert_eq!(parse_response::<Vec<u32>>(response).unwrap(), [1, 2]);
+
+        assert!(parse_response::<Vec<u32>>(b"HTTP/1.0 404 Not Found\r\n\r\n").is_err());
+        assert!(parse_response::<Vec<u32>>(b"HTTP/1.0 200 OK\r\n").is_err());
+    }
+}
@@ -0,0 +1,346 @@
+//! Limits the connections and requests per source IP, so that a single client host can not starve others.
+//!
+//! The limits are applied at the transport layer, before any request reaches the [`Service`](peanutbutter::Service).
+//! HTTP connections beyond the limit have all their requests rejected with `429 Too Many Requests`,
+//! and are closed after the first response. RESP connections beyond the limit are closed right away.
+//! Budget checks and recorded spending beyond the limits are [shed](Shed) instead, and answered
+//! according to the [`OverloadPolicy`](peanutbutter::OverloadPolicy) of their config.
+
+use std::convert::Infallible;
+use std::future::{ready, Future, Ready};
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use axum::extract::Request;
+use axum::http::{header, HeaderValue, Method, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::serve::IncomingStream;
+use axum::Router;
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use peanutbutter::{write_metric, MetricKind, MetricsWriter};
+use tower_service::Service;
+
+/// The number of source IPs above which idle request rate buckets are cleaned up.
+///
+/// Cleaning up is amortized, and only happens once for every this many newly seen source IPs.
+const MAX_IDLE_BUCKETS: usize = 10_000;
+
+/// The routes whose requests are [shed](Shed) rather than rejected when they exceed the limits.
+const SHEDDABLE_ROUTES: &[&str] = &[
+    "/exceeds_budget",
+    "/record_spending",
+    "/exceeds_budget_by_uuid",
+    "/record_spending_by_uuid",
+];
+
+/// Marks a request which exceeded the limits of its source IP, with the reason it would be rejected for.
+///
+/// The request is still routed, so that its handler can answer it according to the
+/// [`OverloadPolicy`](peanutbutter::OverloadPolicy) of its config.
+#[derive(Clone, Copy, Debug)]
+pub struct Shed(pub &'static str);
+
+/// The request rate of a single source IP, as a token bucket.
+#[derive(Debug)]
+struct RateBucket {
+    /// The number of requests that may still be made right away.
+    tokens: f64,
+    /// The time at which the tokens were last refilled.
+    refilled: Instant,
+}
+
+/// Tracks the open connections and the request rate of each source IP.
+#[derive(Debug)]
+pub struct IpLimiter {
+    /// The maximum number of concurrently open connections per source IP.
+    max_connections: Option<usize>,
+    /// The maximum number of requests per second per source IP.
+    max_requests_per_sec: Option<f64>,
+    /// The open connections of each source IP that has any.
+    connections: DashMap<IpAddr, usize>,
+    /// The request rate of each source IP.
+    requests: DashMap<IpAddr, RateBucket>,
+    /// The number of request rate buckets, as [`DashMap::len`] locks all the shards.
+    buckets: AtomicUsize,
+    /// The number of request rate buckets that were ever created, which spaces out their cleanup.
+    created_buckets: AtomicUsize,
+    /// The number of connections that exceeded the connection limit.
+    rejected_connections: AtomicU64,
+    /// The number of requests or commands that exceeded the request limit.
+    rejected_requests: AtomicU64,
+}
+
+/// An open connection, which counts against the connection limit of its source IP until it is dropped.
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    limiter: Arc<IpLimiter>,
+    ip: IpAddr,
+}
+
+impl IpLimiter {
+    /// Creates an [`IpLimiter`], or [`None`] if there are no limits at all.
+    pub fn new(max_connections: Option<usize>, max_requests_per_sec: Option<f64>) -> Option<Self> {
+        if max_connections.is_none() && max_requests_per_sec.is_none() {
+            return None;
+        }
+        Some(Self {
+            max_connections,
+            max_requests_per_sec,
+            connections: Default::default(),
+            requests: Default::default(),
+            buckets: AtomicUsize::new(0),
+            created_buckets: AtomicUsize::new(0),
+            rejected_connections: AtomicU64::new(0),
+            rejected_requests: AtomicU64::new(0),
+        })
+    }
+
+    /// Opens a connection from `ip`, or returns [`None`] if that exceeds the connection limit.
+    pub fn connect(self: &Arc<Self>, ip: IpAddr) -> Option<ConnectionGuard> {
+        let mut connections = self.connections.entry(ip).or_insert(0);
+        if self.max_connections.is_some_and(|max| *connections >= max) {
+            drop(connections);
+            self.rejected_connections.fetch_add(1, Ordering::Relaxed);
+            // The entry might have been created just now.
+            self.connections
+                .remove_if(&ip, |_ip, connections| *connections == 0);
+            return None;
+        }
+        *connections += 1;
+        Some(ConnectionGuard {
+            limiter: self.clone(),
+            ip,
+        })
+    }
+
+    /// Checks whether another request from `ip` is within the request limit, and counts it if it is.
+    ///
+    /// Each source IP may burst up to a second worth of requests.
+    pub fn allow_request(&self, ip: IpAddr) -> bool {
+        let Some(max_requests_per_sec) = self.max_requests_per_sec else {
+            return true;
+        };
+        let now = Instant::now();
+        let burst = max_requests_per_sec.max(1.);
+        let refill = |bucket: &RateBucket| {
+            let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+            (bucket.tokens + elapsed * max_requests_per_sec).min(burst)
+        };
+
+        let mut created = false;
+        let mut bucket = match self.requests.entry(ip) {
+            Entry::Occupied(entry) => entry.into_ref(),
+            Entry::Vacant(entry) => {
+                created = true;
+                entry.insert(RateBucket {
+                    tokens: burst,
+                    refilled: now,
+                })
+            }
+        };
+        bucket.tokens = refill(&bucket);
+        bucket.refilled = now;
+        let allowed = bucket.tokens >= 1.;
+        if allowed {
+            bucket.tokens -= 1.;
+        }
+        drop(bucket);
+
+        if created {
+            self.buckets.fetch_add(1, Ordering::Relaxed);
+            let created = self.created_buckets.fetch_add(1, Ordering::Relaxed) + 1;
+            if created.is_multiple_of(MAX_IDLE_BUCKETS)
+                && self.buckets.load(Ordering::Relaxed) > MAX_IDLE_BUCKETS
+            {
+                // Buckets that refilled completely are no different from new ones.
+                let mut removed = 0;
+                self.requests.retain(|_ip, bucket| {
+                    let idle = refill(bucket) >= burst;
+                    removed += idle as usize;
+                    !idle
+                });
+                self.buckets.fetch_sub(removed, Ordering::Relaxed);
+            }
+        }
+        if !allowed {
+            self.rejected_requests.fetch_add(1, Ordering::Relaxed);
+        }
+        allowed
+    }
+
+    /// Writes the limiter metrics to `out`.
+    pub fn write_metrics(&self, out: &mut impl MetricsWriter) {
+        write_metric(
+            out,
+            "peanutbutter_ip_rejected_connections_total",
+            MetricKind::Counter,
+            "Number of connections rejected because their source IP had too many open connections.",
+            self.rejected_connections.load(Ordering::Relaxed),
+        );
+        write_metric(
+            out,
+            "peanutbutter_ip_rejected_requests_total",
+            MetricKind::Counter,
+            "Number of requests and RESP commands rejected because their source IP sent too many.",
+            self.rejected_requests.load(Ordering::Relaxed),
+        );
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let connections = &self.limiter.connections;
+        if let Some(mut count) = connections.get_mut(&self.ip) {
+            *count = count.saturating_sub(1);
+        }
+        connections.remove_if(&self.ip, |_ip, connections| *connections == 0);
+    }
+}
+
+/// Creates the service of each accepted HTTP connection, applying the limits of its source IP.
+///
+/// This is passed to [`axum::serve`] in place of the [`Router`] itself.
+#[derive(Clone, Debug)]
+pub struct LimitedRouter {
+    pub router: Router,
+    pub limiter: Arc<IpLimiter>,
+}
+
+/// The service of a single HTTP connection, see [`LimitedRouter`].
+#[derive(Clone, Debug)]
+pub struct LimitedConnection {
+    router: Router,
+    limiter: Arc<IpLimiter>,
+    ip: IpAddr,
+    /// The open connection, or [`None`] if it exceeded the connection limit.
+    connection: Option<Arc<ConnectionGuard>>,
+}
+
+impl Service<IncomingStream<'_>> for LimitedRouter {
+    type Response = LimitedConnection;
+    type Error = Infallible;
+    type Future = Ready<Result<LimitedConnection, Infallible>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, stream: IncomingStream<'_>) -> Self::Future {
+        let ip = stream.remote_addr().ip();
+        ready(Ok(LimitedConnection {
+            router: self.router.clone(),
+            limiter: self.limiter.clone(),
+            ip,
+            connection: self.limiter.connect(ip).map(Arc::new),
+        }))
+    }
+}
+
+impl Service<Request> for LimitedConnection {
+    type Response = Response;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, mut request: Request) -> Self::Future {
+        let reason = if self.connection.is_none() {
+            "too many connections"
+        } else if !self.limiter.allow_request(self.ip) {
+            "too many requests"
+        } else {
+            return Box::pin(self.router.call(request));
+        };
+
+        let response: Self::Future = if request.method() == Method::POST
+            && SHEDDABLE_ROUTES.contains(&request.uri().path())
+        {
+            request.extensions_mut().insert(Shed(reason));
+            Box::pin(self.router.call(request))
+        } else {
+            let response = (StatusCode::TOO_MANY_REQUESTS, reason).into_response();
+            Box::pin(ready(Ok(response)))
+        };
+        let close = self.connection.is_none();
+        Box::pin(async move {
+            let mut response = response.await?;
+            if close {
+                // Ask the client to open a new connection, which might be within the limit again.
+                let close = HeaderValue::from_static("close");
+                response.headers_mut().insert(header::CONNECTION, close);
+            }
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_limit() {
+        let limiter = Arc::new(IpLimiter::new(Some(2), None).unwrap());
+        let ip: IpAddr = [10, 0, 0, 1].into();
+        let other: IpAddr = [10, 0, 0, 2].into();
+
+        let first = limiter.connect(ip).unwrap();
+        let second = limiter.connect(ip).unwrap();
+        assert!(limiter.connect(ip).is_none());
+        let _other = limiter.connect(other).unwrap();
+
+        drop(first);
+        let _third = limiter.connect(ip).unwrap();
+        assert!(limiter.connect(ip).is_none());
+        assert_eq!(limiter.rejected_connections.load(Ordering::Relaxed), 2);
+
+        drop((second, _third));
+        assert!(!limiter.connections.contains_key(&ip));
+        assert!(limiter.allow_request(ip));
+    }
+
+    #[test]
+    fn test_request_limit() {
+        assert!(IpLimiter::new(None, None).is_none());
+        let limiter = IpLimiter::new(None, Some(5.)).unwrap();
+        let ip: IpAddr = [10, 0, 0, 1].into();
+
+        // a second worth of requests can be made right away
+        for _ in 0..5 {
+            assert!(limiter.allow_request(ip));
+        }
+        assert!(!limiter.allow_request(ip));
+        assert!(limiter.allow_request([10, 0, 0, 2].into()));
+        assert_eq!(limiter.rejected_requests.load(Ordering::Relaxed), 1);
+
+        std::thread::sleep(std::time::Duration::from_millis(250));
+        assert!(limiter.allow_request(ip));
+    }
+
+    #[test]
+    fn test_idle_buckets() {
+        let limiter = IpLimiter::new(None, Some(1_000_000.)).unwrap();
+        let ip = |idx: usize| IpAddr::from((idx as u32).to_be_bytes());
+
+        // idle buckets are only cleaned up once for every `MAX_IDLE_BUCKETS` new source IPs
+        for idx in 0..MAX_IDLE_BUCKETS * 2 - 1 {
+            assert!(limiter.allow_request(ip(idx)));
+        }
+        assert_eq!(
+            limiter.buckets.load(Ordering::Relaxed),
+            MAX_IDLE_BUCKETS * 2 - 1
+        );
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        assert!(limiter.allow_request(ip(MAX_IDLE_BUCKETS * 2)));
+        // only the bucket of the latest request has not refilled yet
+        assert_eq!(limiter.buckets.load(Ordering::Relaxed), 1);
+        assert_eq!(limiter.requests.len(), 1);
+    }
+}
@@ -1,22 +1,251 @@
+//! A self contained service for keeping track of per-project budgets.
+//!
+//! The [`Service`] manages the budgets of many projects across multiple named [`BudgetingConfig`]s.
+//!
+//! The underlying [`ProjectStats`] can also be used standalone to track the budget of a single
+//! project (or anything, really):
+//!
+//! ```
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//!
+//! use peanutbutter::{BudgetingConfig, Clock, ProjectStats};
+//!
+//! let (clock, mock) = Clock::mock();
+//! # mock.increment(Duration::from_secs(100));
+//! let config = BudgetingConfig::new(
+//!     Duration::from_secs(60), // backoff_duration
+//!     Duration::from_secs(10), // budgeting_window
+//!     Duration::from_secs(1),  // bucket_size
+//!     10.,                     // budget per second
+//! )
+//! .with_clock(clock);
+//!
+//! let mut stats = ProjectStats::new(Arc::new(config));
+//! assert!(!stats.record_spending(50.));
+//! assert!(stats.record_spending(100.));
+//!
+//! let report = stats.report();
+//! assert!(report.exceeds_budget);
+//! assert!(report.spent_budget > report.budget);
+//! ```
+//!
+//! # Features
+//!
+//! - `service` (default): The [`Service`] along with its background maintenance thread, and the server binary.
+//!   Without it, only the core accounting ([`BudgetingConfig`], [`ProjectStats`] and the other
+//!   [`BudgetTracker`]s) is built, which has no OS threads and also compiles to `wasm32`,
+//!   for example to pre-filter traffic in edge workers with the exact same budgeting logic.
+//!   Where [`Clock::new`] has no time source, a [`Clock::mock`] advanced by the host can be used instead.
+//! - `ffi`: A C ABI for embedding the [`Service`] into non-Rust applications, see the `ffi` module.
+
+// The docs of the core accounting link to the `Service`, which only exists with the `service` feature.
+#![cfg_attr(not(feature = "service"), allow(rustdoc::broken_intra_doc_links))]
+
+#[cfg(feature = "service")]
+mod blocked_snapshot;
+#[cfg(feature = "service")]
+mod coalescing;
+mod concurrency;
 mod config;
+mod decision;
+#[cfg(feature = "service")]
+mod events;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "service")]
+mod history;
+#[cfg(feature = "service")]
+mod maintenance;
+#[cfg(feature = "service")]
+mod metrics;
+mod project_key;
+#[cfg(feature = "service")]
+mod reservations;
+#[cfg(feature = "spill")]
+mod spill;
 mod stats;
+#[cfg(feature = "service")]
+mod store;
+#[cfg(feature = "service")]
+mod summary;
+#[cfg(feature = "service")]
+mod tower;
+mod tracker;
+#[cfg(feature = "service")]
+mod unknown_configs;
 
-use std::sync::Arc;
+#[cfg(feature = "service")]
+use std::collections::HashMap;
+#[cfg(feature = "service")]
+use std::ops::{Deref, DerefMut};
+#[cfg(feature = "service")]
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+#[cfg(feature = "service")]
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+#[cfg(feature = "service")]
 use std::thread::JoinHandle;
+#[cfg(feature = "service")]
 use std::time::Duration;
 
-pub use config::BudgetingConfig;
-use config::Timer;
-use dashmap::mapref::entry::Entry;
-use dashmap::mapref::one::RefMut;
+#[cfg(feature = "service")]
+use blocked_snapshot::BlockedSnapshotWriter;
+#[cfg(feature = "service")]
+pub use blocked_snapshot::{config_hash, BlockedSnapshot};
+#[cfg(feature = "service")]
+pub use coalescing::Coalescer;
+pub use concurrency::ConcurrencyTracker;
+#[cfg(feature = "service")]
+pub use config::RegisteredConfig;
+pub use config::{
+    AccountingStrategy, BudgetingConfig, ConfigError, ConfigId, Enforcement, OverloadPolicy,
+    OversizedSpend, RampUp, ReplaceState, SlowBurnWindow, TimeSource, MAX_CONFIG_DURATION,
+};
+#[cfg(feature = "service")]
+use config::{ConfigRegistry, Removal, Timer};
+#[cfg(feature = "service")]
 use dashmap::DashMap;
+pub use decision::{Decision, DecisionOverride, DecisionReason};
+#[cfg(feature = "service")]
+pub use events::Event;
+#[cfg(feature = "service")]
+use events::Events;
+#[cfg(feature = "service")]
+use history::SpendHistory;
+#[cfg(feature = "service")]
+pub use history::{
+    BudgetCandidate, BudgetReport, MinuteAggregate, SpendRateHistogram, MAX_HISTORY_HOURS,
+    MAX_TREND_MINUTES,
+};
+#[cfg(feature = "service")]
 use indexmap::IndexMap;
-use quanta::Clock;
-pub use stats::ProjectStats;
+#[cfg(feature = "service")]
+use maintenance::Maintenance;
+#[cfg(feature = "service")]
+pub use metrics::{write_metric, write_metric_header, write_sample, MetricKind, MetricsWriter};
+#[cfg(feature = "service")]
+use metrics::{Counter, MaintenanceMetrics};
+#[cfg(feature = "service")]
+use pollster::block_on;
+pub use project_key::ProjectKey;
+pub use quanta::{Clock, Instant};
+#[cfg(feature = "service")]
+use reservations::Reservation;
+#[cfg(feature = "service")]
+pub use reservations::ReservationError;
+#[cfg(feature = "spill")]
+pub use spill::SpillStore;
+pub use stats::{ProjectReport, ProjectStats, RefundError};
+#[cfg(feature = "service")]
+pub use store::{MemoryStore, StateStore, StoreFuture};
+#[cfg(feature = "service")]
+pub use summary::{
+    BlockedDurations, FlipFlopper, SpendSummary, Utilizations, BLOCKED_DURATION_BUCKETS,
+    MAX_FLIP_FLOPPERS, UTILIZATION_QUANTILES,
+};
+#[cfg(feature = "service")]
+pub use tower::BudgetRequest;
+pub use tracker::BudgetTracker;
+#[cfg(feature = "service")]
+use unknown_configs::UnknownConfigs;
+
+/// The number of registered configs above which an [`Event::ManyConfigs`] is emitted for every new config.
+#[cfg(feature = "service")]
+pub const CONFIG_COUNT_WARNING: usize = 100;
+
+/// The name of the background maintenance thread of a [`Service`].
+///
+/// The threads scanning the projects in parallel for the maintenance thread are named with this prefix.
+///
+/// Panics within a maintenance pass are recovered from, see [`Service::maintenance_healthy`].
+#[cfg(feature = "service")]
+pub const MAINTENANCE_THREAD: &str = "peanutbutter-maintenance";
+
+/// The maximum TTL of [project weights](Service::set_project_weight) and [acquisitions](Service::acquire),
+/// longer TTLs are capped to it.
+#[cfg(feature = "service")]
+pub const MAX_TTL: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+#[cfg(feature = "service")]
+type Configs = Arc<ConfigRegistry>;
+#[cfg(feature = "service")]
+type SpendSummaries = Arc<RwLock<HashMap<ConfigId, SpendSummary>>>;
+#[cfg(feature = "service")]
+type ProjectWeights = Arc<DashMap<(ConfigId, ProjectKey), ProjectWeight>>;
+#[cfg(feature = "service")]
+type Reservations = Arc<DashMap<u64, Reservation>>;
+
+/// A mutable reference to the [`BudgetTracker`] of a project, locked within its [`StateStore`].
+#[cfg(feature = "service")]
+struct ProjectRef<'a> {
+    /// The config id and project id of the project.
+    key: (ConfigId, ProjectKey),
+    tracker: &'a mut dyn BudgetTracker,
+    /// Whether the tracker was inserted just now, as the project was not known before.
+    inserted: bool,
+}
+
+#[cfg(feature = "service")]
+impl ProjectRef<'_> {
+    /// Returns the config id and project id of the project.
+    fn key(&self) -> &(ConfigId, ProjectKey) {
+        &self.key
+    }
+
+    /// Returns whether the tracker was inserted just now.
+    fn inserted(&self) -> bool {
+        self.inserted
+    }
+}
+
+#[cfg(feature = "service")]
+impl<'a> Deref for ProjectRef<'a> {
+    type Target = dyn BudgetTracker + 'a;
+
+    fn deref(&self) -> &Self::Target {
+        self.tracker
+    }
+}
+
+#[cfg(feature = "service")]
+impl DerefMut for ProjectRef<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.tracker
+    }
+}
 
-type ProjectBudgets = Arc<DashMap<(usize, u64), ProjectStats>>;
-type ProjectRef<'a> = RefMut<'a, (usize, u64), ProjectStats>;
+/// A multiplier applied to all the spending recorded for a project.
+#[cfg(feature = "service")]
+#[derive(Clone, Copy, Debug)]
+struct ProjectWeight {
+    /// The multiplier that is applied to the recorded spending.
+    weight: f64,
+    /// The time after which this weight is no longer applied.
+    expires_at: Instant,
+}
 
+/// The budgeting service, which keeps track of projects across multiple [`BudgetingConfig`]s.
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use peanutbutter::{BudgetingConfig, Service};
+///
+/// let service = Service::new();
+/// let config = BudgetingConfig::new(
+///     Duration::from_secs(60), // backoff_duration
+///     Duration::from_secs(10), // budgeting_window
+///     Duration::from_secs(1),  // bucket_size
+///     10.,                     // budget per second
+/// );
+/// service.try_add_config("symbolication", config).unwrap();
+///
+/// assert!(!service.record_spending("symbolication", 1234, 50.));
+/// assert!(!service.exceeds_budget("symbolication", 1234));
+/// assert!(service.record_spending("symbolication", 1234, 1_000.));
+/// assert!(service.exceeds_budget("symbolication", 1234));
+/// ```
+#[cfg(feature = "service")]
 #[derive(Debug)]
 pub struct Service {
     /// The global [`Timer`] used within all the [`BudgetingConfig`]s.
@@ -24,119 +253,3141 @@ pub struct Service {
     /// The timers clock will be updated regularly (for proper [`Clock::recent`] access).
     timer: Timer,
 
-    /// A map of known configurations.
+    /// A map of known configurations, along with the project stats/budgets of each config.
+    ///
+    /// Configs keep their position in this [`IndexMap`], even when removed.
+    /// Configs can be changed at runtime, which is rare compared to the lookups happening on every request,
+    /// so changes publish a new snapshot of all the configs, while lookups do not take any lock.
+    /// The maintenance thread shares it to clean up the [`StateStore`] of each config.
+    configs: Configs,
+
+    /// Per-config [`SpendSummary`]s, keyed by config id.
+    ///
+    /// These are recomputed by the maintenance thread on every pass.
+    spend_summaries: SpendSummaries,
+
+    /// The hourly spend rates of each config, which are recorded by the maintenance thread.
+    spend_history: Arc<SpendHistory>,
+
+    /// Per-project weights applied to recorded spending, keyed by config id and project id.
+    ///
+    /// Expired weights are cleaned up by the maintenance thread.
+    project_weights: ProjectWeights,
+
+    /// Projects whose budget is accounted to another project, for example after a merge.
+    ///
+    /// Aliases always point to a project which is not aliased itself.
+    project_aliases: RwLock<HashMap<ProjectKey, ProjectKey>>,
+
+    /// The number of project aliases, so that resolving projects does not lock the aliases without any.
+    alias_count: AtomicUsize,
+
+    /// Notes attached to projects by operators, keyed by project.
+    project_notes: RwLock<HashMap<ProjectKey, String>>,
+
+    /// Decisions forced for individual projects, keyed by config name and project.
+    decision_overrides: RwLock<HashMap<String, HashMap<ProjectKey, DecisionOverride>>>,
+
+    /// The number of decision overrides, so that decisions do not lock the overrides without any.
+    override_count: AtomicUsize,
+
+    /// Budget that was reserved up-front, keyed by reservation id.
     ///
-    /// This is a [`IndexMap`] as an optimization, so we do not need to constantly
-    /// [`Arc::clone`] the [`BudgetingConfig`] to index into the main budget map.
-    configs: IndexMap<String, Arc<BudgetingConfig>>,
+    /// Expired reservations are cleaned up by the maintenance thread.
+    reservations: Reservations,
+
+    /// The id of the next registered config.
+    next_config_id: AtomicU32,
+
+    /// The id of the next reservation.
+    next_reservation_id: AtomicU64,
+
+    /// The maximum memory used by the project entries in bytes, or `0` for no limit.
+    memory_limit: Arc<AtomicUsize>,
+
+    /// The number of state changes within a maintenance pass that are aggregated into a single event,
+    /// or `0` to never aggregate them.
+    mass_state_change_threshold: Arc<AtomicUsize>,
+
+    /// Whether the maintenance skips the time the clock jumped forward, instead of expiring all the state.
+    skip_clock_jumps: Arc<AtomicBool>,
+
+    /// The maximum number of registered configs, or `0` for no limit.
+    max_configs: AtomicUsize,
+
+    /// The snapshot of blocked projects shared with co-located clients, which is written on every maintenance pass.
+    blocked_snapshot: Arc<Mutex<Option<BlockedSnapshotWriter>>>,
+
+    /// Metrics describing the health of the maintenance thread.
+    maintenance_metrics: Arc<MaintenanceMetrics>,
+
+    /// Requests for config names which are not registered.
+    unknown_configs: UnknownConfigs,
+
+    /// The number of decisions that would have blocked a project, if not for [`Enforcement::DryRun`].
+    dry_run_blocks: Counter,
+
+    /// The number of recorded spendings that inserted a new project.
+    project_inserts: Counter,
+
+    /// The number of recorded spendings that updated an existing project.
+    project_updates: Counter,
+
+    /// The number of budget checks for projects which are not known.
+    unknown_project_checks: Counter,
+
+    /// Where [`Event`]s are emitted to.
+    events: Arc<Events>,
 
-    /// A concurrent [`DashMap`] containing all the project stats/budgets.
-    project_budgets: ProjectBudgets,
+    /// Whether the background maintenance was asked to stop.
+    maintenance_stopping: Arc<AtomicBool>,
 
-    /// The background thread that updates the [`Timer`] and cleans up stale stats.
-    // TODO: actually implement graceful shutdown
-    #[allow(unused)]
-    maintenance_thread: JoinHandle<()>,
+    /// The background thread that updates the [`Timer`] and cleans up stale trackers,
+    /// until it is [stopped](Service::stop_maintenance).
+    maintenance_thread: Mutex<Option<JoinHandle<()>>>,
 }
 
+#[cfg(feature = "service")]
 impl Service {
     /// Creates a new (empty) Service
+    ///
+    /// The [`TimeSource`] is [detected](TimeSource::detect_host) once per process,
+    /// which blocks the first call for a few milliseconds.
     pub fn new() -> Self {
         let clock = Clock::new();
-        quanta::set_recent(clock.now());
-        let timer = Timer::new(clock.clone());
-        let project_budgets = ProjectBudgets::default();
+        let timer = match TimeSource::detect_host() {
+            TimeSource::Quanta => {
+                quanta::set_recent(clock.now());
+                Timer::new(clock)
+            }
+            TimeSource::Std => Timer::with_std_time(&clock),
+        };
+        Self::with_timer(timer, true)
+    }
 
-        let maintenance_thread = std::thread::spawn({
-            let project_budgets = project_budgets.clone();
-            move || service_maintenance(clock, project_budgets)
-        });
+    /// Returns the [`TimeSource`] that was detected when creating this service.
+    pub fn time_source(&self) -> TimeSource {
+        self.timer.source()
+    }
+
+    /// Creates a new (empty) Service using a mocked [`Clock`], for tests.
+    ///
+    /// The background maintenance thread still runs every 500ms of real time,
+    /// but uses the mocked time for all of its decisions.
+    pub fn with_mock_clock(clock: Clock) -> Self {
+        Self::with_clock(clock, false)
+    }
+
+    /// Creates a new (empty) Service using the given [`Clock`].
+    ///
+    /// The global recent time is only updated for real clocks, as mocked clocks ignore it.
+    fn with_clock(clock: Clock, update_recent: bool) -> Self {
+        Self::with_timer(Timer::new(clock), update_recent)
+    }
+
+    /// Creates a new (empty) Service using the given [`Timer`].
+    fn with_timer(timer: Timer, update_recent: bool) -> Self {
+        let configs = Configs::default();
+        let spend_summaries = SpendSummaries::default();
+        let spend_history = Arc::<SpendHistory>::default();
+        let project_weights = ProjectWeights::default();
+        let reservations = Reservations::default();
+        let memory_limit = Arc::<AtomicUsize>::default();
+        let mass_state_change_threshold = Arc::<AtomicUsize>::default();
+        let skip_clock_jumps = Arc::<AtomicBool>::default();
+        let blocked_snapshot = Arc::<Mutex<Option<BlockedSnapshotWriter>>>::default();
+        let maintenance_metrics = Arc::<MaintenanceMetrics>::default();
+        let events = Arc::<Events>::default();
+        let maintenance_stopping = Arc::<AtomicBool>::default();
+
+        let maintenance = Maintenance {
+            timer: timer.clone(),
+            update_recent,
+            configs: configs.clone(),
+            spend_summaries: spend_summaries.clone(),
+            spend_history: spend_history.clone(),
+            project_weights: project_weights.clone(),
+            reservations: reservations.clone(),
+            memory_limit: memory_limit.clone(),
+            mass_state_change_threshold: mass_state_change_threshold.clone(),
+            last_scan: Default::default(),
+            skip_clock_jumps: skip_clock_jumps.clone(),
+            blocked_snapshot: blocked_snapshot.clone(),
+            metrics: maintenance_metrics.clone(),
+            events: events.clone(),
+            stopped: maintenance_stopping.clone(),
+        };
+        let maintenance_thread = std::thread::Builder::new()
+            .name(MAINTENANCE_THREAD.into())
+            .spawn(move || maintenance.run())
+            .expect("failed to spawn the maintenance thread");
 
         Self {
             timer,
-            configs: Default::default(),
-            project_budgets,
-            maintenance_thread,
+            configs,
+            spend_summaries,
+            spend_history,
+            project_weights,
+            project_aliases: Default::default(),
+            alias_count: AtomicUsize::new(0),
+            project_notes: Default::default(),
+            decision_overrides: Default::default(),
+            override_count: AtomicUsize::new(0),
+            reservations,
+            next_config_id: AtomicU32::new(1),
+            next_reservation_id: AtomicU64::new(1),
+            memory_limit,
+            mass_state_change_threshold,
+            skip_clock_jumps,
+            max_configs: AtomicUsize::new(0),
+            blocked_snapshot,
+            maintenance_metrics,
+            unknown_configs: Default::default(),
+            dry_run_blocks: Default::default(),
+            project_inserts: Default::default(),
+            project_updates: Default::default(),
+            unknown_project_checks: Default::default(),
+            events,
+            maintenance_stopping,
+            maintenance_thread: Mutex::new(Some(maintenance_thread)),
         }
     }
 
     /// Add/register a new [`BudgetingConfig`] with a specific name.
     ///
     /// This function will `panic` when a duplicated config is provided.
-    /// The intention is to only add configuration once on startup,
-    /// and `panic`-ing in that situation is considered acceptable.
-    pub fn add_config(&mut self, name: &str, config: BudgetingConfig) {
-        let config = Arc::new(config.with_timer(self.timer.clone()));
-        let previous = self.configs.insert(name.into(), config);
-        assert!(previous.is_none());
+    #[deprecated = "use `try_add_config` instead, which does not panic on duplicates"]
+    pub fn add_config(&self, name: &str, config: BudgetingConfig) {
+        self.try_add_config(name, config).unwrap();
+    }
+
+    /// Add/register a new [`BudgetingConfig`] with a specific name.
+    ///
+    /// Returns [`ConfigError::Duplicate`] if a config with the same name is already registered,
+    /// or [`ConfigError::Draining`] if it was [removed](Service::remove_config) but not purged yet.
+    pub fn try_add_config(&self, name: &str, config: BudgetingConfig) -> Result<(), ConfigError> {
+        self.try_add_config_with_store(name, config, Arc::new(MemoryStore::default()))
+    }
+
+    /// Add/register a new [`BudgetingConfig`], whose project state is kept in the given [`StateStore`].
+    ///
+    /// This allows alternative storage backends for configs with a very large number of projects.
+    /// See [`Service::try_add_config`] for the possible errors.
+    pub fn try_add_config_with_store(
+        &self,
+        name: &str,
+        config: BudgetingConfig,
+        store: Arc<dyn StateStore>,
+    ) -> Result<(), ConfigError> {
+        // The events are emitted once the change is published, as handlers may look at the configs.
+        let events =
+            (self.configs).update(|configs| self.add_config_to(configs, name, config, store))?;
+        for event in events {
+            self.events.emit(event);
+        }
+        Ok(())
+    }
+
+    /// Registers a config within the given `configs`, see [`Service::try_add_config_with_store`].
+    ///
+    /// Returns the [`Event`]s to emit about the new config.
+    fn add_config_to(
+        &self,
+        configs: &mut IndexMap<String, RegisteredConfig>,
+        name: &str,
+        config: BudgetingConfig,
+        store: Arc<dyn StateStore>,
+    ) -> Result<Vec<Event>, ConfigError> {
+        match configs.get(name).map(|existing| existing.removal) {
+            Some(None) => return Err(ConfigError::Duplicate(name.into())),
+            Some(Some(Removal::Draining)) => return Err(ConfigError::Draining(name.into())),
+            // The slot of a purged config is reused, keeping its position.
+            Some(Some(Removal::Purged)) | None => {}
+        }
+        let num_configs = active_configs(configs);
+        let max_configs = self.max_configs.load(Ordering::Relaxed);
+        if max_configs > 0 && num_configs >= max_configs {
+            return Err(ConfigError::TooMany(max_configs));
+        }
+        let mut events = Vec::new();
+        if num_configs >= CONFIG_COUNT_WARNING {
+            let configs = num_configs + 1;
+            events.push(Event::ManyConfigs { configs });
+        }
+
+        events.extend(config_warnings(name, &config));
+        let config = config.with_name(name).with_timer(self.timer.clone());
+        let config = RegisteredConfig {
+            id: ConfigId(self.next_config_id.fetch_add(1, Ordering::Relaxed)),
+            config: Arc::new(config),
+            enforcement: Enforcement::default(),
+            projects: store,
+            blocked: Default::default(),
+            decisions: Default::default(),
+            oversized_spends: Default::default(),
+            removal: None,
+        };
+        configs.insert(name.into(), config);
+        Ok(events)
+    }
+
+    /// Removes a registered config.
+    ///
+    /// The config is treated as unknown right away, and the maintenance thread purges all of its
+    /// project state in the background. Only then can a config with the same name be added again.
+    /// Returns [`ConfigError::Unknown`] if no config with that name is registered.
+    pub fn remove_config(&self, name: &str) -> Result<(), ConfigError> {
+        self.configs.update(|configs| {
+            let existing = active_config_mut(configs, name)?;
+            existing.removal = Some(Removal::Draining);
+            Ok(())
+        })
+    }
+
+    /// Replaces an already registered [`BudgetingConfig`].
+    ///
+    /// The existing project state is either kept or reset, depending on `state`.
+    /// The [`Enforcement`] of the config is kept as well.
+    /// Returns [`ConfigError::Unknown`] if no config with that name is registered.
+    pub fn replace_config(
+        &self,
+        name: &str,
+        config: BudgetingConfig,
+        state: ReplaceState,
+    ) -> Result<(), ConfigError> {
+        block_on(self.replace_config_async(name, config, state))
+    }
+
+    /// Replaces an already registered [`BudgetingConfig`].
+    ///
+    /// This is the same as [`Service::replace_config`], see [`Service::try_exceeds_budget_async`].
+    /// The [`StateStore`] is only awaited after the new config is published.
+    pub async fn replace_config_async(
+        &self,
+        name: &str,
+        config: BudgetingConfig,
+        state: ReplaceState,
+    ) -> Result<(), ConfigError> {
+        let config = Arc::new(config.with_name(name).with_timer(self.timer.clone()));
+        let registered = self.configs.update(|configs| {
+            let existing = active_config_mut(configs, name)?;
+            existing.config = config.clone();
+            Ok(existing.clone())
+        })?;
+        for event in config_warnings(name, &config) {
+            self.events.emit(event);
+        }
+
+        let projects = registered.projects.as_ref();
+        if state == ReplaceState::Reset {
+            projects.clear().await;
+            registered.blocked.clear();
+            return Ok(());
+        }
+        // Projects inserted in the meantime already use the new config.
+        projects.set_config(config.clone()).await;
+        for project_id in store::project_ids(projects).await {
+            let mut set_config = |tracker: &mut dyn BudgetTracker| {
+                tracker.set_config(config.clone());
+                registered.cache_check(project_id, tracker);
+            };
+            projects.update(project_id, None, &mut set_config).await;
+        }
+        Ok(())
+    }
+
+    /// Enables or disables an already registered config, see [`BudgetingConfig::enabled`].
+    ///
+    /// The existing project state is kept, but no spending is recorded while the config is disabled.
+    /// Returns [`ConfigError::Unknown`] if no config with that name is registered.
+    pub fn set_enabled(&self, name: &str, enabled: bool) -> Result<(), ConfigError> {
+        block_on(self.set_enabled_async(name, enabled))
+    }
+
+    /// Enables or disables an already registered config, see [`BudgetingConfig::enabled`].
+    ///
+    /// This is the same as [`Service::set_enabled`], see [`Service::replace_config_async`].
+    pub async fn set_enabled_async(&self, name: &str, enabled: bool) -> Result<(), ConfigError> {
+        let config = {
+            let configs = self.configs.load();
+            let Some(registered) = active_config(&configs, name) else {
+                return Err(ConfigError::Unknown(name.into()));
+            };
+            BudgetingConfig::clone(&registered.config)
+        };
+        self.replace_config_async(name, config.with_enabled(enabled), ReplaceState::Keep)
+            .await
+    }
+
+    /// Changes the [`Enforcement`] of an already registered config.
+    ///
+    /// Returns [`ConfigError::Unknown`] if no config with that name is registered.
+    pub fn set_enforcement(&self, name: &str, enforcement: Enforcement) -> Result<(), ConfigError> {
+        self.configs.update(|configs| {
+            let existing = active_config_mut(configs, name)?;
+            existing.enforcement = enforcement;
+            Ok(())
+        })
+    }
+
+    /// Returns all the registered configs.
+    pub fn configs(&self) -> IndexMap<String, RegisteredConfig> {
+        let configs = self.configs.load();
+        (configs.iter())
+            .filter(|(_name, registered)| registered.removal.is_none())
+            .map(|(name, registered)| (name.clone(), registered.clone()))
+            .collect()
+    }
+
+    /// Returns how requests for the config are answered when they are shed because the service is overloaded.
+    ///
+    /// An unknown config is [rejected](OverloadPolicy::Reject), without being counted as such.
+    pub fn overload_policy(&self, config: &str) -> OverloadPolicy {
+        let configs = self.configs.load();
+        active_config(&configs, config).map_or(OverloadPolicy::Reject, |registered| {
+            registered.config.overload_policy
+        })
+    }
+
+    /// Registers a handler that is invoked for every [`Event`] happening within the service.
+    ///
+    /// The handler might be invoked from the background maintenance thread.
+    pub fn set_event_handler(&self, handler: impl Fn(&Event) + Send + Sync + 'static) {
+        self.events.set_handler(Box::new(handler));
     }
 
     /// Checks whether this project exceeds its budgets.
     ///
     /// A project that is not (yet) known will always return `false`,
-    /// meaning it does not exceed the budget.
+    /// meaning it does not exceed the budget. The same is true for an unknown config,
+    /// or a config that is not [enforced](Enforcement).
     pub fn exceeds_budget(&self, config: &str, project_id: u64) -> bool {
-        if let Some(mut stats) = self.get_project_stats(config, project_id, false) {
-            stats.exceeds_budget()
-        } else {
-            false
+        self.try_exceeds_budget(config, project_id).unwrap_or(false)
+    }
+
+    /// Checks whether this project exceeds its budgets.
+    ///
+    /// Contrary to [`Service::exceeds_budget`], this returns [`ConfigError::Unknown`]
+    /// if the config is not registered.
+    pub fn try_exceeds_budget(&self, config: &str, project_id: u64) -> Result<bool, ConfigError> {
+        block_on(self.try_exceeds_budget_async(config, project_id))
+    }
+
+    /// Checks whether this project exceeds its budgets.
+    ///
+    /// This is the same as [`Service::try_exceeds_budget`], but awaits the [`StateStore`] of the config
+    /// instead of blocking on it, so that stores doing network or disk I/O do not block async callers.
+    pub async fn try_exceeds_budget_async(
+        &self,
+        config: &str,
+        project_id: u64,
+    ) -> Result<bool, ConfigError> {
+        let project = self.resolve_project(project_id.into());
+        self.with_project_tracker(config, project, false, |registered, tracker| {
+            self.count_project_check(registered, tracker.is_some());
+            let exceeds_budget = tracker.is_some_and(|mut tracker| tracker.check());
+            let exceeds_budget = self.enforce(registered.effective_enforcement(), exceeds_budget);
+            let exceeds_budget = self.override_exceeds_budget(config, project, exceeds_budget);
+            registered.decisions.count(exceeds_budget);
+            exceeds_budget
+        })
+        .await
+    }
+
+    /// Checks whether this project exceeds its budgets, and why.
+    ///
+    /// This is the same as [`Service::try_exceeds_budget`], but the decision comes with its [`DecisionReason`].
+    /// Explaining the decision re-evaluates the spending of the project, so this is slightly more expensive.
+    /// For projects that were never seen, the decision also comes with a [`Decision::cache_for`] hint.
+    pub fn try_exceeds_budget_with_reason(
+        &self,
+        config: &str,
+        project_id: u64,
+    ) -> Result<Decision, ConfigError> {
+        self.try_exceeds_budget_by_key(config, project_id.into())
+    }
+
+    /// Checks whether the project identified by a [`ProjectKey`] exceeds its budgets, and why.
+    ///
+    /// This is the same as [`Service::try_exceeds_budget_with_reason`], but also supports projects
+    /// identified by a UUID.
+    pub fn try_exceeds_budget_by_key(
+        &self,
+        config: &str,
+        project: ProjectKey,
+    ) -> Result<Decision, ConfigError> {
+        block_on(self.try_exceeds_budget_by_key_async(config, project))
+    }
+
+    /// Checks whether the project identified by a [`ProjectKey`] exceeds its budgets, and why.
+    ///
+    /// This is the same as [`Service::try_exceeds_budget_by_key`], see [`Service::try_exceeds_budget_async`].
+    pub async fn try_exceeds_budget_by_key_async(
+        &self,
+        config: &str,
+        project: ProjectKey,
+    ) -> Result<Decision, ConfigError> {
+        let project = self.resolve_project(project);
+        self.with_project_tracker(config, project, false, |registered, tracker| {
+            self.count_project_check(registered, tracker.is_some());
+            let decision = tracker.map(|mut tracker| {
+                let exceeds_budget = tracker.check();
+                (exceeds_budget, tracker.decision_reason(self.timer.now()))
+            });
+            let ttl = registered.config.unknown_project_ttl;
+            let cache_for = (decision.is_none() && !ttl.is_zero()).then_some(ttl);
+            let decision = self.decide(registered.effective_enforcement(), decision);
+            let decision = Decision {
+                cache_for,
+                ..decision
+            };
+            let decision = self.override_decision(config, project, decision);
+            registered.decisions.count(decision.exceeds_budget);
+            decision
+        })
+        .await
+    }
+
+    /// Returns whether this project exceeded its budget the last time it was evaluated.
+    ///
+    /// This is a fast path for callers that prefer latency over exactness.
+    /// Contrary to [`Service::exceeds_budget`], the spending of the project is not re-evaluated,
+    /// and the state store of the config is not locked at all. Instead, the answer is looked up in a set
+    /// of the blocked projects of the config, which is only written to when a project starts or stops
+    /// exceeding its budget. The answer is potentially slightly stale, as the project is only re-evaluated whenever
+    /// spending is recorded, or its budget is checked via [`Service::exceeds_budget`].
+    pub fn exceeds_budget_cached(&self, config: &str, project_id: u64) -> bool {
+        let resolved_id = self.resolve_project(project_id.into());
+        let configs = self.configs.load();
+        let Some(registered) = active_config(&configs, config) else {
+            drop(configs);
+            self.record_unknown_config(config);
+            return false;
+        };
+        let exceeds_budget = match registered.effective_enforcement() {
+            Enforcement::Off => false,
+            enforcement => self.enforce(enforcement, registered.blocked.contains(&resolved_id)),
+        };
+        let exceeds_budget = self.override_exceeds_budget(config, resolved_id, exceeds_budget);
+        registered.decisions.count(exceeds_budget);
+        exceeds_budget
+    }
+
+    /// Checks whether recording the `spent` budget would push this project over its budget.
+    ///
+    /// This does not record anything, which allows admission-control style callers to decide
+    /// before starting expensive work. The `spent` budget is multiplied by the project's weight, if one is set.
+    /// Returns `false` for an unknown config, or a config that is not [enforced](Enforcement::On).
+    pub fn would_exceed(&self, config: &str, project_id: u64, spent: f64) -> bool {
+        self.would_exceed_by_key(config, project_id.into(), spent)
+    }
+
+    /// Checks whether recording the `spent` budget would push the project identified by a [`ProjectKey`]
+    /// over its budget.
+    ///
+    /// This is the same as [`Service::would_exceed`], see [`Service::try_exceeds_budget_by_key`].
+    pub fn would_exceed_by_key(&self, config: &str, project: ProjectKey, spent: f64) -> bool {
+        block_on(self.would_exceed_by_key_async(config, project, spent))
+    }
+
+    /// Checks whether recording the `spent` budget would push the project identified by a [`ProjectKey`]
+    /// over its budget.
+    ///
+    /// This is the same as [`Service::would_exceed_by_key`], see [`Service::try_exceeds_budget_async`].
+    pub async fn would_exceed_by_key_async(
+        &self,
+        config: &str,
+        project: ProjectKey,
+        spent: f64,
+    ) -> bool {
+        let resolved_id = self.resolve_project(project);
+        let configs = self.configs.load();
+        let Some(registered) = active_config(&configs, config) else {
+            drop(configs);
+            self.record_unknown_config(config);
+            return false;
+        };
+        if registered.effective_enforcement() != Enforcement::On {
+            return self.override_exceeds_budget(config, resolved_id, false);
         }
+
+        let key = (registered.id, resolved_id);
+        let spent = spent * self.project_weight(&key);
+        let now = self.timer.now();
+        let mut would_exceed = None;
+        registered
+            .projects
+            .get(resolved_id, &mut |tracker| {
+                would_exceed = Some(tracker.would_exceed(spent, now))
+            })
+            .await;
+        let would_exceed = would_exceed
+            .unwrap_or_else(|| registered.config.new_tracker().would_exceed(spent, now));
+        self.override_exceeds_budget(config, resolved_id, would_exceed)
+    }
+
+    /// Returns a [`ProjectReport`] describing the current state of this project.
+    ///
+    /// Returns [`None`] if the project is not (yet) known, or the config is not [enforced](Enforcement).
+    /// Contrary to [`Service::exceeds_budget`], this does not update the "exceeded" state.
+    pub fn project_report(
+        &self,
+        config: &str,
+        project_id: u64,
+    ) -> Result<Option<ProjectReport>, ConfigError> {
+        self.project_report_by_key(config, project_id.into())
+    }
+
+    /// Returns a [`ProjectReport`] describing the current state of the project identified by a [`ProjectKey`].
+    ///
+    /// This is the same as [`Service::project_report`], see [`Service::try_exceeds_budget_by_key`].
+    pub fn project_report_by_key(
+        &self,
+        config: &str,
+        project: ProjectKey,
+    ) -> Result<Option<ProjectReport>, ConfigError> {
+        block_on(self.project_report_by_key_async(config, project))
+    }
+
+    /// Returns a [`ProjectReport`] describing the current state of the project identified by a [`ProjectKey`].
+    ///
+    /// This is the same as [`Service::project_report_by_key`], see [`Service::try_exceeds_budget_async`].
+    pub async fn project_report_by_key_async(
+        &self,
+        config: &str,
+        project: ProjectKey,
+    ) -> Result<Option<ProjectReport>, ConfigError> {
+        let now = self.timer.now();
+        let project = self.resolve_project(project);
+        self.with_project_tracker(config, project, false, |_registered, tracker| {
+            tracker.map(|tracker| tracker.report(now))
+        })
+        .await
+    }
+
+    /// Returns the spending of each bucket of this project within the current window, oldest first.
+    ///
+    /// Each bucket is given as the time since its start, along with its total spending,
+    /// see [`BudgetTracker::bucket_spending`].
+    /// Returns [`None`] if the project is not (yet) known, or the config is not [enforced](Enforcement).
+    pub fn bucket_spending(
+        &self,
+        config: &str,
+        project_id: u64,
+    ) -> Result<Option<Vec<(Duration, f64)>>, ConfigError> {
+        self.bucket_spending_by_key(config, project_id.into())
+    }
+
+    /// Returns the spending of each bucket of the project identified by a [`ProjectKey`], oldest first.
+    ///
+    /// This is the same as [`Service::bucket_spending`], see [`Service::try_exceeds_budget_by_key`].
+    pub fn bucket_spending_by_key(
+        &self,
+        config: &str,
+        project: ProjectKey,
+    ) -> Result<Option<Vec<(Duration, f64)>>, ConfigError> {
+        block_on(self.bucket_spending_by_key_async(config, project))
+    }
+
+    /// Returns the spending of each bucket of the project identified by a [`ProjectKey`], oldest first.
+    ///
+    /// This is the same as [`Service::bucket_spending_by_key`], see [`Service::try_exceeds_budget_async`].
+    pub async fn bucket_spending_by_key_async(
+        &self,
+        config: &str,
+        project: ProjectKey,
+    ) -> Result<Option<Vec<(Duration, f64)>>, ConfigError> {
+        let now = self.timer.now();
+        let project = self.resolve_project(project);
+        self.with_project_tracker(config, project, false, |_registered, tracker| {
+            tracker.map(|tracker| tracker.bucket_spending(now))
+        })
+        .await
+    }
+
+    /// Returns a [`ProjectReport`] of what the `candidate` config would decide for this project right now.
+    ///
+    /// The candidate is evaluated against the currently recorded [spending](Service::bucket_spending)
+    /// of the project, as a sliding window regardless of its strategy, and without any backoff.
+    /// This does not modify the registered config or the project state.
+    /// Returns [`None`] if the project is not (yet) known, or the config is not [enforced](Enforcement).
+    pub fn simulate_config(
+        &self,
+        config: &str,
+        project_id: u64,
+        candidate: BudgetingConfig,
+    ) -> Result<Option<ProjectReport>, ConfigError> {
+        self.simulate_config_by_key(config, project_id.into(), candidate)
+    }
+
+    /// Returns a [`ProjectReport`] of what the `candidate` config would decide for the project identified
+    /// by a [`ProjectKey`] right now.
+    ///
+    /// This is the same as [`Service::simulate_config`], see [`Service::try_exceeds_budget_by_key`].
+    pub fn simulate_config_by_key(
+        &self,
+        config: &str,
+        project: ProjectKey,
+        candidate: BudgetingConfig,
+    ) -> Result<Option<ProjectReport>, ConfigError> {
+        block_on(self.simulate_config_by_key_async(config, project, candidate))
+    }
+
+    /// Returns a [`ProjectReport`] of what the `candidate` config would decide for the project identified
+    /// by a [`ProjectKey`] right now.
+    ///
+    /// This is the same as [`Service::simulate_config_by_key`], see [`Service::try_exceeds_budget_async`].
+    pub async fn simulate_config_by_key_async(
+        &self,
+        config: &str,
+        project: ProjectKey,
+        candidate: BudgetingConfig,
+    ) -> Result<Option<ProjectReport>, ConfigError> {
+        let now = self.timer.now();
+        let candidate = Arc::new(candidate.with_name(config).with_timer(self.timer.clone()));
+        let project = self.resolve_project(project);
+        self.with_project_tracker(config, project, false, |registered, tracker| {
+            let tracker = tracker?;
+            let spending = tracker.bucket_spending(now);
+            let first_seen = tracker.report(now).first_seen;
+            let bucket_size = registered.config.bucket_size;
+            let stats =
+                ProjectStats::with_spending(candidate, &spending, bucket_size, first_seen, now);
+            Some(BudgetTracker::report(&stats, now))
+        })
+        .await
+    }
+
+    /// Returns how much more this project may spend within the current window before exceeding its budget.
+    ///
+    /// This allows callers to throttle pre-emptively before a project is blocked.
+    /// The project's weight is taken into account, so the result is in the same unit as the recorded spending.
+    /// A project that is not (yet) known has its full budget remaining.
+    /// Returns [`None`] if the config is not registered.
+    pub fn remaining_budget(&self, config: &str, project_id: u64) -> Option<f64> {
+        self.remaining_budget_by_key(config, project_id.into())
+    }
+
+    /// Returns how much more the project identified by a [`ProjectKey`] may spend within the current window.
+    ///
+    /// This is the same as [`Service::remaining_budget`], see [`Service::try_exceeds_budget_by_key`].
+    pub fn remaining_budget_by_key(&self, config: &str, project: ProjectKey) -> Option<f64> {
+        block_on(self.remaining_budget_by_key_async(config, project))
+    }
+
+    /// Returns how much more the project identified by a [`ProjectKey`] may spend within the current window.
+    ///
+    /// This is the same as [`Service::remaining_budget_by_key`], see [`Service::try_exceeds_budget_async`].
+    pub async fn remaining_budget_by_key_async(
+        &self,
+        config: &str,
+        project: ProjectKey,
+    ) -> Option<f64> {
+        let project_id = self.resolve_project(project);
+        let configs = self.configs.load();
+        let Some(registered) = active_config(&configs, config) else {
+            drop(configs);
+            self.record_unknown_config(config);
+            return None;
+        };
+
+        let key = (registered.id, project_id);
+        let now = self.timer.now();
+        let mut remaining_budget = None;
+        registered
+            .projects
+            .get(project_id, &mut |tracker| {
+                remaining_budget = Some(tracker.report(now).remaining_budget)
+            })
+            .await;
+        // An unknown project is reported like a fresh tracker, which accounts for the strategy and ramp-up.
+        let remaining_budget = remaining_budget
+            .unwrap_or_else(|| registered.config.new_tracker().report(now).remaining_budget);
+
+        let weight = self.project_weight(&key);
+        Some(if weight > 0. {
+            remaining_budget / weight
+        } else {
+            f64::INFINITY
+        })
     }
 
     /// Records spent budget.
+    ///
+    /// The `spent` budget is multiplied by the project's weight, if one is set.
+    /// Spending for an unknown config is ignored.
     pub fn record_spending(&self, config: &str, project_id: u64, spent: f64) -> bool {
-        if let Some(mut stats) = self.get_project_stats(config, project_id, true) {
-            stats.record_spending(spent)
-        } else {
-            false
-        }
+        self.try_record_spending(config, project_id, spent)
+            .unwrap_or(false)
+    }
+
+    /// Records spent budget.
+    ///
+    /// This is a compatibility alias of [`Service::record_spending`], for callers which followed
+    /// older docs referring to it under this name.
+    #[deprecated = "use `record_spending` instead"]
+    pub fn record_budget_spend(&self, config: &str, project_id: u64, spent: f64) -> bool {
+        self.record_spending(config, project_id, spent)
+    }
+
+    /// Records spent budget.
+    ///
+    /// Contrary to [`Service::record_spending`], this returns [`ConfigError::Unknown`]
+    /// if the config is not registered.
+    pub fn try_record_spending(
+        &self,
+        config: &str,
+        project_id: u64,
+        spent: f64,
+    ) -> Result<bool, ConfigError> {
+        block_on(self.try_record_spending_async(config, project_id, spent))
     }
 
-    /// Gets a mutable [`ProjectStats`] reference from the concurrent [`DashMap`].
-    fn get_project_stats(
+    /// Records spent budget.
+    ///
+    /// This is the same as [`Service::try_record_spending`], see [`Service::try_exceeds_budget_async`].
+    pub async fn try_record_spending_async(
         &self,
         config: &str,
         project_id: u64,
-        or_insert: bool,
-    ) -> Option<ProjectRef> {
-        let (config_idx, _name, config) = self.configs.get_full(config)?;
-        let key = (config_idx, project_id);
+        spent: f64,
+    ) -> Result<bool, ConfigError> {
+        let decision = self
+            .record_spending_from(config, project_id.into(), spent, None)
+            .await?;
+        Ok(decision.exceeds_budget)
+    }
 
-        match self.project_budgets.entry(key) {
-            Entry::Occupied(e) => Some(e.into_ref()),
-            Entry::Vacant(e) if or_insert => Some(e.insert(ProjectStats::new(config.clone()))),
-            _ => None,
-        }
+    /// Records spent budget, and returns the resulting decision along with its [`DecisionReason`].
+    ///
+    /// This is the same as [`Service::try_record_spending`], see [`Service::try_exceeds_budget_with_reason`].
+    pub fn try_record_spending_with_reason(
+        &self,
+        config: &str,
+        project_id: u64,
+        spent: f64,
+    ) -> Result<Decision, ConfigError> {
+        self.try_record_spending_by_key(config, project_id.into(), spent)
     }
-}
 
-impl Default for Service {
-    fn default() -> Self {
-        Self::new()
+    /// Records spent budget for the project identified by a [`ProjectKey`], and returns the resulting decision.
+    ///
+    /// This is the same as [`Service::try_record_spending_with_reason`], see [`Service::try_exceeds_budget_by_key`].
+    pub fn try_record_spending_by_key(
+        &self,
+        config: &str,
+        project: ProjectKey,
+        spent: f64,
+    ) -> Result<Decision, ConfigError> {
+        block_on(self.try_record_spending_by_key_async(config, project, spent))
     }
-}
 
-/// A background maintenance task that periodically updates the [`Clock`],
-/// and cleans up state [`ProjectStats`].
-fn service_maintenance(timer: Clock, project_budgets: ProjectBudgets) {
-    // We scan the map, and clean up stale entries in two phases.
-    // The [`DashMap`] docs specifically mention that certain operations can deadlock,
-    // such as iterating and calling `remove_if` at the same time.
-    let mut keys_needing_cleanup = vec![];
-
-    loop {
-        std::thread::sleep(Duration::from_millis(500));
-        let now = timer.now();
-        quanta::set_recent(now);
-
-        for entry in project_budgets.iter() {
-            if entry.value().is_stale(now) {
-                keys_needing_cleanup.push(*entry.key());
-            }
-        }
+    /// Records spent budget for the project identified by a [`ProjectKey`], and returns the resulting decision.
+    ///
+    /// This is the same as [`Service::try_record_spending_by_key`], see [`Service::try_exceeds_budget_async`].
+    pub async fn try_record_spending_by_key_async(
+        &self,
+        config: &str,
+        project: ProjectKey,
+        spent: f64,
+    ) -> Result<Decision, ConfigError> {
+        self.record_spending_from(config, project, spent, None)
+            .await
+    }
 
-        for key in keys_needing_cleanup.drain(..) {
-            project_budgets.remove_if(&key, |_k, stats| stats.is_stale(now));
-        }
+    /// Records spent budget along with its `source`, like the producer that generated the spending.
+    ///
+    /// This is the same as [`Service::try_record_spending_with_reason`], but the project remembers its
+    /// most recent sources, which are part of its [`ProjectReport`], to tell apart who caused a project to be blocked.
+    pub fn try_record_spending_from(
+        &self,
+        config: &str,
+        project_id: u64,
+        spent: f64,
+        source: &str,
+    ) -> Result<Decision, ConfigError> {
+        block_on(self.try_record_spending_from_async(config, project_id, spent, source))
+    }
+
+    /// Records spent budget along with its `source`, like the producer that generated the spending.
+    ///
+    /// This is the same as [`Service::try_record_spending_from`], see [`Service::try_exceeds_budget_async`].
+    pub async fn try_record_spending_from_async(
+        &self,
+        config: &str,
+        project_id: u64,
+        spent: f64,
+        source: &str,
+    ) -> Result<Decision, ConfigError> {
+        self.record_spending_from(config, project_id.into(), spent, Some(source))
+            .await
+    }
+
+    /// Records spent budget from an optional `source`, and returns the resulting decision.
+    async fn record_spending_from(
+        &self,
+        config: &str,
+        project: ProjectKey,
+        spent: f64,
+        source: Option<&str>,
+    ) -> Result<Decision, ConfigError> {
+        let project = self.resolve_project(project);
+        let result = self
+            .with_project_tracker(config, project, true, |registered, tracker| {
+                let decision = tracker.map(|mut tracker| {
+                    self.count_project_record(&tracker);
+                    if let Some(source) = source {
+                        tracker.record_source(source);
+                    }
+                    let exceeds_budget = match registered.cap_spend(spent) {
+                        Some(spent) => {
+                            let spent = spent * self.project_weight(tracker.key());
+                            tracker.record(spent)
+                        }
+                        None => tracker.check(),
+                    };
+                    (exceeds_budget, tracker.decision_reason(self.timer.now()))
+                });
+                self.decide(registered.effective_enforcement(), decision)
+            })
+            .await;
+        result.map(|decision| self.override_decision(config, project, decision))
+    }
+
+    /// Refunds previously recorded spending.
+    ///
+    /// The `refunded` budget is multiplied by the project's weight, if one is set, just like spending.
+    /// Refunds for projects that are not (yet) known are ignored.
+    /// See [`BudgetTracker::refund`] for details.
+    pub fn record_refund(
+        &self,
+        config: &str,
+        project_id: u64,
+        refunded: f64,
+    ) -> Result<bool, RefundError> {
+        block_on(self.record_refund_async(config, project_id, refunded))
+    }
+
+    /// Refunds previously recorded spending.
+    ///
+    /// This is the same as [`Service::record_refund`], see [`Service::try_exceeds_budget_async`].
+    pub async fn record_refund_async(
+        &self,
+        config: &str,
+        project_id: u64,
+        refunded: f64,
+    ) -> Result<bool, RefundError> {
+        let project = self.resolve_project(project_id.into());
+        let result = self
+            .with_project_tracker(config, project, false, |registered, tracker| {
+                let Some(mut tracker) = tracker else {
+                    return Ok(false);
+                };
+                let refunded = refunded * self.project_weight(tracker.key());
+                let exceeds_budget = tracker.refund(refunded)?;
+                Ok(self.enforce(registered.effective_enforcement(), exceeds_budget))
+            })
+            .await;
+        let exceeds_budget = result.unwrap_or(Ok(false))?;
+        Ok(self.override_exceeds_budget(config, project, exceeds_budget))
+    }
+
+    /// Reserves budget up-front, for work that will take a while to complete.
+    ///
+    /// The reserved budget is recorded right away, so that in-flight work counts against the budget,
+    /// and concurrent callers can not stampede past it. The reservation is rejected with
+    /// [`ReservationError::ExceedsBudget`] if it would push the project over its budget.
+    /// The `reserved` budget is multiplied by the project's weight, if one is set.
+    ///
+    /// The returned reservation id is later passed to [`Service::commit_reservation`] or
+    /// [`Service::cancel_reservation`]. Reservations expire after the budgeting window of their config,
+    /// at which point the reserved spending has left the window anyway.
+    pub fn reserve(
+        &self,
+        config: &str,
+        project_id: u64,
+        reserved: f64,
+    ) -> Result<u64, ReservationError> {
+        self.reserve_by_key(config, project_id.into(), reserved)
+    }
+
+    /// Reserves budget up-front for the project identified by a [`ProjectKey`].
+    ///
+    /// This is the same as [`Service::reserve`], see [`Service::try_exceeds_budget_by_key`].
+    pub fn reserve_by_key(
+        &self,
+        config: &str,
+        project: ProjectKey,
+        reserved: f64,
+    ) -> Result<u64, ReservationError> {
+        block_on(self.reserve_by_key_async(config, project, reserved))
+    }
+
+    /// Reserves budget up-front for the project identified by a [`ProjectKey`].
+    ///
+    /// This is the same as [`Service::reserve_by_key`], see [`Service::try_exceeds_budget_async`].
+    pub async fn reserve_by_key_async(
+        &self,
+        config: &str,
+        project: ProjectKey,
+        reserved: f64,
+    ) -> Result<u64, ReservationError> {
+        self.reserve_with_ttl(config, project, reserved, None).await
+    }
+
+    /// Acquires `slots` of a config with the [`Concurrency`](AccountingStrategy::Concurrency) strategy.
+    ///
+    /// The acquisition is rejected with [`ReservationError::ExceedsBudget`] if the project would hold
+    /// more slots than its budget allows. The `slots` are multiplied by the project's weight, if one is set.
+    ///
+    /// The returned acquisition id is later passed to [`Service::release`]. Slots which are not released
+    /// within the given `ttl`, capped at [`MAX_TTL`], are released by the background maintenance thread,
+    /// so that leaked acquisitions, for example of crashed callers, do not block the project forever.
+    /// For configs with other strategies, this behaves like [`Service::reserve`] with a custom expiry.
+    pub fn acquire(
+        &self,
+        config: &str,
+        project_id: u64,
+        slots: f64,
+        ttl: Duration,
+    ) -> Result<u64, ReservationError> {
+        self.acquire_by_key(config, project_id.into(), slots, ttl)
+    }
+
+    /// Acquires `slots` for the project identified by a [`ProjectKey`].
+    ///
+    /// This is the same as [`Service::acquire`], see [`Service::try_exceeds_budget_by_key`].
+    pub fn acquire_by_key(
+        &self,
+        config: &str,
+        project: ProjectKey,
+        slots: f64,
+        ttl: Duration,
+    ) -> Result<u64, ReservationError> {
+        block_on(self.acquire_by_key_async(config, project, slots, ttl))
+    }
+
+    /// Acquires `slots` for the project identified by a [`ProjectKey`].
+    ///
+    /// This is the same as [`Service::acquire_by_key`], see [`Service::try_exceeds_budget_async`].
+    pub async fn acquire_by_key_async(
+        &self,
+        config: &str,
+        project: ProjectKey,
+        slots: f64,
+        ttl: Duration,
+    ) -> Result<u64, ReservationError> {
+        self.reserve_with_ttl(config, project, slots, Some(ttl))
+            .await
+    }
+
+    /// Releases all of the slots of an acquisition again.
+    pub fn release(&self, id: u64) -> Result<(), ReservationError> {
+        block_on(self.release_async(id))
+    }
+
+    /// Releases all of the slots of an acquisition again.
+    ///
+    /// This is the same as [`Service::release`], see [`Service::try_exceeds_budget_async`].
+    pub async fn release_async(&self, id: u64) -> Result<(), ReservationError> {
+        self.cancel_reservation_async(id).await
+    }
+
+    /// Reserves budget which expires after the given `ttl`, or after the budgeting window by default.
+    async fn reserve_with_ttl(
+        &self,
+        config: &str,
+        project: ProjectKey,
+        reserved: f64,
+        ttl: Option<Duration>,
+    ) -> Result<u64, ReservationError> {
+        let mut release_on_expiry = false;
+        let project = self.resolve_project(project);
+        let forced = self.decision_override(config, project);
+        let result = self
+            .with_project_tracker(config, project, true, |registered, tracker| {
+                // The expiry is computed before anything is acquired, so that nothing can fail afterwards.
+                let expires_at = self.expires_at(ttl.unwrap_or(registered.config.budgeting_window));
+                release_on_expiry = registered.config.strategy == AccountingStrategy::Concurrency;
+                if forced == Some(DecisionOverride::ForceBlock) {
+                    return Err(ReservationError::ExceedsBudget);
+                }
+                let now = self.timer.now();
+                // Nothing is recorded for configs that are switched off.
+                let Some(mut tracker) = tracker else {
+                    return Ok((0., 1., now, expires_at));
+                };
+                let weight = self.project_weight(tracker.key());
+                let reserved = reserved.max(0.) * weight;
+                let exceeds_budget = tracker.would_exceed(reserved, now);
+                if forced.is_none()
+                    && self.enforce(registered.effective_enforcement(), exceeds_budget)
+                {
+                    return Err(ReservationError::ExceedsBudget);
+                }
+                tracker.acquire(reserved);
+                // The tracker does not go back in time, so this is the time it recorded the budget at.
+                let recorded_at = tracker.report(now).last_updated;
+                Ok((reserved, weight, recorded_at, expires_at))
+            })
+            .await;
+        let (reserved, weight, recorded_at, expires_at) = result??;
+
+        let id = self.next_reservation_id.fetch_add(1, Ordering::Relaxed);
+        let reservation = Reservation {
+            config: config.into(),
+            project,
+            reserved,
+            weight,
+            recorded_at,
+            expires_at,
+            release_on_expiry,
+        };
+        self.reservations.insert(id, reservation);
+        Ok(id)
+    }
+
+    /// Commits a reservation with the `spent` budget that was actually used.
+    ///
+    /// The difference to the reserved budget is either recorded as additional spending,
+    /// or released again. Returns whether the project exceeds its budget afterwards.
+    pub fn commit_reservation(&self, id: u64, spent: f64) -> Result<bool, ReservationError> {
+        block_on(self.commit_reservation_async(id, spent))
+    }
+
+    /// Commits a reservation with the `spent` budget that was actually used.
+    ///
+    /// This is the same as [`Service::commit_reservation`], see [`Service::try_exceeds_budget_async`].
+    pub async fn commit_reservation_async(
+        &self,
+        id: u64,
+        spent: f64,
+    ) -> Result<bool, ReservationError> {
+        let reservation = match self.reservations.remove(&id) {
+            Some((_id, reservation)) if reservation.expires_at > self.timer.now() => reservation,
+            _ => return Err(ReservationError::Unknown(id)),
+        };
+
+        let (config, project) = (&reservation.config, reservation.project);
+        let result = self
+            .with_project_tracker(config, project, true, |registered, tracker| {
+                let Some(mut tracker) = tracker else {
+                    return false;
+                };
+                // The spending is capped just like recorded spending, and rejecting it keeps the reserved budget.
+                let spent = match registered.cap_spend(spent.max(0.)) {
+                    Some(spent) => spent * reservation.weight,
+                    None => reservation.reserved,
+                };
+                let exceeds_budget = if spent >= reservation.reserved {
+                    tracker.record(spent - reservation.reserved)
+                } else {
+                    let released = reservation.reserved - spent;
+                    tracker.release(released, reservation.recorded_at)
+                };
+                self.enforce(registered.effective_enforcement(), exceeds_budget)
+            })
+            .await;
+        let exceeds_budget = result?;
+        Ok(self.override_exceeds_budget(config, project, exceeds_budget))
+    }
+
+    /// Cancels a reservation, releasing all of the reserved budget again.
+    pub fn cancel_reservation(&self, id: u64) -> Result<(), ReservationError> {
+        block_on(self.cancel_reservation_async(id))
+    }
+
+    /// Cancels a reservation, releasing all of the reserved budget again.
+    ///
+    /// This is the same as [`Service::cancel_reservation`], see [`Service::try_exceeds_budget_async`].
+    pub async fn cancel_reservation_async(&self, id: u64) -> Result<(), ReservationError> {
+        self.commit_reservation_async(id, 0.).await.map(|_| ())
+    }
+
+    /// Pre-creates the trackers of the given projects, unless they exist already.
+    ///
+    /// This avoids many threads racing to insert new projects when the first burst of traffic
+    /// of large projects arrives, for example right after startup.
+    /// Pre-warmed projects without any traffic are cleaned up after one budgeting window.
+    /// Returns the number of newly created trackers.
+    pub fn prewarm_projects(
+        &self,
+        config: &str,
+        project_ids: impl IntoIterator<Item = u64>,
+    ) -> Result<usize, ConfigError> {
+        let configs = self.configs.load();
+        let Some(registered) = active_config(&configs, config) else {
+            return Err(ConfigError::Unknown(config.into()));
+        };
+
+        let mut created = 0;
+        let mut insert = || {
+            created += 1;
+            registered.config.new_tracker()
+        };
+        for project_id in project_ids {
+            let project_id = self.resolve_project(project_id.into());
+            let mut update = |_tracker: &mut dyn BudgetTracker| {};
+            block_on(
+                registered
+                    .projects
+                    .update(project_id, Some(&mut insert), &mut update),
+            );
+        }
+        Ok(created)
+    }
+
+    /// Sets a weight multiplier for all the spending recorded for this project.
+    ///
+    /// The weight applies for the given `ttl`, capped at [`MAX_TTL`], after which spending is recorded as-is again.
+    /// Returns `false` if the config is not known.
+    pub fn set_project_weight(
+        &self,
+        config: &str,
+        project_id: u64,
+        weight: f64,
+        ttl: Duration,
+    ) -> bool {
+        self.set_project_weight_by_key(config, project_id.into(), weight, ttl)
+    }
+
+    /// Sets a weight multiplier for all the spending recorded for the project identified by a [`ProjectKey`].
+    ///
+    /// This is the same as [`Service::set_project_weight`], see [`Service::try_exceeds_budget_by_key`].
+    pub fn set_project_weight_by_key(
+        &self,
+        config: &str,
+        project: ProjectKey,
+        weight: f64,
+        ttl: Duration,
+    ) -> bool {
+        let configs = self.configs.load();
+        let Some(registered) = active_config(&configs, config) else {
+            return false;
+        };
+        let expires_at = self.expires_at(ttl);
+        let weight = ProjectWeight { weight, expires_at };
+        let project_id = self.resolve_project(project);
+        self.project_weights
+            .insert((registered.id, project_id), weight);
+        true
+    }
+
+    /// Accounts all the budget of the `project` to the `alias_of` project, across all configs.
+    ///
+    /// This is used when projects are merged, so that the spending recorded under the old key
+    /// counts toward the budget of the new one, and both share the same decisions.
+    /// Spending that was recorded for the old key before stays with it, and expires as usual.
+    /// Projects that were aliased to `project` are re-pointed to `alias_of` as well.
+    /// Returns `false` if this would create a cycle, because `alias_of` is (an alias of) `project`.
+    pub fn set_project_alias(&self, project: ProjectKey, alias_of: ProjectKey) -> bool {
+        let mut aliases = self.project_aliases.write().unwrap();
+        let alias_of = aliases.get(&alias_of).copied().unwrap_or(alias_of);
+        if alias_of == project {
+            return false;
+        }
+        for target in aliases.values_mut() {
+            if *target == project {
+                *target = alias_of;
+            }
+        }
+        aliases.insert(project, alias_of);
+        self.alias_count.store(aliases.len(), Ordering::Release);
+        true
+    }
+
+    /// Removes the alias of the `project`, returning the project it was an alias of.
+    pub fn remove_project_alias(&self, project: ProjectKey) -> Option<ProjectKey> {
+        let mut aliases = self.project_aliases.write().unwrap();
+        let alias_of = aliases.remove(&project);
+        self.alias_count.store(aliases.len(), Ordering::Release);
+        alias_of
+    }
+
+    /// Returns all the project aliases, mapping each aliased project to the project it is an alias of.
+    pub fn project_aliases(&self) -> HashMap<ProjectKey, ProjectKey> {
+        self.project_aliases.read().unwrap().clone()
+    }
+
+    /// Attaches a short note to the `project`, like "blocked manually, see INC-1234", returning the previous one.
+    ///
+    /// Notes carry context about a project during incident handovers, and are kept independently of
+    /// the tracked state of the project, across all configs, until they are removed.
+    pub fn set_project_note(&self, project: ProjectKey, note: impl Into<String>) -> Option<String> {
+        let mut notes = self.project_notes.write().unwrap();
+        notes.insert(project, note.into())
+    }
+
+    /// Removes the note of the `project`, returning it.
+    pub fn remove_project_note(&self, project: ProjectKey) -> Option<String> {
+        self.project_notes.write().unwrap().remove(&project)
+    }
+
+    /// Returns the note attached to the `project`, if there is one.
+    pub fn project_note(&self, project: ProjectKey) -> Option<String> {
+        self.project_notes.read().unwrap().get(&project).cloned()
+    }
+
+    /// Returns all the project notes, keyed by project.
+    pub fn project_notes(&self) -> HashMap<ProjectKey, String> {
+        self.project_notes.read().unwrap().clone()
+    }
+
+    /// Replaces all the [`DecisionOverride`]s, keyed by config name and project.
+    ///
+    /// Overrides force the decision for a project, for example to block or allow it in an emergency,
+    /// regardless of its spending and the [`Enforcement`] of its config. Spending is still recorded as usual.
+    /// The override of a project applies to all of its [aliases](Service::set_project_alias) as well.
+    pub fn set_decision_overrides(
+        &self,
+        overrides: HashMap<String, HashMap<ProjectKey, DecisionOverride>>,
+    ) {
+        let mut current = self.decision_overrides.write().unwrap();
+        *current = overrides;
+        let count = current.values().map(HashMap::len).sum();
+        self.override_count.store(count, Ordering::Release);
+    }
+
+    /// Returns all the [`DecisionOverride`]s, keyed by config name and project.
+    pub fn decision_overrides(&self) -> HashMap<String, HashMap<ProjectKey, DecisionOverride>> {
+        self.decision_overrides.read().unwrap().clone()
+    }
+
+    /// Returns the [`DecisionOverride`] of a [resolved](Service::resolve_project) project, if there is one.
+    ///
+    /// Without any overrides, this does not lock anything.
+    /// Each request is meant to look up its override only once, as the overrides might change in between.
+    fn decision_override(&self, config: &str, project: ProjectKey) -> Option<DecisionOverride> {
+        if self.override_count.load(Ordering::Acquire) == 0 {
+            return None;
+        }
+        let overrides = self.decision_overrides.read().unwrap();
+        overrides.get(config)?.get(&project).copied()
+    }
+
+    /// Applies the [`DecisionOverride`] of a resolved project, if there is one, to a decision.
+    fn override_decision(&self, config: &str, project: ProjectKey, decision: Decision) -> Decision {
+        (self.decision_override(config, project)).map_or(decision, DecisionOverride::decision)
+    }
+
+    /// Applies the [`DecisionOverride`] of a resolved project, if there is one, to whether it exceeds its budget.
+    fn override_exceeds_budget(
+        &self,
+        config: &str,
+        project: ProjectKey,
+        exceeds_budget: bool,
+    ) -> bool {
+        let decision = Decision::from_exceeds_budget(exceeds_budget);
+        self.override_decision(config, project, decision)
+            .exceeds_budget
+    }
+
+    /// Returns the project whose budget the given project is accounted to, which is usually itself.
+    ///
+    /// Without any aliases, this does not lock anything.
+    fn resolve_project(&self, project: ProjectKey) -> ProjectKey {
+        if self.alias_count.load(Ordering::Acquire) == 0 {
+            return project;
+        }
+        let aliases = self.project_aliases.read().unwrap();
+        aliases.get(&project).copied().unwrap_or(project)
+    }
+
+    /// Returns the [`pressure`](SpendSummary::pressure) of each config, along with the highest one.
+    ///
+    /// This summarizes the health of the whole service, so that upstream schedulers can shed load
+    /// before individual projects are blocked. Just like [`Service::spend_summary`], this is computed by
+    /// the background maintenance thread.
+    pub fn pressure(&self) -> (f64, IndexMap<String, f64>) {
+        let pressures: IndexMap<_, _> = self
+            .spend_summary()
+            .into_iter()
+            .map(|(config, summary)| (config, summary.pressure()))
+            .collect();
+        let max_pressure = pressures.values().copied().fold(0., f64::max);
+        (max_pressure, pressures)
+    }
+
+    /// Limits the approximate memory used by all the tracked projects, in bytes.
+    ///
+    /// Once exceeded, the maintenance thread evicts the least recently updated projects which are
+    /// not exceeding their budget, and emits an [`Event::MemoryLimitExceeded`].
+    /// Blocked projects are never evicted, as that would unblock them.
+    pub fn set_memory_limit(&self, limit: Option<usize>) {
+        self.memory_limit
+            .store(limit.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// Shares a snapshot of the blocked projects with clients on the same host, via a shared memory file.
+    ///
+    /// The file at `path` (for example within `/dev/shm`) is replaced, and has room for `capacity` projects.
+    /// The maintenance thread updates it on every pass with the projects which exceed the budget of an
+    /// [enforced](Enforcement) config, ignoring any [decision overrides](Service::set_decision_overrides).
+    /// Clients read it via [`BlockedSnapshot`].
+    pub fn share_blocked_projects(
+        &self,
+        path: &std::path::Path,
+        capacity: usize,
+    ) -> std::io::Result<()> {
+        let writer = BlockedSnapshotWriter::create(path, capacity)?;
+        *self
+            .blocked_snapshot
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(writer);
+        Ok(())
+    }
+
+    /// Writes the [shared](Service::share_blocked_projects) snapshot of blocked projects right away,
+    /// instead of waiting for the next maintenance pass.
+    ///
+    /// This does nothing if the blocked projects are not shared.
+    pub fn flush_blocked_snapshot(&self) {
+        maintenance::write_blocked_snapshot(&self.configs, &self.blocked_snapshot);
+    }
+
+    /// Aggregates the state changes of projects into a single [`Event::MassStateChange`],
+    /// once more than `threshold` projects changed their "exceeded" state within a single maintenance pass.
+    ///
+    /// This replaces the individual events of that pass, so that a sudden change in traffic,
+    /// or a misconfigured budget, does not flood the event handler.
+    pub fn set_mass_state_change_threshold(&self, threshold: Option<usize>) {
+        self.mass_state_change_threshold
+            .store(threshold.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// Skips the time the clock jumped forward between two maintenance passes.
+    ///
+    /// The clock can jump when a suspended VM resumes, in which case all the windows, backoffs and
+    /// stale projects covered by the jump would expire at once, unblocking all the projects.
+    /// When enabled, the state of all projects is shifted forward instead, as if the jump did not happen.
+    /// Either way, the jump is reported as an [`Event::ClockJumped`].
+    pub fn set_skip_clock_jumps(&self, skip: bool) {
+        self.skip_clock_jumps.store(skip, Ordering::Relaxed);
+    }
+
+    /// Caps the number of registered configs, or removes the cap with `None`.
+    ///
+    /// Registering more configs fails with [`ConfigError::TooMany`], while the existing ones are kept.
+    /// Regardless of the cap, an [`Event::ManyConfigs`] is emitted for every config registered beyond
+    /// [`CONFIG_COUNT_WARNING`].
+    pub fn set_max_configs(&self, max: Option<usize>) {
+        self.max_configs.store(max.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// Counts a budget check of a project, which is `known` or not.
+    ///
+    /// Checks of configs that are switched off never look up the project, so they are not counted.
+    fn count_project_check(&self, registered: &RegisteredConfig, known: bool) {
+        if !known && registered.effective_enforcement() != Enforcement::Off {
+            self.unknown_project_checks.add(1);
+        }
+    }
+
+    /// Counts recorded spending of a project, which either inserted or updated its tracker.
+    fn count_project_record(&self, project: &ProjectRef<'_>) {
+        match project.inserted() {
+            true => self.project_inserts.add(1),
+            false => self.project_updates.add(1),
+        }
+    }
+
+    /// Applies the [`Enforcement`] of a config to a decision.
+    fn enforce(&self, enforcement: Enforcement, exceeds_budget: bool) -> bool {
+        match enforcement {
+            Enforcement::On => exceeds_budget,
+            Enforcement::DryRun => {
+                if exceeds_budget {
+                    self.dry_run_blocks.add(1);
+                }
+                false
+            }
+            Enforcement::Off => false,
+        }
+    }
+
+    /// Applies the [`Enforcement`] of a config to a decision of a tracker and its reason.
+    ///
+    /// Without a tracker, the project is not known (yet), and does not exceed its budget.
+    fn decide(
+        &self,
+        enforcement: Enforcement,
+        decision: Option<(bool, DecisionReason)>,
+    ) -> Decision {
+        let (exceeds_budget, reason) = decision.unwrap_or((false, DecisionReason::UnderBudget));
+        let reason = match enforcement {
+            Enforcement::DryRun if exceeds_budget => DecisionReason::DryRun,
+            Enforcement::Off => DecisionReason::NotEnforced,
+            _ => reason,
+        };
+        Decision {
+            exceeds_budget: self.enforce(enforcement, exceeds_budget),
+            reason,
+            cache_for: None,
+        }
+    }
+
+    /// Returns the time at which something with the given `ttl` expires, with the `ttl` capped at [`MAX_TTL`].
+    fn expires_at(&self, ttl: Duration) -> Instant {
+        let now = self.timer.now();
+        // Only a (mocked) clock close to its very end can overflow with a capped TTL.
+        now.checked_add(ttl.min(MAX_TTL)).unwrap_or(now)
+    }
+
+    /// Returns the currently applicable weight for the project identified by `key`.
+    fn project_weight(&self, key: &(ConfigId, ProjectKey)) -> f64 {
+        match self.project_weights.get(key) {
+            Some(weight) if weight.expires_at > self.timer.now() => weight.weight,
+            _ => 1.,
+        }
+    }
+
+    /// Returns a [`SpendSummary`] for each of the registered configs.
+    ///
+    /// The summaries are computed by the background maintenance thread,
+    /// and can thus lag behind by one maintenance interval.
+    pub fn spend_summary(&self) -> IndexMap<String, SpendSummary> {
+        let spend_summaries = self.spend_summaries.read().unwrap();
+        let configs = self.configs.load();
+        (configs.iter())
+            .filter(|(_name, registered)| registered.removal.is_none())
+            .map(|(name, registered)| {
+                let summary = spend_summaries.get(&registered.id).cloned();
+                let summary = SpendSummary {
+                    allowed_decisions: registered.decisions.allowed.get(),
+                    blocked_decisions: registered.decisions.blocked.get(),
+                    ..summary.unwrap_or_default()
+                };
+                (name.clone(), summary)
+            })
+            .collect()
+    }
+
+    /// Returns a [`BudgetReport`] of how different budgets would have affected the projects of a config.
+    ///
+    /// The report is based on the spend rates of the tracked projects within the last `hours`
+    /// (up to [`MAX_HISTORY_HOURS`]), which are sampled by the background maintenance thread on every pass.
+    /// This helps to choose the budget of a config empirically.
+    pub fn budget_report(&self, config: &str, hours: usize) -> Result<BudgetReport, ConfigError> {
+        let configs = self.configs.load();
+        let registered =
+            active_config(&configs, config).ok_or_else(|| ConfigError::Unknown(config.into()))?;
+        let (hours, spend_rates) = self.spend_history.spend_rates(registered.id, hours);
+        Ok(BudgetReport::new(
+            hours,
+            registered.config.budget,
+            &spend_rates,
+        ))
+    }
+
+    /// Returns the [`MinuteAggregate`]s of the spending of a config within the last `minutes`, oldest first.
+    ///
+    /// The aggregates of up to [`MAX_TREND_MINUTES`] minutes are retained in memory,
+    /// based on the [`SpendSummary`]s computed by the background maintenance thread on every pass.
+    /// This answers basic questions about the trend of a config without an external metrics system.
+    pub fn spend_trend(
+        &self,
+        config: &str,
+        minutes: usize,
+    ) -> Result<Vec<MinuteAggregate>, ConfigError> {
+        let configs = self.configs.load();
+        let registered =
+            active_config(&configs, config).ok_or_else(|| ConfigError::Unknown(config.into()))?;
+        let now = self.timer.precise_now();
+        Ok(self.spend_history.trend(registered.id, minutes, now))
+    }
+
+    /// Returns how long ago the background maintenance thread completed its last pass.
+    ///
+    /// Returns [`None`] if no maintenance pass has completed yet.
+    /// An ever-growing age means that cleanup of stale projects is falling behind.
+    pub fn maintenance_age(&self) -> Option<Duration> {
+        self.maintenance_metrics
+            .last_pass_age(self.timer.precise_now())
+    }
+
+    /// Returns whether the background maintenance thread stopped altogether.
+    ///
+    /// The maintenance thread recovers from panics within a pass, so this hints at a bug in the thread itself.
+    /// Without it, the time is no longer updated, and stale projects are never cleaned up.
+    pub fn maintenance_stopped(&self) -> bool {
+        let thread = self.maintenance_thread.lock().unwrap();
+        thread.as_ref().is_none_or(JoinHandle::is_finished)
+    }
+
+    /// Stops the background maintenance thread, and waits for its current pass to finish.
+    ///
+    /// This is meant for a graceful shutdown, so that the final state is not changed by another pass.
+    /// Afterwards, the time is no longer updated, stale projects are never cleaned up,
+    /// and [`Service::maintenance_stopped`] returns `true`.
+    pub fn stop_maintenance(&self) {
+        self.maintenance_stopping.store(true, Ordering::Release);
+        let Some(thread) = self.maintenance_thread.lock().unwrap().take() else {
+            return;
+        };
+        thread.thread().unpark();
+        // A panic outside of a pass was reported already, and there is nothing left to stop.
+        let _ = thread.join();
+    }
+
+    /// Returns whether the last pass of the background maintenance thread succeeded.
+    ///
+    /// A failing maintenance pass hints at a bug, as it means that stale projects are not cleaned up.
+    /// See [`Event::MaintenancePanicked`].
+    pub fn maintenance_healthy(&self) -> bool {
+        self.maintenance_metrics.is_healthy()
+    }
+
+    /// Renders the service metrics in the Prometheus text format.
+    pub fn render_metrics(&self) -> String {
+        block_on(self.render_metrics_async())
+    }
+
+    /// Renders the service metrics in the Prometheus text format.
+    ///
+    /// This is the same as [`Service::render_metrics`], see [`Service::try_exceeds_budget_async`].
+    pub async fn render_metrics_async(&self) -> String {
+        let mut out = String::new();
+        self.write_metrics_async(&mut out).await;
+        out
+    }
+
+    /// Writes the service metrics to `out`.
+    pub fn write_metrics(&self, out: &mut impl MetricsWriter) {
+        block_on(self.write_metrics_async(out))
+    }
+
+    /// Writes the service metrics to `out`.
+    ///
+    /// This is the same as [`Service::write_metrics`], see [`Service::try_exceeds_budget_async`].
+    pub async fn write_metrics_async(&self, out: &mut impl MetricsWriter) {
+        let mut tracked_projects = Vec::new();
+        for (config, registered) in self.configs() {
+            tracked_projects.push((config, registered.projects.len().await));
+        }
+        self.write_metrics_with(out, &tracked_projects);
+    }
+
+    /// Writes the service metrics to `out`, given the number of tracked projects of each config.
+    fn write_metrics_with(
+        &self,
+        out: &mut impl MetricsWriter,
+        tracked_projects: &[(String, usize)],
+    ) {
+        let metrics = &self.maintenance_metrics;
+
+        let num_configs = active_configs(&self.configs.load());
+        write_metric(
+            out,
+            "peanutbutter_configs",
+            MetricKind::Gauge,
+            "Number of registered configs.",
+            num_configs,
+        );
+        write_metric(
+            out,
+            "peanutbutter_maintenance_passes_total",
+            MetricKind::Counter,
+            "Number of completed maintenance passes.",
+            metrics.passes.get(),
+        );
+        write_metric(
+            out,
+            "peanutbutter_maintenance_pass_duration_seconds",
+            MetricKind::Gauge,
+            "Duration of the last maintenance pass.",
+            metrics.pass_duration.get(),
+        );
+        write_metric(
+            out,
+            "peanutbutter_maintenance_entries_scanned",
+            MetricKind::Gauge,
+            "Number of project entries scanned in the last maintenance pass.",
+            metrics.entries_scanned.get(),
+        );
+        write_metric(
+            out,
+            "peanutbutter_maintenance_entries_removed_total",
+            MetricKind::Counter,
+            "Number of stale project entries removed by maintenance.",
+            metrics.entries_removed.get(),
+        );
+        write_metric(
+            out,
+            "peanutbutter_maintenance_blocked_entries_removed_total",
+            MetricKind::Counter,
+            "Number of stale project entries removed while exceeding their budget.",
+            metrics.blocked_entries_removed.get(),
+        );
+        write_metric(
+            out,
+            "peanutbutter_state_memory_bytes",
+            MetricKind::Gauge,
+            "Approximate memory used by all the tracked projects, as of the last maintenance pass.",
+            metrics.state_memory.get(),
+        );
+        write_metric(
+            out,
+            "peanutbutter_maintenance_entries_evicted_total",
+            MetricKind::Counter,
+            "Number of project entries evicted because of the memory limit.",
+            metrics.entries_evicted.get(),
+        );
+        write_metric(
+            out,
+            "peanutbutter_expired_acquisitions_total",
+            MetricKind::Counter,
+            "Number of acquired slots released because they were not released before their TTL.",
+            metrics.expired_acquisitions.get(),
+        );
+        write_metric(
+            out,
+            "peanutbutter_maintenance_clock_jumps_total",
+            MetricKind::Counter,
+            "Number of times the clock jumped forward between two maintenance passes.",
+            metrics.clock_jumps.get(),
+        );
+        write_metric(
+            out,
+            "peanutbutter_maintenance_failed_passes_total",
+            MetricKind::Counter,
+            "Number of maintenance passes that failed because of a panic.",
+            metrics.failed_passes.get(),
+        );
+        if let Some(age) = self.maintenance_age() {
+            write_metric(
+                out,
+                "peanutbutter_maintenance_last_pass_age_seconds",
+                MetricKind::Gauge,
+                "Time since the last completed maintenance pass.",
+                age.as_secs_f64(),
+            );
+        }
+
+        let name = "peanutbutter_tracked_projects";
+        write_metric_header(
+            out,
+            name,
+            MetricKind::Gauge,
+            "Number of projects currently tracked per config.",
+        );
+        for (config, tracked_projects) in tracked_projects {
+            write_sample(out, name, &[("config", config)], tracked_projects);
+        }
+
+        let name = "peanutbutter_oversized_spends_total";
+        write_metric_header(
+            out,
+            name,
+            MetricKind::Counter,
+            "Number of recorded spendings per config above its max_single_spend, which were clamped or rejected.",
+        );
+        for (config, registered) in self.configs().iter() {
+            let oversized_spends = registered.oversized_spends.get();
+            write_sample(out, name, &[("config", config)], oversized_spends);
+        }
+
+        let name = "peanutbutter_decisions_total";
+        write_metric_header(
+            out,
+            name,
+            MetricKind::Counter,
+            "Number of budget checks per config, by whether they allowed or blocked the project.",
+        );
+        for (config, registered) in self.configs().iter() {
+            let decisions = &registered.decisions;
+            for (decision, count) in [
+                ("allowed", &decisions.allowed),
+                ("blocked", &decisions.blocked),
+            ] {
+                let labels = [("config", config.as_str()), ("decision", decision)];
+                write_sample(out, name, &labels, count.get());
+            }
+        }
+
+        let name = "peanutbutter_pressure";
+        write_metric_header(
+            out,
+            name,
+            MetricKind::Gauge,
+            "Ratio of the total spend rate to the total budget of the tracked projects, per config.",
+        );
+        let (_max_pressure, pressures) = self.pressure();
+        for (config, pressure) in &pressures {
+            write_sample(out, name, &[("config", config)], pressure);
+        }
+
+        let name = "peanutbutter_flip_flopping_project_transitions";
+        write_metric_header(
+            out,
+            name,
+            MetricKind::Gauge,
+            "Changes of the exceeded state within the last hour, for the most frequently changing projects per config.",
+        );
+        for (config, summary) in self.spend_summary() {
+            for flip_flopper in &summary.flip_floppers {
+                let project_id = flip_flopper.project_id.to_string();
+                let labels = [("config", config.as_str()), ("project_id", &project_id)];
+                write_sample(out, name, &labels, flip_flopper.transitions);
+            }
+        }
+
+        let name = "peanutbutter_blocked_duration_seconds";
+        write_metric_header(
+            out,
+            name,
+            MetricKind::Histogram,
+            "How long the currently blocked projects have been exceeding their budget, per config.",
+        );
+        for (config, summary) in self.spend_summary() {
+            let durations = &summary.blocked_durations;
+            for (le, count) in durations.cumulative_buckets() {
+                let labels = [("config", config.as_str()), ("le", &le.to_string())];
+                write_sample(out, &format!("{name}_bucket"), &labels, count);
+            }
+            let labels = [("config", config.as_str()), ("le", "+Inf")];
+            write_sample(out, &format!("{name}_bucket"), &labels, durations.count);
+            let labels = [("config", config.as_str())];
+            write_sample(out, &format!("{name}_sum"), &labels, durations.sum);
+            write_sample(out, &format!("{name}_count"), &labels, durations.count);
+        }
+
+        let name = "peanutbutter_budget_utilization";
+        write_metric_header(
+            out,
+            name,
+            MetricKind::Gauge,
+            "Quantiles of the ratio of spend rate to budget across the tracked projects, per config.",
+        );
+        for (config, summary) in self.spend_summary() {
+            for quantile in UTILIZATION_QUANTILES {
+                let Some(utilization) = summary.utilizations.quantile(quantile) else {
+                    continue;
+                };
+                let labels = [
+                    ("config", config.as_str()),
+                    ("quantile", &quantile.to_string()),
+                ];
+                write_sample(out, name, &labels, utilization);
+            }
+        }
+
+        write_metric(
+            out,
+            "peanutbutter_reservations",
+            MetricKind::Gauge,
+            "Number of reservations which are neither committed, canceled nor expired.",
+            self.reservations.len(),
+        );
+        write_metric(
+            out,
+            "peanutbutter_dry_run_blocks_total",
+            MetricKind::Counter,
+            "Number of decisions that would have exceeded the budget of a config in dry-run mode.",
+            self.dry_run_blocks.get(),
+        );
+        write_metric(
+            out,
+            "peanutbutter_project_inserts_total",
+            MetricKind::Counter,
+            "Number of recorded spendings that inserted a new project.",
+            self.project_inserts.get(),
+        );
+        write_metric(
+            out,
+            "peanutbutter_project_updates_total",
+            MetricKind::Counter,
+            "Number of recorded spendings that updated an existing project.",
+            self.project_updates.get(),
+        );
+        write_metric(
+            out,
+            "peanutbutter_unknown_project_checks_total",
+            MetricKind::Counter,
+            "Number of budget checks for projects which are not known.",
+            self.unknown_project_checks.get(),
+        );
+        write_metric(
+            out,
+            "peanutbutter_unknown_config_requests_total",
+            MetricKind::Counter,
+            "Number of requests for config names which are not registered.",
+            self.unknown_configs.total.get(),
+        );
+        let unknown_configs = self.unknown_configs.requests();
+        if !unknown_configs.is_empty() {
+            let name = "peanutbutter_unknown_config_requests_by_name_total";
+            write_metric_header(
+                out,
+                name,
+                MetricKind::Counter,
+                "Number of requests per unknown config name, for a bounded set of names.",
+            );
+            for (config, requests) in &unknown_configs {
+                write_sample(out, name, &[("config", config)], requests);
+            }
+        }
+    }
+
+    /// Records a request for an unknown config, which is periodically reported as an [`Event`].
+    ///
+    /// This must not be called while looking at the configs, so that the event handler may do so as well.
+    fn record_unknown_config(&self, config: &str) {
+        if let Some(requests) = self.unknown_configs.record(config, self.timer.now()) {
+            self.events.emit(Event::UnknownConfig {
+                config: config.into(),
+                requests,
+            });
+        }
+    }
+
+    /// Calls `f` with a mutable [`BudgetTracker`] reference from the [`StateStore`]
+    /// of the config, along with the [`RegisteredConfig`] itself.
+    ///
+    /// The configs are not locked while `f` runs, which sees the configs as of the call.
+    /// Requests for unknown configs are recorded. The `project` needs to be [resolved](Service::resolve_project)
+    /// already, so that requests resolving it once can pass the same key to [`Service::decision_override`].
+    /// No tracker is passed for configs with [`Enforcement::Off`].
+    async fn with_project_tracker<R: Send>(
+        &self,
+        config: &str,
+        project_id: ProjectKey,
+        or_insert: bool,
+        f: impl FnOnce(&RegisteredConfig, Option<ProjectRef<'_>>) -> R + Send,
+    ) -> Result<R, ConfigError> {
+        let configs = self.configs.load();
+        let Some(registered) = active_config(&configs, config) else {
+            drop(configs);
+            self.record_unknown_config(config);
+            return Err(ConfigError::Unknown(config.into()));
+        };
+        if registered.effective_enforcement() == Enforcement::Off {
+            return Ok(f(registered, None));
+        }
+        let key = (registered.id, project_id);
+
+        let inserted = AtomicBool::new(false);
+        let mut insert = || {
+            inserted.store(true, Ordering::Relaxed);
+            registered.config.new_tracker()
+        };
+        let insert = or_insert.then_some(&mut insert as &mut (dyn FnMut() -> _ + Send));
+        let mut f = Some(f);
+        let mut result = None;
+        let mut update = |tracker: &mut dyn BudgetTracker| {
+            if let Some(f) = f.take() {
+                let project = ProjectRef {
+                    key,
+                    tracker: &mut *tracker,
+                    inserted: inserted.load(Ordering::Relaxed),
+                };
+                result = Some(f(registered, Some(project)));
+                registered.cache_check(project_id, tracker);
+            }
+        };
+        registered
+            .projects
+            .update(project_id, insert, &mut update)
+            .await;
+        Ok(match f {
+            Some(f) => f(registered, None),
+            None => result.expect("`f` was called with the tracker"),
+        })
+    }
+}
+
+/// Returns the number of configs that were not removed.
+#[cfg(feature = "service")]
+fn active_configs(configs: &IndexMap<String, RegisteredConfig>) -> usize {
+    let active = configs
+        .values()
+        .filter(|registered| registered.removal.is_none());
+    active.count()
+}
+
+/// Looks up a config that was not removed.
+#[cfg(feature = "service")]
+fn active_config<'a>(
+    configs: &'a IndexMap<String, RegisteredConfig>,
+    name: &str,
+) -> Option<&'a RegisteredConfig> {
+    configs
+        .get(name)
+        .filter(|registered| registered.removal.is_none())
+}
+
+/// Looks up a config that was not removed for modification.
+///
+/// Returns [`ConfigError::Unknown`] if no such config is registered.
+#[cfg(feature = "service")]
+fn active_config_mut<'a>(
+    configs: &'a mut IndexMap<String, RegisteredConfig>,
+    name: &str,
+) -> Result<&'a mut RegisteredConfig, ConfigError> {
+    configs
+        .get_mut(name)
+        .filter(|registered| registered.removal.is_none())
+        .ok_or_else(|| ConfigError::Unknown(name.into()))
+}
+
+/// Returns an [`Event::ConfigWarning`] for every likely misconfiguration of the given config.
+#[cfg(feature = "service")]
+fn config_warnings(name: &str, config: &BudgetingConfig) -> impl Iterator<Item = Event> {
+    let name = name.to_owned();
+    (config.warnings().into_iter()).map(move |warning| Event::ConfigWarning {
+        config: name.clone(),
+        warning,
+    })
+}
+
+#[cfg(feature = "service")]
+impl Default for Service {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(all(test, feature = "service"))]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    fn test_service() -> Service {
+        let service = Service::new();
+        service.try_add_config("test", test_config(10.)).unwrap();
+        service
+    }
+
+    fn test_config(budget: f64) -> BudgetingConfig {
+        BudgetingConfig::new(
+            Duration::from_secs(60),
+            Duration::from_secs(10),
+            Duration::from_secs(1),
+            budget,
+        )
+    }
+
+    #[test]
+    fn test_add_config() {
+        let service = test_service();
+
+        assert_eq!(
+            service.try_add_config("test", test_config(20.)),
+            Err(ConfigError::Duplicate("test".into()))
+        );
+        assert_eq!(
+            service.replace_config("unknown", test_config(20.), ReplaceState::Keep),
+            Err(ConfigError::Unknown("unknown".into()))
+        );
+        assert_eq!(
+            service.set_enforcement("unknown", Enforcement::Off),
+            Err(ConfigError::Unknown("unknown".into()))
+        );
+    }
+
+    #[test]
+    fn test_max_configs() {
+        let service = test_service();
+        let events = Arc::new(Mutex::new(vec![]));
+        let captured = events.clone();
+        service.set_event_handler(move |event| {
+            if let Event::ManyConfigs { .. } = event {
+                captured.lock().unwrap().push(event.clone())
+            }
+        });
+
+        service.set_max_configs(Some(2));
+        service.try_add_config("second", test_config(10.)).unwrap();
+        assert_eq!(
+            service.try_add_config("third", test_config(10.)),
+            Err(ConfigError::TooMany(2))
+        );
+        // removed configs do not count
+        service.remove_config("second").unwrap();
+        service.try_add_config("third", test_config(10.)).unwrap();
+        assert!(service
+            .render_metrics()
+            .contains("peanutbutter_configs 2\n"));
+
+        service.set_max_configs(None);
+        for idx in 0..CONFIG_COUNT_WARNING {
+            service
+                .try_add_config(&idx.to_string(), test_config(10.))
+                .unwrap();
+        }
+        let expected = [101, 102].map(|configs| Event::ManyConfigs { configs });
+        assert_eq!(*events.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_enforcement() {
+        let service = test_service();
+        assert!(service.record_spending("test", 1, 150.));
+
+        service
+            .set_enforcement("test", Enforcement::DryRun)
+            .unwrap();
+        assert!(!service.exceeds_budget("test", 1));
+        assert!(!service.record_spending("test", 2, 150.));
+        assert_eq!(service.dry_run_blocks.get(), 2);
+
+        service.set_enforcement("test", Enforcement::Off).unwrap();
+        assert!(!service.exceeds_budget("test", 1));
+        assert!(!service.record_spending("test", 3, 150.));
+        assert_eq!(service.dry_run_blocks.get(), 2);
+
+        // the enforcement survives replacing the config
+        service
+            .replace_config("test", test_config(10.), ReplaceState::Keep)
+            .unwrap();
+        assert_eq!(service.configs()["test"].enforcement, Enforcement::Off);
+
+        service.set_enforcement("test", Enforcement::On).unwrap();
+        assert!(service.exceeds_budget("test", 1));
+        assert!(service.exceeds_budget("test", 2));
+        // spending was not recorded while enforcement was off
+        assert!(!service.exceeds_budget("test", 3));
+    }
+
+    #[test]
+    fn test_decision_reasons() {
+        let service = test_service();
+        let decision = |exceeds_budget, reason| {
+            Ok(Decision {
+                exceeds_budget,
+                reason,
+                cache_for: None,
+            })
+        };
+        // projects that were never seen may be cached for a bucket
+        let unknown = |reason| {
+            Ok(Decision {
+                exceeds_budget: false,
+                reason,
+                cache_for: Some(Duration::from_secs(1)),
+            })
+        };
+        assert_eq!(
+            service.try_record_spending_with_reason("test", 1, 50.),
+            decision(false, DecisionReason::UnderBudget)
+        );
+        assert_eq!(
+            service.try_record_spending_with_reason("test", 1, 100.),
+            decision(true, DecisionReason::OverBudget)
+        );
+        assert_eq!(
+            service.try_exceeds_budget_with_reason("test", 2),
+            unknown(DecisionReason::UnderBudget)
+        );
+        assert_eq!(
+            service.try_exceeds_budget_with_reason("unknown", 1),
+            Err(ConfigError::Unknown("unknown".into()))
+        );
+
+        service
+            .set_enforcement("test", Enforcement::DryRun)
+            .unwrap();
+        assert_eq!(
+            service.try_exceeds_budget_with_reason("test", 1),
+            decision(false, DecisionReason::DryRun)
+        );
+        assert_eq!(
+            service.try_exceeds_budget_with_reason("test", 2),
+            unknown(DecisionReason::UnderBudget)
+        );
+        assert_eq!(service.dry_run_blocks.get(), 1);
+
+        service.set_enforcement("test", Enforcement::Off).unwrap();
+        assert_eq!(
+            service.try_record_spending_with_reason("test", 1, 100.),
+            decision(false, DecisionReason::NotEnforced)
+        );
+
+        let config = test_config(10.).with_unknown_project_ttl(Duration::ZERO);
+        service.try_add_config("no-ttl", config).unwrap();
+        assert_eq!(
+            service.try_exceeds_budget_with_reason("no-ttl", 2),
+            decision(false, DecisionReason::UnderBudget)
+        );
+    }
+
+    #[test]
+    fn test_project_report() {
+        let service = test_service();
+        assert_eq!(service.project_report("test", 1), Ok(None));
+        assert_eq!(
+            service.project_report("unknown", 1),
+            Err(ConfigError::Unknown("unknown".into()))
+        );
+
+        service.record_spending("test", 1, 10.);
+        let decision = service.try_record_spending_from("test", 1, 200., "producer-a");
+        assert!(decision.unwrap().exceeds_budget);
+        service
+            .try_record_spending_from("test", 1, 10., "producer-b")
+            .unwrap();
+
+        let report = service.project_report("test", 1).unwrap().unwrap();
+        assert!(report.exceeds_budget);
+        let sources: Vec<_> = report.recent_sources.iter().map(|s| &**s).collect();
+        assert_eq!(sources, ["producer-b", "producer-a"]);
+
+        assert_eq!(service.bucket_spending("test", 2), Ok(None));
+        let spending = service.bucket_spending("test", 1).unwrap().unwrap();
+        assert_eq!(spending.iter().map(|b| b.1).sum::<f64>(), 220.);
+    }
+
+    #[test]
+    fn test_simulate_config() {
+        let service = test_service();
+        assert!(!service.record_spending("test", 1, 50.));
+
+        let report = service.simulate_config("test", 1, test_config(1.)).unwrap();
+        let report = report.unwrap();
+        assert!(report.exceeds_budget);
+        assert_eq!(report.budget, 1.);
+        let report = service
+            .simulate_config("test", 1, test_config(10.))
+            .unwrap();
+        assert!(!report.unwrap().exceeds_budget);
+
+        // the registered config is left as-is
+        assert!(!service.exceeds_budget("test", 1));
+        assert_eq!(service.configs()["test"].config.budget, 10.);
+
+        assert_eq!(
+            service.simulate_config("test", 2, test_config(1.)),
+            Ok(None)
+        );
+        assert_eq!(
+            service.simulate_config("unknown", 1, test_config(1.)),
+            Err(ConfigError::Unknown("unknown".into()))
+        );
+    }
+
+    #[test]
+    fn test_decision_overrides() {
+        let service = test_service();
+        assert!(service.record_spending("test", 1, 150.));
+        assert!(!service.record_spending("test", 2, 10.));
+
+        let overrides = HashMap::from([(
+            "test".into(),
+            HashMap::from([
+                (1.into(), DecisionOverride::ForceAllow),
+                (2.into(), DecisionOverride::ForceBlock),
+            ]),
+        )]);
+        service.set_decision_overrides(overrides.clone());
+        assert_eq!(service.decision_overrides(), overrides);
+        assert_eq!(service.override_count.load(Ordering::Relaxed), 2);
+
+        assert!(!service.exceeds_budget("test", 1));
+        assert!(!service.exceeds_budget_cached("test", 1));
+        assert!(service.exceeds_budget("test", 2));
+        assert!(service.would_exceed("test", 2, 0.));
+        assert_eq!(
+            service.try_record_spending_with_reason("test", 2, 1.),
+            Ok(DecisionOverride::ForceBlock.decision())
+        );
+        assert_eq!(
+            service.reserve("test", 2, 1.),
+            Err(ReservationError::ExceedsBudget)
+        );
+        assert!(service.reserve("test", 1, 1000.).is_ok());
+
+        // overrides apply to aliases, and regardless of the enforcement
+        assert!(service.set_project_alias(3.into(), 2.into()));
+        service.set_enforcement("test", Enforcement::Off).unwrap();
+        assert!(service.exceeds_budget("test", 3));
+        assert!(!service.exceeds_budget("other", 2));
+
+        service.set_decision_overrides(HashMap::new());
+        assert_eq!(service.override_count.load(Ordering::Relaxed), 0);
+        assert!(!service.exceeds_budget("test", 2));
+    }
+
+    #[test]
+    fn test_project_notes() {
+        let service = test_service();
+        assert_eq!(service.project_note(1.into()), None);
+        assert_eq!(service.set_project_note(1.into(), "blocked manually"), None);
+        assert_eq!(
+            service.set_project_note(1.into(), "see INC-1234"),
+            Some("blocked manually".into())
+        );
+        service.set_project_note(2.into(), "noisy");
+        assert_eq!(service.project_note(1.into()), Some("see INC-1234".into()));
+        assert_eq!(service.project_notes().len(), 2);
+
+        assert_eq!(service.remove_project_note(2.into()), Some("noisy".into()));
+        assert_eq!(service.remove_project_note(2.into()), None);
+        assert_eq!(service.project_notes().len(), 1);
+    }
+
+    #[test]
+    fn test_max_single_spend() {
+        let service = Service::new();
+        let config = test_config(10.).with_max_single_spend(50., OversizedSpend::Clamp);
+        service.try_add_config("clamp", config).unwrap();
+        let config = test_config(10.).with_max_single_spend(50., OversizedSpend::Reject);
+        service.try_add_config("reject", config).unwrap();
+
+        // a single bogus record can not exceed the budget on its own
+        assert!(!service.record_spending("clamp", 1, 1e12));
+        assert!(!service.record_spending("clamp", 1, 50.));
+        let decision = service.try_record_spending_with_reason("clamp", 1, 1e12);
+        assert!(decision.unwrap().exceeds_budget);
+
+        assert!(!service.record_spending("reject", 1, 1e12));
+        assert!(!service.record_spending("reject", 1, 1e12));
+        assert_eq!(service.remaining_budget("reject", 1), Some(100.));
+        assert!(!service.record_spending("reject", 1, 50.));
+
+        // the spending committed to a reservation is capped as well
+        let reservation = service.reserve("clamp", 2, 10.).unwrap();
+        assert_eq!(service.commit_reservation(reservation, 1e12), Ok(false));
+        assert_eq!(service.remaining_budget("clamp", 2), Some(50.));
+        let reservation = service.reserve("reject", 2, 10.).unwrap();
+        assert_eq!(service.commit_reservation(reservation, 1e12), Ok(false));
+        assert_eq!(service.remaining_budget("reject", 2), Some(90.));
+
+        let metrics = service.render_metrics();
+        assert!(metrics.contains("peanutbutter_oversized_spends_total{config=\"clamp\"} 3\n"));
+        assert!(metrics.contains("peanutbutter_oversized_spends_total{config=\"reject\"} 3\n"));
+    }
+
+    #[test]
+    fn test_project_keys() {
+        let service = test_service();
+        let uuid = ProjectKey::parse_uuid("67e55044-10b1-426f-9247-bb680e5fe0c8").unwrap();
+        let decision = service.try_record_spending_by_key("test", uuid, 150.);
+        assert!(decision.unwrap().exceeds_budget);
+        let decision = service.try_exceeds_budget_by_key("test", uuid).unwrap();
+        assert_eq!(decision.reason, DecisionReason::OverBudget);
+
+        // numeric project ids are keyed separately, and keep supporting aliases
+        assert!(!service.exceeds_budget("test", 1));
+        assert!(service.record_spending("test", 1, 150.));
+        service.set_project_alias(2.into(), 1.into());
+        let decision = service.try_exceeds_budget_by_key("test", 2.into()).unwrap();
+        assert!(decision.exceeds_budget);
+        assert_eq!(
+            service.try_exceeds_budget_by_key("unknown", uuid),
+            Err(ConfigError::Unknown("unknown".into()))
+        );
+
+        // projects identified by UUID support aliases, overrides, notes, weights and reservations as well
+        let other = ProjectKey::parse_uuid("6f1c5b1e-1a3f-4d8e-9c5b-0d2e7c4a9b31").unwrap();
+        let budget = service.remaining_budget_by_key("test", other).unwrap();
+        assert!(!service.would_exceed_by_key("test", other, budget * 0.75));
+        assert!(service.set_project_weight_by_key("test", other, 2., Duration::from_secs(60)));
+        assert!(service.would_exceed_by_key("test", other, budget * 0.75));
+        assert_eq!(
+            service.remaining_budget_by_key("test", other),
+            Some(budget / 2.)
+        );
+        let reservation = service.reserve_by_key("test", other, 1.).unwrap();
+        let remaining = budget / 2. - 1.;
+        assert_eq!(
+            service.remaining_budget_by_key("test", other),
+            Some(remaining)
+        );
+        service.cancel_reservation(reservation).unwrap();
+
+        assert!(service.set_project_alias(other, uuid));
+        assert!(
+            service
+                .try_exceeds_budget_by_key("test", other)
+                .unwrap()
+                .exceeds_budget
+        );
+        let overrides = HashMap::from([(
+            "test".into(),
+            HashMap::from([(uuid, DecisionOverride::ForceAllow)]),
+        )]);
+        service.set_decision_overrides(overrides);
+        assert!(
+            !service
+                .try_exceeds_budget_by_key("test", other)
+                .unwrap()
+                .exceeds_budget
+        );
+        service.set_project_note(uuid, "merged");
+        assert_eq!(service.project_note(uuid), Some("merged".into()));
+    }
+
+    #[test]
+    fn test_decision_counters() {
+        let service = test_service();
+        assert!(service.record_spending("test", 1, 150.));
+        assert!(service.exceeds_budget("test", 1));
+        assert!(service.exceeds_budget_cached("test", 1));
+        assert!(!service.exceeds_budget("test", 2));
+        service.try_exceeds_budget_with_reason("test", 3).unwrap();
+        // recording spending and unknown configs are not counted
+        service.record_spending("test", 2, 10.);
+        service.exceeds_budget("unknown", 1);
+
+        // the answers are counted after applying the enforcement and any overrides
+        service
+            .set_enforcement("test", Enforcement::DryRun)
+            .unwrap();
+        assert!(!service.exceeds_budget("test", 1));
+        let overrides = HashMap::from([(
+            "test".into(),
+            HashMap::from([(2.into(), DecisionOverride::ForceBlock)]),
+        )]);
+        service.set_decision_overrides(overrides);
+        assert!(service.exceeds_budget_cached("test", 2));
+
+        let summary = &service.spend_summary()["test"];
+        assert_eq!(summary.allowed_decisions, 3);
+        assert_eq!(summary.blocked_decisions, 3);
+        let metrics = service.render_metrics();
+        assert!(metrics
+            .contains("peanutbutter_decisions_total{config=\"test\",decision=\"allowed\"} 3\n"));
+        assert!(metrics
+            .contains("peanutbutter_decisions_total{config=\"test\",decision=\"blocked\"} 3\n"));
+
+        // the counters are kept when the config is replaced
+        service
+            .replace_config("test", test_config(10.), ReplaceState::Reset)
+            .unwrap();
+        assert_eq!(service.spend_summary()["test"].blocked_decisions, 3);
+    }
+
+    #[test]
+    fn test_project_lookup_metrics() {
+        let service = test_service();
+        service.record_spending("test", 1, 10.);
+        service.record_spending("test", 1, 10.);
+        service
+            .try_record_spending_with_reason("test", 2, 10.)
+            .unwrap();
+        assert!(!service.exceeds_budget("test", 1));
+        assert!(!service.exceeds_budget("test", 3));
+        // cached checks do not look up the project
+        assert!(!service.exceeds_budget_cached("test", 4));
+        service.try_exceeds_budget_with_reason("test", 5).unwrap();
+
+        assert_eq!(service.project_inserts.get(), 2);
+        assert_eq!(service.project_updates.get(), 1);
+        assert_eq!(service.unknown_project_checks.get(), 2);
+
+        // configs that are switched off do not look up projects at all
+        service.set_enforcement("test", Enforcement::Off).unwrap();
+        service.record_spending("test", 1, 10.);
+        assert!(!service.exceeds_budget("test", 3));
+        let metrics = service.render_metrics();
+        assert!(metrics.contains("peanutbutter_project_inserts_total 2\n"));
+        assert!(metrics.contains("peanutbutter_project_updates_total 1\n"));
+        assert!(metrics.contains("peanutbutter_unknown_project_checks_total 2\n"));
+    }
+
+    #[test]
+    fn test_enabled() {
+        let service = test_service();
+        assert!(service.record_spending("test", 1, 150.));
+
+        service.set_enabled("test", false).unwrap();
+        assert!(!service.configs()["test"].config.enabled);
+        // requests are still validated
+        assert_eq!(
+            service.try_exceeds_budget("unknown", 1),
+            Err(ConfigError::Unknown("unknown".into()))
+        );
+        assert_eq!(service.try_exceeds_budget("test", 1), Ok(false));
+        assert_eq!(service.try_record_spending("test", 2, 150.), Ok(false));
+        // the enforcement is kept, but does not apply while disabled
+        assert_eq!(service.configs()["test"].enforcement, Enforcement::On);
+        assert_eq!(
+            service.configs()["test"].effective_enforcement(),
+            Enforcement::Off
+        );
+
+        service.set_enabled("test", true).unwrap();
+        assert!(service.exceeds_budget("test", 1));
+        // spending was discarded while disabled
+        assert!(!service.exceeds_budget("test", 2));
+        assert_eq!(
+            service.set_enabled("unknown", true),
+            Err(ConfigError::Unknown("unknown".into()))
+        );
+    }
+
+    #[test]
+    fn test_remove_config() {
+        let service = test_service();
+        service.try_add_config("other", test_config(10.)).unwrap();
+        assert!(service.record_spending("test", 1, 150.));
+        let ids: Vec<_> = service.configs().values().map(|r| r.id).collect();
+        assert_eq!(ids, [ConfigId(1), ConfigId(2)]);
+
+        service.remove_config("test").unwrap();
+        assert_eq!(
+            service.try_exceeds_budget("test", 1),
+            Err(ConfigError::Unknown("test".into()))
+        );
+        assert_eq!(
+            service.remove_config("test"),
+            Err(ConfigError::Unknown("test".into()))
+        );
+        assert_eq!(
+            service.try_add_config("test", test_config(10.)),
+            Err(ConfigError::Draining("test".into()))
+        );
+        assert!(!service.configs().contains_key("test"));
+        assert!(!service.spend_summary().contains_key("test"));
+        // other configs are unaffected
+        assert!(service.record_spending("other", 1, 150.));
+        assert_eq!(service.configs()["other"].id, ConfigId(2));
+    }
+
+    #[test]
+    fn test_replace_config() {
+        let service = test_service();
+
+        assert!(service.record_spending("test", 1, 150.));
+        assert!(!service.record_spending("test", 2, 50.));
+
+        service
+            .replace_config("test", test_config(1.), ReplaceState::Keep)
+            .unwrap();
+        // the already recorded spending counts against the new, lower budget
+        assert!(service.exceeds_budget("test", 1));
+        assert!(service.exceeds_budget("test", 2));
+
+        service
+            .replace_config("test", test_config(1.), ReplaceState::Reset)
+            .unwrap();
+        assert!(!service.exceeds_budget("test", 1));
+        assert!(!service.exceeds_budget("test", 2));
+    }
+
+    #[test]
+    fn test_project_weight() {
+        let service = test_service();
+
+        assert!(!service.set_project_weight("unknown", 1, 0.5, Duration::from_secs(60)));
+        assert!(service.set_project_weight("test", 1, 0.5, Duration::from_secs(60)));
+
+        // 120 would be over the budget, but the weighted 60 is not
+        assert!(!service.record_spending("test", 1, 120.));
+        assert!(service.record_spending("test", 2, 120.));
+
+        // TTLs which would overflow the clock are capped
+        assert!(service.set_project_weight("test", 3, 0.5, Duration::MAX));
+        assert!(!service.record_spending("test", 3, 120.));
+    }
+
+    #[test]
+    fn test_project_aliases() {
+        let service = test_service();
+
+        assert!(service.set_project_alias(1.into(), 2.into()));
+        assert!(!service.record_spending("test", 1, 60.));
+        assert!(service.record_spending("test", 2, 60.));
+        assert!(service.exceeds_budget("test", 1));
+        assert!(!service.exceeds_budget("test", 3));
+
+        // aliasing the target re-points the existing aliases, and cycles are rejected
+        assert!(service.set_project_alias(2.into(), 3.into()));
+        assert_eq!(
+            service.project_aliases(),
+            HashMap::from([(1.into(), 3.into()), (2.into(), 3.into())])
+        );
+        assert!(!service.set_project_alias(3.into(), 1.into()));
+        assert!(!service.set_project_alias(3.into(), 3.into()));
+
+        assert_eq!(service.remove_project_alias(1.into()), Some(3.into()));
+        assert_eq!(service.remove_project_alias(1.into()), None);
+        // the spending recorded while aliased stays with the target
+        assert!(!service.exceeds_budget("test", 1));
+
+        // without any aliases left, projects are resolved without locking the aliases
+        assert_eq!(service.remove_project_alias(2.into()), Some(3.into()));
+        assert_eq!(service.alias_count.load(Ordering::Relaxed), 0);
+        assert!(service.exceeds_budget("test", 2));
+    }
+
+    #[test]
+    fn test_exceeds_budget_cached() {
+        let service = test_service();
+        assert!(!service.exceeds_budget_cached("test", 1));
+        assert!(!service.exceeds_budget_cached("unknown", 1));
+
+        assert!(service.record_spending("test", 1, 150.));
+        assert!(service.exceeds_budget_cached("test", 1));
+
+        // the state store is not locked, even while the project is being updated
+        let projects = service.configs()["test"].projects.clone();
+        block_on(projects.update(1.into(), None, &mut |_tracker| {
+            assert!(service.exceeds_budget_cached("test", 1));
+        }));
+
+        service
+            .replace_config("test", test_config(10.), ReplaceState::Reset)
+            .unwrap();
+        assert!(!service.exceeds_budget_cached("test", 1));
+        assert!(service.record_spending("test", 1, 150.));
+        service.set_enforcement("test", Enforcement::Off).unwrap();
+        assert!(!service.exceeds_budget_cached("test", 1));
+    }
+
+    /// A [`StateStore`] which is not ready right away, like a remote backend.
+    #[derive(Debug, Default)]
+    struct RemoteStore(MemoryStore);
+
+    impl RemoteStore {
+        /// Returns [`Poll::Pending`](std::task::Poll::Pending) once, like a request in flight.
+        async fn round_trip() {
+            let mut sent = false;
+            std::future::poll_fn(|cx| {
+                if std::mem::replace(&mut sent, true) {
+                    return std::task::Poll::Ready(());
+                }
+                cx.waker().wake_by_ref();
+                std::task::Poll::Pending
+            })
+            .await
+        }
+    }
+
+    impl StateStore for RemoteStore {
+        fn get<'a>(
+            &'a self,
+            project_id: ProjectKey,
+            f: &'a mut (dyn FnMut(&dyn BudgetTracker) + Send),
+        ) -> StoreFuture<'a, bool> {
+            Box::pin(async move {
+                Self::round_trip().await;
+                self.0.get(project_id, f).await
+            })
+        }
+
+        fn update<'a>(
+            &'a self,
+            project_id: ProjectKey,
+            insert: Option<&'a mut (dyn FnMut() -> Box<dyn BudgetTracker> + Send)>,
+            f: &'a mut (dyn FnMut(&mut dyn BudgetTracker) + Send),
+        ) -> StoreFuture<'a, bool> {
+            Box::pin(async move {
+                Self::round_trip().await;
+                self.0.update(project_id, insert, f).await
+            })
+        }
+
+        fn insert(
+            &self,
+            project_id: ProjectKey,
+            tracker: Box<dyn BudgetTracker>,
+        ) -> StoreFuture<'_, Option<Box<dyn BudgetTracker>>> {
+            Box::pin(async move {
+                Self::round_trip().await;
+                self.0.insert(project_id, tracker).await
+            })
+        }
+
+        fn remove<'a>(
+            &'a self,
+            project_id: ProjectKey,
+            predicate: &'a (dyn Fn(&dyn BudgetTracker) -> bool + Sync),
+        ) -> StoreFuture<'a, Option<Box<dyn BudgetTracker>>> {
+            Box::pin(async move {
+                Self::round_trip().await;
+                self.0.remove(project_id, predicate).await
+            })
+        }
+
+        fn num_partitions(&self) -> usize {
+            self.0.num_partitions()
+        }
+
+        fn scan<'a>(
+            &'a self,
+            partition: usize,
+            f: &'a mut (dyn FnMut(ProjectKey, &dyn BudgetTracker) + Send),
+        ) -> StoreFuture<'a, ()> {
+            Box::pin(async move {
+                Self::round_trip().await;
+                self.0.scan(partition, f).await
+            })
+        }
+
+        fn len(&self) -> StoreFuture<'_, usize> {
+            Box::pin(async move {
+                Self::round_trip().await;
+                self.0.len().await
+            })
+        }
+
+        fn clear(&self) -> StoreFuture<'_, ()> {
+            Box::pin(async move {
+                Self::round_trip().await;
+                self.0.clear().await
+            })
+        }
+    }
+
+    #[test]
+    fn test_async_store() {
+        let service = Service::new();
+        let store = Arc::new(RemoteStore::default());
+        (service.try_add_config_with_store("test", test_config(10.), store.clone())).unwrap();
+
+        let decision = block_on(service.try_record_spending_by_key_async("test", 1.into(), 150.));
+        assert!(decision.unwrap().exceeds_budget);
+        assert_eq!(
+            block_on(service.try_exceeds_budget_async("test", 1)),
+            Ok(true)
+        );
+        assert!(!block_on(service.would_exceed_by_key_async(
+            "test",
+            2.into(),
+            5.
+        )));
+        let remaining_budget = block_on(service.remaining_budget_by_key_async("test", 2.into()));
+        assert_eq!(remaining_budget, Some(100.));
+        let report = block_on(service.project_report_by_key_async("test", 1.into()));
+        assert!(report.unwrap().unwrap().exceeds_budget);
+
+        let id = block_on(service.reserve_by_key_async("test", 2.into(), 5.)).unwrap();
+        assert_eq!(
+            block_on(service.commit_reservation_async(id, 2.)),
+            Ok(false)
+        );
+        assert_eq!(block_on(store.len()), 2);
+        let metrics = block_on(service.render_metrics_async());
+        assert!(metrics.contains("peanutbutter_tracked_projects{config=\"test\"} 2"));
+        let replaced = service.replace_config_async("test", test_config(1.), ReplaceState::Keep);
+        assert_eq!(block_on(replaced), Ok(()));
+        assert_eq!(service.remaining_budget("test", 2), Some(8.));
+
+        // the synchronous methods block on the same store
+        assert!(service.exceeds_budget("test", 1));
+        assert!(!service.record_spending("test", 3, 1.));
+        assert_eq!(block_on(store.len()), 3);
+        service
+            .replace_config("test", test_config(10.), ReplaceState::Reset)
+            .unwrap();
+        assert!(block_on(store.is_empty()));
+    }
+
+    #[test]
+    fn test_would_exceed() {
+        let service = test_service();
+        assert!(!service.would_exceed("test", 1, 50.));
+        assert!(service.would_exceed("test", 1, 150.));
+        assert!(!service.would_exceed("unknown", 1, 150.));
+        // nothing was recorded
+        assert!(!service.record_spending("test", 1, 50.));
+        assert!(service.would_exceed("test", 1, 100.));
+
+        service
+            .set_enforcement("test", Enforcement::DryRun)
+            .unwrap();
+        assert!(!service.would_exceed("test", 1, 100.));
+    }
+
+    #[test]
+    fn test_reservations() {
+        let service = test_service();
+        let reservation = service.reserve("test", 1, 60.).unwrap();
+        // the reserved budget counts against the budget right away
+        assert_eq!(
+            service.reserve("test", 1, 60.),
+            Err(ReservationError::ExceedsBudget)
+        );
+
+        assert_eq!(service.commit_reservation(reservation, 30.), Ok(false));
+        assert_eq!(
+            service.commit_reservation(reservation, 30.),
+            Err(ReservationError::Unknown(reservation))
+        );
+        // only the actually spent budget is kept
+        let reservation = service.reserve("test", 1, 60.).unwrap();
+        service.cancel_reservation(reservation).unwrap();
+        assert!(!service.would_exceed("test", 1, 70.));
+        assert!(service.would_exceed("test", 1, 71.));
+
+        // spending more than reserved is recorded as well
+        let reservation = service.reserve("test", 1, 10.).unwrap();
+        assert_eq!(service.commit_reservation(reservation, 100.), Ok(true));
+
+        assert_eq!(
+            service.reserve("unknown", 1, 10.),
+            Err(ReservationError::Config(ConfigError::Unknown(
+                "unknown".into()
+            )))
+        );
+    }
+
+    #[test]
+    fn test_reservation_buckets() {
+        let (clock, mock) = Clock::mock();
+        mock.increment(Duration::from_secs(100));
+        let service = Service::with_mock_clock(clock);
+        service.try_add_config("test", test_config(10.)).unwrap();
+
+        // the released budget is taken from the bucket it was reserved in, not the latest one
+        let reservation = service.reserve("test", 1, 60.).unwrap();
+        mock.increment(Duration::from_secs(2));
+        service.record_spending("test", 1, 30.);
+        service.cancel_reservation(reservation).unwrap();
+        assert!(!service.would_exceed("test", 1, 70.));
+        assert!(service.would_exceed("test", 1, 71.));
+
+        // budget reserved in a bucket which left the window is not taken from later spending
+        let ttl = Duration::from_secs(60);
+        let acquisition = service.acquire("test", 2, 60., ttl).unwrap();
+        mock.increment(Duration::from_secs(20));
+        service.record_spending("test", 2, 30.);
+        service.release(acquisition).unwrap();
+        assert!(!service.would_exceed("test", 2, 70.));
+        assert!(service.would_exceed("test", 2, 71.));
+    }
+
+    #[test]
+    fn test_acquisitions() {
+        let (clock, mock) = Clock::mock();
+        mock.increment(Duration::from_secs(100));
+        let service = Service::with_mock_clock(clock);
+        let config = test_config(2.).with_strategy(AccountingStrategy::Concurrency);
+        service.try_add_config("test", config).unwrap();
+
+        let ttl = Duration::from_secs(5);
+        let first = service.acquire("test", 1, 1., ttl).unwrap();
+        service.acquire("test", 1, 1., ttl).unwrap();
+        assert_eq!(
+            service.acquire("test", 1, 1., ttl),
+            Err(ReservationError::ExceedsBudget)
+        );
+        assert!(!service.exceeds_budget("test", 1));
+
+        // released slots can be acquired again right away
+        service.release(first).unwrap();
+        assert_eq!(
+            service.release(first),
+            Err(ReservationError::Unknown(first))
+        );
+        service.acquire("test", 1, 1., ttl).unwrap();
+
+        // TTLs which would overflow the clock are capped
+        let forever = service.acquire("test", 2, 1., Duration::MAX).unwrap();
+        service.release(forever).unwrap();
+
+        // leaked slots are released by the maintenance once they expire
+        mock.increment(ttl * 2);
+        let started = std::time::Instant::now();
+        while !service
+            .render_metrics()
+            .contains("peanutbutter_expired_acquisitions_total 2\n")
+        {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(service.remaining_budget("test", 1), Some(2.));
+
+        // plain recorded spending does not hold on to its slots forever
+        assert!(service.record_spending("test", 2, 3.));
+        mock.increment(Duration::from_secs(11));
+        assert!(!service.exceeds_budget("test", 2));
+    }
+
+    #[test]
+    fn test_aliased_reservations() {
+        let (clock, mock) = Clock::mock();
+        mock.increment(Duration::from_secs(100));
+        let service = Service::with_mock_clock(clock);
+        let config = test_config(2.).with_strategy(AccountingStrategy::Concurrency);
+        service.try_add_config("concurrency", config).unwrap();
+        service.try_add_config("test", test_config(10.)).unwrap();
+        assert!(service.set_project_alias(1.into(), 2.into()));
+
+        // leaked slots of an aliased project are released from the project they were acquired for
+        let ttl = Duration::from_secs(5);
+        service.acquire("concurrency", 1, 2., ttl).unwrap();
+        assert_eq!(service.remaining_budget("concurrency", 2), Some(0.));
+        mock.increment(ttl * 2);
+        let started = std::time::Instant::now();
+        while service.remaining_budget("concurrency", 2) != Some(2.) {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(50));
+        }
+
+        // reservations are committed to the project they were reserved for, even if the alias changed
+        let reservation = service.reserve("test", 1, 60.).unwrap();
+        assert!(service.set_project_alias(1.into(), 3.into()));
+        assert_eq!(service.commit_reservation(reservation, 150.), Ok(true));
+        assert!(service.exceeds_budget("test", 2));
+        assert!(!service.exceeds_budget("test", 3));
+    }
+
+    #[test]
+    fn test_share_blocked_projects() {
+        let service = test_service();
+        assert!(service.record_spending("test", 1, 150.));
+        assert!(!service.record_spending("test", 2, 10.));
+
+        let path = std::env::temp_dir().join(format!("pb-shared-{}", std::process::id()));
+        service.share_blocked_projects(&path, 16).unwrap();
+        let snapshot = BlockedSnapshot::open(&path).unwrap();
+        let started = std::time::Instant::now();
+        while !snapshot.is_blocked("test", 1) {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        assert!(!snapshot.is_blocked("test", 2));
+        assert!(!snapshot.is_blocked("unknown", 1));
+
+        // projects of configs which are not enforced are never blocked
+        service
+            .set_enforcement("test", Enforcement::DryRun)
+            .unwrap();
+        while snapshot.is_blocked("test", 1) {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_remaining_budget() {
+        let service = test_service();
+        assert_eq!(service.remaining_budget("unknown", 1), None);
+        assert_eq!(service.remaining_budget("test", 1), Some(100.));
+
+        service.record_spending("test", 1, 40.);
+        assert!(service.remaining_budget("test", 1).unwrap() < 100.);
+
+        service.set_project_weight("test", 2, 0.5, Duration::from_secs(60));
+        assert_eq!(service.remaining_budget("test", 2), Some(200.));
+
+        // unknown projects start out ramping up, just like a new tracker would
+        let ramp_up = test_config(10.).with_ramp_up(Duration::from_secs(60), 0.1);
+        service.try_add_config("ramp_up", ramp_up).unwrap();
+        assert_eq!(service.remaining_budget("ramp_up", 1), Some(10.));
+
+        // unknown projects of concurrency configs have all their slots remaining
+        let concurrency = test_config(2.).with_strategy(AccountingStrategy::Concurrency);
+        service.try_add_config("concurrency", concurrency).unwrap();
+        assert_eq!(service.remaining_budget("concurrency", 1), Some(2.));
+    }
+
+    #[test]
+    fn test_stop_maintenance() {
+        let service = test_service();
+        assert!(!service.maintenance_stopped());
+
+        // the parked thread is woken up, instead of finishing its sleep
+        let started = std::time::Instant::now();
+        service.stop_maintenance();
+        assert!(started.elapsed() < Duration::from_millis(400));
+        assert!(service.maintenance_stopped());
+        service.stop_maintenance();
+    }
+
+    #[test]
+    fn test_prewarm_projects() {
+        let service = test_service();
+        assert!(service.record_spending("test", 1, 150.));
+
+        assert_eq!(service.prewarm_projects("test", [1, 2, 3]), Ok(2));
+        assert_eq!(block_on(service.configs()["test"].projects.len()), 3);
+        // the existing project was left as-is
+        assert!(service.exceeds_budget("test", 1));
+        assert!(!service.exceeds_budget("test", 2));
+
+        assert_eq!(
+            service.prewarm_projects("unknown", [1]),
+            Err(ConfigError::Unknown("unknown".into()))
+        );
+    }
+
+    #[test]
+    fn test_config_warnings() {
+        let service = test_service();
+        let events = Arc::new(Mutex::new(vec![]));
+        let captured = events.clone();
+        service.set_event_handler(move |event| captured.lock().unwrap().push(event.clone()));
+
+        let config = |budget| {
+            let second = Duration::from_secs(1);
+            BudgetingConfig::new(second, Duration::from_secs(10), second, budget)
+        };
+        service.try_add_config("valid", config(10.)).unwrap();
+        assert!(events.lock().unwrap().is_empty());
+
+        let zero_budget = config(0.);
+        service.try_add_config("zero", zero_budget.clone()).unwrap();
+        service
+            .replace_config("valid", zero_budget, ReplaceState::Keep)
+            .unwrap();
+        let expected = ["zero", "valid"].map(|config| Event::ConfigWarning {
+            config: config.into(),
+            warning: "`budget` is zero, so any spending exceeds it",
+        });
+        assert_eq!(*events.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_config_warnings_reentrant() {
+        // handlers may change the configs, e.g. to disable misconfigured ones
+        let service = Arc::new(test_service());
+        let weak = Arc::downgrade(&service);
+        service.set_event_handler(move |event| {
+            if let (Event::ConfigWarning { config, .. }, Some(service)) = (event, weak.upgrade()) {
+                service.set_enforcement(config, Enforcement::Off).unwrap();
+            }
+        });
+
+        let second = Duration::from_secs(1);
+        let zero_budget = BudgetingConfig::new(second, Duration::from_secs(10), second, 0.);
+        service.try_add_config("zero", zero_budget.clone()).unwrap();
+        service
+            .replace_config("test", zero_budget, ReplaceState::Keep)
+            .unwrap();
+        let configs = service.configs();
+        assert_eq!(configs["zero"].enforcement, Enforcement::Off);
+        assert_eq!(configs["test"].enforcement, Enforcement::Off);
+    }
+
+    #[test]
+    fn test_unknown_config() {
+        let service = test_service();
+        let events = Arc::new(std::sync::Mutex::new(vec![]));
+        let events_ = events.clone();
+        service.set_event_handler(move |event| events_.lock().unwrap().push(event.clone()));
+
+        assert!(!service.record_spending("unknown", 1, 150.));
+        assert!(!service.exceeds_budget("unknown", 1));
+        assert_eq!(
+            service.try_exceeds_budget("unknown", 1),
+            Err(ConfigError::Unknown("unknown".into()))
+        );
+        assert_eq!(service.try_record_spending("test", 1, 150.), Ok(true));
+
+        // only the first request is reported, the rest is rate limited
+        assert_eq!(
+            *events.lock().unwrap(),
+            [Event::UnknownConfig {
+                config: "unknown".into(),
+                requests: 1
+            }]
+        );
+        let metrics = service.render_metrics();
+        assert!(metrics.contains("peanutbutter_unknown_config_requests_total 3\n"));
+        assert!(metrics.contains(
+            "peanutbutter_unknown_config_requests_by_name_total{config=\"unknown\"} 3\n"
+        ));
+    }
+
+    /// Returns the resident set size of this process in bytes, which is only known on Linux.
+    fn resident_memory() -> Option<usize> {
+        let status = std::fs::read_to_string("/proc/self/status").ok()?;
+        let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+        let kilobytes = line.split_whitespace().nth(1)?.parse::<usize>().ok()?;
+        Some(kilobytes * 1024)
+    }
+
+    /// Drives a churn of project ids for several minutes, and checks that the state does not keep growing.
+    ///
+    /// The mocked time runs ten times faster than the real time, so that the background maintenance
+    /// cleans up projects which left the window every few seconds of mocked time.
+    /// Run with `cargo test soak -- --ignored`, and `PEANUTBUTTER_SOAK_SECS` to change its duration.
+    #[test]
+    #[ignore = "runs for several minutes"]
+    fn test_soak_project_churn() {
+        let soak_secs = std::env::var("PEANUTBUTTER_SOAK_SECS")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or(180);
+        let soak = Duration::from_secs(soak_secs);
+
+        let (clock, mock) = Clock::mock();
+        mock.increment(Duration::from_secs(100));
+        let service = Service::with_mock_clock(clock);
+        service.try_add_config("soak", test_config(10.)).unwrap();
+        let projects = || block_on(service.configs.load()["soak"].projects.len());
+
+        // A simple LCG, so that the traffic is random-ish, but deterministic.
+        let mut seed = 42u64;
+        let mut random = |bound: u64| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+            (seed >> 33) % bound
+        };
+
+        let started = std::time::Instant::now();
+        let mut samples = vec![];
+        let mut next_sample = started;
+        let mut base = 0;
+        while started.elapsed() < soak {
+            // the active projects slide along, so that the inactive ones become stale
+            base += 10;
+            for _ in 0..200 {
+                let project_id = base + random(1_000);
+                match random(100) {
+                    0 => {
+                        // some projects spend heavily, and get blocked
+                        service.record_spending("soak", project_id, 1_000.);
+                    }
+                    1 => {
+                        let ttl = Duration::from_secs(5);
+                        service.set_project_weight("soak", project_id, 2., ttl);
+                    }
+                    2 => {
+                        if let Ok(reservation) = service.reserve("soak", project_id, 1.) {
+                            if random(2) == 0 {
+                                let _ = service.commit_reservation(reservation, 1.);
+                            }
+                        }
+                    }
+                    3..=49 => {
+                        service.exceeds_budget("soak", project_id);
+                    }
+                    _ => {
+                        service.record_spending("soak", project_id, random(10) as f64);
+                    }
+                }
+            }
+            mock.increment(Duration::from_millis(100));
+            std::thread::sleep(Duration::from_millis(10));
+
+            if std::time::Instant::now() >= next_sample {
+                next_sample += soak / 20;
+                let sample = (
+                    projects(),
+                    service.project_weights.len(),
+                    service.reservations.len(),
+                    resident_memory().unwrap_or_default(),
+                );
+                samples.push(sample);
+            }
+        }
+
+        // The first samples are taken while the state still builds up.
+        let (first, second) = samples[samples.len() / 5..].split_at(samples.len() * 2 / 5);
+        let peak = |samples: &[(usize, usize, usize, usize)], f: fn(&_) -> usize| {
+            samples.iter().map(f).max().unwrap_or_default()
+        };
+        // The samples are reported as (projects, weights, reservations, resident memory).
+        assert!(
+            peak(second, |s| s.0) <= peak(first, |s| s.0) * 11 / 10,
+            "{samples:?}"
+        );
+        assert!(
+            peak(second, |s| s.1) <= peak(first, |s| s.1) * 11 / 10 + 10,
+            "{samples:?}"
+        );
+        assert!(
+            peak(second, |s| s.2) <= peak(first, |s| s.2) * 11 / 10 + 10,
+            "{samples:?}"
+        );
+        // The allocator does not necessarily return freed memory, but it should be reused.
+        assert!(
+            peak(second, |s| s.3) <= peak(first, |s| s.3) * 5 / 4,
+            "{samples:?}"
+        );
     }
 }
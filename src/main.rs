@@ -1,95 +1,1570 @@
+mod canary;
+mod capture;
+mod control_plane;
+mod cors;
+mod daemon;
+mod encoding;
+mod feature_flags;
+mod healthcheck;
+mod http_source;
+mod ip_limits;
+#[cfg(feature = "otel")]
+mod otel;
+mod overrides;
+mod panics;
+mod replay;
+mod request_id;
+mod resp;
+mod sampling;
+mod self_test;
+mod settings;
+mod shutdown;
+mod supervisor;
+
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::io;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
-use axum::extract::{Json, State};
-use axum::routing::{get, post};
-use axum::Router;
-use serde::{Deserialize, Serialize};
+use axum::extract::{DefaultBodyLimit, FromRef, Json, Path, Query, Request, State};
+use axum::http::StatusCode;
+use axum::middleware::{from_fn_with_state, map_request_with_state, map_response_with_state};
+use axum::response::{Html, Response};
+use axum::routing::{delete, get, post};
+use axum::{Extension, Router};
+use indexmap::IndexMap;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use socket2::SockRef;
+use tokio::net::{TcpListener, TcpSocket};
+use tokio::runtime::Builder;
 
+use canary::Canary;
+use capture::{Capture, CapturedRequest};
+use cors::Cors;
+use encoding::Negotiated;
+use http_source::HttpSource;
+use ip_limits::{IpLimiter, LimitedRouter, Shed};
 use peanutbutter::*;
+use request_id::RequestId;
+use settings::{ConfigSettings, Settings};
+use shutdown::ShutdownReport;
+use supervisor::{Supervisor, SupervisorHandle};
 
-fn default_service() -> Service {
-    let backoff_duration = Duration::from_secs(5 * 60);
-    let budgeting_window = Duration::from_secs(2 * 60);
-    let bucket_size = Duration::from_secs(10);
+/// Creates the [`Service`] with all the configs and pre-warmed projects of the given [`Settings`].
+fn create_service(settings: &Settings) -> Result<Service, Box<dyn std::error::Error>> {
+    let service = Service::new();
+    // Set first, so that warnings about the configs below are logged as well.
+    service.set_event_handler(|event| println!("{event}"));
+    service.set_memory_limit(settings.max_state_memory);
+    service.set_mass_state_change_threshold(settings.mass_state_change_threshold);
+    service.set_skip_clock_jumps(settings.skip_clock_jumps);
+    service.set_max_configs(settings.max_configs);
+    service.try_add_config(healthcheck::CONFIG, healthcheck::config())?;
+    for (name, config) in &settings.configs {
+        service.try_add_config(name, config.to_config()?)?;
+    }
+    for (&project_id, &alias_of) in &settings.project_aliases {
+        if !service.set_project_alias(project_id, alias_of) {
+            return Err(format!("project alias {project_id} -> {alias_of} forms a cycle").into());
+        }
+    }
+    for (name, project_ids) in &settings.prewarm_projects {
+        service.prewarm_projects(name, project_ids.iter().copied())?;
+    }
+    if let Some(path) = &settings.blocked_snapshot_path {
+        service.share_blocked_projects(path, settings.blocked_snapshot_capacity)?;
+    }
+    Ok(service)
+}
 
-    let mut service = Service::new();
+/// The state shared by all the HTTP handlers.
+#[derive(Clone)]
+struct AppState {
+    service: Arc<Service>,
+    /// Whether requests for unknown configs are rejected, see [`Settings::strict_configs`].
+    strict_configs: bool,
+    /// Coalesces concurrent identical checks, see [`Settings::coalesce_checks`].
+    coalescer: Option<Arc<Coalescer>>,
+    /// Metrics of the HTTP server itself, shared across all acceptors and runtimes.
+    http_metrics: Arc<HttpMetrics>,
+    /// All the addresses the HTTP and RESP servers listen on.
+    listeners: Arc<Vec<Arc<ListenerMetrics>>>,
+    /// The optional secondary instance that decisions are compared against.
+    canary: Option<Arc<Canary>>,
+    /// The optional capture of requests, for later replay.
+    capture: Option<Arc<Capture>>,
+    /// Whether all requests are logged, see [`Settings::log_requests`].
+    log_requests: bool,
+    /// The origins allowed to call the reporting endpoints, see [`Settings::cors_origins`].
+    cors: Option<Arc<Cors>>,
+    /// The limits per source IP, shared by the HTTP and RESP servers.
+    ip_limiter: Option<Arc<IpLimiter>>,
+}
 
-    service.add_config(
-        "symbolication-native",
-        BudgetingConfig::new(backoff_duration, budgeting_window, bucket_size, 5.0),
-    );
-    service.add_config(
-        "symbolication-js",
-        BudgetingConfig::new(backoff_duration, budgeting_window, bucket_size, 5.0),
-    );
+/// Metrics describing the HTTP server, complementing the [`Service`] metrics.
+#[derive(Debug, Default)]
+struct HttpMetrics {
+    /// The number of requests rejected because their body exceeded [`Settings::max_body_size`].
+    oversized_payloads: AtomicU64,
+}
 
-    service.add_config(
-        "symbolication-jvm",
-        BudgetingConfig::new(backoff_duration, budgeting_window, bucket_size, 7.5),
-    );
+/// The transport served on a listening address.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Transport {
+    Http,
+    Resp,
+}
 
-    service
+/// Metrics of a single listening address, shared across all of its acceptors and runtimes.
+#[derive(Debug)]
+pub struct ListenerMetrics {
+    transport: Transport,
+    addr: SocketAddr,
+    /// The number of HTTP requests or RESP commands received on this address.
+    pub requests: AtomicU64,
 }
 
-#[derive(Deserialize)]
+impl ListenerMetrics {
+    fn new(transport: Transport, addr: SocketAddr) -> Arc<Self> {
+        let requests = AtomicU64::new(0);
+        Arc::new(Self {
+            transport,
+            addr,
+            requests,
+        })
+    }
+}
+
+impl FromRef<AppState> for Arc<Service> {
+    fn from_ref(state: &AppState) -> Self {
+        state.service.clone()
+    }
+}
+
+#[derive(Deserialize, Serialize)]
 struct RecordSpendingRequest {
     config_name: String,
     project_id: u64,
     spent: f64,
+    #[serde(default)]
+    refund: bool,
+    /// Who generated the spending, like `symbolicator-lpq-7f3`, which shows up in project reports.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 struct ExceedsBudgetRequest {
     config_name: String,
     project_id: u64,
 }
 
+#[derive(Deserialize)]
+struct RecordSpendingByUuidRequest {
+    config_name: String,
+    project_uuid: String,
+    spent: f64,
+}
+
+#[derive(Deserialize)]
+struct ExceedsBudgetByUuidRequest {
+    config_name: String,
+    project_uuid: String,
+}
+
+/// Parses the [`ProjectKey`] of a project identified by a UUID instead of a numeric id.
+fn parse_project_uuid(uuid: &str) -> Result<ProjectKey, (StatusCode, String)> {
+    ProjectKey::parse_uuid(uuid).ok_or_else(|| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("invalid `project_uuid`: {uuid}"),
+        )
+    })
+}
+
+/// Identifies the project of a request, either by its numeric `project_id` or by its `project_uuid`.
+///
+/// The `project_id` is accepted as a string as well, as flattened query strings only consist of strings.
+#[derive(Deserialize)]
+struct ProjectParam {
+    #[serde(default, deserialize_with = "numeric_project_id")]
+    project_id: Option<u64>,
+    project_uuid: Option<String>,
+}
+
+/// Deserializes an optional numeric project id from a number, or a string of one.
+fn numeric_project_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    let Some(project) = Option::<ProjectKey>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    let project_id = project.project_id().ok_or_else(|| {
+        de::Error::custom("`project_id` needs to be numeric, use `project_uuid` for UUIDs")
+    })?;
+    Ok(Some(project_id))
+}
+
+impl ProjectParam {
+    /// Returns the [`ProjectKey`] of the project, which needs to be identified in exactly one way.
+    fn key(&self) -> Result<ProjectKey, (StatusCode, String)> {
+        match (self.project_id, &self.project_uuid) {
+            (Some(project_id), None) => Ok(project_id.into()),
+            (None, Some(uuid)) => parse_project_uuid(uuid),
+            _ => {
+                let message = "either `project_id` or `project_uuid` is required";
+                Err((StatusCode::BAD_REQUEST, message.into()))
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct WouldExceedRequest {
+    config_name: String,
+    #[serde(flatten)]
+    project: ProjectParam,
+    spent: f64,
+}
+
+#[derive(Deserialize)]
+struct RemainingBudgetRequest {
+    config_name: String,
+    #[serde(flatten)]
+    project: ProjectParam,
+}
+
+#[derive(Deserialize)]
+struct ReserveRequest {
+    config_name: String,
+    #[serde(flatten)]
+    project: ProjectParam,
+    reserved: f64,
+}
+
+#[derive(Deserialize)]
+struct AcquireRequest {
+    config_name: String,
+    #[serde(flatten)]
+    project: ProjectParam,
+    #[serde(default = "default_slots")]
+    slots: f64,
+    ttl_secs: f64,
+}
+
+fn default_slots() -> f64 {
+    1.
+}
+
+#[derive(Deserialize)]
+struct ReleaseRequest {
+    acquisition_id: u64,
+}
+
+#[derive(Deserialize)]
+struct CommitReservationRequest {
+    reservation_id: u64,
+    #[serde(default)]
+    spent: f64,
+}
+
+#[derive(Deserialize)]
+struct SetProjectWeightRequest {
+    config_name: String,
+    #[serde(flatten)]
+    project: ProjectParam,
+    weight: f64,
+    ttl_secs: u64,
+}
+
 #[derive(Serialize)]
 struct ExceedsBudgetResponse {
     exceeds_budget: bool,
+    /// Why the decision was made, which is only explained for checks and recorded spending.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<DecisionReason>,
+    /// How long the client may cache the decision, which is only given for projects that were never seen.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache_for_ms: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct ReserveResponse {
+    reservation_id: Option<u64>,
+    exceeds_budget: bool,
+}
+
+#[derive(Serialize)]
+struct AcquireResponse {
+    acquisition_id: Option<u64>,
+    exceeds_budget: bool,
+}
+
+#[derive(Serialize)]
+struct RemainingBudgetResponse {
+    remaining_budget: f64,
+}
+
+/// Turns the result of a request against a possibly unknown config into a response.
+///
+/// Unknown configs are only rejected with `strict_configs`, and never exceed their budget otherwise.
+/// Their decision does not have a reason.
+fn config_response(
+    result: Result<Decision, ConfigError>,
+    strict_configs: bool,
+) -> Result<ExceedsBudgetResponse, (StatusCode, String)> {
+    let response = match result {
+        Ok(decision) => ExceedsBudgetResponse {
+            exceeds_budget: decision.exceeds_budget,
+            reason: Some(decision.reason),
+            cache_for_ms: decision.cache_for.map(|ttl| ttl.as_millis() as u64),
+        },
+        Err(err) if strict_configs => return Err((StatusCode::NOT_FOUND, err.to_string())),
+        Err(_) => ExceedsBudgetResponse {
+            exceeds_budget: false,
+            reason: None,
+            cache_for_ms: None,
+        },
+    };
+    Ok(response)
+}
+
+/// Answers a [`Shed`] request according to the [`OverloadPolicy`] of its config.
+fn shed_response(
+    service: &Service,
+    config: &str,
+    Shed(reason): Shed,
+) -> Result<ExceedsBudgetResponse, (StatusCode, String)> {
+    match service.overload_policy(config) {
+        OverloadPolicy::Allow => Ok(ExceedsBudgetResponse {
+            exceeds_budget: false,
+            reason: Some(DecisionReason::Overloaded),
+            cache_for_ms: None,
+        }),
+        OverloadPolicy::Reject => Err((StatusCode::TOO_MANY_REQUESTS, reason.into())),
+    }
 }
 
 async fn record_spending(
-    State(service): State<Arc<Service>>,
-    Json(request): Json<RecordSpendingRequest>,
-) -> Json<ExceedsBudgetResponse> {
-    let exceeds_budget =
-        service.record_spending(&request.config_name, request.project_id, request.spent);
-    Json(ExceedsBudgetResponse { exceeds_budget })
+    State(state): State<AppState>,
+    Extension(request_id): Extension<RequestId>,
+    shed: Option<Extension<Shed>>,
+    Negotiated(encoding, request): Negotiated<RecordSpendingRequest>,
+) -> Result<Negotiated<ExceedsBudgetResponse>, (StatusCode, String)> {
+    let service = &state.service;
+    if let Some(Extension(shed)) = shed {
+        let response = shed_response(service, &request.config_name, shed)?;
+        return Ok(Negotiated(encoding, response));
+    }
+    let response = if request.refund {
+        let exceeds_budget = service
+            .record_refund_async(&request.config_name, request.project_id, request.spent)
+            .await
+            .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+        ExceedsBudgetResponse {
+            exceeds_budget,
+            reason: None,
+            cache_for_ms: None,
+        }
+    } else {
+        if !(request.spent.is_finite() && request.spent >= 0.) {
+            let message =
+                "`spent` needs to be positive and finite, refunds need to be marked as `refund`";
+            return Err((StatusCode::BAD_REQUEST, message.into()));
+        }
+        let (config_name, project_id, spent) =
+            (&request.config_name, request.project_id, request.spent);
+        let result = match &request.source {
+            Some(source) => {
+                (service.try_record_spending_from_async(config_name, project_id, spent, source))
+                    .await
+            }
+            None => {
+                (service.try_record_spending_by_key_async(config_name, project_id.into(), spent))
+                    .await
+            }
+        };
+        config_response(result, state.strict_configs)?
+    };
+
+    if let Some(canary) = &state.canary {
+        let local = response.exceeds_budget;
+        canary.compare(
+            "/record_spending",
+            request.project_id,
+            &request,
+            &request_id,
+            local,
+        );
+    }
+    if let Some(capture) = &state.capture {
+        let request = CapturedRequest::RecordSpending(request);
+        capture.record(
+            request,
+            &request_id,
+            response.exceeds_budget,
+            response.reason,
+        );
+    }
+    Ok(Negotiated(encoding, response))
 }
 
 async fn exceeds_budget(
+    State(state): State<AppState>,
+    Extension(request_id): Extension<RequestId>,
+    shed: Option<Extension<Shed>>,
+    Negotiated(encoding, request): Negotiated<ExceedsBudgetRequest>,
+) -> Result<Negotiated<ExceedsBudgetResponse>, (StatusCode, String)> {
+    if let Some(Extension(shed)) = shed {
+        let response = shed_response(&state.service, &request.config_name, shed)?;
+        return Ok(Negotiated(encoding, response));
+    }
+    let (config_name, project_id) = (&request.config_name, request.project_id);
+    let result = match &state.coalescer {
+        Some(coalescer) => {
+            (coalescer.try_exceeds_budget(&state.service, config_name, project_id)).await
+        }
+        None => {
+            let project = project_id.into();
+            (state.service)
+                .try_exceeds_budget_by_key_async(config_name, project)
+                .await
+        }
+    };
+    let response = config_response(result, state.strict_configs)?;
+
+    if let Some(canary) = &state.canary {
+        let local = response.exceeds_budget;
+        canary.compare(
+            "/exceeds_budget",
+            request.project_id,
+            &request,
+            &request_id,
+            local,
+        );
+    }
+    if let Some(capture) = &state.capture {
+        let request = CapturedRequest::ExceedsBudget(request);
+        capture.record(
+            request,
+            &request_id,
+            response.exceeds_budget,
+            response.reason,
+        );
+    }
+    Ok(Negotiated(encoding, response))
+}
+
+async fn exceeds_budget_by_uuid(
+    State(state): State<AppState>,
+    shed: Option<Extension<Shed>>,
+    Negotiated(encoding, request): Negotiated<ExceedsBudgetByUuidRequest>,
+) -> Result<Negotiated<ExceedsBudgetResponse>, (StatusCode, String)> {
+    if let Some(Extension(shed)) = shed {
+        let response = shed_response(&state.service, &request.config_name, shed)?;
+        return Ok(Negotiated(encoding, response));
+    }
+    let project = parse_project_uuid(&request.project_uuid)?;
+    let result = (state.service)
+        .try_exceeds_budget_by_key_async(&request.config_name, project)
+        .await;
+    let response = config_response(result, state.strict_configs)?;
+    Ok(Negotiated(encoding, response))
+}
+
+async fn record_spending_by_uuid(
+    State(state): State<AppState>,
+    shed: Option<Extension<Shed>>,
+    Negotiated(encoding, request): Negotiated<RecordSpendingByUuidRequest>,
+) -> Result<Negotiated<ExceedsBudgetResponse>, (StatusCode, String)> {
+    if let Some(Extension(shed)) = shed {
+        let response = shed_response(&state.service, &request.config_name, shed)?;
+        return Ok(Negotiated(encoding, response));
+    }
+    let project = parse_project_uuid(&request.project_uuid)?;
+    if !(request.spent.is_finite() && request.spent >= 0.) {
+        let message = "`spent` needs to be positive and finite";
+        return Err((StatusCode::BAD_REQUEST, message.into()));
+    }
+    let result = (state.service)
+        .try_record_spending_by_key_async(&request.config_name, project, request.spent)
+        .await;
+    let response = config_response(result, state.strict_configs)?;
+    Ok(Negotiated(encoding, response))
+}
+
+async fn would_exceed(
+    State(service): State<Arc<Service>>,
+    Json(request): Json<WouldExceedRequest>,
+) -> Result<Json<ExceedsBudgetResponse>, (StatusCode, String)> {
+    let project = request.project.key()?;
+    let exceeds_budget = service
+        .would_exceed_by_key_async(&request.config_name, project, request.spent)
+        .await;
+    Ok(Json(ExceedsBudgetResponse {
+        exceeds_budget,
+        reason: None,
+        cache_for_ms: None,
+    }))
+}
+
+async fn reserve(
+    State(service): State<Arc<Service>>,
+    Json(request): Json<ReserveRequest>,
+) -> Result<Json<ReserveResponse>, (StatusCode, String)> {
+    if !(request.reserved.is_finite() && request.reserved >= 0.) {
+        let message = "`reserved` needs to be positive and finite";
+        return Err((StatusCode::BAD_REQUEST, message.into()));
+    }
+
+    let project = request.project.key()?;
+    let result = service
+        .reserve_by_key_async(&request.config_name, project, request.reserved)
+        .await;
+    let reservation_id = match result {
+        Ok(id) => Some(id),
+        Err(ReservationError::ExceedsBudget) => None,
+        Err(err) => return Err((StatusCode::NOT_FOUND, err.to_string())),
+    };
+    Ok(Json(ReserveResponse {
+        reservation_id,
+        exceeds_budget: reservation_id.is_none(),
+    }))
+}
+
+async fn commit_reservation(
+    State(service): State<Arc<Service>>,
+    Json(request): Json<CommitReservationRequest>,
+) -> Result<Json<ExceedsBudgetResponse>, (StatusCode, String)> {
+    if !(request.spent.is_finite() && request.spent >= 0.) {
+        let message = "`spent` needs to be positive and finite";
+        return Err((StatusCode::BAD_REQUEST, message.into()));
+    }
+
+    let exceeds_budget = service
+        .commit_reservation_async(request.reservation_id, request.spent)
+        .await
+        .map_err(|err| (StatusCode::NOT_FOUND, err.to_string()))?;
+    Ok(Json(ExceedsBudgetResponse {
+        exceeds_budget,
+        reason: None,
+        cache_for_ms: None,
+    }))
+}
+
+async fn cancel_reservation(
+    State(service): State<Arc<Service>>,
+    Json(request): Json<CommitReservationRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    service
+        .cancel_reservation_async(request.reservation_id)
+        .await
+        .map_err(|err| (StatusCode::NOT_FOUND, err.to_string()))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn acquire(
+    State(service): State<Arc<Service>>,
+    Json(request): Json<AcquireRequest>,
+) -> Result<Json<AcquireResponse>, (StatusCode, String)> {
+    if !(request.slots.is_finite() && request.slots >= 0.) {
+        let message = "`slots` needs to be positive and finite";
+        return Err((StatusCode::BAD_REQUEST, message.into()));
+    }
+    let ttl = Duration::try_from_secs_f64(request.ttl_secs)
+        .ok()
+        .filter(|ttl| *ttl <= MAX_TTL)
+        .ok_or_else(|| {
+            let message = "`ttl_secs` needs to be positive and at most a year";
+            (StatusCode::BAD_REQUEST, message.into())
+        })?;
+
+    let project = request.project.key()?;
+    let result = service
+        .acquire_by_key_async(&request.config_name, project, request.slots, ttl)
+        .await;
+    let acquisition_id = match result {
+        Ok(id) => Some(id),
+        Err(ReservationError::ExceedsBudget) => None,
+        Err(err) => return Err((StatusCode::NOT_FOUND, err.to_string())),
+    };
+    Ok(Json(AcquireResponse {
+        acquisition_id,
+        exceeds_budget: acquisition_id.is_none(),
+    }))
+}
+
+async fn release(
+    State(service): State<Arc<Service>>,
+    Json(request): Json<ReleaseRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    service
+        .release_async(request.acquisition_id)
+        .await
+        .map_err(|err| (StatusCode::NOT_FOUND, err.to_string()))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn remaining_budget(
+    State(service): State<Arc<Service>>,
+    Json(request): Json<RemainingBudgetRequest>,
+) -> Result<Json<RemainingBudgetResponse>, (StatusCode, String)> {
+    let project = request.project.key()?;
+    let remaining_budget = service
+        .remaining_budget_by_key_async(&request.config_name, project)
+        .await
+        .ok_or_else(|| {
+            let message = format!("config `{}` is not registered", request.config_name);
+            (StatusCode::NOT_FOUND, message)
+        })?;
+    Ok(Json(RemainingBudgetResponse { remaining_budget }))
+}
+
+async fn set_project_weight(
+    State(service): State<Arc<Service>>,
+    Json(request): Json<SetProjectWeightRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    if !request.weight.is_finite() || request.weight < 0. {
+        let message = "`weight` needs to be positive and finite";
+        return Err((StatusCode::BAD_REQUEST, message.into()));
+    }
+    let ttl = Duration::from_secs(request.ttl_secs);
+    if ttl > MAX_TTL {
+        let message = "`ttl_secs` needs to be at most a year";
+        return Err((StatusCode::BAD_REQUEST, message.into()));
+    }
+    let project = request.project.key()?;
+
+    if service.set_project_weight_by_key(&request.config_name, project, request.weight, ttl) {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        let message = format!("config `{}` is not registered", request.config_name);
+        Err((StatusCode::NOT_FOUND, message))
+    }
+}
+
+#[derive(Deserialize)]
+struct SetProjectAliasRequest {
+    #[serde(flatten)]
+    project: ProjectParam,
+    alias_of: ProjectKey,
+}
+
+async fn set_project_alias(
+    State(service): State<Arc<Service>>,
+    Json(request): Json<SetProjectAliasRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let project = request.project.key()?;
+    if service.set_project_alias(project, request.alias_of) {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        let message = format!("aliasing {project} to {} forms a cycle", request.alias_of);
+        Err((StatusCode::CONFLICT, message))
+    }
+}
+
+async fn remove_project_alias(
+    State(service): State<Arc<Service>>,
+    Path(project): Path<ProjectKey>,
+) -> StatusCode {
+    match service.remove_project_alias(project) {
+        Some(_) => StatusCode::NO_CONTENT,
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+async fn project_aliases(
+    State(service): State<Arc<Service>>,
+) -> Json<BTreeMap<ProjectKey, ProjectKey>> {
+    Json(service.project_aliases().into_iter().collect())
+}
+
+/// The maximum length of a project note, in bytes.
+const MAX_NOTE_LEN: usize = 256;
+
+#[derive(Deserialize)]
+struct SetProjectNoteRequest {
+    #[serde(flatten)]
+    project: ProjectParam,
+    note: String,
+}
+
+async fn set_project_note(
     State(service): State<Arc<Service>>,
-    Json(request): Json<ExceedsBudgetRequest>,
-) -> Json<ExceedsBudgetResponse> {
-    let exceeds_budget = service.exceeds_budget(&request.config_name, request.project_id);
-    Json(ExceedsBudgetResponse { exceeds_budget })
+    Json(request): Json<SetProjectNoteRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    if request.note.is_empty() || request.note.len() > MAX_NOTE_LEN {
+        let message = format!("`note` needs to be between 1 and {MAX_NOTE_LEN} bytes long");
+        return Err((StatusCode::BAD_REQUEST, message));
+    }
+    let project = request.project.key()?;
+    service.set_project_note(project, request.note);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn remove_project_note(
+    State(service): State<Arc<Service>>,
+    Path(project): Path<ProjectKey>,
+) -> StatusCode {
+    match service.remove_project_note(project) {
+        Some(_) => StatusCode::NO_CONTENT,
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+async fn project_notes(State(service): State<Arc<Service>>) -> Json<BTreeMap<ProjectKey, String>> {
+    Json(service.project_notes().into_iter().collect())
+}
+
+async fn remove_config(
+    State(service): State<Arc<Service>>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    match service.remove_config(&name) {
+        Ok(()) => Ok(StatusCode::NO_CONTENT),
+        Err(err) => Err((StatusCode::NOT_FOUND, err.to_string())),
+    }
+}
+
+#[derive(Deserialize)]
+struct SetEnabledRequest {
+    enabled: bool,
+}
+
+async fn set_config_enabled(
+    State(service): State<Arc<Service>>,
+    Path(name): Path<String>,
+    Json(request): Json<SetEnabledRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    match service.set_enabled_async(&name, request.enabled).await {
+        Ok(()) => Ok(StatusCode::NO_CONTENT),
+        Err(err) => Err((StatusCode::NOT_FOUND, err.to_string())),
+    }
+}
+
+#[derive(Deserialize)]
+struct BudgetReportQuery {
+    #[serde(default = "default_report_hours")]
+    hours: usize,
+}
+
+fn default_report_hours() -> usize {
+    MAX_HISTORY_HOURS
+}
+
+async fn budget_report(
+    State(service): State<Arc<Service>>,
+    Path(name): Path<String>,
+    Query(query): Query<BudgetReportQuery>,
+) -> Result<Json<BudgetReport>, (StatusCode, String)> {
+    service
+        .budget_report(&name, query.hours)
+        .map(Json)
+        .map_err(|err| (StatusCode::NOT_FOUND, err.to_string()))
+}
+
+#[derive(Deserialize)]
+struct SpendTrendQuery {
+    #[serde(default = "default_trend_minutes")]
+    minutes: usize,
+}
+
+fn default_trend_minutes() -> usize {
+    MAX_TREND_MINUTES
+}
+
+/// The JSON representation of a [`MinuteAggregate`].
+#[derive(Serialize)]
+struct MinuteAggregateResponse {
+    age_secs: f64,
+    spend_rate: f64,
+    blocked_projects: usize,
+}
+
+async fn spend_trend(
+    State(service): State<Arc<Service>>,
+    Path(name): Path<String>,
+    Query(query): Query<SpendTrendQuery>,
+) -> Result<Json<Vec<MinuteAggregateResponse>>, (StatusCode, String)> {
+    let trend = service
+        .spend_trend(&name, query.minutes)
+        .map_err(|err| (StatusCode::NOT_FOUND, err.to_string()))?;
+    let trend = trend.into_iter().map(|aggregate| MinuteAggregateResponse {
+        age_secs: aggregate.age.as_secs_f64(),
+        spend_rate: aggregate.spend_rate,
+        blocked_projects: aggregate.blocked_projects,
+    });
+    Ok(Json(trend.collect()))
+}
+
+/// The JSON representation of a [`ProjectReport`].
+#[derive(Serialize)]
+struct ProjectReportResponse {
+    exceeds_budget: bool,
+    spent_budget: f64,
+    budget: f64,
+    remaining_budget: f64,
+    backoff_remaining_secs: Option<f64>,
+    blocked_for_secs: Option<f64>,
+    transitions: usize,
+    recent_sources: Vec<String>,
+    note: Option<String>,
+}
+
+impl From<ProjectReport> for ProjectReportResponse {
+    fn from(report: ProjectReport) -> Self {
+        Self {
+            exceeds_budget: report.exceeds_budget,
+            spent_budget: report.spent_budget,
+            budget: report.budget,
+            remaining_budget: report.remaining_budget,
+            backoff_remaining_secs: report.backoff_remaining.map(|d| d.as_secs_f64()),
+            blocked_for_secs: report.blocked_for.map(|d| d.as_secs_f64()),
+            transitions: report.transitions,
+            recent_sources: report
+                .recent_sources
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            note: None,
+        }
+    }
+}
+
+async fn project_report(
+    State(service): State<Arc<Service>>,
+    Path((name, project_id)): Path<(String, ProjectKey)>,
+) -> Result<Json<ProjectReportResponse>, (StatusCode, String)> {
+    match service.project_report_by_key_async(&name, project_id).await {
+        Ok(Some(report)) => {
+            let mut report = ProjectReportResponse::from(report);
+            report.note = service.project_note(project_id);
+            Ok(Json(report))
+        }
+        Ok(None) => {
+            let message = format!("project {project_id} of config `{name}` is not tracked");
+            Err((StatusCode::NOT_FOUND, message))
+        }
+        Err(err) => Err((StatusCode::NOT_FOUND, err.to_string())),
+    }
+}
+
+#[derive(Deserialize)]
+struct SimulateConfigRequest {
+    config_name: String,
+    #[serde(flatten)]
+    project: ProjectParam,
+    /// The candidate config, in the same format as the config file.
+    config: ConfigSettings,
+}
+
+#[derive(Serialize)]
+struct SimulateConfigResponse {
+    current: ProjectReportResponse,
+    simulated: ProjectReportResponse,
+}
+
+/// Evaluates a candidate config against the recorded spending of a project, see [`Service::simulate_config`].
+async fn simulate_config(
+    State(service): State<Arc<Service>>,
+    Json(request): Json<SimulateConfigRequest>,
+) -> Result<Json<SimulateConfigResponse>, (StatusCode, String)> {
+    let SimulateConfigRequest {
+        config_name,
+        project,
+        config,
+    } = request;
+    let project_id = project.key()?;
+    let candidate = config
+        .to_config()
+        .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+    if candidate.strategy != AccountingStrategy::SlidingWindow {
+        let message = "only the `sliding-window` strategy can be simulated";
+        return Err((StatusCode::BAD_REQUEST, message.into()));
+    }
+
+    let not_found = |err: ConfigError| (StatusCode::NOT_FOUND, err.to_string());
+    let current = service
+        .project_report_by_key_async(&config_name, project_id)
+        .await
+        .map_err(not_found)?;
+    let simulated = service
+        .simulate_config_by_key_async(&config_name, project_id, candidate)
+        .await
+        .map_err(not_found)?;
+    match current.zip(simulated) {
+        Some((current, simulated)) => Ok(Json(SimulateConfigResponse {
+            current: current.into(),
+            simulated: simulated.into(),
+        })),
+        None => {
+            let message = format!("project {project_id} of config `{config_name}` is not tracked");
+            Err((StatusCode::NOT_FOUND, message))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ProjectHistoryQuery {
+    config: String,
+    #[serde(flatten)]
+    project: ProjectParam,
+}
+
+/// A time series in the format of the Grafana JSON datasource.
+#[derive(Serialize)]
+struct TimeSeries {
+    target: String,
+    /// Pairs of the value and its unix timestamp in milliseconds, oldest first.
+    datapoints: Vec<(f64, u64)>,
+}
+
+/// Returns the spending of each bucket of a project within the current window, see [`Service::bucket_spending`].
+async fn project_history(
+    State(service): State<Arc<Service>>,
+    Query(query): Query<ProjectHistoryQuery>,
+) -> Result<Json<Vec<TimeSeries>>, (StatusCode, String)> {
+    let ProjectHistoryQuery { config, project } = query;
+    let project_id = project.key()?;
+    let buckets = match service
+        .bucket_spending_by_key_async(&config, project_id)
+        .await
+    {
+        Ok(Some(buckets)) => buckets,
+        Ok(None) => {
+            let message = format!("project {project_id} of config `{config}` is not tracked");
+            return Err((StatusCode::NOT_FOUND, message));
+        }
+        Err(err) => return Err((StatusCode::NOT_FOUND, err.to_string())),
+    };
+
+    let now_ms = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64);
+    let datapoints = (buckets.into_iter())
+        .map(|(age, spent)| (spent, now_ms.saturating_sub(age.as_millis() as u64)))
+        .collect();
+    let target = format!("{config}/{project_id}");
+    Ok(Json(vec![TimeSeries { target, datapoints }]))
+}
+
+async fn spend_summary(
+    State(service): State<Arc<Service>>,
+) -> Json<IndexMap<String, SpendSummary>> {
+    Json(service.spend_summary())
+}
+
+async fn flip_floppers(
+    State(service): State<Arc<Service>>,
+) -> Json<IndexMap<String, Vec<FlipFlopper>>> {
+    let flip_floppers = service
+        .spend_summary()
+        .into_iter()
+        .map(|(config, summary)| (config, summary.flip_floppers))
+        .collect();
+    Json(flip_floppers)
+}
+
+#[derive(Serialize)]
+struct PressureResponse {
+    pressure: f64,
+    configs: IndexMap<String, f64>,
+}
+
+async fn pressure(State(service): State<Arc<Service>>) -> Json<PressureResponse> {
+    let (pressure, configs) = service.pressure();
+    Json(PressureResponse { pressure, configs })
+}
+
+#[derive(Serialize)]
+struct ConfigResponse {
+    id: ConfigId,
+    #[serde(flatten)]
+    settings: ConfigSettings,
+    enforcement: Enforcement,
+    derived: DerivedValues,
+}
+
+/// Values derived from the settings of a [`BudgetingConfig`], to spot misconfigurations.
+#[derive(Serialize)]
+struct DerivedValues {
+    num_buckets: usize,
+    window_to_bucket_ratio: f64,
+    min_enforceable_rate: f64,
+    warnings: Vec<&'static str>,
+}
+
+impl From<&BudgetingConfig> for DerivedValues {
+    fn from(config: &BudgetingConfig) -> Self {
+        Self {
+            num_buckets: config.num_buckets(),
+            window_to_bucket_ratio: config.window_to_bucket_ratio(),
+            min_enforceable_rate: config.min_enforceable_rate(),
+            warnings: config.warnings(),
+        }
+    }
+}
+
+async fn configs(State(service): State<Arc<Service>>) -> Json<IndexMap<String, ConfigResponse>> {
+    let configs = service
+        .configs()
+        .into_iter()
+        .map(|(name, registered)| {
+            let config = ConfigResponse {
+                id: registered.id,
+                settings: ConfigSettings::from_config(&registered.config),
+                enforcement: registered.enforcement,
+                derived: DerivedValues::from(&*registered.config),
+            };
+            (name, config)
+        })
+        .collect();
+    Json(configs)
+}
+
+/// A static page visualizing the reporting endpoints, see `src/dashboard.html`.
+async fn dashboard() -> Html<&'static str> {
+    Html(include_str!("dashboard.html"))
 }
 
 async fn health() -> &'static str {
     "OK"
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let mut args = std::env::args().skip(1);
-    let addr = args.next().unwrap_or("0.0.0.0:4433".into());
-    let addr: SocketAddr = addr.parse()?;
+/// The maximum age of the last maintenance pass before the service is considered unready.
+const MAX_MAINTENANCE_AGE: Duration = Duration::from_secs(10);
+
+async fn ready(State(service): State<Arc<Service>>) -> (StatusCode, String) {
+    if !service.maintenance_healthy() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Last maintenance pass panicked".into(),
+        );
+    }
+    match service.maintenance_age() {
+        Some(age) if age <= MAX_MAINTENANCE_AGE => (
+            StatusCode::OK,
+            format!("OK, last maintenance pass {:.1}s ago", age.as_secs_f64()),
+        ),
+        Some(age) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("Last maintenance pass {:.1}s ago", age.as_secs_f64()),
+        ),
+        None => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "No maintenance pass completed yet".into(),
+        ),
+    }
+}
+
+async fn metrics(State(state): State<AppState>) -> String {
+    render_metrics(&state).await
+}
+
+/// Renders the metrics of the [`Service`] and the server in the Prometheus text format.
+async fn render_metrics(state: &AppState) -> String {
+    let mut out = String::new();
+    state.service.write_metrics_async(&mut out).await;
+    write_server_metrics(state, &mut out);
+    out
+}
+
+/// Writes the metrics of the [`Service`] and the server to `out`, for exporting them via OTLP.
+#[cfg(feature = "otel")]
+fn write_metrics(state: &AppState, out: &mut impl MetricsWriter) {
+    state.service.write_metrics(out);
+    write_server_metrics(state, out);
+}
+
+/// Writes the metrics of the server itself to `out`.
+fn write_server_metrics(state: &AppState, out: &mut impl MetricsWriter) {
+    write_metric(
+        out,
+        "peanutbutter_http_oversized_payloads_total",
+        MetricKind::Counter,
+        "Number of HTTP requests rejected because of their body size.",
+        state
+            .http_metrics
+            .oversized_payloads
+            .load(Ordering::Relaxed),
+    );
+
+    let name = "peanutbutter_listener_requests_total";
+    let help = "Number of HTTP requests or RESP commands received per listening address.";
+    write_metric_header(out, name, MetricKind::Counter, help);
+    for listener in state.listeners.iter() {
+        let transport = match listener.transport {
+            Transport::Http => "http",
+            Transport::Resp => "resp",
+        };
+        let addr = listener.addr.to_string();
+        let labels = [("transport", transport), ("addr", addr.as_str())];
+        let requests = listener.requests.load(Ordering::Relaxed);
+        write_sample(out, name, &labels, requests);
+    }
+
+    if let Some(coalescer) = &state.coalescer {
+        coalescer.write_metrics(out);
+    }
+    if let Some(canary) = &state.canary {
+        canary.write_metrics(out);
+    }
+    if let Some(capture) = &state.capture {
+        capture.write_metrics(out);
+    }
+    if let Some(ip_limiter) = &state.ip_limiter {
+        ip_limiter.write_metrics(out);
+    }
+}
+
+/// Counts the requests received on a listening address.
+async fn count_request(State(listener): State<Arc<ListenerMetrics>>, request: Request) -> Request {
+    listener.requests.fetch_add(1, Ordering::Relaxed);
+    request
+}
 
-    let service = Arc::new(default_service());
+/// Counts the requests that were rejected by the [`DefaultBodyLimit`].
+async fn count_oversized_payloads(State(state): State<AppState>, response: Response) -> Response {
+    if response.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        state
+            .http_metrics
+            .oversized_payloads
+            .fetch_add(1, Ordering::Relaxed);
+    }
+    response
+}
 
-    let app = Router::new()
+fn app(state: AppState, max_body_size: usize, listener: Arc<ListenerMetrics>) -> Router {
+    let mut router = Router::new()
         .route("/_health", get(health))
+        .route("/_ready", get(ready))
+        .route("/metrics", get(metrics))
         .route("/record_spending", post(record_spending))
         .route("/exceeds_budget", post(exceeds_budget))
-        .with_state(service);
+        .route("/record_spending_by_uuid", post(record_spending_by_uuid))
+        .route("/exceeds_budget_by_uuid", post(exceeds_budget_by_uuid))
+        .route("/remaining_budget", post(remaining_budget))
+        .route("/would_exceed", post(would_exceed))
+        .route("/reserve", post(reserve))
+        .route("/commit_reservation", post(commit_reservation))
+        .route("/cancel_reservation", post(cancel_reservation))
+        .route("/acquire", post(acquire))
+        .route("/release", post(release))
+        .route("/spend_summary", get(spend_summary))
+        .route("/project_history", get(project_history))
+        .route("/simulate_config", post(simulate_config))
+        .route("/pressure", get(pressure))
+        .route("/flip_floppers", get(flip_floppers))
+        .route("/configs", get(configs))
+        .route("/dashboard", get(dashboard))
+        .route("/admin/project_weight", post(set_project_weight))
+        .route(
+            "/admin/project_aliases",
+            get(project_aliases).post(set_project_alias),
+        )
+        .route(
+            "/admin/project_aliases/:project_id",
+            delete(remove_project_alias),
+        )
+        .route(
+            "/admin/project_notes",
+            get(project_notes).post(set_project_note),
+        )
+        .route(
+            "/admin/project_notes/:project_id",
+            delete(remove_project_note),
+        )
+        .route("/admin/configs/:name", delete(remove_config))
+        .route("/admin/configs/:name/enabled", post(set_config_enabled))
+        .route("/admin/budget_report/:name", get(budget_report))
+        .route("/admin/spend_trend/:name", get(spend_trend))
+        .route("/admin/projects/:name/:project_id", get(project_report))
+        .layer(DefaultBodyLimit::max(max_body_size))
+        .layer(map_response_with_state(
+            state.clone(),
+            count_oversized_payloads,
+        ));
+    if let Some(cors) = &state.cors {
+        router = router.layer(from_fn_with_state(cors.clone(), cors::allow_origins));
+    }
+    #[cfg(feature = "otel")]
+    {
+        router = router.layer(axum::middleware::from_fn(otel::trace_request));
+    }
+    router
+        .layer(from_fn_with_state(
+            state.log_requests,
+            request_id::tag_request,
+        ))
+        .layer(map_request_with_state(listener, count_request))
+        .with_state(state)
+}
 
-    println!("Starting server on `{addr}`…");
-    let listener = tokio::net::TcpListener::bind(addr).await?;
+/// Binds a [`TcpListener`], optionally with `SO_REUSEPORT`.
+///
+/// With `SO_REUSEPORT`, multiple listeners can be bound to the same address,
+/// and the kernel balances incoming connections across all of them.
+/// With `only_v6`, an IPv6 listener does not accept IPv4 connections, so that
+/// it can coexist with an IPv4 listener on the same port.
+fn bind(addr: SocketAddr, reuseport: bool, only_v6: bool) -> io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(true)?;
+    if reuseport {
+        socket.set_reuseport(true)?;
+    }
+    if only_v6 {
+        SockRef::from(&socket).set_only_v6(true)?;
+    }
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+/// Binds all the configured servers, and runs them as supervised tasks on the current runtime.
+///
+/// Each listening address gets one acceptor task per configured acceptor.
+fn serve(settings: &Settings, state: &AppState, supervisor: &SupervisorHandle) -> io::Result<()> {
+    let reuseport = settings.reuseport();
 
-    axum::serve(listener, app).await?;
+    let listeners = state.listeners.clone();
+    for _ in 0..settings.acceptors {
+        for metrics in listeners.iter() {
+            let addr = metrics.addr;
+            // An IPv6 wildcard address would otherwise also claim the port for IPv4.
+            let only_v6 = addr.is_ipv6()
+                && (listeners.iter())
+                    .any(|other| other.addr.is_ipv4() && other.addr.port() == addr.port());
+            let listener = bind(addr, reuseport, only_v6)?;
 
+            match metrics.transport {
+                Transport::Http => {
+                    let app = app(state.clone(), settings.max_body_size, metrics.clone());
+                    let name = format!("HTTP server on `{addr}`");
+                    match state.ip_limiter.clone() {
+                        Some(limiter) => {
+                            let app = LimitedRouter {
+                                router: app,
+                                limiter,
+                            };
+                            supervisor.spawn(name, async move { axum::serve(listener, app).await })
+                        }
+                        None => {
+                            supervisor.spawn(name, async move { axum::serve(listener, app).await })
+                        }
+                    }
+                }
+                Transport::Resp => {
+                    let service = state.service.clone();
+                    let strict_configs = settings.strict_configs;
+                    let coalescer = state.coalescer.clone();
+                    let metrics = metrics.clone();
+                    let limiter = state.ip_limiter.clone();
+                    let name = format!("RESP server on `{addr}`");
+                    let server = resp::serve(
+                        listener,
+                        service,
+                        strict_configs,
+                        coalescer,
+                        limiter,
+                        metrics,
+                    );
+                    supervisor.spawn(name, server);
+                }
+            }
+        }
+    }
     Ok(())
 }
+
+/// Fails once the background maintenance thread of the `service` stopped.
+async fn watch_maintenance(service: Arc<Service>) -> Result<(), &'static str> {
+    loop {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        if service.maintenance_stopped() {
+            return Err("the maintenance thread stopped");
+        }
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<_> = std::env::args().skip(1).collect();
+    if args.first().is_some_and(|arg| arg == "self-test") {
+        self_test::run()?;
+        println!("Self-test passed");
+        return Ok(());
+    }
+    if args.first().is_some_and(|arg| arg == "replay") {
+        return replay::run(&args[1..]);
+    }
+    if args.first().is_some_and(|arg| arg == "healthcheck") {
+        return healthcheck::run(&args[1..]);
+    }
+
+    let started = Instant::now();
+    let settings = Arc::new(Settings::from_args(args)?);
+    let log_file = match &settings.log_file {
+        Some(path) => Some(daemon::LogFile::open(path)?),
+        None => None,
+    };
+    // The PID file is removed once `main` returns, or by the panic hook.
+    let _pid_file = match &settings.pid_file {
+        Some(path) => Some(daemon::PidFile::create(path)?),
+        None => None,
+    };
+    let service = Arc::new(create_service(&settings)?);
+    panics::install(settings.pid_file.clone(), service.clone());
+    println!("Using the `{}` time source", service.time_source());
+
+    // All the components run until the first one of them fails, which shuts down the process.
+    let supervisor = Supervisor::new();
+    let handle = supervisor.handle();
+
+    // The pollers of external config sources share a separate runtime,
+    // so that they are not affected by the load of the servers.
+    let pollers = Builder::new_current_thread().enable_all().build()?;
+    let guard = pollers.enter();
+    if let Some(url) = &settings.control_plane_url {
+        let control_plane = HttpSource::new(url)?;
+        let interval = Duration::from_secs(settings.control_plane_interval_secs);
+        let local = settings.configs.clone();
+        let service = service.clone();
+        println!("Fetching configs from control plane `{url}`…");
+        handle.spawn("control plane poller".into(), async move {
+            control_plane::run(control_plane, interval, local, service).await;
+            Ok::<_, Infallible>(())
+        });
+    }
+    if let Some(url) = &settings.feature_flags_url {
+        let provider = HttpSource::new(url)?;
+        let interval = Duration::from_secs(settings.feature_flags_interval_secs);
+        let service = service.clone();
+        println!("Fetching feature flags from `{url}`…");
+        handle.spawn("feature flags poller".into(), async move {
+            feature_flags::run(provider, interval, service).await;
+            Ok::<_, Infallible>(())
+        });
+    }
+    if let Some(path) = &settings.overrides_path {
+        let path = path.clone();
+        let interval = Duration::from_secs(settings.overrides_interval_secs);
+        let service = service.clone();
+        println!("Reading decision overrides from `{}`…", path.display());
+        handle.spawn("decision overrides poller".into(), async move {
+            overrides::run(path, interval, service).await;
+            Ok::<_, Infallible>(())
+        });
+    }
+    handle.spawn("maintenance".into(), watch_maintenance(service.clone()));
+    if let Some(log_file) = log_file {
+        handle.spawn("log file reopener".into(), log_file.reopen_on_signal());
+    }
+    handle.spawn("signal handler".into(), daemon::wait_for_termination()?);
+    drop(guard);
+    std::thread::spawn(move || pollers.block_on(std::future::pending::<()>()));
+
+    let canary = match &settings.canary_url {
+        Some(url) => {
+            println!(
+                "Comparing {} of projects against canary `{url}`…",
+                settings.canary_sample_rate
+            );
+            Some(Arc::new(Canary::new(url, settings.canary_sample_rate)?))
+        }
+        None => None,
+    };
+    let capture = match &settings.capture_path {
+        Some(path) => {
+            println!(
+                "Capturing {} of projects to `{}`…",
+                settings.capture_sample_rate,
+                path.display()
+            );
+            Some(Arc::new(Capture::new(path, settings.capture_sample_rate)?))
+        }
+        None => None,
+    };
+
+    let cors = match &settings.cors_origins[..] {
+        [] => None,
+        origins => Some(Arc::new(Cors::new(origins)?)),
+    };
+    let ip_limiter = IpLimiter::new(
+        settings.max_connections_per_ip,
+        settings.max_requests_per_ip,
+    );
+
+    let http_listeners =
+        (settings.addrs.iter()).map(|addr| ListenerMetrics::new(Transport::Http, *addr));
+    let resp_listeners =
+        (settings.resp_addrs.iter()).map(|addr| ListenerMetrics::new(Transport::Resp, *addr));
+    let listeners = http_listeners.chain(resp_listeners).collect();
+
+    let state = AppState {
+        service,
+        strict_configs: settings.strict_configs,
+        coalescer: (settings.coalesce_checks).then(Default::default),
+        http_metrics: Default::default(),
+        listeners: Arc::new(listeners),
+        canary,
+        capture,
+        log_requests: settings.log_requests,
+        cors,
+        ip_limiter: ip_limiter.map(Arc::new),
+    };
+
+    // The exporters flush the remaining spans and metrics when they are dropped on shutdown.
+    #[cfg(feature = "otel")]
+    let _telemetry = {
+        let state = state.clone();
+        let telemetry = otel::Telemetry::from_env(move |out| write_metrics(&state, out))?;
+        if let Some(telemetry) = &telemetry {
+            println!("Exporting {} via OTLP…", telemetry.signals());
+        }
+        telemetry
+    };
+
+    for addr in &settings.addrs {
+        println!("Starting server on `{addr}`…");
+    }
+    for resp_addr in &settings.resp_addrs {
+        println!("Starting RESP server on `{resp_addr}`…");
+    }
+
+    // The runtime needs to outlive the supervisor, as it runs all the servers.
+    let _runtime = if !settings.thread_per_core {
+        let runtime = Builder::new_multi_thread().enable_all().build()?;
+        let _guard = runtime.enter();
+        serve(&settings, &state, &handle)?;
+        daemon::notify("READY=1")?;
+        Some(runtime)
+    } else {
+        // Each core runs its own single-threaded runtime with its own set of listeners,
+        // so that connections never migrate between cores.
+        let num_threads = std::thread::available_parallelism()?.get();
+        println!("Running {num_threads} thread-per-core runtimes…");
+
+        let (ready, bound) = std::sync::mpsc::channel();
+        for idx in 0..num_threads {
+            let settings = settings.clone();
+            let state = state.clone();
+            let supervisor = handle.clone();
+            let ready = ready.clone();
+            handle.spawn_thread(format!("runtime {idx}"), move || {
+                let runtime = Builder::new_current_thread().enable_all().build()?;
+                let _guard = runtime.enter();
+                serve(&settings, &state, &supervisor)?;
+                // Dropping the sender right away makes sure that waiting for all runtimes stops
+                // if any of them fails to bind its listeners.
+                let _ = ready.send(());
+                drop(ready);
+                runtime.block_on(std::future::pending::<io::Result<()>>())
+            });
+        }
+        drop(ready);
+        if bound.iter().take(num_threads).count() == num_threads {
+            daemon::notify("READY=1")?;
+        }
+        None
+    };
+    drop(handle);
+
+    let failure = supervisor.wait();
+    if daemon::termination_requested() {
+        println!("Termination was requested, shutting down…");
+        let _ = daemon::notify("STOPPING=1");
+        let requests = (state.listeners.iter())
+            .map(|listener| listener.requests.load(Ordering::Relaxed))
+            .sum();
+        let blocked_snapshot = settings.blocked_snapshot_path.as_deref();
+        let report = ShutdownReport::flush(
+            &state.service,
+            blocked_snapshot,
+            started.elapsed(),
+            requests,
+        );
+        println!("{report}");
+        return Ok(());
+    }
+    println!("{failure}, shutting down…");
+    let _ = daemon::notify("STOPPING=1");
+    Err(failure.to_string().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Uri;
+
+    use super::*;
+
+    const UUID: &str = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+
+    fn test_service() -> Arc<Service> {
+        let service = Service::new();
+        let config = BudgetingConfig::new(
+            Duration::from_secs(60),
+            Duration::from_secs(10),
+            Duration::from_secs(1),
+            10.,
+        );
+        service.try_add_config("test", config).unwrap();
+        let project = ProjectKey::parse_uuid(UUID).unwrap();
+        service
+            .try_record_spending_by_key("test", project, 20.)
+            .unwrap();
+        Arc::new(service)
+    }
+
+    #[tokio::test]
+    async fn test_simulate_config_by_uuid() {
+        let service = test_service();
+        let request = serde_json::json!({
+            "config_name": "test",
+            "project_uuid": UUID,
+            "config": {"backoff": 60, "window": 10, "bucket": 1, "budget": 1},
+        });
+        let request = serde_json::from_value(request).unwrap();
+        let Json(response) = simulate_config(State(service.clone()), Json(request))
+            .await
+            .unwrap();
+        assert!(!response.current.exceeds_budget);
+        assert!(response.simulated.exceeds_budget);
+
+        let request = serde_json::json!({
+            "config_name": "test",
+            "project_uuid": "not-a-uuid",
+            "config": {"backoff": 60, "window": 10, "bucket": 1, "budget": 1},
+        });
+        let request = serde_json::from_value(request).unwrap();
+        let Err((status, _message)) = simulate_config(State(service), Json(request)).await else {
+            panic!("invalid UUIDs are rejected");
+        };
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_project_history_by_uuid() {
+        let service = test_service();
+        let uri: Uri = format!("/project_history?config=test&project_uuid={UUID}")
+            .parse()
+            .unwrap();
+        let query = Query::try_from_uri(&uri).unwrap();
+        let Json(series) = project_history(State(service.clone()), query)
+            .await
+            .unwrap();
+        assert_eq!(series[0].target, format!("test/{UUID}"));
+        let spent: f64 = series[0].datapoints.iter().map(|(spent, _ms)| spent).sum();
+        assert_eq!(spent, 20.);
+
+        // numeric ids are still accepted, but the project is not tracked
+        let uri: Uri = "/project_history?config=test&project_id=1".parse().unwrap();
+        let query = Query::try_from_uri(&uri).unwrap();
+        let Err((status, _message)) = project_history(State(service), query).await else {
+            panic!("unknown projects are not found");
+        };
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
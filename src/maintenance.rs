@@ -0,0 +1,1018 @@
+use std::num::NonZeroUsize;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use pollster::block_on;
+use quanta::Instant;
+
+use crate::blocked_snapshot::{config_hash, BlockedSnapshotWriter};
+use crate::config::{ConfigId, Enforcement, Removal, Timer, RECENT_TIME_INTERVAL};
+use crate::events::{Event, Events};
+use crate::history::SpendHistory;
+use crate::metrics::MaintenanceMetrics;
+use crate::reservations::Reservation;
+use crate::store::StateStore;
+use crate::{
+    active_config, BudgetTracker, Configs, ProjectWeights, Reservations, SpendSummaries,
+    SpendSummary, MAINTENANCE_THREAD,
+};
+
+/// The maximum number of threads that scan the [`StateStore`] partitions in parallel.
+const MAX_MAINTENANCE_WORKERS: usize = 4;
+
+/// The interval between two maintenance passes, which also update the recent time.
+const PASS_INTERVAL: Duration = RECENT_TIME_INTERVAL;
+
+/// The minimum time between two maintenance passes that is considered a jump of the clock.
+///
+/// This is way longer than any pass should ever take, but short enough to not expire most windows.
+const CLOCK_JUMP_THRESHOLD: Duration = Duration::from_secs(30);
+
+/// The approximate memory used by each entry of a [`StateStore`], excluding the tracker itself.
+const ENTRY_OVERHEAD: usize = std::mem::size_of::<(u64, Box<dyn BudgetTracker>)>();
+
+/// A background maintenance task that periodically updates the recent time,
+/// cleans up stale [`BudgetTracker`]s and aggregates the [`SpendSummary`]s.
+#[derive(Debug)]
+pub(crate) struct Maintenance {
+    /// The [`Timer`] used to update the recent time.
+    pub timer: Timer,
+    /// Whether the global recent time is updated, which is not done for mocked clocks.
+    pub update_recent: bool,
+    /// The configs, whose project stats are cleaned up.
+    pub configs: Configs,
+    /// The summaries which are recomputed on every pass.
+    pub spend_summaries: SpendSummaries,
+    /// The history of spend rates, which the summaries are added to on every pass.
+    pub spend_history: Arc<SpendHistory>,
+    /// The project weights, which are cleaned up once expired.
+    pub project_weights: ProjectWeights,
+    /// The reservations, which are cleaned up once expired.
+    pub reservations: Reservations,
+    /// The maximum memory used by the project entries in bytes, or `0` for no limit.
+    ///
+    /// Projects are evicted when this is exceeded.
+    pub memory_limit: Arc<AtomicUsize>,
+    /// The number of state changes within one pass above which they are reported as a single [`Event`],
+    /// or `0` to always report them individually.
+    pub mass_state_change_threshold: Arc<AtomicUsize>,
+    /// The time of the scan of the previous pass, since which state changes are counted.
+    pub last_scan: Mutex<Option<Instant>>,
+    /// Whether the state of all projects is shifted forward when the clock jumped.
+    pub skip_clock_jumps: Arc<AtomicBool>,
+    /// The snapshot of blocked projects which is written on every pass, if shared.
+    pub blocked_snapshot: Arc<Mutex<Option<BlockedSnapshotWriter>>>,
+    /// Metrics describing the health of the maintenance itself.
+    pub metrics: Arc<MaintenanceMetrics>,
+    /// Where [`Event`]s happening during maintenance are emitted to.
+    pub events: Arc<Events>,
+    /// Whether the maintenance was asked to stop, which it does before its next pass.
+    pub stopped: Arc<AtomicBool>,
+}
+
+impl Maintenance {
+    /// Runs the maintenance until it is [stopped](Self::stopped).
+    ///
+    /// The thread is parked in between passes, so that unparking it after stopping takes effect right away.
+    pub fn run(self) {
+        let num_workers = std::thread::available_parallelism()
+            .map_or(1, NonZeroUsize::get)
+            .min(MAX_MAINTENANCE_WORKERS);
+        let workers = ScanWorkers::spawn(num_workers);
+
+        loop {
+            let started = std::time::Instant::now();
+            while let Some(remaining) = PASS_INTERVAL.checked_sub(started.elapsed()) {
+                if self.stopped.load(Ordering::Acquire) {
+                    return;
+                }
+                std::thread::park_timeout(remaining);
+            }
+            let now = self.timer.precise_now();
+            if self.update_recent {
+                quanta::set_recent(now);
+            }
+
+            self.run_guarded_pass(now, &workers);
+        }
+    }
+
+    /// Runs a single maintenance pass, recovering from any panic within it.
+    ///
+    /// A panicking pass is recorded in the metrics and reported as an [`Event`],
+    /// so that a single bug does not silently stop all future cleanup.
+    fn run_guarded_pass(&self, now: Instant, workers: &ScanWorkers) {
+        let pass = panic::catch_unwind(AssertUnwindSafe(|| self.run_pass(now, workers)));
+        let Err(payload) = pass else {
+            return;
+        };
+
+        let message = if let Some(message) = payload.downcast_ref::<&str>() {
+            message.to_string()
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            message.clone()
+        } else {
+            "unknown panic".into()
+        };
+        self.metrics.record_failed_pass(self.timer.precise_now());
+        self.events.emit(Event::MaintenancePanicked { message });
+    }
+
+    /// Emits the [`Event`]s of a scan, which are aggregated if there are too many state changes.
+    fn emit_state_changes(&self, config_names: &[String], scan: &mut ScanResult) {
+        let state_changes: usize = scan.summaries.iter().map(|s| s.state_changes).sum();
+        let threshold = self.mass_state_change_threshold.load(Ordering::Relaxed);
+        if threshold == 0 || state_changes <= threshold {
+            for event in scan.events.drain(..) {
+                self.events.emit(event);
+            }
+            return;
+        }
+
+        let mut configs: Vec<_> = (config_names.iter().cloned())
+            .zip(scan.summaries.iter().map(|s| s.state_changes))
+            .filter(|(_name, state_changes)| *state_changes > 0)
+            .collect();
+        configs.sort_by_key(|(_name, state_changes)| std::cmp::Reverse(*state_changes));
+        scan.events.clear();
+        self.events.emit(Event::MassStateChange {
+            state_changes,
+            configs,
+        });
+    }
+
+    /// Handles a jump of the clock between two passes, by optionally skipping the jumped time.
+    ///
+    /// Otherwise, all the windows and backoffs covered by the jump expire at once.
+    fn handle_clock_jump(&self, project_budgets: &[Arc<dyn StateStore>], jumped: Duration) {
+        let skipped_projects = if self.skip_clock_jumps.load(Ordering::Relaxed) {
+            skip_time(project_budgets, jumped.saturating_sub(PASS_INTERVAL))
+        } else {
+            0
+        };
+        self.metrics.clock_jumps.add(1);
+        self.events.emit(Event::ClockJumped {
+            jumped,
+            skipped_projects,
+        });
+    }
+
+    /// Runs a single maintenance pass.
+    fn run_pass(&self, now: Instant, workers: &ScanWorkers) {
+        // The pass works on a snapshot of the configs, which can be changed during the pass.
+        let mut config_ids = vec![];
+        let mut config_names = vec![];
+        let mut project_budgets = vec![];
+        let mut draining = vec![];
+        for (name, registered) in self.configs.load().iter() {
+            config_ids.push(registered.id);
+            config_names.push(name.clone());
+            project_budgets.push(registered.projects.clone());
+            if registered.removal == Some(Removal::Draining) {
+                draining.push((registered.id, registered.projects.clone()));
+            }
+        }
+        purge_removed_configs(&draining, &self.project_weights);
+
+        let since = self.last_scan.lock().unwrap().replace(now);
+        if let Some(jumped) = since.map(|since| now.saturating_duration_since(since)) {
+            if jumped > CLOCK_JUMP_THRESHOLD {
+                self.handle_clock_jump(&project_budgets, jumped);
+            }
+        }
+        let mut scan = workers.scan(&project_budgets, since, now);
+        // Configs without any projects are summarized as well, so that their history is kept.
+        (scan.summaries).resize(config_ids.len(), SpendSummary::default());
+        self.emit_state_changes(&config_names, &mut scan);
+        let mut summaries = config_ids
+            .into_iter()
+            .zip(std::mem::take(&mut scan.summaries))
+            .collect();
+        self.spend_history.record(now, &summaries);
+        std::mem::swap(&mut *self.spend_summaries.write().unwrap(), &mut summaries);
+
+        let memory_limit = self.memory_limit.load(Ordering::Relaxed);
+        if memory_limit > 0 && scan.memory_usage > memory_limit {
+            let used_bytes = scan.memory_usage;
+            let (evicted, freed) = evict_projects(&project_budgets, now, used_bytes - memory_limit);
+            scan.evicted = evicted;
+            scan.memory_usage -= freed;
+            self.events.emit(Event::MemoryLimitExceeded {
+                used_bytes,
+                limit_bytes: memory_limit,
+                evicted,
+            });
+        }
+
+        // `retain` only ever locks one shard at a time, so it can't deadlock with itself.
+        self.project_weights
+            .retain(|_k, weight| weight.expires_at > now);
+        let mut expired = vec![];
+        self.reservations.retain(|_id, reservation| {
+            let retain = reservation.expires_at > now;
+            if !retain && reservation.release_on_expiry {
+                expired.push(reservation.clone());
+            }
+            retain
+        });
+        scan.expired_acquisitions = release_expired(&self.configs, &expired);
+        reconcile_blocked(&self.configs);
+
+        self.metrics
+            .record_pass(now, self.timer.precise_now(), &scan);
+        write_blocked_snapshot(&self.configs, &self.blocked_snapshot);
+
+        if !draining.is_empty() {
+            self.configs.update(|configs| {
+                for registered in configs.values_mut() {
+                    if draining.iter().any(|(id, _projects)| *id == registered.id) {
+                        registered.removal = Some(Removal::Purged);
+                    }
+                }
+            });
+        }
+    }
+}
+
+/// Writes the projects which exceed the budget of an enforced config to the `blocked_snapshot`, if there is one.
+///
+/// The projects are collected before locking the writer, so that a panic while scanning does not poison it,
+/// and a concurrent flush does not wait for the whole scan.
+pub(crate) fn write_blocked_snapshot(
+    configs: &Configs,
+    blocked_snapshot: &Mutex<Option<BlockedSnapshotWriter>>,
+) {
+    let lock = || {
+        blocked_snapshot
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    };
+    if lock().is_none() {
+        return;
+    }
+    let enforced: Vec<_> = (configs.load().iter())
+        .filter(|(_name, registered)| {
+            registered.removal.is_none() && registered.effective_enforcement() == Enforcement::On
+        })
+        .map(|(name, registered)| (config_hash(name), registered.projects.clone()))
+        .collect();
+
+    let mut blocked = vec![];
+    for (config, projects) in enforced {
+        for partition in 0..projects.num_partitions() {
+            block_on(projects.scan(partition, &mut |project, tracker| {
+                // The snapshot only holds numeric project ids.
+                if let Some(project_id) = project.project_id() {
+                    if tracker.cached_check() {
+                        blocked.push((config, project_id));
+                    }
+                }
+            }));
+        }
+    }
+    if let Some(writer) = lock().as_mut() {
+        writer.write(&mut blocked, SystemTime::now());
+    }
+}
+
+/// Removes the projects from the [blocked](crate::RegisteredConfig::blocked) sets which no longer exceed their budget.
+///
+/// This catches up with projects that were removed from their [`StateStore`], or that were changed
+/// by the maintenance without going through the [`Service`](crate::Service).
+fn reconcile_blocked(configs: &Configs) {
+    for registered in configs.load().values() {
+        let blocked: Vec<_> = registered.blocked.iter().map(|project| *project).collect();
+        for project_id in blocked {
+            // The tracker stays locked while updating the set, just like on the request path.
+            let mut reconcile = |tracker: &dyn BudgetTracker| {
+                registered.cache_check(project_id, tracker);
+            };
+            if !block_on(registered.projects.get(project_id, &mut reconcile)) {
+                registered.blocked.remove(&project_id);
+            }
+        }
+    }
+}
+
+/// Releases the budget of the `expired` reservations, and returns how many were released.
+///
+/// Projects which were cleaned up in the meantime are skipped.
+fn release_expired(configs: &Configs, expired: &[Reservation]) -> usize {
+    if expired.is_empty() {
+        return 0;
+    }
+    let configs = configs.load();
+    let mut released = 0;
+    for reservation in expired {
+        let Some(registered) = active_config(&configs, &reservation.config) else {
+            continue;
+        };
+        let project_id = reservation.project;
+        let mut release = |tracker: &mut dyn BudgetTracker| {
+            tracker.release(reservation.reserved, reservation.recorded_at);
+            registered.cache_check(project_id, tracker);
+        };
+        if block_on(registered.projects.update(project_id, None, &mut release)) {
+            released += 1;
+        }
+    }
+    released
+}
+
+/// The outcome of scanning the [`StateStore`]s.
+#[derive(Debug, Default)]
+pub(crate) struct ScanResult {
+    /// The per-config [`SpendSummary`]s, indexed by the position of the config.
+    pub summaries: Vec<SpendSummary>,
+    /// The number of scanned project entries.
+    pub scanned: usize,
+    /// The number of stale project entries that were removed.
+    pub removed: usize,
+    /// The number of removed project entries which were still exceeding their budget.
+    pub removed_blocked: usize,
+    /// The approximate memory used by the remaining project entries, in bytes.
+    pub memory_usage: usize,
+    /// The number of project entries that were evicted because of the memory limit.
+    pub evicted: usize,
+    /// The number of acquisitions whose slots were released because they expired.
+    pub expired_acquisitions: usize,
+    /// The [`Event`]s that happened during the scan, which are emitted afterwards.
+    pub events: Vec<Event>,
+}
+
+impl ScanResult {
+    /// Merges the `other` result into this one.
+    fn merge(&mut self, other: ScanResult) {
+        if self.summaries.len() < other.summaries.len() {
+            self.summaries
+                .resize(other.summaries.len(), SpendSummary::default());
+        }
+        for (summary, other) in self.summaries.iter_mut().zip(other.summaries) {
+            summary.merge(&other);
+        }
+        self.scanned += other.scanned;
+        self.removed += other.removed;
+        self.removed_blocked += other.removed_blocked;
+        self.memory_usage += other.memory_usage;
+        self.evicted += other.evicted;
+        self.events.extend(other.events);
+    }
+}
+
+/// The scan of a disjoint set of [`StateStore`] partitions, which is run by one of the [`ScanWorkers`].
+struct ScanJob {
+    /// The stores of all the configs, indexed by the position of their config.
+    project_budgets: Arc<[Arc<dyn StateStore>]>,
+    /// The partitions to scan, as the position of their config and the partition within its store.
+    partitions: Vec<(usize, usize)>,
+    /// The time of the scan of the previous pass.
+    since: Option<Instant>,
+    /// The time of this scan.
+    now: Instant,
+}
+
+impl ScanJob {
+    /// Scans all the partitions of this job.
+    fn run(&self) -> ScanResult {
+        let mut result = ScanResult::default();
+        for &(config_idx, partition) in &self.partitions {
+            let projects = self.project_budgets[config_idx].as_ref();
+            let (since, now) = (self.since, self.now);
+            scan_partition(projects, since, config_idx, partition, now, &mut result);
+        }
+        result
+    }
+}
+
+/// A single thread of the [`ScanWorkers`], along with the channels to pass it jobs and get back their results.
+struct ScanWorker {
+    jobs: mpsc::Sender<ScanJob>,
+    results: mpsc::Receiver<thread::Result<ScanResult>>,
+    thread: JoinHandle<()>,
+}
+
+/// A small pool of threads which scan the [`StateStore`] partitions in parallel.
+///
+/// The pool is owned by the maintenance thread, and its threads are kept for all the passes.
+/// Panics of a job are caught by its worker, and propagated to the pass.
+pub(crate) struct ScanWorkers {
+    workers: Vec<ScanWorker>,
+}
+
+impl ScanWorkers {
+    /// Spawns `num_workers` threads, named after the [`MAINTENANCE_THREAD`].
+    pub fn spawn(num_workers: usize) -> Self {
+        let workers = (0..num_workers.max(1))
+            .map(|worker| {
+                let (jobs, pending_jobs) = mpsc::channel::<ScanJob>();
+                let (finished_jobs, results) = mpsc::channel();
+                let thread = thread::Builder::new()
+                    .name(format!("{MAINTENANCE_THREAD}-{worker}"))
+                    .spawn(move || {
+                        for job in pending_jobs {
+                            let result = panic::catch_unwind(AssertUnwindSafe(|| job.run()));
+                            if finished_jobs.send(result).is_err() {
+                                return;
+                            }
+                        }
+                    })
+                    .expect("failed to spawn a maintenance scan worker");
+                ScanWorker {
+                    jobs,
+                    results,
+                    thread,
+                }
+            })
+            .collect();
+        Self { workers }
+    }
+
+    /// Scans all the partitions of the [`StateStore`] of each config.
+    ///
+    /// The `project_budgets` are indexed by the position of their config.
+    /// Each worker is responsible for a disjoint set of partitions, which it cleans up
+    /// and aggregates into per-config [`SpendSummary`]s.
+    pub fn scan(
+        &self,
+        project_budgets: &[Arc<dyn StateStore>],
+        since: Option<Instant>,
+        now: Instant,
+    ) -> ScanResult {
+        let partitions: Vec<_> = project_budgets
+            .iter()
+            .enumerate()
+            .flat_map(|(config_idx, projects)| {
+                (0..projects.num_partitions()).map(move |partition| (config_idx, partition))
+            })
+            .collect();
+        let project_budgets: Arc<[_]> = project_budgets.into();
+
+        let num_workers = self.workers.len();
+        for (idx, worker) in self.workers.iter().enumerate() {
+            let job = ScanJob {
+                project_budgets: project_budgets.clone(),
+                partitions: partitions
+                    .iter()
+                    .skip(idx)
+                    .step_by(num_workers)
+                    .copied()
+                    .collect(),
+                since,
+                now,
+            };
+            worker.jobs.send(job).expect("scan workers are running");
+        }
+
+        // All the results are received before propagating a panic, so that none is left for the next pass.
+        let results: Vec<_> = (self.workers.iter())
+            .map(|worker| worker.results.recv().expect("scan workers are running"))
+            .collect();
+        let mut result = ScanResult::default();
+        for worker_result in results {
+            // Propagate the original panic of a worker, including its message.
+            result.merge(worker_result.unwrap_or_else(|payload| panic::resume_unwind(payload)));
+        }
+        result
+    }
+}
+
+impl Drop for ScanWorkers {
+    fn drop(&mut self) {
+        for ScanWorker { jobs, thread, .. } in self.workers.drain(..) {
+            // Closing the channel of jobs stops the worker.
+            drop(jobs);
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Scans a single partition, and clean up its stale entries in two phases.
+///
+/// The [`StateStore`] must not be accessed from within [`StateStore::scan`],
+/// which can deadlock, so entries are removed only after the scan. Afterwards, the partition is
+/// [compacted](StateStore::compact).
+fn scan_partition(
+    projects: &dyn StateStore,
+    since: Option<Instant>,
+    config_idx: usize,
+    partition: usize,
+    now: Instant,
+    result: &mut ScanResult,
+) {
+    let mut keys_needing_cleanup = vec![];
+    let summaries = &mut result.summaries;
+
+    let mut scan = |project_id, tracker: &dyn BudgetTracker| {
+        result.scanned += 1;
+        if tracker.is_stale(now) {
+            keys_needing_cleanup.push(project_id);
+            return;
+        }
+
+        result.memory_usage += ENTRY_OVERHEAD + tracker.memory_usage();
+        if summaries.len() <= config_idx {
+            summaries.resize(config_idx + 1, SpendSummary::default());
+        }
+        summaries[config_idx].add_project(project_id, tracker, now, since);
+    };
+    block_on(projects.scan(partition, &mut scan));
+
+    for project_id in keys_needing_cleanup {
+        let is_stale = |tracker: &dyn BudgetTracker| tracker.is_stale(now);
+        let Some(tracker) = block_on(projects.remove(project_id, &is_stale)) else {
+            continue;
+        };
+
+        result.removed += 1;
+        if tracker.report(now).exceeds_budget {
+            result.removed_blocked += 1;
+            if result.summaries.len() <= config_idx {
+                (result.summaries).resize(config_idx + 1, SpendSummary::default());
+            }
+            result.summaries[config_idx].state_changes += 1;
+            result.events.push(Event::BlockedProjectCleanedUp {
+                config: tracker.config().name.clone(),
+                project_id,
+            });
+        }
+    }
+    block_on(projects.compact(partition, now));
+}
+
+/// Purges all the project state of the configs that are `draining` after being removed.
+fn purge_removed_configs(
+    draining: &[(ConfigId, Arc<dyn StateStore>)],
+    project_weights: &ProjectWeights,
+) {
+    if draining.is_empty() {
+        return;
+    }
+    for (_id, projects) in draining {
+        block_on(projects.clear());
+    }
+    project_weights.retain(|(config_id, _project_id), _weight| {
+        !draining.iter().any(|(id, _projects)| id == config_id)
+    });
+}
+
+/// Shifts the state of all the projects forward by the `skipped` time.
+///
+/// Returns the number of shifted projects.
+fn skip_time(project_budgets: &[Arc<dyn StateStore>], skipped: Duration) -> usize {
+    let mut skipped_projects = 0;
+    for projects in project_budgets {
+        for partition in 0..projects.num_partitions() {
+            let mut project_ids = vec![];
+            block_on(projects.scan(partition, &mut |project_id, _tracker| {
+                project_ids.push(project_id);
+            }));
+            for project_id in project_ids {
+                let mut skip = |tracker: &mut dyn BudgetTracker| tracker.skip_time(skipped);
+                if block_on(projects.update(project_id, None, &mut skip)) {
+                    skipped_projects += 1;
+                }
+            }
+        }
+    }
+    skipped_projects
+}
+
+/// Evicts projects which are not exceeding their budget, until at least `to_free` bytes are freed.
+///
+/// The least recently updated projects are evicted first.
+/// Returns the number of evicted projects, and the approximate number of freed bytes.
+fn evict_projects(
+    project_budgets: &[Arc<dyn StateStore>],
+    now: Instant,
+    to_free: usize,
+) -> (usize, usize) {
+    let mut candidates = vec![];
+    for (config_idx, projects) in project_budgets.iter().enumerate() {
+        for partition in 0..projects.num_partitions() {
+            block_on(projects.scan(partition, &mut |project_id, tracker| {
+                if !tracker.cached_check() {
+                    let last_updated = tracker.report(now).last_updated;
+                    candidates.push((last_updated, config_idx, project_id));
+                }
+            }));
+        }
+    }
+    candidates.sort_unstable_by_key(|(last_updated, _config_idx, _project_id)| *last_updated);
+
+    let (mut evicted, mut freed) = (0, 0);
+    for (_last_updated, config_idx, project_id) in candidates {
+        if freed >= to_free {
+            break;
+        }
+        // The project might have started exceeding its budget in the meantime.
+        let not_blocked = |tracker: &dyn BudgetTracker| !tracker.cached_check();
+        if let Some(tracker) =
+            block_on(project_budgets[config_idx].remove(project_id, &not_blocked))
+        {
+            evicted += 1;
+            freed += ENTRY_OVERHEAD + tracker.memory_usage();
+        }
+    }
+    (evicted, freed)
+}
+
+#[cfg(test)]
+mod tests {
+    use quanta::Clock;
+
+    use crate::config::{BudgetingConfig, RegisteredConfig};
+    use crate::stats::ProjectReport;
+    use crate::store::{project_ids, MemoryStore};
+    use crate::{ProjectKey, ProjectStats};
+
+    use super::*;
+
+    impl Maintenance {
+        /// Creates a [`Maintenance`] without any state, along with all the [`Event`]s it emits.
+        fn for_test(timer: Timer) -> (Self, Arc<Mutex<Vec<Event>>>) {
+            let maintenance = Self {
+                timer,
+                update_recent: false,
+                configs: Default::default(),
+                spend_summaries: Default::default(),
+                spend_history: Default::default(),
+                project_weights: Default::default(),
+                reservations: Default::default(),
+                memory_limit: Default::default(),
+                mass_state_change_threshold: Default::default(),
+                last_scan: Default::default(),
+                skip_clock_jumps: Default::default(),
+                blocked_snapshot: Default::default(),
+                metrics: Default::default(),
+                events: Default::default(),
+                stopped: Default::default(),
+            };
+            let emitted = Arc::new(Mutex::new(vec![]));
+            maintenance.events.set_handler(Box::new({
+                let emitted = emitted.clone();
+                move |event| emitted.lock().unwrap().push(event.clone())
+            }));
+            (maintenance, emitted)
+        }
+    }
+
+    #[test]
+    fn test_scan_project_budgets() {
+        let (clock, mock) = Clock::mock();
+        mock.increment(Duration::from_secs(100));
+        let timer = Timer::new(clock);
+
+        let config = BudgetingConfig::new(
+            Duration::from_secs(10),
+            Duration::from_secs(5),
+            Duration::from_secs(1),
+            10.,
+        )
+        .with_timer(timer.clone());
+        let config = Arc::new(config);
+
+        let project_budgets: [Arc<dyn StateStore>; 2] = [
+            Arc::new(MemoryStore::default()),
+            Arc::new(MemoryStore::default()),
+        ];
+        for project_id in 0..100 {
+            let mut stats = ProjectStats::new(config.clone());
+            stats.record_spending(if project_id == 42 { 1_000. } else { 1. });
+            let projects = &project_budgets[project_id as usize % 2];
+            block_on(projects.insert(project_id.into(), Box::new(stats)));
+        }
+        let num_projects = || {
+            (project_budgets.iter())
+                .map(|p| block_on(p.len()))
+                .sum::<usize>()
+        };
+
+        let since = timer.now() - Duration::from_secs(1);
+        let workers = ScanWorkers::spawn(3);
+        let scan = workers.scan(&project_budgets, Some(since), timer.now());
+        assert_eq!(scan.summaries.len(), 2);
+        assert_eq!(scan.summaries[0].blocked_projects, 1);
+        assert_eq!(scan.summaries[0].state_changes, 1);
+        assert_eq!(scan.summaries[0].tracked_projects, 50);
+        assert_eq!(scan.summaries[1].tracked_projects, 50);
+        assert_eq!((scan.scanned, scan.removed), (100, 0));
+        assert_eq!(num_projects(), 100);
+
+        // only the projects which still exceed their budget stay blocked
+        let registered = RegisteredConfig {
+            config: config.clone(),
+            enforcement: Default::default(),
+            projects: project_budgets[0].clone(),
+            blocked: Default::default(),
+            decisions: Default::default(),
+            oversized_spends: Default::default(),
+            removal: None,
+            id: ConfigId(1),
+        };
+        registered.blocked.insert(42.into());
+        registered.blocked.insert(44.into());
+        let blocked = registered.blocked.clone();
+        let configs = Configs::default();
+        configs.update(|configs| configs.insert("test".into(), registered));
+        reconcile_blocked(&configs);
+        assert!(blocked.contains(&42.into()));
+        assert!(!blocked.contains(&44.into()));
+
+        mock.increment(Duration::from_secs(10));
+
+        let scan = workers.scan(&project_budgets, Some(since), timer.now());
+        // only the cleaned up blocked project changed its state
+        assert_eq!(scan.summaries.len(), 1);
+        assert_eq!(scan.summaries[0].tracked_projects, 0);
+        assert_eq!(scan.summaries[0].state_changes, 1);
+        assert_eq!((scan.scanned, scan.removed), (100, 100));
+        assert_eq!(scan.removed_blocked, 1);
+        assert_eq!(num_projects(), 0);
+        // the removed project is no longer blocked for cached checks
+        reconcile_blocked(&configs);
+        assert!(blocked.is_empty());
+        assert_eq!(
+            scan.events,
+            [Event::BlockedProjectCleanedUp {
+                config: String::new(),
+                project_id: 42.into()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_mass_state_change() {
+        let (clock, _mock) = Clock::mock();
+        let (maintenance, emitted) = Maintenance::for_test(Timer::new(clock));
+
+        let cleaned_up = |project_id: u64| Event::BlockedProjectCleanedUp {
+            config: "a".into(),
+            project_id: project_id.into(),
+        };
+        let scan = || {
+            let summary = |state_changes| SpendSummary {
+                state_changes,
+                ..Default::default()
+            };
+            ScanResult {
+                summaries: vec![summary(2), summary(0), summary(3)],
+                events: vec![cleaned_up(1), cleaned_up(2)],
+                ..Default::default()
+            }
+        };
+        let names = ["a".into(), "b".into(), "c".into()];
+
+        // without a threshold, all the individual events are emitted
+        maintenance.emit_state_changes(&names, &mut scan());
+        assert_eq!(*emitted.lock().unwrap(), [cleaned_up(1), cleaned_up(2)]);
+        emitted.lock().unwrap().clear();
+
+        maintenance
+            .mass_state_change_threshold
+            .store(4, Ordering::Relaxed);
+        maintenance.emit_state_changes(&names, &mut scan());
+        assert_eq!(
+            *emitted.lock().unwrap(),
+            [Event::MassStateChange {
+                state_changes: 5,
+                configs: vec![("c".into(), 3), ("a".into(), 2)],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_evict_projects() {
+        let (clock, mock) = Clock::mock();
+        mock.increment(Duration::from_secs(100));
+        let config = BudgetingConfig::new(
+            Duration::from_secs(10),
+            Duration::from_secs(5),
+            Duration::from_secs(1),
+            10.,
+        )
+        .with_timer(Timer::new(clock.clone()));
+        let config = Arc::new(config);
+
+        let project_budgets: [Arc<dyn StateStore>; 1] = [Arc::new(MemoryStore::default())];
+        for project_id in 0..10 {
+            let mut stats = ProjectStats::new(config.clone());
+            stats.record_spending(if project_id == 0 { 1_000. } else { 1. });
+            block_on(project_budgets[0].insert(project_id.into(), Box::new(stats)));
+            mock.increment(Duration::from_millis(100));
+        }
+        // the blocked project also remembers its transition, so the size of another one is used
+        let mut entry_size = ENTRY_OVERHEAD;
+        block_on(project_budgets[0].get(1.into(), &mut |tracker| {
+            entry_size += tracker.memory_usage()
+        }));
+        assert!(entry_size > ENTRY_OVERHEAD);
+
+        let (evicted, freed) = evict_projects(&project_budgets, clock.now(), entry_size * 3);
+        assert_eq!((evicted, freed), (3, entry_size * 3));
+        // the blocked project is kept, along with the most recently updated ones
+        let mut remaining = block_on(project_ids(project_budgets[0].as_ref()));
+        remaining.sort();
+        assert_eq!(remaining, [0, 4, 5, 6, 7, 8, 9].map(ProjectKey::from));
+
+        let (evicted, _freed) = evict_projects(&project_budgets, clock.now(), usize::MAX);
+        assert_eq!(evicted, 6);
+        assert_eq!(block_on(project_budgets[0].len()), 1);
+    }
+
+    /// A [`BudgetTracker`] with a bug.
+    #[derive(Debug)]
+    struct PanickingTracker(Arc<BudgetingConfig>);
+
+    impl BudgetTracker for PanickingTracker {
+        fn record(&mut self, _spent: f64) -> bool {
+            false
+        }
+
+        fn check(&mut self) -> bool {
+            false
+        }
+
+        fn cached_check(&self) -> bool {
+            false
+        }
+
+        fn would_exceed(&self, _spent: f64, _now: Instant) -> bool {
+            false
+        }
+
+        fn is_stale(&self, _now: Instant) -> bool {
+            panic!("bug in is_stale");
+        }
+
+        fn report(&self, now: Instant) -> ProjectReport {
+            BudgetTracker::report(&ProjectStats::new(self.0.clone()), now)
+        }
+
+        fn config(&self) -> &Arc<BudgetingConfig> {
+            &self.0
+        }
+
+        fn set_config(&mut self, config: Arc<BudgetingConfig>) {
+            self.0 = config;
+        }
+    }
+
+    #[test]
+    fn test_panicking_pass() {
+        let (clock, mock) = Clock::mock();
+        mock.increment(Duration::from_secs(100));
+        let config = BudgetingConfig::new(
+            Duration::from_secs(10),
+            Duration::from_secs(5),
+            Duration::from_secs(1),
+            10.,
+        );
+
+        let (maintenance, emitted) = Maintenance::for_test(Timer::new(clock.clone()));
+        let workers = ScanWorkers::spawn(2);
+
+        maintenance.run_guarded_pass(clock.now(), &workers);
+        assert!(maintenance.metrics.is_healthy());
+
+        let config = Arc::new(config);
+        let projects: Arc<dyn StateStore> = Arc::new(MemoryStore::default());
+        block_on(projects.insert(1.into(), Box::new(PanickingTracker(config.clone()))));
+        let registered = RegisteredConfig {
+            config,
+            enforcement: Default::default(),
+            projects: projects.clone(),
+            blocked: Default::default(),
+            decisions: Default::default(),
+            oversized_spends: Default::default(),
+            removal: None,
+            id: ConfigId(1),
+        };
+        let configs = &maintenance.configs;
+        configs.update(|configs| configs.insert("test".into(), registered));
+        mock.increment(Duration::from_secs(1));
+        maintenance.run_guarded_pass(clock.now(), &workers);
+
+        assert!(!maintenance.metrics.is_healthy());
+        assert_eq!(maintenance.metrics.failed_passes.get(), 1);
+        assert_eq!(
+            *emitted.lock().unwrap(),
+            [Event::MaintenancePanicked {
+                message: "bug in is_stale".into()
+            }]
+        );
+
+        // the maintenance is healthy again once a pass succeeds
+        block_on(projects.clear());
+        mock.increment(Duration::from_secs(1));
+        maintenance.run_guarded_pass(clock.now(), &workers);
+        assert!(maintenance.metrics.is_healthy());
+    }
+
+    #[test]
+    fn test_clock_jump() {
+        let (clock, mock) = Clock::mock();
+        mock.increment(Duration::from_secs(100));
+        let config = BudgetingConfig::new(
+            Duration::from_secs(10),
+            Duration::from_secs(5),
+            Duration::from_secs(1),
+            10.,
+        )
+        .with_timer(Timer::new(clock.clone()));
+        let config = Arc::new(config);
+
+        let (maintenance, emitted) = Maintenance::for_test(Timer::new(clock.clone()));
+        maintenance.skip_clock_jumps.store(true, Ordering::Relaxed);
+        let workers = ScanWorkers::spawn(2);
+
+        let projects: Arc<dyn StateStore> = Arc::new(MemoryStore::default());
+        let mut stats = ProjectStats::new(config.clone());
+        assert!(stats.record_spending(100.));
+        block_on(projects.insert(1.into(), Box::new(stats)));
+        let registered = RegisteredConfig {
+            config,
+            enforcement: Default::default(),
+            projects: projects.clone(),
+            blocked: Default::default(),
+            decisions: Default::default(),
+            oversized_spends: Default::default(),
+            removal: None,
+            id: ConfigId(1),
+        };
+        registered.blocked.insert(1.into());
+        let blocked = registered.blocked.clone();
+        let configs = &maintenance.configs;
+        configs.update(|configs| configs.insert("test".into(), registered));
+        maintenance.run_pass(clock.now(), &workers);
+
+        // the jumped time is skipped, so the project is still in backoff
+        mock.increment(Duration::from_secs(60));
+        maintenance.run_pass(clock.now(), &workers);
+        assert_eq!(block_on(projects.len()), 1);
+        let mut report = None;
+        block_on(projects.get(1.into(), &mut |tracker| {
+            report = Some(tracker.report(clock.now()))
+        }));
+        let report = report.unwrap();
+        assert!(report.exceeds_budget);
+        assert!(report.backoff_remaining.is_some());
+        assert!(blocked.contains(&1.into()));
+        assert_eq!(
+            emitted.lock().unwrap().drain(..).collect::<Vec<_>>(),
+            [Event::ClockJumped {
+                jumped: Duration::from_secs(60),
+                skipped_projects: 1
+            }]
+        );
+
+        // otherwise, everything covered by the jump expires at once
+        maintenance.skip_clock_jumps.store(false, Ordering::Relaxed);
+        mock.increment(Duration::from_secs(60));
+        maintenance.run_pass(clock.now(), &workers);
+        assert_eq!(block_on(projects.len()), 0);
+        // the removed project is no longer blocked for cached checks
+        assert!(blocked.is_empty());
+        assert_eq!(maintenance.metrics.clock_jumps.get(), 2);
+        assert_eq!(
+            emitted.lock().unwrap()[0],
+            Event::ClockJumped {
+                jumped: Duration::from_secs(60),
+                skipped_projects: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_purge_removed_configs() {
+        let config = Arc::new(BudgetingConfig::new(
+            Duration::from_secs(10),
+            Duration::from_secs(5),
+            Duration::from_secs(1),
+            10.,
+        ));
+        let configs: [(ConfigId, Arc<dyn StateStore>); 2] = [
+            (ConfigId(1), Arc::new(MemoryStore::default())),
+            (ConfigId(2), Arc::new(MemoryStore::default())),
+        ];
+        let project_weights = ProjectWeights::default();
+        for (id, projects) in &configs {
+            block_on(projects.insert(1.into(), config.new_tracker()));
+            let weight = crate::ProjectWeight {
+                weight: 2.,
+                expires_at: config.now() + Duration::from_secs(60),
+            };
+            project_weights.insert((*id, 1.into()), weight);
+        }
+
+        purge_removed_configs(&[], &project_weights);
+        assert_eq!(project_weights.len(), 2);
+        purge_removed_configs(&configs[..1], &project_weights);
+        assert!(block_on(configs[0].1.is_empty()));
+        assert_eq!(block_on(configs[1].1.len()), 1);
+        assert!(!project_weights.contains_key(&(ConfigId(1), 1.into())));
+        assert!(project_weights.contains_key(&(ConfigId(2), 1.into())));
+    }
+}
@@ -0,0 +1,276 @@
+use std::fmt::{Display, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use quanta::Instant;
+
+use crate::maintenance::ScanResult;
+
+/// A monotonically increasing counter.
+#[derive(Debug, Default)]
+pub(crate) struct Counter(AtomicU64);
+
+impl Counter {
+    /// Increments the counter by `value`.
+    pub fn add(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Returns the current value of the counter.
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A gauge holding an arbitrary floating point value.
+#[derive(Debug, Default)]
+pub(crate) struct Gauge(AtomicU64);
+
+impl Gauge {
+    /// Sets the gauge to `value`.
+    pub fn set(&self, value: f64) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Returns the current value of the gauge.
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+/// The number of budget checks of a single config, by their answer.
+#[derive(Debug, Default)]
+pub(crate) struct DecisionCounters {
+    /// The number of checks answering that the project does not exceed its budget.
+    pub allowed: Counter,
+    /// The number of checks answering that the project exceeds its budget.
+    pub blocked: Counter,
+}
+
+impl DecisionCounters {
+    /// Counts the answer of a single budget check.
+    pub fn count(&self, exceeds_budget: bool) {
+        match exceeds_budget {
+            true => self.blocked.add(1),
+            false => self.allowed.add(1),
+        }
+    }
+}
+
+/// Metrics describing the health of the background maintenance thread.
+#[derive(Debug, Default)]
+pub(crate) struct MaintenanceMetrics {
+    /// The number of completed maintenance passes.
+    pub passes: Counter,
+    /// The duration of the last maintenance pass, in seconds.
+    pub pass_duration: Gauge,
+    /// The number of project entries that were scanned in the last maintenance pass.
+    pub entries_scanned: Gauge,
+    /// The number of stale project entries that were removed.
+    pub entries_removed: Counter,
+    /// The number of stale project entries that were removed while exceeding their budget.
+    pub blocked_entries_removed: Counter,
+    /// The approximate memory used by all the project entries, in bytes.
+    pub state_memory: Gauge,
+    /// The number of project entries that were evicted because of the memory limit.
+    pub entries_evicted: Counter,
+    /// The number of acquisitions whose slots were released because they expired.
+    pub expired_acquisitions: Counter,
+    /// The number of times the clock jumped forward between two maintenance passes.
+    pub clock_jumps: Counter,
+    /// The time at which the last maintenance pass was completed.
+    pub last_pass: Mutex<Option<Instant>>,
+    /// The number of maintenance passes that failed because of a panic.
+    pub failed_passes: Counter,
+    /// The time at which the last maintenance pass failed.
+    pub last_failure: Mutex<Option<Instant>>,
+}
+
+impl MaintenanceMetrics {
+    /// Records a successfully completed maintenance pass.
+    pub fn record_pass(&self, started: Instant, finished: Instant, scan: &ScanResult) {
+        self.passes.add(1);
+        self.pass_duration
+            .set(finished.duration_since(started).as_secs_f64());
+        self.entries_scanned.set(scan.scanned as f64);
+        self.entries_removed.add(scan.removed as u64);
+        self.blocked_entries_removed
+            .add(scan.removed_blocked as u64);
+        self.state_memory.set(scan.memory_usage as f64);
+        self.entries_evicted.add(scan.evicted as u64);
+        self.expired_acquisitions
+            .add(scan.expired_acquisitions as u64);
+        *self.last_pass.lock().unwrap() = Some(finished);
+    }
+
+    /// Records a maintenance pass that failed because of a panic.
+    pub fn record_failed_pass(&self, now: Instant) {
+        self.failed_passes.add(1);
+        *self.last_failure.lock().unwrap() = Some(now);
+    }
+
+    /// Returns whether the last maintenance pass succeeded.
+    ///
+    /// This is also the case if no pass has run yet.
+    pub fn is_healthy(&self) -> bool {
+        let last_pass = *self.last_pass.lock().unwrap();
+        match *self.last_failure.lock().unwrap() {
+            Some(last_failure) => last_pass.is_some_and(|last_pass| last_pass > last_failure),
+            None => true,
+        }
+    }
+
+    /// Returns how long ago the last maintenance pass has completed.
+    ///
+    /// Returns [`None`] if no maintenance pass has completed yet.
+    pub fn last_pass_age(&self, now: Instant) -> Option<Duration> {
+        let last_pass = (*self.last_pass.lock().unwrap())?;
+        Some(now.saturating_duration_since(last_pass))
+    }
+}
+
+/// The kind of a metric, as defined by the Prometheus text format.
+#[derive(Clone, Copy, Debug)]
+pub enum MetricKind {
+    /// A monotonically increasing counter.
+    Counter,
+    /// A value that can go up and down.
+    Gauge,
+    /// A distribution of values, written as `_bucket`, `_sum` and `_count` samples.
+    Histogram,
+}
+
+/// A destination of metrics, which are written with [`write_metric_header`] and [`write_sample`].
+///
+/// A [`String`] collects the metrics in the Prometheus text format, while other writers can record
+/// them without going through the text format.
+pub trait MetricsWriter {
+    /// Starts a metric, which is followed by its samples.
+    fn header(&mut self, name: &str, kind: MetricKind, help: &str);
+
+    /// Adds a single sample of the current metric with the given labels.
+    ///
+    /// The samples of histograms are named with a `_bucket`, `_sum` or `_count` suffix.
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: &dyn Display);
+}
+
+impl MetricsWriter for String {
+    fn header(&mut self, name: &str, kind: MetricKind, help: &str) {
+        let kind = match kind {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+            MetricKind::Histogram => "histogram",
+        };
+        let _ = writeln!(self, "# HELP {name} {help}");
+        let _ = writeln!(self, "# TYPE {name} {kind}");
+    }
+
+    /// Label values are escaped as needed.
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: &dyn Display) {
+        self.push_str(name);
+        if !labels.is_empty() {
+            self.push('{');
+            for (i, (label, label_value)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.push(',');
+                }
+                let _ = write!(self, "{label}=\"");
+                for c in label_value.chars() {
+                    match c {
+                        '\\' => self.push_str("\\\\"),
+                        '"' => self.push_str("\\\""),
+                        '\n' => self.push_str("\\n"),
+                        c => self.push(c),
+                    }
+                }
+                self.push('"');
+            }
+            self.push('}');
+        }
+        let _ = writeln!(self, " {value}");
+    }
+}
+
+/// Writes a single metric, like in the Prometheus text format.
+///
+/// This can be used to append additional metrics to the output of
+/// [`Service::render_metrics`](crate::Service::render_metrics).
+pub fn write_metric(
+    out: &mut impl MetricsWriter,
+    name: &str,
+    kind: MetricKind,
+    help: &str,
+    value: impl Display,
+) {
+    write_metric_header(out, name, kind, help);
+    write_sample(out, name, &[], value);
+}
+
+/// Writes the `HELP` and `TYPE` header of a metric, like in the Prometheus text format.
+///
+/// This should be followed by one or more [`write_sample`] calls.
+pub fn write_metric_header(out: &mut impl MetricsWriter, name: &str, kind: MetricKind, help: &str) {
+    out.header(name, kind, help);
+}
+
+/// Writes a single sample of a metric with the given labels.
+pub fn write_sample(
+    out: &mut impl MetricsWriter,
+    name: &str,
+    labels: &[(&str, &str)],
+    value: impl Display,
+) {
+    out.sample(name, labels, &value);
+}
+
+#[cfg(test)]
+mod tests {
+    use quanta::Clock;
+
+    use super::*;
+
+    #[test]
+    fn test_maintenance_metrics() {
+        let (clock, mock) = Clock::mock();
+        let metrics = MaintenanceMetrics::default();
+        assert_eq!(metrics.last_pass_age(clock.now()), None);
+
+        let started = clock.now();
+        mock.increment(Duration::from_millis(20));
+        let scan = |scanned, removed| ScanResult {
+            scanned,
+            removed,
+            removed_blocked: 1,
+            ..Default::default()
+        };
+        metrics.record_pass(started, clock.now(), &scan(10, 3));
+        metrics.record_pass(started, clock.now(), &scan(7, 2));
+
+        assert_eq!(metrics.passes.get(), 2);
+        assert_eq!(metrics.pass_duration.get(), 0.02);
+        assert_eq!(metrics.entries_scanned.get(), 7.);
+        assert_eq!(metrics.entries_removed.get(), 5);
+        assert_eq!(metrics.blocked_entries_removed.get(), 2);
+
+        mock.increment(Duration::from_secs(1));
+        assert_eq!(
+            metrics.last_pass_age(clock.now()),
+            Some(Duration::from_secs(1))
+        );
+    }
+
+    #[test]
+    fn test_write_metric() {
+        let mut out = String::new();
+        write_metric(&mut out, "some_total", MetricKind::Counter, "Some help.", 3);
+        assert_eq!(
+            out,
+            "# HELP some_total Some help.\n# TYPE some_total counter\nsome_total 3\n"
+        );
+
+        let mut out = String::new();
+        write_sample(&mut out, "some", &[("a", "1"), ("b", "\"\\\n")], 2.5);
+        assert_eq!(out, "some{a=\"1\",b=\"\\\"\\\\\\n\"} 2.5\n");
+    }
+}
@@ -0,0 +1,463 @@
+//! Exports traces of the HTTP requests and the metrics to an OpenTelemetry collector via OTLP, with the `otel`
+//! feature.
+//!
+//! The exporters of the OpenTelemetry SDK are configured by the standard `OTEL_*` environment variables, and each
+//! signal is only exported if an OTLP endpoint is given for it. Every HTTP request is traced as a server span, which
+//! continues the trace of an incoming `traceparent` header. The metrics are the ones served by `/metrics`, which are
+//! observed by instruments of the same name right when the SDK collects them.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::extract::{MatchedPath, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use opentelemetry::metrics::{AsyncInstrument, Meter, MeterProvider};
+use opentelemetry::propagation::TextMapPropagator;
+use opentelemetry::trace::{Span, SpanKind, Status, Tracer};
+use opentelemetry::{global, KeyValue};
+use opentelemetry_http::HeaderExtractor;
+use opentelemetry_otlp::{MetricExporter, Protocol, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::metrics::SdkMeterProvider;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use peanutbutter::{MetricKind, MetricsWriter};
+
+use crate::request_id::RequestId;
+
+/// The name of the tracer and meter, and the default `service.name`.
+const NAME: &str = "peanutbutter";
+
+/// How long rendered metrics are reused by the other instruments.
+///
+/// The SDK observes all instruments right after each other, so the metrics are rendered once per collection.
+const SNAPSHOT_TTL: Duration = Duration::from_secs(1);
+
+/// The protocols of the OTLP exporters per signal, as configured by the environment.
+#[derive(Debug, PartialEq)]
+struct Exports {
+    traces: Option<Protocol>,
+    metrics: Option<Protocol>,
+}
+
+impl Exports {
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        if var("OTEL_SDK_DISABLED").is_some_and(|v| v.trim() == "true") {
+            return Ok(Self {
+                traces: None,
+                metrics: None,
+            });
+        }
+        Ok(Self {
+            traces: signal_protocol(&var, "TRACES")?,
+            metrics: signal_protocol(&var, "METRICS")?,
+        })
+    }
+}
+
+/// Returns the protocol a signal is exported with, or `None` if it is not exported.
+///
+/// Signal-specific variables take precedence over the general ones.
+fn signal_protocol(
+    var: &impl Fn(&str) -> Option<String>,
+    signal: &str,
+) -> Result<Option<Protocol>, String> {
+    let exporter = var(&format!("OTEL_{signal}_EXPORTER")).unwrap_or("otlp".into());
+    match exporter.trim() {
+        "otlp" => {}
+        "none" => return Ok(None),
+        other => return Err(format!("OpenTelemetry exporter `{other}` is not supported")),
+    }
+    let signal_var = |name: &str| var(&name.replace("OTLP_", &format!("OTLP_{signal}_")));
+    let endpoint = signal_var("OTEL_EXPORTER_OTLP_ENDPOINT");
+    if endpoint
+        .or_else(|| var("OTEL_EXPORTER_OTLP_ENDPOINT"))
+        .is_none()
+    {
+        return Ok(None);
+    }
+
+    let protocol = signal_var("OTEL_EXPORTER_OTLP_PROTOCOL");
+    match protocol
+        .or_else(|| var("OTEL_EXPORTER_OTLP_PROTOCOL"))
+        .as_deref()
+    {
+        None | Some("http/protobuf") => Ok(Some(Protocol::HttpBinary)),
+        Some("http/json") => Ok(Some(Protocol::HttpJson)),
+        Some(other) => Err(format!(
+            "OTLP protocol `{other}` is not supported, only `http/protobuf` and `http/json` are"
+        )),
+    }
+}
+
+/// The OpenTelemetry providers, which export the remaining spans and metrics when dropped.
+#[derive(Debug)]
+pub struct Telemetry {
+    tracer_provider: Option<SdkTracerProvider>,
+    meter_provider: Option<SdkMeterProvider>,
+}
+
+impl Telemetry {
+    /// Sets up the exporters configured by the `OTEL_*` environment variables.
+    ///
+    /// The spans of [`trace_request`] are exported by the global tracer provider, and the metrics written by
+    /// `write_metrics` are observed at every collection. Returns `None` if neither is exported.
+    pub fn from_env(
+        write_metrics: impl Fn(&mut Observations) + Send + Sync + 'static,
+    ) -> Result<Option<Self>, String> {
+        let var = |name: &str| std::env::var(name).ok();
+        let exports = Exports::from_vars(var)?;
+        if exports.traces.is_none() && exports.metrics.is_none() {
+            return Ok(None);
+        }
+
+        // The default resource includes `OTEL_SERVICE_NAME` and `OTEL_RESOURCE_ATTRIBUTES`.
+        let mut resource = Resource::builder();
+        let attributes = var("OTEL_RESOURCE_ATTRIBUTES").unwrap_or_default();
+        if var("OTEL_SERVICE_NAME").is_none() && !attributes.contains("service.name=") {
+            resource = resource.with_service_name(NAME);
+        }
+        let resource = resource.build();
+
+        let tracer_provider = match exports.traces {
+            Some(protocol) => {
+                let exporter = SpanExporter::builder()
+                    .with_http()
+                    .with_protocol(protocol)
+                    .build()
+                    .map_err(|err| format!("Failed to set up the OTLP span exporter: {err}"))?;
+                let provider = SdkTracerProvider::builder()
+                    .with_batch_exporter(exporter)
+                    .with_resource(resource.clone())
+                    .build();
+                global::set_tracer_provider(provider.clone());
+                Some(provider)
+            }
+            None => None,
+        };
+
+        let meter_provider = match exports.metrics {
+            Some(protocol) => {
+                let exporter = MetricExporter::builder()
+                    .with_http()
+                    .with_protocol(protocol)
+                    .build()
+                    .map_err(|err| format!("Failed to set up the OTLP metric exporter: {err}"))?;
+                let provider = SdkMeterProvider::builder()
+                    .with_periodic_exporter(exporter)
+                    .with_resource(resource)
+                    .build();
+                register_instruments(&provider.meter(NAME), write_metrics);
+                Some(provider)
+            }
+            None => None,
+        };
+
+        Ok(Some(Self {
+            tracer_provider,
+            meter_provider,
+        }))
+    }
+
+    /// Describes the exported signals, for logging.
+    pub fn signals(&self) -> &'static str {
+        match (&self.tracer_provider, &self.meter_provider) {
+            (Some(_), Some(_)) => "traces and metrics",
+            (Some(_), None) => "traces",
+            _ => "metrics",
+        }
+    }
+}
+
+/// Traces a request as a server span, continuing the trace of its `traceparent` header.
+///
+/// Server errors mark the span as failed.
+pub async fn trace_request(request: Request, next: Next) -> Response {
+    let parent = TraceContextPropagator::new().extract(&HeaderExtractor(request.headers()));
+    let method = request.method().to_string();
+    let route = (request.extensions().get::<MatchedPath>()).map(|path| path.as_str().to_owned());
+    let name = match &route {
+        Some(route) => format!("{method} {route}"),
+        None => method.clone(),
+    };
+
+    let mut attributes = vec![
+        KeyValue::new("http.request.method", method),
+        KeyValue::new("url.path", request.uri().path().to_owned()),
+    ];
+    if let Some(route) = route {
+        attributes.push(KeyValue::new("http.route", route));
+    }
+    if let Some(request_id) = request.extensions().get::<RequestId>() {
+        attributes.push(KeyValue::new(
+            "peanutbutter.request_id",
+            request_id.to_string(),
+        ));
+    }
+    let tracer = global::tracer(NAME);
+    let mut span = (tracer.span_builder(name))
+        .with_kind(SpanKind::Server)
+        .with_attributes(attributes)
+        .start_with_context(&tracer, &parent);
+
+    let response = next.run(request).await;
+    let status = response.status();
+    span.set_attribute(KeyValue::new(
+        "http.response.status_code",
+        i64::from(status.as_u16()),
+    ));
+    if status.is_server_error() {
+        span.set_status(Status::error(status.to_string()));
+    }
+    span.end();
+    response
+}
+
+/// The samples of the metrics per sample name, with the instruments they are observed by.
+#[derive(Debug, Default)]
+pub struct Observations {
+    /// The name, kind and description of every instrument.
+    instruments: Vec<(String, MetricKind, String)>,
+    samples: HashMap<String, Vec<(Vec<KeyValue>, f64)>>,
+}
+
+impl MetricsWriter for Observations {
+    /// Histograms are split into one instrument per suffix, with the `le` label of the buckets as attribute.
+    fn header(&mut self, name: &str, kind: MetricKind, help: &str) {
+        let suffixes: &[&str] = match kind {
+            MetricKind::Histogram => &["_bucket", "_sum", "_count"],
+            _ => &[""],
+        };
+        for suffix in suffixes {
+            (self.instruments).push((format!("{name}{suffix}"), kind, help.into()));
+        }
+    }
+
+    /// OTLP/JSON can not represent the non-finite values which Prometheus allows, so they are skipped.
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: &dyn Display) {
+        let Ok(value) = value.to_string().parse::<f64>() else {
+            return;
+        };
+        if !value.is_finite() {
+            return;
+        }
+        let attributes = labels
+            .iter()
+            .map(|(label, value)| KeyValue::new(label.to_string(), value.to_string()))
+            .collect();
+        let samples = self.samples.entry(name.into()).or_default();
+        samples.push((attributes, value));
+    }
+}
+
+/// The metrics of the latest collection, which are shared by all instruments.
+struct Snapshot<F> {
+    write_metrics: F,
+    latest: Mutex<Option<(Instant, Arc<Observations>)>>,
+}
+
+impl<F: Fn(&mut Observations)> Snapshot<F> {
+    /// Returns the current metrics, which are rendered again once they are older than [`SNAPSHOT_TTL`].
+    fn observe(&self, now: Instant) -> Arc<Observations> {
+        let mut latest = self.latest.lock().unwrap();
+        match &*latest {
+            Some((rendered, observations)) if now.duration_since(*rendered) < SNAPSHOT_TTL => {
+                observations.clone()
+            }
+            _ => {
+                let mut observations = Observations::default();
+                (self.write_metrics)(&mut observations);
+                let observations = Arc::new(observations);
+                *latest = Some((now, observations.clone()));
+                observations
+            }
+        }
+    }
+}
+
+/// Registers an observable instrument for every metric written by `write_metrics`.
+///
+/// Counters are observed as cumulative counters, and everything else as gauges. The only histogram describes
+/// the currently blocked projects, so its buckets can go down as well.
+fn register_instruments(
+    meter: &Meter,
+    write_metrics: impl Fn(&mut Observations) + Send + Sync + 'static,
+) {
+    let mut discovered = Observations::default();
+    write_metrics(&mut discovered);
+    let snapshot = Arc::new(Snapshot {
+        write_metrics,
+        latest: Mutex::new(None),
+    });
+
+    for (name, kind, help) in discovered.instruments {
+        let snapshot = snapshot.clone();
+        let sample_name = name.clone();
+        let callback = move |observer: &dyn AsyncInstrument<f64>| {
+            let observations = snapshot.observe(Instant::now());
+            let samples = observations.samples.get(&sample_name);
+            for (attributes, value) in samples.into_iter().flatten() {
+                observer.observe(*value, attributes);
+            }
+        };
+        match kind {
+            MetricKind::Counter => {
+                let counter = meter.f64_observable_counter(name);
+                counter
+                    .with_description(help)
+                    .with_callback(callback)
+                    .build();
+            }
+            MetricKind::Gauge | MetricKind::Histogram => {
+                let gauge = meter.f64_observable_gauge(name);
+                gauge.with_description(help).with_callback(callback).build();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry_sdk::error::OTelSdkResult;
+    use opentelemetry_sdk::metrics::data::{AggregatedMetrics, MetricData, ResourceMetrics};
+    use opentelemetry_sdk::metrics::exporter::PushMetricExporter;
+    use opentelemetry_sdk::metrics::{PeriodicReader, Temporality};
+    use peanutbutter::{write_metric, write_metric_header, write_sample};
+
+    use super::*;
+
+    fn exports(vars: &[(&str, &str)]) -> Result<Exports, String> {
+        let vars: HashMap<_, _> = vars.iter().copied().collect();
+        Exports::from_vars(|name| vars.get(name).map(|v| v.to_string()))
+    }
+
+    #[test]
+    fn test_exports() {
+        let none = Exports {
+            traces: None,
+            metrics: None,
+        };
+        assert_eq!(exports(&[]).unwrap(), none);
+        let endpoint = ("OTEL_EXPORTER_OTLP_ENDPOINT", "http://collector:4318");
+        let disabled = ("OTEL_SDK_DISABLED", "true");
+        assert_eq!(exports(&[endpoint, disabled]).unwrap(), none);
+
+        let both = Exports {
+            traces: Some(Protocol::HttpBinary),
+            metrics: Some(Protocol::HttpBinary),
+        };
+        assert_eq!(exports(&[endpoint]).unwrap(), both);
+        let no_traces = ("OTEL_TRACES_EXPORTER", "none");
+        let metrics = exports(&[endpoint, no_traces]).unwrap();
+        assert_eq!(metrics.traces, None);
+        assert_eq!(metrics.metrics, Some(Protocol::HttpBinary));
+
+        // the signal-specific variables take precedence
+        let traces = ("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT", "https://traces:4318");
+        let traces = exports(&[traces]).unwrap();
+        assert_eq!(traces.traces, Some(Protocol::HttpBinary));
+        assert_eq!(traces.metrics, None);
+        let grpc = ("OTEL_EXPORTER_OTLP_PROTOCOL", "grpc");
+        assert!(exports(&[endpoint, grpc]).is_err());
+        let json = ("OTEL_EXPORTER_OTLP_METRICS_PROTOCOL", "http/json");
+        let metrics = exports(&[endpoint, grpc, json, no_traces]).unwrap();
+        assert_eq!(metrics.metrics, Some(Protocol::HttpJson));
+        let prometheus = ("OTEL_METRICS_EXPORTER", "prometheus");
+        assert!(exports(&[endpoint, prometheus]).is_err());
+    }
+
+    /// The data points of the last export by metric name.
+    type Points = HashMap<String, Vec<(Vec<KeyValue>, f64)>>;
+
+    #[derive(Clone, Default)]
+    struct TestExporter(Arc<Mutex<Points>>);
+
+    impl PushMetricExporter for TestExporter {
+        async fn export(&self, metrics: &ResourceMetrics) -> OTelSdkResult {
+            let mut points = self.0.lock().unwrap();
+            points.clear();
+            for metric in metrics.scope_metrics().flat_map(|scope| scope.metrics()) {
+                let data = match metric.data() {
+                    AggregatedMetrics::F64(MetricData::Sum(sum)) => {
+                        assert!(sum.is_monotonic());
+                        (sum.data_points())
+                            .map(|p| (p.attributes().cloned().collect(), p.value()))
+                            .collect()
+                    }
+                    AggregatedMetrics::F64(MetricData::Gauge(gauge)) => (gauge.data_points())
+                        .map(|p| (p.attributes().cloned().collect(), p.value()))
+                        .collect(),
+                    other => panic!("unexpected data {other:?}"),
+                };
+                points.insert(metric.name().into(), data);
+            }
+            Ok(())
+        }
+
+        fn force_flush(&self) -> OTelSdkResult {
+            Ok(())
+        }
+
+        fn shutdown_with_timeout(&self, _timeout: Duration) -> OTelSdkResult {
+            Ok(())
+        }
+
+        fn temporality(&self) -> Temporality {
+            Temporality::Cumulative
+        }
+    }
+
+    fn write_test_metrics(out: &mut impl MetricsWriter, checks: u64) {
+        write_metric(
+            out,
+            "pb_checks_total",
+            MetricKind::Counter,
+            "Checks.",
+            checks,
+        );
+        write_metric_header(out, "pb_pressure", MetricKind::Gauge, "Pressure.");
+        write_sample(out, "pb_pressure", &[("config", "a")], 0.5);
+        write_sample(out, "pb_pressure", &[("config", "b")], f64::NAN);
+        write_metric_header(out, "pb_blocked", MetricKind::Histogram, "Blocked.");
+        write_sample(out, "pb_blocked_bucket", &[("le", "1")], 2);
+        write_sample(out, "pb_blocked_bucket", &[("le", "+Inf")], 3);
+        write_sample(out, "pb_blocked_sum", &[], 4.5);
+        write_sample(out, "pb_blocked_count", &[], 3);
+    }
+
+    #[test]
+    fn test_instruments() {
+        let exporter = TestExporter::default();
+        let reader = PeriodicReader::builder(exporter.clone()).build();
+        let provider = SdkMeterProvider::builder().with_reader(reader).build();
+        let checks = Arc::new(Mutex::new(12));
+        let observed = checks.clone();
+        register_instruments(&provider.meter(NAME), move |out| {
+            write_test_metrics(out, *observed.lock().unwrap())
+        });
+
+        provider.force_flush().unwrap();
+        let points = exporter.0.lock().unwrap().clone();
+        assert_eq!(points["pb_checks_total"], [(vec![], 12.)]);
+        // the non-finite sample is skipped
+        let config = KeyValue::new("config", "a");
+        assert_eq!(points["pb_pressure"], [(vec![config], 0.5)]);
+        let mut buckets = points["pb_blocked_bucket"].clone();
+        buckets.sort_by(|a, b| a.1.total_cmp(&b.1));
+        let le = |le| vec![KeyValue::new("le", le)];
+        assert_eq!(buckets, [(le("1"), 2.), (le("+Inf"), 3.)]);
+        assert_eq!(points["pb_blocked_sum"], [(vec![], 4.5)]);
+        assert_eq!(points["pb_blocked_count"], [(vec![], 3.)]);
+        assert_eq!(points.len(), 5);
+
+        // later collections render the metrics again
+        *checks.lock().unwrap() = 15;
+        std::thread::sleep(SNAPSHOT_TTL);
+        provider.force_flush().unwrap();
+        let points = exporter.0.lock().unwrap().clone();
+        assert_eq!(points["pb_checks_total"], [(vec![], 15.)]);
+    }
+}
@@ -0,0 +1,77 @@
+//! Periodically reloads the [`DecisionOverride`]s of projects from a local file.
+//!
+//! The file is expected to contain a JSON object keyed by config name, mapping project ids to
+//! `"force_block"` or `"force_allow"`, like `{"symbolication-native": {"1234": "force_block"}}`.
+//! Projects identified by UUID are keyed by their UUID instead.
+//! This allows pushing decisions via config management in an emergency, even if the admin API is unreachable.
+//! A missing file means that there are no overrides, while an invalid file keeps the previous ones.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use peanutbutter::{DecisionOverride, ProjectKey, Service};
+
+type Overrides = HashMap<String, HashMap<ProjectKey, DecisionOverride>>;
+
+/// Reads the overrides from the file at `path`, which has none if it does not exist.
+fn read(path: &Path) -> Result<Overrides, String> {
+    match std::fs::read_to_string(path) {
+        Ok(file) => serde_json::from_str(&file).map_err(|err| err.to_string()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Overrides::new()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Applies the `overrides` to the [`Service`], if they changed.
+fn apply(service: &Service, overrides: Overrides) {
+    if service.decision_overrides() != overrides {
+        let count: usize = overrides.values().map(HashMap::len).sum();
+        println!("Applying {count} decision overrides");
+        service.set_decision_overrides(overrides);
+    }
+}
+
+/// Reloads the overrides from the file at `path` every `interval` forever, and applies them to the [`Service`].
+pub async fn run(path: PathBuf, interval: Duration, service: Arc<Service>) {
+    loop {
+        match read(&path) {
+            Ok(overrides) => apply(&service, overrides),
+            Err(err) => println!(
+                "Failed to read decision overrides from `{}`: {err}",
+                path.display()
+            ),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_overrides() {
+        let path = std::env::temp_dir().join("peanutbutter-test-overrides.json");
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(read(&path), Ok(Overrides::new()));
+
+        let service = Service::new();
+        let uuid = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+        let file = format!(r#"{{"a": {{"1": "force_block", "{uuid}": "force_allow"}}}}"#);
+        std::fs::write(&path, file).unwrap();
+        apply(&service, read(&path).unwrap());
+        let overrides = service.decision_overrides();
+        assert_eq!(overrides["a"][&1.into()], DecisionOverride::ForceBlock);
+        let uuid = ProjectKey::parse_uuid(uuid).unwrap();
+        assert_eq!(overrides["a"][&uuid], DecisionOverride::ForceAllow);
+
+        std::fs::write(&path, r#"{"a": {"1": "block"}}"#).unwrap();
+        assert!(read(&path).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read(&path), Ok(Overrides::new()));
+    }
+}
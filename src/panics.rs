@@ -0,0 +1,174 @@
+//! A process-wide panic hook, which exits the process instead of leaving it running half-dead.
+//!
+//! The [`Supervisor`](crate::supervisor::Supervisor) shuts down the server once a component fails, but a panic
+//! elsewhere, like in a request handler on a tokio worker, would otherwise only kill that task and go unnoticed.
+//! The hook runs before any panic is caught, so that panicking components exit with the same distinct status.
+//! Panicking passes of the maintenance thread, including its scan workers, are the exception,
+//! as those are recovered from, and reported via the metrics and the readiness check instead.
+
+use std::io::{self, Write};
+use std::panic::PanicHookInfo;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+use peanutbutter::{Service, MAINTENANCE_THREAD};
+
+/// The status the process exits with after a panic, which is `EX_SOFTWARE` of `sysexits.h`.
+pub const EXIT_CODE: i32 = 70;
+
+/// How long the hook waits for the final snapshot of blocked projects before exiting anyway.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Installs the panic hook, which flushes the `service` and removes the optional PID file before exiting.
+///
+/// The panic itself is still reported by the default hook, including a backtrace with `RUST_BACKTRACE=1`.
+pub fn install(pid_file: Option<PathBuf>, service: Arc<Service>) {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        let thread = std::thread::current();
+        if !exits_on_panic(thread.name()) {
+            return;
+        }
+
+        eprintln!("{}", describe(thread.name(), info));
+        flush(&service);
+        if let Some(path) = &pid_file {
+            let _ = std::fs::remove_file(path);
+        }
+        let _ = io::stdout().flush();
+        let _ = io::stderr().flush();
+        std::process::exit(EXIT_CODE);
+    }));
+}
+
+/// Writes the snapshot of blocked projects one last time, just like on a graceful shutdown.
+///
+/// The panicking thread might still hold locks of the `service`, so the snapshot is written by another thread,
+/// which is given up on after the [`FLUSH_TIMEOUT`]. Only the first panic flushes, so that a panic
+/// while flushing does not flush again.
+fn flush(service: &Arc<Service>) {
+    static FLUSHED: AtomicBool = AtomicBool::new(false);
+    if FLUSHED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    let service = service.clone();
+    std::thread::spawn(move || {
+        service.flush_blocked_snapshot();
+        let _ = sender.send(());
+    });
+    let _ = receiver.recv_timeout(FLUSH_TIMEOUT);
+}
+
+/// Returns whether a panic on the thread with the given name exits the process.
+///
+/// The scan workers of the maintenance thread are named after it, with a suffix.
+fn exits_on_panic(thread: Option<&str>) -> bool {
+    let suffix = thread.and_then(|thread| thread.strip_prefix(MAINTENANCE_THREAD));
+    !suffix.is_some_and(|suffix| suffix.is_empty() || suffix.starts_with('-'))
+}
+
+/// Describes the panic, which is logged right before exiting.
+fn describe(thread: Option<&str>, info: &PanicHookInfo<'_>) -> String {
+    let thread = thread.unwrap_or("<unnamed>");
+    let location = info
+        .location()
+        .map_or("unknown location".into(), |l| l.to_string());
+    format!("Exiting with status {EXIT_CODE} after a panic in thread `{thread}` at {location}")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use peanutbutter::{BudgetTracker, BudgetingConfig, Instant, MemoryStore, ProjectReport};
+    use peanutbutter::{ProjectStats, StateStore};
+
+    use super::*;
+
+    #[test]
+    fn test_exits_on_panic() {
+        assert!(exits_on_panic(None));
+        assert!(exits_on_panic(Some("tokio-runtime-worker")));
+        assert!(exits_on_panic(Some("peanutbutter-maintenancex")));
+        assert!(!exits_on_panic(Some(MAINTENANCE_THREAD)));
+        assert!(!exits_on_panic(Some("peanutbutter-maintenance-1")));
+    }
+
+    /// A [`BudgetTracker`] with a bug, which panics when it is scanned by the maintenance.
+    #[derive(Debug)]
+    struct PanickingTracker(Arc<BudgetingConfig>);
+
+    impl BudgetTracker for PanickingTracker {
+        fn record(&mut self, _spent: f64) -> bool {
+            false
+        }
+
+        fn check(&mut self) -> bool {
+            false
+        }
+
+        fn cached_check(&self) -> bool {
+            false
+        }
+
+        fn would_exceed(&self, _spent: f64, _now: Instant) -> bool {
+            false
+        }
+
+        fn is_stale(&self, _now: Instant) -> bool {
+            panic!("bug in is_stale");
+        }
+
+        fn report(&self, now: Instant) -> ProjectReport {
+            BudgetTracker::report(&ProjectStats::new(self.0.clone()), now)
+        }
+
+        fn config(&self) -> &Arc<BudgetingConfig> {
+            &self.0
+        }
+
+        fn set_config(&mut self, config: Arc<BudgetingConfig>) {
+            self.0 = config;
+        }
+    }
+
+    #[test]
+    fn test_panicking_scan_does_not_exit() {
+        // The hook is process-wide, so it only looks at the panics of this test.
+        let exits = Arc::new(Mutex::new(vec![]));
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new({
+            let exits = exits.clone();
+            move |info| {
+                if info.payload().downcast_ref::<&str>() == Some(&"bug in is_stale") {
+                    let exits_on_panic = exits_on_panic(std::thread::current().name());
+                    exits.lock().unwrap().push(exits_on_panic);
+                }
+            }
+        }));
+
+        let service = Service::new();
+        let second = Duration::from_secs(1);
+        let config = BudgetingConfig::new(second, second, second, 10.);
+        let store = MemoryStore::default();
+        let tracker = PanickingTracker(Arc::new(config.clone()));
+        pollster::block_on(store.insert(1.into(), Box::new(tracker)));
+        (service.try_add_config_with_store("test", config, Arc::new(store))).unwrap();
+        let started = std::time::Instant::now();
+        while service.maintenance_healthy() {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        service.stop_maintenance();
+        std::panic::set_hook(default_hook);
+
+        let exits = exits.lock().unwrap();
+        assert!(!exits.is_empty());
+        assert!(!exits.contains(&true));
+    }
+}
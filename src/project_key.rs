@@ -0,0 +1,171 @@
+use std::fmt;
+
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Identifies a project within a config, either by its numeric project id or by a UUID.
+///
+/// Both share a single 128-bit key space: Numeric project ids are the lowest 2^64 keys,
+/// while UUIDs are keyed by their full 128-bit value. Any UUID other than the nil UUID has its
+/// version bits set within its upper half, so the two kinds of keys never collide,
+/// and clients do not have to hash UUIDs down to a (lossy) numeric id.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ProjectKey(u128);
+
+impl ProjectKey {
+    /// Creates the key of a project identified by a 128-bit UUID.
+    ///
+    /// Returns [`None`] for UUIDs whose upper half is zero, like the nil UUID,
+    /// as those overlap with the numeric project ids.
+    pub fn from_uuid(uuid: u128) -> Option<Self> {
+        (uuid > u64::MAX as u128).then_some(Self(uuid))
+    }
+
+    /// Parses a UUID in its hyphenated (`67e55044-10b1-426f-9247-bb680e5fe0c8`) or simple form,
+    /// see [`ProjectKey::from_uuid`].
+    pub fn parse_uuid(uuid: &str) -> Option<Self> {
+        let hyphenated = uuid.len() == 36
+            && (uuid.char_indices()).all(|(idx, c)| matches!(idx, 8 | 13 | 18 | 23) == (c == '-'));
+        let simple: String = match hyphenated {
+            true => uuid.chars().filter(|&c| c != '-').collect(),
+            false => uuid.into(),
+        };
+        if simple.len() != 32 || !simple.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        Self::from_uuid(u128::from_str_radix(&simple, 16).ok()?)
+    }
+
+    /// Parses a numeric project id, or a UUID as in [`ProjectKey::parse_uuid`].
+    pub fn parse(project: &str) -> Option<Self> {
+        match project.parse::<u64>() {
+            Ok(project_id) => Some(project_id.into()),
+            Err(_) => Self::parse_uuid(project),
+        }
+    }
+
+    /// Returns the numeric project id, or [`None`] if the project is identified by a UUID.
+    pub fn project_id(self) -> Option<u64> {
+        u64::try_from(self.0).ok()
+    }
+
+    /// Returns the full 128-bit key, which identifies the project in the on-disk map of a
+    /// [`SpillStore`](crate::SpillStore).
+    #[cfg(feature = "spill")]
+    pub(crate) fn to_bits(self) -> u128 {
+        self.0
+    }
+}
+
+impl From<u64> for ProjectKey {
+    fn from(project_id: u64) -> Self {
+        Self(project_id.into())
+    }
+}
+
+/// Formats numeric project ids as numbers, and UUIDs in their hyphenated form.
+impl fmt::Display for ProjectKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(project_id) = self.project_id() {
+            return write!(f, "{project_id}");
+        }
+        let hex = format!("{:032x}", self.0);
+        let (a, rest) = hex.split_at(8);
+        let (b, rest) = rest.split_at(4);
+        let (c, rest) = rest.split_at(4);
+        let (d, e) = rest.split_at(4);
+        write!(f, "{a}-{b}-{c}-{d}-{e}")
+    }
+}
+
+/// Serializes numeric project ids as numbers, and UUIDs as hyphenated strings.
+impl Serialize for ProjectKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.project_id() {
+            Some(project_id) => serializer.serialize_u64(project_id),
+            None => serializer.collect_str(self),
+        }
+    }
+}
+
+/// Deserializes numeric project ids from numbers or strings, and UUIDs from strings, see [`ProjectKey::parse`].
+///
+/// Accepting strings allows keying maps by project, as JSON object keys are always strings.
+impl<'de> Deserialize<'de> for ProjectKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ProjectKeyVisitor;
+
+        impl Visitor<'_> for ProjectKeyVisitor {
+            type Value = ProjectKey;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a numeric project id or a UUID")
+            }
+
+            fn visit_u64<E: de::Error>(self, project_id: u64) -> Result<Self::Value, E> {
+                Ok(project_id.into())
+            }
+
+            fn visit_i64<E: de::Error>(self, project_id: i64) -> Result<Self::Value, E> {
+                let project_id = u64::try_from(project_id)
+                    .map_err(|_| E::invalid_value(de::Unexpected::Signed(project_id), &self))?;
+                Ok(project_id.into())
+            }
+
+            fn visit_str<E: de::Error>(self, project: &str) -> Result<Self::Value, E> {
+                ProjectKey::parse(project)
+                    .ok_or_else(|| E::invalid_value(de::Unexpected::Str(project), &self))
+            }
+        }
+
+        deserializer.deserialize_any(ProjectKeyVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_project_key() {
+        let uuid = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+        let key = ProjectKey::parse_uuid(uuid).unwrap();
+        assert_eq!(key.to_string(), uuid);
+        assert_eq!(key.project_id(), None);
+        assert_eq!(
+            ProjectKey::parse_uuid("67E5504410B1426F9247BB680E5FE0C8"),
+            Some(key)
+        );
+        assert_eq!(serde_json::to_string(&key).unwrap(), format!("\"{uuid}\""));
+        assert_eq!(ProjectKey::parse(uuid), Some(key));
+        let deserialized: ProjectKey = serde_json::from_str(&format!("\"{uuid}\"")).unwrap();
+        assert_eq!(deserialized, key);
+
+        let numeric = ProjectKey::from(42);
+        assert_eq!(numeric.project_id(), Some(42));
+        assert_eq!(serde_json::to_string(&numeric).unwrap(), "42");
+        assert_ne!(ProjectKey::from(u64::MAX), key);
+        assert_eq!(ProjectKey::parse("42"), Some(numeric));
+        assert_eq!(serde_json::from_str::<ProjectKey>("42").unwrap(), numeric);
+        let keyed: std::collections::HashMap<ProjectKey, u64> =
+            serde_json::from_str(&format!(r#"{{"42": 1, "{uuid}": 2}}"#)).unwrap();
+        assert_eq!(keyed[&numeric], 1);
+        assert_eq!(keyed[&key], 2);
+        assert!(serde_json::from_str::<ProjectKey>("-1").is_err());
+        assert!(serde_json::from_str::<ProjectKey>("\"project\"").is_err());
+
+        // UUIDs overlapping with the numeric project ids are rejected
+        assert_eq!(ProjectKey::from_uuid(42), None);
+        assert_eq!(
+            ProjectKey::parse_uuid("00000000-0000-0000-0000-000000000000"),
+            None
+        );
+        for invalid in [
+            "",
+            "67e55044-10b1-426f-9247-bb680e5fe0c",
+            "+7e5504410b1426f9247bb680e5fe0c8",
+        ] {
+            assert_eq!(ProjectKey::parse_uuid(invalid), None);
+        }
+    }
+}
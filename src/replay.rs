@@ -0,0 +1,180 @@
+//! Replays a [captured](crate::capture) request log against a fresh service with a virtual clock.
+//!
+//! This reproduces the decisions of production locally, for example to find out why a project
+//! was blocked at a certain time. The replayed decisions are compared with the captured ones.
+//! The buckets of the fresh service are not aligned exactly with the ones of the original service,
+//! so decisions right at the edge of the budget can still differ.
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::time::Duration;
+
+use peanutbutter::{Clock, Service};
+
+use crate::capture::{CapturedEntry, CapturedRequest};
+use crate::settings::Settings;
+
+/// The virtual time at which the replay starts, so that budgeting windows never reach before it.
+const REPLAY_EPOCH: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// The outcome of a replay.
+#[derive(Debug, Default, PartialEq)]
+pub struct ReplaySummary {
+    /// The number of replayed requests.
+    pub replayed: usize,
+    /// The number of replayed decisions that differed from the captured ones.
+    pub diverged: usize,
+}
+
+/// Replays the request log at `path` using the configs of the given [`Settings`].
+///
+/// With a `project_id`, all the decisions of that project are printed.
+/// Otherwise, only diverging decisions are printed.
+pub fn replay(
+    path: &Path,
+    settings: &Settings,
+    project_id: Option<u64>,
+) -> Result<ReplaySummary, Box<dyn Error>> {
+    let file = File::open(path)
+        .map_err(|err| format!("failed to open capture file `{}`: {err}", path.display()))?;
+
+    let (clock, mock) = Clock::mock();
+    mock.increment(REPLAY_EPOCH);
+    let service = Service::with_mock_clock(clock);
+    for (name, config) in &settings.configs {
+        service.try_add_config(name, config.to_config()?)?;
+    }
+
+    let mut summary = ReplaySummary::default();
+    let mut last_timestamp_ms = None;
+    for (line_idx, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let entry: CapturedEntry = serde_json::from_str(&line)
+            .map_err(|err| format!("invalid entry on line {}: {err}", line_idx + 1))?;
+
+        // Entries of concurrent requests can be slightly out of order, which is ignored.
+        let elapsed_ms =
+            last_timestamp_ms.map_or(0, |last| entry.timestamp_ms.saturating_sub(last));
+        mock.increment(Duration::from_millis(elapsed_ms));
+        last_timestamp_ms = last_timestamp_ms.max(Some(entry.timestamp_ms));
+
+        let exceeds_budget = match &entry.request {
+            CapturedRequest::RecordSpending(request) if request.refund => service
+                .record_refund(&request.config_name, request.project_id, request.spent)
+                .unwrap_or(false),
+            CapturedRequest::RecordSpending(request) => {
+                service.record_spending(&request.config_name, request.project_id, request.spent)
+            }
+            CapturedRequest::ExceedsBudget(request) => {
+                service.exceeds_budget(&request.config_name, request.project_id)
+            }
+        };
+
+        summary.replayed += 1;
+        let diverged = exceeds_budget != entry.exceeds_budget;
+        if diverged {
+            summary.diverged += 1;
+        }
+        if diverged || project_id == Some(entry.request.project_id()) {
+            let path = match entry.request {
+                CapturedRequest::RecordSpending(_) => "/record_spending",
+                CapturedRequest::ExceedsBudget(_) => "/exceeds_budget",
+            };
+            println!(
+                "{} {path} project {} of config `{}`: captured {}, replayed {exceeds_budget}{}",
+                entry.timestamp_ms,
+                entry.request.project_id(),
+                entry.request.config_name(),
+                entry.exceeds_budget,
+                if diverged { " (diverged)" } else { "" },
+            );
+        }
+    }
+    Ok(summary)
+}
+
+/// Runs the `replay` subcommand with the given arguments.
+///
+/// Expects the path of the capture file, an optional `--project <id>`,
+/// and any of the regular arguments to configure the configs, like `--config <path>`.
+pub fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let (path, args) = args
+        .split_first()
+        .ok_or("`replay` requires a capture file")?;
+    let mut project_id = None;
+    let mut settings_args = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--project" {
+            let value = args.next().ok_or("`--project` requires a value")?;
+            project_id = Some(value.parse()?);
+        } else {
+            settings_args.push(arg.clone());
+        }
+    }
+    let settings = Settings::from_args(settings_args)?;
+
+    let summary = replay(path.as_ref(), &settings, project_id)?;
+    println!(
+        "Replayed {} requests, {} decisions diverged",
+        summary.replayed, summary.diverged
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use indexmap::IndexMap;
+
+    use crate::settings::ConfigSettings;
+
+    use super::*;
+
+    #[test]
+    fn test_replay() {
+        let path = std::env::temp_dir().join("peanutbutter-test-capture.jsonl");
+        let entry = |timestamp_ms, path, request: &str, exceeds_budget| {
+            format!(
+                r#"{{"timestamp_ms": {timestamp_ms}, "path": "{path}", {request}, "exceeds_budget": {exceeds_budget}}}"#
+            )
+        };
+        let spend = r#""config_name": "test", "project_id": 1, "spent": 150"#;
+        let check = r#""config_name": "test", "project_id": 1"#;
+        let lines = [
+            entry(1_000, "/record_spending", spend, true),
+            entry(2_000, "/exceeds_budget", check, true),
+            // the backoff is over after 60s, so the project is unblocked
+            entry(62_000, "/exceeds_budget", check, false),
+            // a decision that can not be reproduced
+            entry(63_000, "/exceeds_budget", check, true),
+        ];
+        std::fs::write(&path, lines.join("\n")).unwrap();
+
+        let settings = Settings {
+            configs: IndexMap::from([(
+                "test".into(),
+                serde_json::from_str::<ConfigSettings>(
+                    r#"{"backoff_secs": 60, "window_secs": 10, "bucket_secs": 1, "budget": 10}"#,
+                )
+                .unwrap(),
+            )]),
+            ..Default::default()
+        };
+        let summary = replay(&path, &settings, Some(1)).unwrap();
+        assert_eq!(
+            summary,
+            ReplaySummary {
+                replayed: 4,
+                diverged: 1
+            }
+        );
+
+        std::fs::write(&path, "{").unwrap();
+        assert!(replay(&path, &settings, None).is_err());
+    }
+}
@@ -0,0 +1,119 @@
+//! Tags every HTTP request with an id, which is returned in the `X-Request-Id` response header.
+//!
+//! Clients can pass their own id in the `X-Request-Id` request header, otherwise one is generated.
+//! The id is included in all the log lines and captured entries of a request, so that a decision
+//! reported by a client can be correlated with what the server logged for it.
+//! Optionally, every request is logged along with its id, status and latency.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Instant, SystemTime};
+
+use axum::extract::{Request, State};
+use axum::http::{HeaderName, HeaderValue};
+use axum::middleware::Next;
+use axum::response::Response;
+
+/// The header carrying the request id, both in requests and responses.
+pub const HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// The maximum length of request ids passed by clients, longer ones are replaced.
+const MAX_LEN: usize = 128;
+
+/// The id of a single HTTP request, available to handlers as an [`Extension`](axum::Extension).
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequestId(Arc<str>);
+
+impl RequestId {
+    /// Generates a new id, which is unique across restarts of the server.
+    pub fn generate() -> Self {
+        static PREFIX: OnceLock<u32> = OnceLock::new();
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        let prefix = PREFIX.get_or_init(|| {
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH);
+            let nanos = now.map_or(0, |now| now.as_nanos() as u32);
+            nanos ^ std::process::id().rotate_left(16)
+        });
+        let counter = COUNTER.fetch_add(1, Ordering::Relaxed);
+        Self(format!("{prefix:08x}-{counter:x}").into())
+    }
+
+    /// Takes the id passed by the client, if it is a non-empty and printable ASCII string.
+    fn from_header(value: &HeaderValue) -> Option<Self> {
+        let value = value.to_str().ok()?;
+        let valid = !value.is_empty()
+            && value.len() <= MAX_LEN
+            && value.bytes().all(|b| b.is_ascii_graphic());
+        valid.then(|| Self(value.into()))
+    }
+
+    /// Returns the id as a string.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Assigns a [`RequestId`] to the request, and returns it in the response.
+///
+/// Requests that fail with a client or server error are logged along with their id.
+/// With `log_requests`, all other requests are logged as well.
+pub async fn tag_request(
+    State(log_requests): State<bool>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let request_id = (request.headers().get(&HEADER))
+        .and_then(RequestId::from_header)
+        .unwrap_or_else(RequestId::generate);
+    let method = request.method().clone();
+    let path = request.uri().path().to_owned();
+    request.extensions_mut().insert(request_id.clone());
+
+    let start = Instant::now();
+    let mut response = next.run(request).await;
+    let status = response.status();
+    if status.is_client_error() || status.is_server_error() {
+        println!("Request `{request_id}` to `{path}` failed with `{status}`");
+    } else if log_requests {
+        let elapsed = start.elapsed();
+        println!("Request `{request_id}`: {method} `{path}` -> `{status}` in {elapsed:?}");
+    }
+    if let Ok(value) = HeaderValue::from_str(request_id.as_str()) {
+        response.headers_mut().insert(HEADER, value);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_id() {
+        let first = RequestId::generate();
+        let second = RequestId::generate();
+        assert_ne!(first, second);
+        assert_eq!(
+            first.as_str().split('-').next(),
+            second.as_str().split('-').next()
+        );
+
+        let value = HeaderValue::from_static("client-id-1");
+        assert_eq!(
+            RequestId::from_header(&value).map(|id| id.to_string()),
+            Some("client-id-1".into())
+        );
+        assert!(RequestId::from_header(&HeaderValue::from_static("")).is_none());
+        assert!(RequestId::from_header(&HeaderValue::from_static("with space")).is_none());
+        let long = HeaderValue::from_str(&"a".repeat(MAX_LEN + 1)).unwrap();
+        assert!(RequestId::from_header(&long).is_none());
+    }
+}
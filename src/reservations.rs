@@ -0,0 +1,58 @@
+use std::fmt;
+
+use quanta::Instant;
+
+use crate::config::ConfigError;
+use crate::ProjectKey;
+
+/// An error that can happen when reserving budget, or committing a reservation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReservationError {
+    /// The config of the reservation is not registered.
+    Config(ConfigError),
+    /// The project does not have enough budget left for the reservation.
+    ExceedsBudget,
+    /// No reservation with the given id exists, or it has expired.
+    Unknown(u64),
+}
+
+impl fmt::Display for ReservationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Config(err) => err.fmt(f),
+            Self::ExceedsBudget => f.write_str("the project does not have enough budget left"),
+            Self::Unknown(id) => write!(f, "reservation `{id}` does not exist or has expired"),
+        }
+    }
+}
+
+impl std::error::Error for ReservationError {}
+
+impl From<ConfigError> for ReservationError {
+    fn from(err: ConfigError) -> Self {
+        Self::Config(err)
+    }
+}
+
+/// Budget that was reserved up-front, and which is still waiting to be committed or canceled.
+#[derive(Clone, Debug)]
+pub(crate) struct Reservation {
+    /// The name of the config the budget was reserved in.
+    pub config: String,
+    /// The project the budget was reserved for, after resolving its [alias](crate::Service::set_project_alias).
+    ///
+    /// The budget is committed to or released from this project, even if the alias changes in the meantime.
+    pub project: ProjectKey,
+    /// The reserved budget, as it was recorded including the project's weight.
+    pub reserved: f64,
+    /// The weight of the project at the time of the reservation, which also applies to its commit.
+    pub weight: f64,
+    /// The time at which the reserved budget was recorded, which is where it is released from.
+    pub recorded_at: Instant,
+    /// The time after which this reservation can no longer be committed or canceled.
+    pub expires_at: Instant,
+    /// Whether the reserved budget is released once the reservation expires.
+    ///
+    /// This is the case for acquired slots, which would be held forever otherwise.
+    pub release_on_expiry: bool,
+}
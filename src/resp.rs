@@ -0,0 +1,377 @@
+//! A minimal frontend speaking the Redis serialization protocol (RESP).
+//!
+//! This allows using any existing Redis client library to talk to the [`Service`],
+//! using the following commands:
+//!
+//! - `PB.CHECK <config_name> <project_id>`: Returns `1` if the project exceeds its budget, `0` otherwise.
+//! - `PB.RECORD <config_name> <project_id> <spent>`: Records spent budget, and returns the same as `PB.CHECK`.
+//! - `PING`: Returns `PONG`.
+//!
+//! Unknown config names are treated as not exceeding the budget,
+//! unless the server runs with strict configs, in which case an error is returned.
+//! Commands beyond the request limit of the client's IP are answered with an error,
+//! or with `0` for configs which [allow](peanutbutter::OverloadPolicy::Allow) shed requests.
+
+use std::io;
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use peanutbutter::{Coalescer, OverloadPolicy, Service};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::ip_limits::IpLimiter;
+use crate::ListenerMetrics;
+
+/// The maximum number of arguments accepted for a single command.
+const MAX_ARGS: usize = 8;
+
+/// The maximum length of a single argument.
+const MAX_ARG_LEN: usize = 1024;
+
+/// Accepts RESP connections on the given `listener` forever.
+///
+/// Connections beyond the connection limit of their IP are closed right away.
+/// The received commands are counted in the [`ListenerMetrics`] of the listener.
+pub async fn serve(
+    listener: TcpListener,
+    service: Arc<Service>,
+    strict_configs: bool,
+    coalescer: Option<Arc<Coalescer>>,
+    limiter: Option<Arc<IpLimiter>>,
+    metrics: Arc<ListenerMetrics>,
+) -> io::Result<()> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let ip = addr.ip();
+        let connection = match &limiter {
+            Some(limiter) => match limiter.connect(ip) {
+                Some(connection) => Some(connection),
+                None => continue,
+            },
+            None => None,
+        };
+        let service = service.clone();
+        let coalescer = coalescer.clone();
+        let limiter = limiter.clone();
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            let coalescer = coalescer.as_deref();
+            let limiter = limiter.as_deref().map(|limiter| (limiter, ip));
+            // Connection errors only affect that single client.
+            let _ = handle_connection(
+                stream,
+                &service,
+                strict_configs,
+                coalescer,
+                limiter,
+                &metrics,
+            )
+            .await;
+            drop(connection);
+        });
+    }
+}
+
+/// Executes commands read from a single connection until the client disconnects.
+///
+/// The `limiter` applies the request limit of the client's IP to each command.
+async fn handle_connection(
+    stream: TcpStream,
+    service: &Service,
+    strict_configs: bool,
+    coalescer: Option<&Coalescer>,
+    limiter: Option<(&IpLimiter, IpAddr)>,
+    metrics: &ListenerMetrics,
+) -> io::Result<()> {
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+
+    while let Some(command) = read_command(&mut reader).await? {
+        metrics.requests.fetch_add(1, Ordering::Relaxed);
+        let limited = limiter.is_some_and(|(limiter, ip)| !limiter.allow_request(ip));
+        let response = match command {
+            Ok(args) if limited => shed(service, &args),
+            Ok(args) => execute(service, strict_configs, coalescer, &args).await,
+            Err(error) => format!("-ERR {error}\r\n"),
+        };
+        writer.write_all(response.as_bytes()).await?;
+
+        // Only flush once all the pipelined commands have been answered.
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
+    }
+
+    writer.flush().await
+}
+
+/// Reads a single command, either as a RESP array of bulk strings, or as an inline command.
+///
+/// Returns `None` when the connection was closed, and an inner error for malformed commands.
+async fn read_command<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+) -> io::Result<Option<Result<Vec<String>, &'static str>>> {
+    let mut line = String::new();
+    if read_line(reader, &mut line).await? == 0 {
+        return Ok(None);
+    }
+    let line = line.trim_end();
+
+    let Some(num_args) = line.strip_prefix('*') else {
+        let args = line.split_whitespace().map(String::from).collect();
+        return Ok(Some(Ok(args)));
+    };
+    let num_args = match num_args.parse::<usize>() {
+        Ok(num_args) if num_args <= MAX_ARGS => num_args,
+        _ => return protocol_error("invalid number of arguments"),
+    };
+
+    let mut args = Vec::with_capacity(num_args);
+    for _ in 0..num_args {
+        let mut line = String::new();
+        read_line(reader, &mut line).await?;
+        let len = match line.trim_end().strip_prefix('$').map(str::parse::<usize>) {
+            Some(Ok(len)) if len <= MAX_ARG_LEN => len,
+            _ => return protocol_error("invalid bulk string"),
+        };
+
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg).await?;
+        arg.truncate(len);
+        match String::from_utf8(arg) {
+            Ok(arg) => args.push(arg),
+            Err(_) => return protocol_error("invalid utf-8 in argument"),
+        }
+    }
+
+    Ok(Some(Ok(args)))
+}
+
+/// Reads a single line, including its line ending, into `line`, and returns its length.
+///
+/// Lines are limited to [`MAX_ARG_LEN`], so that clients can not grow the buffer without bounds
+/// by never sending a line ending. Longer lines are a protocol error.
+async fn read_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    line: &mut String,
+) -> io::Result<usize> {
+    let limit = MAX_ARG_LEN + 2;
+    let len = (&mut *reader).take(limit as u64).read_line(line).await?;
+    if len == limit && !line.ends_with('\n') {
+        return protocol_error("line too long");
+    }
+    Ok(len)
+}
+
+/// Creates an [`io::Error`] for unrecoverable protocol errors.
+///
+/// Once the framing is broken, the rest of the stream can not be parsed anymore.
+fn protocol_error<T>(message: &'static str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+/// Answers a command beyond the request limit according to the [`OverloadPolicy`] of its config.
+fn shed(service: &Service, args: &[String]) -> String {
+    let config_name = match args {
+        [command, config_name, ..]
+            if matches!(
+                command.to_ascii_uppercase().as_str(),
+                "PB.CHECK" | "PB.RECORD"
+            ) =>
+        {
+            config_name
+        }
+        _ => return "-ERR too many requests\r\n".into(),
+    };
+    match service.overload_policy(config_name) {
+        OverloadPolicy::Allow => ":0\r\n".into(),
+        OverloadPolicy::Reject => "-ERR too many requests\r\n".into(),
+    }
+}
+
+/// Executes a single command against the [`Service`], returning the serialized RESP response.
+///
+/// With `strict_configs`, an unknown config name results in an error response.
+/// `PB.CHECK` commands go through the `coalescer`, if there is one.
+async fn execute(
+    service: &Service,
+    strict_configs: bool,
+    coalescer: Option<&Coalescer>,
+    args: &[String],
+) -> String {
+    let Some((command, args)) = args.split_first() else {
+        return "-ERR empty command\r\n".into();
+    };
+
+    let exceeds_budget = match (command.to_ascii_uppercase().as_str(), args) {
+        ("PING", []) => return "+PONG\r\n".into(),
+        ("PB.CHECK", [config_name, project_id]) => {
+            let Ok(project_id) = project_id.parse() else {
+                return "-ERR invalid project_id\r\n".into();
+            };
+            match coalescer {
+                Some(coalescer) => (coalescer.try_exceeds_budget(service, config_name, project_id))
+                    .await
+                    .map(|decision| decision.exceeds_budget),
+                None => {
+                    service
+                        .try_exceeds_budget_async(config_name, project_id)
+                        .await
+                }
+            }
+        }
+        ("PB.RECORD", [config_name, project_id, spent]) => {
+            let (Ok(project_id), Ok(spent)) = (project_id.parse(), spent.parse()) else {
+                return "-ERR invalid project_id or spent\r\n".into();
+            };
+            (service.try_record_spending_async(config_name, project_id, spent)).await
+        }
+        ("PING" | "PB.CHECK" | "PB.RECORD", _) => {
+            return format!("-ERR wrong number of arguments for `{command}`\r\n")
+        }
+        _ => return format!("-ERR unknown command `{command}`\r\n"),
+    };
+    let exceeds_budget = match exceeds_budget {
+        Ok(exceeds_budget) => exceeds_budget,
+        Err(error) if strict_configs => return format!("-ERR {error}\r\n"),
+        Err(_) => false,
+    };
+
+    format!(":{}\r\n", exceeds_budget as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use peanutbutter::BudgetingConfig;
+
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_read_command() {
+        let mut input: &[u8] = b"*3\r\n$8\r\nPB.CHECK\r\n$4\r\ntest\r\n$2\r\n42\r\nPING\r\n";
+
+        let command = read_command(&mut input).await.unwrap().unwrap();
+        assert_eq!(command, Ok(args(&["PB.CHECK", "test", "42"])));
+        let command = read_command(&mut input).await.unwrap().unwrap();
+        assert_eq!(command, Ok(args(&["PING"])));
+        assert!(read_command(&mut input).await.unwrap().is_none());
+
+        let mut input: &[u8] = b"*1\r\n$9999\r\n";
+        assert!(read_command(&mut input).await.is_err());
+
+        // lines without a line ending are bounded, for inline commands as well as headers
+        let endless = "PING ".repeat(MAX_ARG_LEN);
+        let mut input = endless.as_bytes();
+        assert!(read_command(&mut input).await.is_err());
+        let header = format!("*1\r\n${}\r\n\r\n", "0".repeat(MAX_ARG_LEN * 2));
+        let mut input = header.as_bytes();
+        assert!(read_command(&mut input).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_execute() {
+        let service = Service::new();
+        let config = BudgetingConfig::new(
+            Duration::from_secs(60),
+            Duration::from_secs(10),
+            Duration::from_secs(1),
+            10.,
+        );
+        service.try_add_config("test", config).unwrap();
+
+        assert_eq!(
+            execute(&service, false, None, &args(&["ping"])).await,
+            "+PONG\r\n"
+        );
+        assert_eq!(
+            execute(&service, false, None, &args(&["PB.CHECK", "test", "1"])).await,
+            ":0\r\n"
+        );
+        assert_eq!(
+            execute(
+                &service,
+                false,
+                None,
+                &args(&["PB.RECORD", "test", "1", "1000"])
+            )
+            .await,
+            ":1\r\n"
+        );
+        assert_eq!(
+            execute(&service, false, None, &args(&["PB.CHECK", "test", "1"])).await,
+            ":1\r\n"
+        );
+        let coalescer = Coalescer::default();
+        assert_eq!(
+            execute(
+                &service,
+                false,
+                Some(&coalescer),
+                &args(&["PB.CHECK", "test", "1"])
+            )
+            .await,
+            ":1\r\n"
+        );
+        assert_eq!(
+            execute(&service, false, None, &args(&["PB.CHECK", "test"])).await,
+            "-ERR wrong number of arguments for `PB.CHECK`\r\n"
+        );
+        assert_eq!(
+            execute(&service, false, None, &args(&["PB.CHECK", "test", "abc"])).await,
+            "-ERR invalid project_id\r\n"
+        );
+        assert_eq!(
+            execute(&service, false, None, &args(&["GET", "foo"])).await,
+            "-ERR unknown command `GET`\r\n"
+        );
+
+        assert_eq!(
+            execute(&service, false, None, &args(&["PB.CHECK", "unknown", "1"])).await,
+            ":0\r\n"
+        );
+        assert_eq!(
+            execute(&service, true, None, &args(&["PB.CHECK", "unknown", "1"])).await,
+            "-ERR config `unknown` is not registered\r\n"
+        );
+    }
+
+    #[test]
+    fn test_shed() {
+        let service = Service::new();
+        let config = BudgetingConfig::new(
+            Duration::from_secs(60),
+            Duration::from_secs(10),
+            Duration::from_secs(1),
+            10.,
+        );
+        service.try_add_config("reject", config.clone()).unwrap();
+        let config = config.with_overload_policy(OverloadPolicy::Allow);
+        service.try_add_config("allow", config).unwrap();
+        service.record_spending("allow", 1, 1000.);
+
+        let rejected = "-ERR too many requests\r\n";
+        assert_eq!(
+            shed(&service, &args(&["PB.CHECK", "reject", "1"])),
+            rejected
+        );
+        assert_eq!(
+            shed(&service, &args(&["PB.CHECK", "unknown", "1"])),
+            rejected
+        );
+        assert_eq!(shed(&service, &args(&["PING"])), rejected);
+        assert_eq!(shed(&service, &args(&["pb.check", "allow", "1"])), ":0\r\n");
+        assert_eq!(
+            shed(&service, &args(&["PB.RECORD", "allow", "1", "1"])),
+            ":0\r\n"
+        );
+    }
+}
@@ -0,0 +1,47 @@
+//! Sampling of projects, for features that only apply to a fraction of all the traffic.
+//!
+//! Projects are sampled as a whole, so that all the requests of a sampled project are included.
+//! This is required for anything that reproduces decisions, as these depend on all the spending of a project.
+
+/// Decides which projects are sampled, based on a hash of their id.
+#[derive(Clone, Copy, Debug)]
+pub struct ProjectSampler {
+    /// Projects whose hashed id is below this threshold are sampled.
+    threshold: u64,
+}
+
+impl ProjectSampler {
+    /// Creates a [`ProjectSampler`] including the given fraction of projects.
+    pub fn new(sample_rate: f64) -> Result<Self, String> {
+        if !(0. ..=1.).contains(&sample_rate) {
+            return Err(format!("invalid sample rate: {sample_rate}"));
+        }
+        let threshold = (sample_rate * u64::MAX as f64) as u64;
+        Ok(Self { threshold })
+    }
+
+    /// Returns whether the requests of this project are sampled.
+    pub fn is_sampled(&self, project_id: u64) -> bool {
+        // Fibonacci hashing spreads sequential project ids evenly.
+        project_id.wrapping_mul(0x9e37_79b9_7f4a_7c15) < self.threshold
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_sampled() {
+        let sampled = |rate| {
+            let sampler = ProjectSampler::new(rate).unwrap();
+            (0..10_000).filter(|id| sampler.is_sampled(*id)).count()
+        };
+        assert_eq!(sampled(0.), 0);
+        assert_eq!(sampled(1.), 10_000);
+        assert!((900..1_100).contains(&sampled(0.1)));
+
+        assert!(ProjectSampler::new(1.5).is_err());
+        assert!(ProjectSampler::new(f64::NAN).is_err());
+    }
+}
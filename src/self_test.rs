@@ -0,0 +1,83 @@
+//! A scripted scenario exercising the core budgeting logic, as a deployment smoke check.
+//!
+//! The [`Service`] runs with a mocked clock, so that a project can be driven through its whole
+//! lifecycle within a few seconds: spend → block → backoff → unblock → stale cleanup.
+
+use std::time::Duration;
+
+use peanutbutter::{BudgetingConfig, Clock, Service};
+
+/// The name of the config used by the self-test.
+const CONFIG: &str = "self-test";
+
+/// How long to wait for the background maintenance to pick up a change.
+const MAINTENANCE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Checks that `actual` matches the `expected` outcome of a step.
+fn expect(step: &str, actual: bool, expected: bool) -> Result<(), String> {
+    if actual != expected {
+        return Err(format!(
+            "self-test failed at `{step}`: expected {expected}, got {actual}"
+        ));
+    }
+    println!("Self-test step `{step}` passed");
+    Ok(())
+}
+
+/// Waits until the maintenance reports the given number of tracked projects.
+fn wait_for_tracked_projects(step: &str, service: &Service, expected: usize) -> Result<(), String> {
+    let started = std::time::Instant::now();
+    loop {
+        let tracked_projects = service.spend_summary()[CONFIG].tracked_projects;
+        if tracked_projects == expected {
+            println!("Self-test step `{step}` passed");
+            return Ok(());
+        }
+        if started.elapsed() > MAINTENANCE_TIMEOUT {
+            return Err(format!(
+                "self-test failed at `{step}`: expected {expected} tracked projects, got {tracked_projects}"
+            ));
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Runs the scripted scenario, and returns an error describing the first divergence.
+pub fn run() -> Result<(), String> {
+    let (clock, mock) = Clock::mock();
+    mock.increment(Duration::from_secs(1_000));
+    let service = Service::with_mock_clock(clock);
+
+    let backoff = Duration::from_secs(60);
+    let window = Duration::from_secs(10);
+    let config = BudgetingConfig::new(backoff, window, Duration::from_secs(1), 10.);
+    service
+        .try_add_config(CONFIG, config)
+        .map_err(|err| err.to_string())?;
+
+    expect("spend", service.record_spending(CONFIG, 1, 50.), false)?;
+    expect("block", service.record_spending(CONFIG, 1, 100.), true)?;
+    wait_for_tracked_projects("track", &service, 1)?;
+
+    mock.increment(window * 2);
+    expect("backoff", service.exceeds_budget(CONFIG, 1), true)?;
+
+    mock.increment(backoff);
+    expect("unblock", service.exceeds_budget(CONFIG, 1), false)?;
+
+    // Unblocking starts another backoff, after which the project is stale.
+    mock.increment(backoff + window * 2);
+    wait_for_tracked_projects("stale cleanup", &service, 0)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_self_test() {
+        assert_eq!(run(), Ok(()));
+    }
+}
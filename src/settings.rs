@@ -0,0 +1,816 @@
+use std::error::Error;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use indexmap::IndexMap;
+use peanutbutter::{
+    AccountingStrategy, BudgetingConfig, OverloadPolicy, OversizedSpend, ProjectKey,
+    MAX_CONFIG_DURATION,
+};
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// The settings of the server, read from an optional JSON config file and command line arguments.
+///
+/// Command line arguments take precedence over the config file.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// The addresses of the HTTP server, for example an IPv4 and an IPv6 address.
+    #[serde(alias = "addr", deserialize_with = "one_or_many")]
+    pub addrs: Vec<SocketAddr>,
+    /// The addresses of the optional RESP server.
+    #[serde(alias = "resp_addr", deserialize_with = "one_or_many")]
+    pub resp_addrs: Vec<SocketAddr>,
+    /// Whether to run a separate single-threaded runtime per core.
+    pub thread_per_core: bool,
+    /// The number of acceptor tasks per server and runtime.
+    ///
+    /// Each acceptor has its own `SO_REUSEPORT` listener.
+    pub acceptors: usize,
+    /// Whether requests for unknown config names are rejected with an error.
+    ///
+    /// By default, unknown configs never exceed their budget.
+    pub strict_configs: bool,
+    /// Whether concurrent identical `exceeds_budget` checks are coalesced into a single one.
+    pub coalesce_checks: bool,
+    /// Whether every HTTP request is logged, not only the failed ones.
+    pub log_requests: bool,
+    /// The origins allowed to call the reporting endpoints from browsers, or `*` for any origin.
+    pub cors_origins: Vec<String>,
+    /// The maximum number of concurrently open HTTP and RESP connections per source IP.
+    pub max_connections_per_ip: Option<usize>,
+    /// The maximum number of HTTP requests and RESP commands per second per source IP.
+    pub max_requests_per_ip: Option<f64>,
+    /// The maximum size of HTTP request bodies, in bytes.
+    ///
+    /// Larger requests are rejected with `413 Payload Too Large`.
+    pub max_body_size: usize,
+    /// The maximum memory used by the tracked projects, in bytes.
+    ///
+    /// See [`Service::set_memory_limit`](peanutbutter::Service::set_memory_limit).
+    pub max_state_memory: Option<usize>,
+    /// The number of state changes within a maintenance pass that are aggregated into a single event.
+    ///
+    /// See [`Service::set_mass_state_change_threshold`](peanutbutter::Service::set_mass_state_change_threshold).
+    pub mass_state_change_threshold: Option<usize>,
+    /// Whether the time the clock jumped forward is skipped, instead of expiring all the state.
+    ///
+    /// See [`Service::set_skip_clock_jumps`](peanutbutter::Service::set_skip_clock_jumps).
+    pub skip_clock_jumps: bool,
+    /// The maximum number of configs, including the ones of the control plane.
+    ///
+    /// See [`Service::set_max_configs`](peanutbutter::Service::set_max_configs).
+    pub max_configs: Option<usize>,
+    /// The budgeting configs, keyed by name.
+    pub configs: IndexMap<String, ConfigSettings>,
+    /// Projects that are pre-warmed at startup, keyed by config name.
+    ///
+    /// See [`Service::prewarm_projects`](peanutbutter::Service::prewarm_projects).
+    pub prewarm_projects: IndexMap<String, Vec<u64>>,
+    /// Projects whose budget is accounted to another project, keyed by the aliased project.
+    ///
+    /// Projects are given by their numeric id or by their UUID, see [`ProjectKey`].
+    /// See [`Service::set_project_alias`](peanutbutter::Service::set_project_alias).
+    pub project_aliases: IndexMap<ProjectKey, ProjectKey>,
+    /// The optional URL of a control plane serving budgeting configs as JSON.
+    ///
+    /// The configs of the control plane take precedence over the local `configs`.
+    pub control_plane_url: Option<String>,
+    /// How often configs are fetched from the control plane, in seconds.
+    pub control_plane_interval_secs: u64,
+    /// The optional URL of a feature-flag provider serving the [`Enforcement`] of each config as JSON.
+    ///
+    /// [`Enforcement`]: peanutbutter::Enforcement
+    pub feature_flags_url: Option<String>,
+    /// How often feature flags are fetched, in seconds.
+    pub feature_flags_interval_secs: u64,
+    /// The optional path of a file forcing the decisions of individual projects.
+    ///
+    /// See [`Service::set_decision_overrides`](peanutbutter::Service::set_decision_overrides).
+    pub overrides_path: Option<PathBuf>,
+    /// How often the decision overrides are reloaded, in seconds.
+    pub overrides_interval_secs: u64,
+    /// The optional URL of a secondary instance, whose decisions are compared with the local ones.
+    pub canary_url: Option<String>,
+    /// The fraction of projects whose requests are forwarded to the canary instance.
+    pub canary_sample_rate: f64,
+    /// The optional path of a file to which requests are captured, for later replay.
+    pub capture_path: Option<PathBuf>,
+    /// The fraction of projects whose requests are captured.
+    pub capture_sample_rate: f64,
+    /// The optional path of a shared memory file with a snapshot of the blocked projects.
+    ///
+    /// See [`Service::share_blocked_projects`](peanutbutter::Service::share_blocked_projects).
+    pub blocked_snapshot_path: Option<PathBuf>,
+    /// The maximum number of blocked projects within the snapshot.
+    pub blocked_snapshot_capacity: usize,
+    /// The optional path of a file to which the id of the process is written.
+    pub pid_file: Option<PathBuf>,
+    /// The optional path of a file to which all the output is written, instead of stdout and stderr.
+    pub log_file: Option<PathBuf>,
+}
+
+/// The settings of a single [`BudgetingConfig`].
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigSettings {
+    /// The [`BudgetingConfig::backoff_duration`], in seconds.
+    #[serde(alias = "backoff", deserialize_with = "secs")]
+    pub backoff_secs: f64,
+    /// The [`BudgetingConfig::budgeting_window`], in seconds.
+    #[serde(alias = "window", deserialize_with = "secs")]
+    pub window_secs: f64,
+    /// The [`BudgetingConfig::bucket_size`], in seconds.
+    #[serde(alias = "bucket", deserialize_with = "secs")]
+    pub bucket_secs: f64,
+    /// The [`BudgetingConfig::budget`].
+    #[serde(deserialize_with = "per_sec")]
+    pub budget: f64,
+    /// The [`BudgetingConfig::grace_period`], in seconds.
+    #[serde(default, alias = "grace_period", deserialize_with = "secs")]
+    pub grace_period_secs: f64,
+    /// Whether [`BudgetingConfig::allow_refunds`] is set.
+    #[serde(default)]
+    pub allow_refunds: bool,
+    /// The [`BudgetingConfig::unknown_project_ttl`] in seconds, which defaults to `bucket_secs`.
+    #[serde(
+        default,
+        alias = "unknown_project_ttl",
+        deserialize_with = "optional_secs"
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unknown_project_ttl_secs: Option<f64>,
+    /// The [`BudgetingConfig::strategy`].
+    #[serde(default)]
+    pub strategy: AccountingStrategy,
+    /// The [`BudgetingConfig::overload_policy`].
+    #[serde(default)]
+    pub overload_policy: OverloadPolicy,
+    /// The [`BudgetingConfig::max_single_spend`], if there is one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_single_spend: Option<f64>,
+    /// The [`BudgetingConfig::oversized_spend`].
+    #[serde(default)]
+    pub oversized_spend: OversizedSpend,
+    /// Whether the config is [`BudgetingConfig::enabled`].
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// The length of the [`SlowBurnWindow`] in seconds, if there is one.
+    ///
+    /// [`SlowBurnWindow`]: peanutbutter::SlowBurnWindow
+    #[serde(default, alias = "slow_window", deserialize_with = "optional_secs")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slow_window_secs: Option<f64>,
+    /// The budget of the slow-burn window, which defaults to the regular `budget`.
+    #[serde(default, deserialize_with = "optional_per_sec")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slow_budget: Option<f64>,
+    /// The length of the [`RampUp`] of new projects in seconds, if there is one.
+    ///
+    /// [`RampUp`]: peanutbutter::RampUp
+    #[serde(default, alias = "ramp_up", deserialize_with = "optional_secs")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ramp_up_secs: Option<f64>,
+    /// The fraction of the budget new projects start with, which defaults to `0.1`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ramp_up_fraction: Option<f64>,
+}
+
+impl ConfigSettings {
+    /// Creates the settings of a config without grace period and refunds.
+    fn new(backoff_secs: f64, window_secs: f64, bucket_secs: f64, budget: f64) -> Self {
+        Self {
+            backoff_secs,
+            window_secs,
+            bucket_secs,
+            budget,
+            grace_period_secs: 0.,
+            allow_refunds: false,
+            unknown_project_ttl_secs: None,
+            strategy: AccountingStrategy::default(),
+            overload_policy: OverloadPolicy::default(),
+            max_single_spend: None,
+            oversized_spend: OversizedSpend::default(),
+            enabled: true,
+            slow_window_secs: None,
+            slow_budget: None,
+            ramp_up_secs: None,
+            ramp_up_fraction: None,
+        }
+    }
+
+    /// Creates the settings describing an existing [`BudgetingConfig`].
+    pub fn from_config(config: &BudgetingConfig) -> Self {
+        Self {
+            backoff_secs: config.backoff_duration.as_secs_f64(),
+            window_secs: config.budgeting_window.as_secs_f64(),
+            bucket_secs: config.bucket_size.as_secs_f64(),
+            budget: config.budget,
+            grace_period_secs: config.grace_period.as_secs_f64(),
+            allow_refunds: config.allow_refunds,
+            unknown_project_ttl_secs: (config.unknown_project_ttl != config.bucket_size)
+                .then_some(config.unknown_project_ttl.as_secs_f64()),
+            strategy: config.strategy,
+            overload_policy: config.overload_policy,
+            max_single_spend: config.max_single_spend,
+            oversized_spend: config.oversized_spend,
+            enabled: config.enabled,
+            slow_window_secs: config
+                .slow_burn
+                .map(|slow_burn| slow_burn.budgeting_window.as_secs_f64()),
+            slow_budget: config.slow_burn.map(|slow_burn| slow_burn.budget),
+            ramp_up_secs: config.ramp_up.map(|ramp_up| ramp_up.duration.as_secs_f64()),
+            ramp_up_fraction: config.ramp_up.map(|ramp_up| ramp_up.initial_fraction),
+        }
+    }
+
+    /// Validates the settings, and turns them into a [`BudgetingConfig`].
+    pub fn to_config(&self) -> Result<BudgetingConfig, String> {
+        let duration = |name, secs: f64| {
+            Duration::try_from_secs_f64(secs)
+                .ok()
+                .filter(|duration| *duration <= MAX_CONFIG_DURATION)
+                .ok_or_else(|| format!("invalid `{name}`, needs to be at most a year: {secs}"))
+        };
+        let backoff_duration = duration("backoff_secs", self.backoff_secs)?;
+        let budgeting_window = duration("window_secs", self.window_secs)?;
+        let bucket_size = duration("bucket_secs", self.bucket_secs)?;
+        let grace_period = duration("grace_period_secs", self.grace_period_secs)?;
+
+        if bucket_size.as_micros() == 0 || bucket_size > budgeting_window {
+            return Err("`bucket_secs` needs to be positive and within `window_secs`".into());
+        }
+        if !(self.budget.is_finite() && self.budget >= 0.) {
+            return Err(format!("invalid `budget`: {}", self.budget));
+        }
+
+        let mut config =
+            BudgetingConfig::new(backoff_duration, budgeting_window, bucket_size, self.budget)
+                .with_grace_period(grace_period)
+                .with_allow_refunds(self.allow_refunds)
+                .with_strategy(self.strategy)
+                .with_overload_policy(self.overload_policy)
+                .with_enabled(self.enabled);
+        if let Some(max_single_spend) = self.max_single_spend {
+            if !(max_single_spend.is_finite() && max_single_spend > 0.) {
+                return Err(format!("invalid `max_single_spend`: {max_single_spend}"));
+            }
+            config = config.with_max_single_spend(max_single_spend, self.oversized_spend);
+        }
+        if let Some(ttl_secs) = self.unknown_project_ttl_secs {
+            let ttl = duration("unknown_project_ttl_secs", ttl_secs)?;
+            config = config.with_unknown_project_ttl(ttl);
+        }
+        match (self.slow_window_secs, self.slow_budget) {
+            (Some(slow_window_secs), slow_budget) => {
+                let slow_window = duration("slow_window_secs", slow_window_secs)?;
+                if slow_window <= budgeting_window {
+                    return Err("`slow_window_secs` needs to be longer than `window_secs`".into());
+                }
+                let slow_budget = slow_budget.unwrap_or(self.budget);
+                if !(slow_budget.is_finite() && slow_budget >= 0.) {
+                    return Err(format!("invalid `slow_budget`: {slow_budget}"));
+                }
+                config = config.with_slow_burn(slow_window, slow_budget);
+            }
+            (None, Some(_)) => return Err("`slow_budget` requires `slow_window_secs`".into()),
+            (None, None) => {}
+        }
+        match (self.ramp_up_secs, self.ramp_up_fraction) {
+            (Some(ramp_up_secs), fraction) => {
+                let ramp_up = duration("ramp_up_secs", ramp_up_secs)?;
+                let fraction = fraction.unwrap_or(DEFAULT_RAMP_UP_FRACTION);
+                if !(0. ..=1.).contains(&fraction) {
+                    return Err(format!(
+                        "`ramp_up_fraction` needs to be between 0 and 1: {fraction}"
+                    ));
+                }
+                config = config.with_ramp_up(ramp_up, fraction);
+            }
+            (None, Some(_)) => return Err("`ramp_up_fraction` requires `ramp_up_secs`".into()),
+            (None, None) => {}
+        }
+        Ok(config)
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            addrs: vec![([0, 0, 0, 0], 4433).into()],
+            resp_addrs: vec![],
+            thread_per_core: false,
+            acceptors: 1,
+            strict_configs: false,
+            coalesce_checks: false,
+            log_requests: false,
+            cors_origins: vec![],
+            max_connections_per_ip: None,
+            max_requests_per_ip: None,
+            max_body_size: 64 * 1024,
+            max_state_memory: None,
+            mass_state_change_threshold: None,
+            skip_clock_jumps: false,
+            max_configs: None,
+            configs: default_configs(),
+            prewarm_projects: IndexMap::new(),
+            project_aliases: IndexMap::new(),
+            control_plane_url: None,
+            control_plane_interval_secs: 30,
+            feature_flags_url: None,
+            feature_flags_interval_secs: 5,
+            overrides_path: None,
+            overrides_interval_secs: 1,
+            canary_url: None,
+            canary_sample_rate: 0.01,
+            capture_path: None,
+            capture_sample_rate: 0.01,
+            blocked_snapshot_path: None,
+            blocked_snapshot_capacity: 65_536,
+            pid_file: None,
+            log_file: None,
+        }
+    }
+}
+
+/// The fraction of the budget new projects start with, unless configured otherwise.
+const DEFAULT_RAMP_UP_FRACTION: f64 = 0.1;
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// Deserializes either a single value, or a list of values.
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+/// A number, or a string with a unit, like `"2m"` or `"600/m"`.
+enum NumberOrUnit {
+    Number(f64),
+    Unit(String),
+}
+
+impl<'de> Deserialize<'de> for NumberOrUnit {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl serde::de::Visitor<'_> for Visitor {
+            type Value = NumberOrUnit;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a number, or a string with a unit")
+            }
+
+            fn visit_f64<E>(self, value: f64) -> Result<Self::Value, E> {
+                Ok(NumberOrUnit::Number(value))
+            }
+
+            fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E> {
+                Ok(NumberOrUnit::Number(value as f64))
+            }
+
+            fn visit_u64<E>(self, value: u64) -> Result<Self::Value, E> {
+                Ok(NumberOrUnit::Number(value as f64))
+            }
+
+            fn visit_str<E>(self, value: &str) -> Result<Self::Value, E> {
+                Ok(NumberOrUnit::Unit(value.to_owned()))
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+/// Deserializes a duration in seconds, or a string with a unit like `"500ms"`, `"10s"`, `"2m"`, `"1h"` or `"1d"`.
+fn secs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    optional_secs(deserializer)?.ok_or_else(|| serde::de::Error::custom("expected a duration"))
+}
+
+/// Deserializes an optional duration, see [`secs`].
+fn optional_secs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f64>, D::Error> {
+    let Some(value) = Option::<NumberOrUnit>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    let secs = match value {
+        NumberOrUnit::Number(secs) => secs,
+        NumberOrUnit::Unit(duration) => parse_duration(&duration).ok_or_else(|| {
+            serde::de::Error::custom(format!(
+                "invalid duration `{duration}`, expected seconds or a number with one of the units \
+                 `ms`, `s`, `m`, `h` or `d`, like `\"2m\"`"
+            ))
+        })?,
+    };
+    Ok(Some(secs))
+}
+
+/// Deserializes a per-second budget, or a string with a unit like `"600/m"`, see [`parse_budget`].
+fn per_sec<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    optional_per_sec(deserializer)?.ok_or_else(|| serde::de::Error::custom("expected a budget"))
+}
+
+/// Deserializes an optional per-second budget, see [`per_sec`].
+fn optional_per_sec<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f64>, D::Error> {
+    let Some(value) = Option::<NumberOrUnit>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    let budget = match value {
+        NumberOrUnit::Number(budget) => budget,
+        NumberOrUnit::Unit(budget) => parse_budget(&budget).ok_or_else(|| {
+            serde::de::Error::custom(format!(
+                "invalid budget `{budget}`, expected a per-second budget or a number per unit of time, \
+                 like `\"600/m\"`"
+            ))
+        })?,
+    };
+    Ok(Some(budget))
+}
+
+/// Parses a duration with a unit into seconds, for example `"2m"` into `120`.
+fn parse_duration(duration: &str) -> Option<f64> {
+    let duration = duration.trim();
+    let split = duration
+        .find(|c: char| c.is_ascii_alphabetic())
+        .unwrap_or(duration.len());
+    let (value, unit) = duration.split_at(split);
+    let value: f64 = value.trim().parse().ok()?;
+    let unit_secs = match unit {
+        "ms" => 0.001,
+        "" | "s" => 1.,
+        "m" => 60.,
+        "h" => 60. * 60.,
+        "d" => 24. * 60. * 60.,
+        _ => return None,
+    };
+    Some(value * unit_secs)
+}
+
+/// Parses a budget per unit of time into a per-second budget, for example `"600/m"` into `10`.
+///
+/// The unit of time can be any duration accepted by [`parse_duration`], like `"1/500ms"`.
+/// A plain number is a per-second budget.
+fn parse_budget(budget: &str) -> Option<f64> {
+    let (value, per) = budget.split_once('/').unwrap_or((budget, "1"));
+    let value: f64 = value.trim().parse().ok()?;
+    let per = per.trim();
+    // `"600/m"` is shorthand for `"600/1m"`.
+    let per = match per.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        true => parse_duration(&format!("1{per}"))?,
+        false => parse_duration(per)?,
+    };
+    (per > 0.).then(|| value / per)
+}
+
+/// The configs used when none are configured explicitly.
+fn default_configs() -> IndexMap<String, ConfigSettings> {
+    let (backoff_secs, window_secs, bucket_secs) = (5. * 60., 2. * 60., 10.);
+    let config = |budget| ConfigSettings::new(backoff_secs, window_secs, bucket_secs, budget);
+
+    IndexMap::from([
+        ("symbolication-native".into(), config(5.0)),
+        ("symbolication-js".into(), config(5.0)),
+        ("symbolication-jvm".into(), config(7.5)),
+    ])
+}
+
+impl Settings {
+    /// Parses the [`Settings`] from the given command line arguments.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Self, Box<dyn Error>> {
+        let args: Vec<_> = args.into_iter().collect();
+
+        let mut settings = match args.iter().position(|arg| arg == "--config") {
+            Some(idx) => {
+                let path = args.get(idx + 1).ok_or("`--config` requires a path")?;
+                Self::from_file(path.as_ref())?
+            }
+            None => Self::default(),
+        };
+
+        // Addresses given on the command line replace the ones of the config file.
+        let (mut addrs, mut resp_addrs, mut cors_origins) = (vec![], vec![], vec![]);
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = |name| args.next().ok_or(format!("`{name}` requires a value"));
+            match arg.as_str() {
+                "--config" => {
+                    value("--config")?;
+                }
+                "--resp" => resp_addrs.push(value("--resp")?.parse()?),
+                "--acceptors" => settings.acceptors = value("--acceptors")?.parse()?,
+                "--max-body-size" => settings.max_body_size = value("--max-body-size")?.parse()?,
+                "--max-state-memory" => {
+                    settings.max_state_memory = Some(value("--max-state-memory")?.parse()?)
+                }
+                "--thread-per-core" => settings.thread_per_core = true,
+                "--strict-configs" => settings.strict_configs = true,
+                "--coalesce-checks" => settings.coalesce_checks = true,
+                "--log-requests" => settings.log_requests = true,
+                "--cors-origin" => cors_origins.push(value("--cors-origin")?),
+                "--max-connections-per-ip" => {
+                    settings.max_connections_per_ip =
+                        Some(value("--max-connections-per-ip")?.parse()?)
+                }
+                "--max-requests-per-ip" => {
+                    settings.max_requests_per_ip = Some(value("--max-requests-per-ip")?.parse()?)
+                }
+                "--control-plane" => settings.control_plane_url = Some(value("--control-plane")?),
+                "--feature-flags" => settings.feature_flags_url = Some(value("--feature-flags")?),
+                "--overrides" => settings.overrides_path = Some(value("--overrides")?.into()),
+                "--canary" => settings.canary_url = Some(value("--canary")?),
+                "--capture" => settings.capture_path = Some(value("--capture")?.into()),
+                "--blocked-snapshot" => {
+                    settings.blocked_snapshot_path = Some(value("--blocked-snapshot")?.into())
+                }
+                "--pid-file" => settings.pid_file = Some(value("--pid-file")?.into()),
+                "--log-file" => settings.log_file = Some(value("--log-file")?.into()),
+                _ => addrs.push(arg.parse()?),
+            }
+        }
+        if !addrs.is_empty() {
+            settings.addrs = addrs;
+        }
+        if !resp_addrs.is_empty() {
+            settings.resp_addrs = resp_addrs;
+        }
+        if !cors_origins.is_empty() {
+            settings.cors_origins = cors_origins;
+        }
+
+        if settings.addrs.is_empty() {
+            return Err("at least one address is required".into());
+        }
+        if settings.acceptors == 0 {
+            return Err("at least one acceptor is required".into());
+        }
+        if settings.control_plane_interval_secs == 0
+            || settings.feature_flags_interval_secs == 0
+            || settings.overrides_interval_secs == 0
+        {
+            return Err("polling intervals need to be positive".into());
+        }
+        if settings.max_connections_per_ip == Some(0)
+            || settings
+                .max_requests_per_ip
+                .is_some_and(|max| !(max.is_finite() && max > 0.))
+        {
+            return Err("per-IP limits need to be positive".into());
+        }
+        if !(0. ..=1.).contains(&settings.canary_sample_rate)
+            || !(0. ..=1.).contains(&settings.capture_sample_rate)
+        {
+            return Err("sample rates need to be between 0 and 1".into());
+        }
+        for (name, config) in &settings.configs {
+            if name == crate::healthcheck::CONFIG {
+                return Err(format!("config name `{name}` is reserved").into());
+            }
+            config
+                .to_config()
+                .map_err(|err| format!("invalid config `{name}`: {err}"))?;
+        }
+
+        Ok(settings)
+    }
+
+    /// Reads the [`Settings`] from a JSON config file.
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let file = std::fs::read_to_string(path)
+            .map_err(|err| format!("failed to read config file `{}`: {err}", path.display()))?;
+        // The path of the error points to the offending key, like `configs.native.window_secs`.
+        let deserializer = &mut serde_json::Deserializer::from_str(&file);
+        let settings = serde_path_to_error::deserialize(deserializer).map_err(|err| {
+            let key = err.path().to_string();
+            format!(
+                "invalid config file `{}` at `{key}`: {}",
+                path.display(),
+                err.inner()
+            )
+        })?;
+        Ok(settings)
+    }
+
+    /// Whether listeners need to be bound with `SO_REUSEPORT`.
+    pub fn reuseport(&self) -> bool {
+        self.thread_per_core || self.acceptors > 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_from_args() {
+        let settings = Settings::from_args(args(&[])).unwrap();
+        assert_eq!(settings, Settings::default());
+        assert!(!settings.reuseport());
+
+        let settings = Settings::from_args(args(&[
+            "127.0.0.1:1234",
+            "[::1]:1234",
+            "--resp",
+            "127.0.0.1:6379",
+            "--acceptors",
+            "4",
+            "--strict-configs",
+            "--coalesce-checks",
+            "--log-requests",
+            "--cors-origin",
+            "https://dashboard.test",
+            "--max-body-size",
+            "1024",
+            "--max-connections-per-ip",
+            "16",
+            "--max-requests-per-ip",
+            "100",
+            "--max-state-memory",
+            "1048576",
+            "--canary",
+            "http://canary:4433",
+            "--blocked-snapshot",
+            "/dev/shm/peanutbutter",
+        ]))
+        .unwrap();
+        assert_eq!(
+            settings.addrs,
+            [
+                "127.0.0.1:1234".parse().unwrap(),
+                "[::1]:1234".parse().unwrap()
+            ]
+        );
+        assert_eq!(settings.resp_addrs, ["127.0.0.1:6379".parse().unwrap()]);
+        assert_eq!(settings.acceptors, 4);
+        assert!(settings.strict_configs);
+        assert!(settings.coalesce_checks);
+        assert!(settings.log_requests);
+        assert_eq!(settings.cors_origins, ["https://dashboard.test"]);
+        assert_eq!(settings.max_body_size, 1024);
+        assert_eq!(settings.max_connections_per_ip, Some(16));
+        assert_eq!(settings.max_requests_per_ip, Some(100.));
+        assert_eq!(settings.max_state_memory, Some(1 << 20));
+        assert_eq!(settings.canary_url.as_deref(), Some("http://canary:4433"));
+        let blocked_snapshot = settings.blocked_snapshot_path.as_deref();
+        assert_eq!(blocked_snapshot, Some(Path::new("/dev/shm/peanutbutter")));
+        assert!(settings.reuseport());
+
+        assert!(Settings::from_args(args(&["--acceptors", "0"])).is_err());
+        assert!(Settings::from_args(args(&["--max-requests-per-ip", "0"])).is_err());
+        assert!(Settings::from_args(args(&["--resp"])).is_err());
+    }
+
+    #[test]
+    fn test_from_file() {
+        let path = std::env::temp_dir().join("peanutbutter-test-settings.json");
+        std::fs::write(&path, r#"{"addr": "127.0.0.1:1234", "acceptors": 2}"#).unwrap();
+        let path = path.to_str().unwrap();
+
+        let settings = Settings::from_args(args(&["--config", path])).unwrap();
+        assert_eq!(settings.addrs, ["127.0.0.1:1234".parse().unwrap()]);
+        assert_eq!(settings.acceptors, 2);
+
+        // command line arguments take precedence
+        let settings = Settings::from_args(args(&["--acceptors", "3", "--config", path])).unwrap();
+        assert_eq!(settings.acceptors, 3);
+
+        let file = r#"{"addrs": ["0.0.0.0:1234", "[::]:1234"], "resp_addr": "[::]:6379"}"#;
+        std::fs::write(path, file).unwrap();
+        let settings = Settings::from_args(args(&["--config", path])).unwrap();
+        assert_eq!(settings.addrs.len(), 2);
+        assert_eq!(settings.resp_addrs, ["[::]:6379".parse().unwrap()]);
+
+        std::fs::write(path, r#"{"unknown": 1}"#).unwrap();
+        assert!(Settings::from_args(args(&["--config", path])).is_err());
+    }
+
+    #[test]
+    fn test_configs() {
+        let path = std::env::temp_dir().join("peanutbutter-test-configs.json");
+        let file = r#"{"configs": {"test": {
+            "backoff_secs": 60, "window_secs": 10, "bucket_secs": 0.5, "budget": 10, "allow_refunds": true,
+            "overload_policy": "allow"
+        }}, "prewarm_projects": {"test": [1, 2]}, "project_aliases": {"3": 4, "67e55044-10b1-426f-9247-bb680e5fe0c8": 4}}"#;
+        std::fs::write(&path, file).unwrap();
+        let path = path.to_str().unwrap();
+
+        let settings = Settings::from_args(args(&["--config", path])).unwrap();
+        assert_eq!(settings.configs.len(), 1);
+        assert_eq!(settings.prewarm_projects["test"], [1, 2]);
+        assert_eq!(settings.project_aliases[&ProjectKey::from(3)], 4.into());
+        let uuid = ProjectKey::parse_uuid("67e55044-10b1-426f-9247-bb680e5fe0c8").unwrap();
+        assert_eq!(settings.project_aliases[&uuid], 4.into());
+        let config = settings.configs["test"].to_config().unwrap();
+        assert_eq!(config.bucket_size, Duration::from_millis(500));
+        assert!(config.allow_refunds);
+        assert_eq!(config.overload_policy, OverloadPolicy::Allow);
+        assert_eq!(
+            ConfigSettings::from_config(&config),
+            settings.configs["test"]
+        );
+
+        let mut invalid = ConfigSettings::new(60., 10., 20., 10.);
+        assert!(invalid.to_config().is_err());
+        invalid.bucket_secs = -1.;
+        assert!(invalid.to_config().is_err());
+        invalid.bucket_secs = 1.;
+        invalid.budget = f64::NAN;
+        assert!(invalid.to_config().is_err());
+        invalid.budget = 10.;
+        invalid.backoff_secs = 1.8e10;
+        assert!(invalid.to_config().is_err());
+
+        let mut slow_burn = ConfigSettings::new(60., 10., 1., 10.);
+        slow_burn.slow_window_secs = Some(3600.);
+        let config = slow_burn.to_config().unwrap();
+        assert_eq!(config.slow_burn.unwrap().budget, 10.);
+        slow_burn.slow_budget = Some(2.);
+        let config = slow_burn.to_config().unwrap();
+        assert_eq!(ConfigSettings::from_config(&config), slow_burn);
+        slow_burn.slow_window_secs = Some(5.);
+        assert!(slow_burn.to_config().is_err());
+        slow_burn.slow_window_secs = None;
+        assert!(slow_burn.to_config().is_err());
+
+        let mut ramp_up = ConfigSettings::new(60., 10., 1., 10.);
+        ramp_up.ramp_up_secs = Some(600.);
+        let config = ramp_up.to_config().unwrap();
+        assert_eq!(config.ramp_up.unwrap().initial_fraction, 0.1);
+        ramp_up.ramp_up_fraction = Some(0.5);
+        let config = ramp_up.to_config().unwrap();
+        assert_eq!(ConfigSettings::from_config(&config), ramp_up);
+        ramp_up.ramp_up_fraction = Some(2.);
+        assert!(ramp_up.to_config().is_err());
+        ramp_up.ramp_up_secs = None;
+        assert!(ramp_up.to_config().is_err());
+
+        let mut max_single_spend = ConfigSettings::new(60., 10., 1., 10.);
+        max_single_spend.max_single_spend = Some(100.);
+        max_single_spend.oversized_spend = OversizedSpend::Reject;
+        let config = max_single_spend.to_config().unwrap();
+        assert_eq!(config.max_single_spend, Some(100.));
+        assert_eq!(ConfigSettings::from_config(&config), max_single_spend);
+        max_single_spend.max_single_spend = Some(0.);
+        assert!(max_single_spend.to_config().is_err());
+
+        let mut ttl = ConfigSettings::new(60., 10., 1., 10.);
+        let config = ttl.to_config().unwrap();
+        assert_eq!(config.unknown_project_ttl, Duration::from_secs(1));
+        ttl.unknown_project_ttl_secs = Some(0.);
+        let config = ttl.to_config().unwrap();
+        assert_eq!(config.unknown_project_ttl, Duration::ZERO);
+        assert_eq!(ConfigSettings::from_config(&config), ttl);
+        ttl.unknown_project_ttl_secs = Some(-1.);
+        assert!(ttl.to_config().is_err());
+    }
+
+    #[test]
+    fn test_units() {
+        let json = r#"{
+            "backoff": "2m", "window": "10s", "bucket": "500ms", "budget": "600/m", "slow_window": "1h"
+        }"#;
+        let settings: ConfigSettings = serde_json::from_str(json).unwrap();
+        assert_eq!(settings.backoff_secs, 120.);
+        assert_eq!(settings.window_secs, 10.);
+        assert_eq!(settings.bucket_secs, 0.5);
+        assert_eq!(settings.budget, 10.);
+        assert_eq!(settings.slow_window_secs, Some(3600.));
+        assert_eq!(settings.slow_budget, None);
+
+        assert_eq!(parse_duration("1.5 h"), Some(5400.));
+        assert_eq!(parse_duration("1d"), Some(86400.));
+        assert_eq!(parse_duration("2x"), None);
+        assert_eq!(parse_budget("1/500ms"), Some(2.));
+        assert_eq!(parse_budget("7200 / h"), Some(2.));
+        assert_eq!(parse_budget("5"), Some(5.));
+        assert_eq!(parse_budget("5/0s"), None);
+
+        let path = std::env::temp_dir().join("peanutbutter-test-units.json");
+        let file = r#"{"configs": {"test": {
+            "backoff": "1m", "window": "2x", "bucket": "1s", "budget": 10
+        }}}"#;
+        std::fs::write(&path, file).unwrap();
+        let err = Settings::from_file(&path).unwrap_err().to_string();
+        assert!(err.contains("at `configs.test.window`"), "{err}");
+        assert!(err.contains("invalid duration `2x`"), "{err}");
+    }
+}
@@ -0,0 +1,99 @@
+//! The report logged when the server shuts down gracefully, so that operators can confirm a clean hand-off.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use peanutbutter::Service;
+use serde::Serialize;
+
+/// The final state of the server, after it was flushed on a graceful shutdown.
+///
+/// It is logged as a single line of JSON, so that deploy tooling can pick it up.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ShutdownReport {
+    /// The number of projects tracked across all the configs, as of the last maintenance pass.
+    pub tracked_projects: usize,
+    /// The number of tracked projects which exceed their budget, as of the last maintenance pass.
+    pub blocked_projects: usize,
+    /// The shared memory file the final snapshot of blocked projects was written to, if any.
+    pub blocked_snapshot: Option<PathBuf>,
+    /// How long the server was running, in seconds.
+    pub uptime_secs: f64,
+    /// The number of HTTP requests and RESP commands served across all the listeners.
+    pub requests: u64,
+}
+
+impl ShutdownReport {
+    /// Flushes the final state of the `service`, and reports on it.
+    ///
+    /// The background maintenance is stopped first, so that it does not overwrite the final snapshot.
+    /// The snapshot of blocked projects is written one last time if it is shared via `blocked_snapshot`,
+    /// so that co-located clients keep the latest decisions until the next server takes over.
+    pub fn flush(
+        service: &Service,
+        blocked_snapshot: Option<&Path>,
+        uptime: Duration,
+        requests: u64,
+    ) -> Self {
+        service.stop_maintenance();
+        service.flush_blocked_snapshot();
+        let summaries = service.spend_summary();
+        Self {
+            tracked_projects: summaries.values().map(|s| s.tracked_projects).sum(),
+            blocked_projects: summaries.values().map(|s| s.blocked_projects).sum(),
+            blocked_snapshot: blocked_snapshot.map(PathBuf::from),
+            uptime_secs: uptime.as_secs_f64(),
+            requests,
+        }
+    }
+}
+
+impl fmt::Display for ShutdownReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let json = serde_json::to_string(self).map_err(|_| fmt::Error)?;
+        write!(f, "Shut down cleanly: {json}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use peanutbutter::{BlockedSnapshot, BudgetingConfig};
+
+    use super::*;
+
+    #[test]
+    fn test_shutdown_report() {
+        let path = std::env::temp_dir().join(format!("pb-shutdown-{}", std::process::id()));
+        let service = Service::new();
+        let config = BudgetingConfig::new(
+            Duration::from_secs(60),
+            Duration::from_secs(10),
+            Duration::from_secs(1),
+            10.,
+        );
+        service.try_add_config("test", config).unwrap();
+        service.share_blocked_projects(&path, 16).unwrap();
+        service.record_spending("test", 1, 1000.);
+
+        // the snapshot is written right away, without waiting for the maintenance
+        let report = ShutdownReport::flush(&service, Some(&path), Duration::from_secs(90), 3);
+        assert!(BlockedSnapshot::open(&path).unwrap().is_blocked("test", 1));
+        assert_eq!(report.blocked_snapshot.as_deref(), Some(path.as_path()));
+        assert_eq!((report.uptime_secs, report.requests), (90., 3));
+        assert!(service.maintenance_stopped());
+
+        let report = ShutdownReport {
+            tracked_projects: 2,
+            blocked_projects: 1,
+            blocked_snapshot: None,
+            uptime_secs: 1.5,
+            requests: 7,
+        };
+        assert_eq!(
+            report.to_string(),
+            r#"Shut down cleanly: {"tracked_projects":2,"blocked_projects":1,"blocked_snapshot":null,"uptime_secs":1.5,"requests":7}"#
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
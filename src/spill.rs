@@ -0,0 +1,836 @@
+//! A [`StateStore`] which spills idle projects to an on-disk map, to cap the memory of configs with
+//! a huge number of mostly idle projects.
+//!
+//! Projects which were not updated for a while are compacted into the spending of their buckets,
+//! which is written to a [`redb`] database, keyed by the 128-bit [`ProjectKey`]. Each value consists of
+//! little-endian 64-bit words: the bucket size and the age of the project at the time it was spilled,
+//! the time it was spilled since the first spill of the store, all in nanoseconds, followed by the age
+//! and the total spending of every bucket with any spending. The database is only a second tier of
+//! the in-memory state, and is replaced when the store is created.
+//!
+//! The database is only ever accessed by a dedicated thread, which batches the queued accesses into
+//! shared write transactions. The accesses to a single project are queued while holding its shard of
+//! [`SpillStore::spilled`], so that they run in order.
+
+use std::future::Future;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use std::time::Duration;
+use std::{fmt, io, iter, thread};
+
+use arc_swap::ArcSwapOption;
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use quanta::Instant;
+use redb::{Database, Durability, ReadableTable, Table, TableDefinition};
+use tokio::sync::{oneshot, OwnedMutexGuard};
+
+#[cfg(test)]
+use crate::store::project_ids;
+use crate::store::{StateStore, StoreFuture};
+use crate::{AccountingStrategy, BudgetTracker, BudgetingConfig, ProjectKey, ProjectStats};
+
+/// The table of the spilled projects.
+const PROJECTS: TableDefinition<u128, &[u8]> = TableDefinition::new("projects");
+
+/// The number of words before the buckets of a spilled project.
+const HEADER_WORDS: usize = 3;
+
+/// The maximum number of queued disk accesses which share a single write transaction.
+const MAX_BATCH: usize = 1024;
+
+/// The name of the thread which accesses the database of a [`SpillStore`].
+const SPILL_THREAD: &str = "peanutbutter-spill";
+
+/// The compacted state of a spilled project.
+#[derive(Debug, PartialEq)]
+struct Spilled {
+    /// The bucket size of the config at the time the project was spilled.
+    bucket_size: Duration,
+    /// The time since the project was first seen, at the time it was spilled.
+    age: Duration,
+    /// The time the project was spilled, since the [`SpillStore::epoch`].
+    spilled_at: Duration,
+    /// The age and the total spending of each bucket, as of the time the project was spilled.
+    spending: Vec<(Duration, f64)>,
+}
+
+impl Spilled {
+    /// Compacts a tracker which is spilled at `now`.
+    fn new(tracker: &dyn BudgetTracker, now: Instant, epoch: Instant) -> Self {
+        let spending = tracker.bucket_spending(now);
+        Self {
+            bucket_size: tracker.config().bucket_size,
+            age: now.saturating_duration_since(tracker.report(now).first_seen),
+            spilled_at: now.saturating_duration_since(epoch),
+            spending: spending
+                .into_iter()
+                .filter(|(_age, spent)| *spent > 0.)
+                .collect(),
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let nanos = |duration: Duration| u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        let header = [self.bucket_size, self.age, self.spilled_at].map(nanos);
+        let buckets =
+            (self.spending.iter()).flat_map(|&(age, spent)| [nanos(age), spent.to_bits()]);
+        let words = header.into_iter().chain(buckets);
+        words.flat_map(u64::to_le_bytes).collect()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let words: Vec<_> = (bytes.chunks_exact(8))
+            .map(|word| u64::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        let (header, buckets) = words.split_at_checked(HEADER_WORDS)?;
+        if !bytes.len().is_multiple_of(8) || !buckets.len().is_multiple_of(2) {
+            return None;
+        }
+        let spending = (buckets.chunks_exact(2))
+            .map(|bucket| (Duration::from_nanos(bucket[0]), f64::from_bits(bucket[1])))
+            .collect();
+        Some(Self {
+            bucket_size: Duration::from_nanos(header[0]),
+            age: Duration::from_nanos(header[1]),
+            spilled_at: Duration::from_nanos(header[2]),
+            spending,
+        })
+    }
+}
+
+/// The table of the spilled projects, opened within a write transaction.
+type ProjectTable<'txn> = Table<'txn, u128, &'static [u8]>;
+
+/// A queued disk access, which returns a callback for the result of committing its transaction.
+type Job = Box<
+    dyn for<'txn> FnOnce(&mut ProjectTable<'txn>) -> Box<dyn FnOnce(Result<(), &io::Error>) + Send>
+        + Send,
+>;
+
+/// The database of a [`SpillStore`], which is accessed by its own thread.
+struct Disk {
+    jobs: Sender<Job>,
+    io_errors: Arc<AtomicU64>,
+}
+
+impl Disk {
+    /// Replaces the database at `path`, and spawns the thread accessing it.
+    ///
+    /// The thread stops once the disk is dropped.
+    fn create(path: &Path) -> io::Result<Self> {
+        match std::fs::remove_file(path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        let db = Database::create(path).map_err(io::Error::other)?;
+        let (jobs, queued) = mpsc::channel();
+        thread::Builder::new()
+            .name(SPILL_THREAD.into())
+            .spawn(move || run_jobs(&db, &queued))?;
+        Ok(Self {
+            jobs,
+            io_errors: Default::default(),
+        })
+    }
+
+    /// Queues a disk access right away, and returns its result once its transaction is committed.
+    ///
+    /// Failed accesses are counted as [`SpillStore::io_errors`], even if the result is not awaited.
+    fn run<T: Send + 'static>(
+        &self,
+        access: impl for<'txn> FnOnce(&mut ProjectTable<'txn>) -> io::Result<T> + Send + 'static,
+    ) -> impl Future<Output = io::Result<T>> + Send + 'static {
+        let (sender, receiver) = oneshot::channel();
+        let io_errors = self.io_errors.clone();
+        let job: Job = Box::new(move |table: &mut ProjectTable<'_>| {
+            let result = access(table);
+            Box::new(move |committed: Result<(), &io::Error>| {
+                let result = match committed {
+                    Ok(()) => result,
+                    Err(err) => Err(io::Error::new(err.kind(), err.to_string())),
+                };
+                if result.is_err() {
+                    io_errors.fetch_add(1, Ordering::Relaxed);
+                }
+                let _ = sender.send(result);
+            })
+        });
+        // A job which is never run drops its sender, which is reported below.
+        let _ = self.jobs.send(job);
+
+        let io_errors = self.io_errors.clone();
+        async move {
+            receiver.await.unwrap_or_else(|_| {
+                io_errors.fetch_add(1, Ordering::Relaxed);
+                Err(io::Error::other("the disk access was not run"))
+            })
+        }
+    }
+
+    /// Queues the removal of a project, without waiting for it.
+    fn remove(&self, project_id: ProjectKey) {
+        let key = project_id.to_bits();
+        drop(self.run(move |table| {
+            table.remove(key).map_err(io::Error::other)?;
+            Ok(())
+        }));
+    }
+}
+
+/// Runs the queued disk accesses until the [`Disk`] is dropped, batching them into shared transactions.
+fn run_jobs(db: &Database, queued: &Receiver<Job>) {
+    while let Ok(job) = queued.recv() {
+        let jobs = iter::once(job).chain(queued.try_iter().take(MAX_BATCH - 1));
+        let mut callbacks = Vec::new();
+        let run = || {
+            let mut txn = db.begin_write().map_err(io::Error::other)?;
+            // The database is replaced on every start anyway.
+            txn.set_durability(Durability::None);
+            {
+                let mut table = txn.open_table(PROJECTS).map_err(io::Error::other)?;
+                callbacks.extend(jobs.map(|job| job(&mut table)));
+            }
+            txn.commit().map_err(io::Error::other)
+        };
+        let committed = run();
+        for callback in callbacks {
+            callback(committed.as_ref().copied());
+        }
+    }
+}
+
+/// The state of a project which is spilled to disk.
+enum Slot {
+    /// The project was spilled at the given time since the [`SpillStore::epoch`].
+    Spilled(Duration),
+    /// The project is [claimed](Claim), and other accesses wait for this lock.
+    Claimed(Arc<tokio::sync::Mutex<()>>),
+}
+
+/// Where a project which is not in memory was found.
+enum Lookup<'a> {
+    /// The project is not spilled.
+    Missing,
+    /// The project is spilled, and was claimed.
+    Spilled(Claim<'a>),
+    /// The project was claimed by another access in the meantime, and needs to be looked up again.
+    Moved,
+}
+
+/// A spilled project, which other accesses wait for until the claim is dropped.
+///
+/// The project stays spilled, unless it is [removed](Claim::remove) from disk.
+struct Claim<'a> {
+    store: &'a SpillStore,
+    project_id: ProjectKey,
+    spilled_at: Duration,
+    lock: Arc<tokio::sync::Mutex<()>>,
+    _guard: OwnedMutexGuard<()>,
+    removed: bool,
+}
+
+impl Claim<'_> {
+    /// Reads the project from disk, and restores its tracker.
+    async fn read(&self) -> Option<Box<dyn BudgetTracker>> {
+        let key = self.project_id.to_bits();
+        let read = self.store.disk.run(move |table| {
+            let value = table.get(key).map_err(io::Error::other)?;
+            Ok(value.and_then(|value| Spilled::decode(value.value())))
+        });
+        let spilled = read.await.ok().flatten()?;
+        self.store.restore(spilled)
+    }
+
+    /// Removes the project from disk, as it is either restored or removed for good.
+    fn remove(&mut self) {
+        self.store.disk.remove(self.project_id);
+        self.removed = true;
+    }
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        let store = self.store;
+        let Entry::Occupied(mut slot) = store.spilled.entry(self.project_id) else {
+            return;
+        };
+        // The store might have been cleared in the meantime.
+        if !matches!(slot.get(), Slot::Claimed(lock) if Arc::ptr_eq(lock, &self.lock)) {
+            return;
+        }
+        if self.removed {
+            slot.remove();
+            store.spilled_len.fetch_sub(1, Ordering::Relaxed);
+        } else {
+            slot.insert(Slot::Spilled(self.spilled_at));
+        }
+    }
+}
+
+/// A [`StateStore`] which keeps recently updated projects in memory, and spills idle ones to disk.
+///
+/// Projects which were not updated for `idle_after` are spilled by the maintenance, and restored
+/// on their next access. Only the spending of their buckets is kept, so a restored project has no
+/// backoff, transitions, or recent sources. This is why projects which exceed their budget or are in
+/// backoff are never spilled, and neither are projects of configs with the
+/// [`Concurrency`](AccountingStrategy::Concurrency) strategy or a [`SlowBurnWindow`](crate::SlowBurnWindow),
+/// whose state is not captured by the buckets.
+///
+/// Spilled projects count towards the [length](StateStore::len) of the store, but are not
+/// [scanned](StateStore::scan), so they are not part of the spend summaries. They are restored
+/// with the config of the store, and stale ones are removed from disk once per `idle_after`.
+/// Only the keys of spilled projects are kept in memory, so that accesses to projects which are not spilled
+/// never wait for the disk, which is accessed by a dedicated thread. If writing to disk fails, projects stay in memory,
+/// and if reading fails, they are restored as unknown projects. Both are counted as [`SpillStore::io_errors`].
+pub struct SpillStore {
+    hot: DashMap<ProjectKey, Box<dyn BudgetTracker>>,
+    /// The projects which are spilled to disk.
+    ///
+    /// A project is either in `hot` or in here, and shards of `hot` are always locked first.
+    spilled: DashMap<ProjectKey, Slot>,
+    /// The number of `spilled` projects.
+    spilled_len: AtomicUsize,
+    disk: Disk,
+    /// The config the spilled projects are restored with.
+    config: ArcSwapOption<BudgetingConfig>,
+    /// The time of the first spill, which all the spilled times are relative to.
+    epoch: OnceLock<Instant>,
+    /// When the spilled projects are checked for stale ones next.
+    next_sweep: Mutex<Option<Instant>>,
+    idle_after: Duration,
+}
+
+impl SpillStore {
+    /// Creates a store which spills projects idle for `idle_after` to a database at `path`.
+    ///
+    /// The file at `path` is replaced.
+    pub fn create(path: &Path, idle_after: Duration) -> io::Result<Self> {
+        Ok(Self {
+            hot: DashMap::default(),
+            spilled: DashMap::default(),
+            spilled_len: AtomicUsize::new(0),
+            disk: Disk::create(path)?,
+            config: ArcSwapOption::empty(),
+            epoch: OnceLock::new(),
+            next_sweep: Mutex::new(None),
+            idle_after,
+        })
+    }
+
+    /// Returns the number of projects which are spilled to disk.
+    pub fn spilled(&self) -> usize {
+        self.spilled_len.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of failed disk accesses.
+    pub fn io_errors(&self) -> u64 {
+        self.disk.io_errors.load(Ordering::Relaxed)
+    }
+
+    /// Returns whether the tracker can be spilled at `now`.
+    fn is_idle(&self, tracker: &dyn BudgetTracker, now: Instant) -> bool {
+        let config = tracker.config();
+        if config.strategy != AccountingStrategy::SlidingWindow
+            || config.slow_burn.is_some()
+            || tracker.cached_check()
+        {
+            return false;
+        }
+        let report = tracker.report(now);
+        report.backoff_remaining.is_none()
+            && now.saturating_duration_since(report.last_updated) >= self.idle_after
+    }
+
+    /// Looks up a project which is not in memory, and claims it if it is spilled.
+    async fn lookup(&self, project_id: ProjectKey) -> Lookup<'_> {
+        if self.spilled() == 0 {
+            return Lookup::Missing;
+        }
+        let claimed = match self.spilled.get_mut(&project_id) {
+            None => return Lookup::Missing,
+            Some(mut slot) => match &*slot {
+                Slot::Claimed(lock) => lock.clone(),
+                &Slot::Spilled(spilled_at) => {
+                    let lock = Arc::new(tokio::sync::Mutex::new(()));
+                    let guard = (lock.clone().try_lock_owned()).expect("new locks are unlocked");
+                    *slot = Slot::Claimed(lock.clone());
+                    return Lookup::Spilled(Claim {
+                        store: self,
+                        project_id,
+                        spilled_at,
+                        lock,
+                        _guard: guard,
+                        removed: false,
+                    });
+                }
+            },
+        };
+        drop(claimed.lock().await);
+        Lookup::Moved
+    }
+
+    /// Restores a [`ProjectStats`] from its spilled state, as of the current time of the config.
+    fn restore(&self, spilled: Spilled) -> Option<Box<dyn BudgetTracker>> {
+        let config = self.config.load_full()?;
+        let epoch = *self.epoch.get()?;
+        let now = config.now();
+        let spilled_at = epoch + spilled.spilled_at;
+        let idle = now.saturating_duration_since(spilled_at);
+        let spending: Vec<_> = (spilled.spending.iter())
+            .map(|&(age, spent)| (age + idle, spent))
+            .collect();
+        let first_seen = spilled_at.checked_sub(spilled.age).unwrap_or(spilled_at);
+        let stats =
+            ProjectStats::with_spending(config, &spending, spilled.bucket_size, first_seen, now);
+        Some(Box::new(stats))
+    }
+
+    /// Removes the spilled projects which are stale at `now`.
+    ///
+    /// Spilled projects neither exceed their budget nor are in backoff, so they are stale once
+    /// they were spilled a whole window ago, just like [`BudgetTracker::is_stale`].
+    fn sweep(&self, now: Instant) {
+        let (Some(config), Some(&epoch)) = (self.config.load_full(), self.epoch.get()) else {
+            return;
+        };
+        let Some(earliest_time) = config
+            .truncated_now(now)
+            .checked_sub(config.budgeting_window)
+        else {
+            return;
+        };
+        self.spilled.retain(|&project_id, slot| {
+            let Slot::Spilled(spilled_at) = *slot else {
+                return true;
+            };
+            let is_stale = epoch + spilled_at < earliest_time;
+            if is_stale {
+                self.disk.remove(project_id);
+                self.spilled_len.fetch_sub(1, Ordering::Relaxed);
+            }
+            !is_stale
+        });
+    }
+}
+
+impl fmt::Debug for SpillStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SpillStore")
+            .field("projects", &self.hot.len())
+            .field("spilled", &self.spilled())
+            .field("idle_after", &self.idle_after)
+            .finish()
+    }
+}
+
+impl StateStore for SpillStore {
+    fn get<'a>(
+        &'a self,
+        project_id: ProjectKey,
+        f: &'a mut (dyn FnMut(&dyn BudgetTracker) + Send),
+    ) -> StoreFuture<'a, bool> {
+        Box::pin(async move {
+            loop {
+                if let Some(tracker) = self.hot.get(&project_id) {
+                    f(tracker.as_ref());
+                    return true;
+                }
+                match self.lookup(project_id).await {
+                    Lookup::Missing => return false,
+                    Lookup::Moved => continue,
+                    Lookup::Spilled(claim) => {
+                        // Reading a spilled project does not restore it, as it might stay idle.
+                        let Some(tracker) = claim.read().await else {
+                            return false;
+                        };
+                        f(tracker.as_ref());
+                        return true;
+                    }
+                }
+            }
+        })
+    }
+
+    fn update<'a>(
+        &'a self,
+        project_id: ProjectKey,
+        mut insert: Option<&'a mut (dyn FnMut() -> Box<dyn BudgetTracker> + Send)>,
+        f: &'a mut (dyn FnMut(&mut dyn BudgetTracker) + Send),
+    ) -> StoreFuture<'a, bool> {
+        Box::pin(async move {
+            loop {
+                if let Some(mut tracker) = self.hot.get_mut(&project_id) {
+                    f(tracker.as_mut());
+                    return true;
+                }
+                let (claim, restored) = match self.lookup(project_id).await {
+                    Lookup::Missing => (None, None),
+                    Lookup::Moved => continue,
+                    Lookup::Spilled(mut claim) => {
+                        let restored = claim.read().await;
+                        claim.remove();
+                        (Some(claim), restored)
+                    }
+                };
+                // The claim is only released once the project is back in memory.
+                let mut tracker = match self.hot.entry(project_id) {
+                    Entry::Occupied(entry) => entry.into_ref(),
+                    Entry::Vacant(entry) => {
+                        let tracker = restored.or_else(|| insert.as_mut().map(|insert| insert()));
+                        let Some(tracker) = tracker else {
+                            return false;
+                        };
+                        entry.insert(tracker)
+                    }
+                };
+                f(tracker.as_mut());
+                drop(tracker);
+                drop(claim);
+                return true;
+            }
+        })
+    }
+
+    fn insert(
+        &self,
+        project_id: ProjectKey,
+        tracker: Box<dyn BudgetTracker>,
+    ) -> StoreFuture<'_, Option<Box<dyn BudgetTracker>>> {
+        Box::pin(async move {
+            loop {
+                if let Some(mut existing) = self.hot.get_mut(&project_id) {
+                    return Some(std::mem::replace(&mut *existing, tracker));
+                }
+                let (claim, spilled) = match self.lookup(project_id).await {
+                    Lookup::Missing => (None, None),
+                    Lookup::Moved => continue,
+                    Lookup::Spilled(mut claim) => {
+                        let spilled = claim.read().await;
+                        claim.remove();
+                        (Some(claim), spilled)
+                    }
+                };
+                let replaced = self.hot.insert(project_id, tracker);
+                drop(claim);
+                return replaced.or(spilled);
+            }
+        })
+    }
+
+    fn remove<'a>(
+        &'a self,
+        project_id: ProjectKey,
+        predicate: &'a (dyn Fn(&dyn BudgetTracker) -> bool + Sync),
+    ) -> StoreFuture<'a, Option<Box<dyn BudgetTracker>>> {
+        Box::pin(async move {
+            loop {
+                let removed =
+                    (self.hot).remove_if(&project_id, |_k, tracker| predicate(tracker.as_ref()));
+                if let Some((_project_id, tracker)) = removed {
+                    return Some(tracker);
+                }
+                if self.hot.contains_key(&project_id) {
+                    return None;
+                }
+                match self.lookup(project_id).await {
+                    Lookup::Missing => return None,
+                    Lookup::Moved => continue,
+                    Lookup::Spilled(mut claim) => {
+                        let tracker = claim.read().await?;
+                        if !predicate(tracker.as_ref()) {
+                            return None;
+                        }
+                        claim.remove();
+                        return Some(tracker);
+                    }
+                }
+            }
+        })
+    }
+
+    fn num_partitions(&self) -> usize {
+        self.hot.shards().len()
+    }
+
+    fn scan<'a>(
+        &'a self,
+        partition: usize,
+        f: &'a mut (dyn FnMut(ProjectKey, &dyn BudgetTracker) + Send),
+    ) -> StoreFuture<'a, ()> {
+        let shard = self.hot.shards()[partition].read();
+        for (project_id, tracker) in shard.iter() {
+            f(*project_id, tracker.get().as_ref());
+        }
+        Box::pin(std::future::ready(()))
+    }
+
+    fn len(&self) -> StoreFuture<'_, usize> {
+        Box::pin(std::future::ready(self.hot.len() + self.spilled()))
+    }
+
+    fn clear(&self) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            self.hot.clear();
+            self.spilled.retain(|_project_id, _slot| {
+                self.spilled_len.fetch_sub(1, Ordering::Relaxed);
+                false
+            });
+            let cleared = self
+                .disk
+                .run(|table| table.retain(|_key, _value| false).map_err(io::Error::other));
+            let _ = cleared.await;
+        })
+    }
+
+    fn compact(&self, partition: usize, now: Instant) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            let epoch = *self.epoch.get_or_init(|| now);
+            let mut idle = vec![];
+            for (project_id, tracker) in self.hot.shards()[partition].read().iter() {
+                let tracker = tracker.get().as_ref();
+                if self.is_idle(tracker, now) {
+                    if self.config.load().is_none() {
+                        self.config.store(Some(tracker.config().clone()));
+                    }
+                    let report = tracker.report(now);
+                    let spilled = Spilled::new(tracker, now, epoch);
+                    idle.push((*project_id, report.last_updated, spilled));
+                }
+            }
+
+            if !idle.is_empty() {
+                // The projects stay in memory while they are written, and are only removed
+                // from memory if they were not updated in the meantime.
+                let values: Vec<_> = (idle.iter())
+                    .map(|(project_id, _last_updated, spilled)| {
+                        (project_id.to_bits(), spilled.encode())
+                    })
+                    .collect();
+                let written = self.disk.run(move |table| {
+                    for (key, value) in &values {
+                        table
+                            .insert(key, value.as_slice())
+                            .map_err(io::Error::other)?;
+                    }
+                    Ok(())
+                });
+                if written.await.is_err() {
+                    return;
+                }
+                for (project_id, last_updated, spilled) in idle {
+                    let tracker = match self.hot.entry(project_id) {
+                        Entry::Occupied(tracker)
+                            if tracker.get().report(now).last_updated == last_updated =>
+                        {
+                            tracker
+                        }
+                        _ => {
+                            if let Entry::Vacant(_slot) = self.spilled.entry(project_id) {
+                                self.disk.remove(project_id);
+                            }
+                            continue;
+                        }
+                    };
+                    if let Entry::Vacant(slot) = self.spilled.entry(project_id) {
+                        slot.insert(Slot::Spilled(spilled.spilled_at));
+                        self.spilled_len.fetch_add(1, Ordering::Relaxed);
+                        tracker.remove();
+                    }
+                }
+            }
+
+            let mut next_sweep = self
+                .next_sweep
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            let sweep_due = next_sweep.is_none_or(|next_sweep| now >= next_sweep);
+            if partition == 0 && self.spilled() > 0 && sweep_due {
+                *next_sweep = Some(now + self.idle_after);
+                self.sweep(now);
+            }
+        })
+    }
+
+    fn set_config(&self, config: Arc<BudgetingConfig>) -> StoreFuture<'_, ()> {
+        self.config.store(Some(config));
+        Box::pin(std::future::ready(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pollster::block_on;
+    use quanta::Clock;
+
+    use super::*;
+
+    #[test]
+    fn test_encoding() {
+        let spilled = Spilled {
+            bucket_size: Duration::from_secs(1),
+            age: Duration::from_secs(60),
+            spilled_at: Duration::from_millis(1_500),
+            spending: vec![(Duration::from_secs(3), 1.5), (Duration::ZERO, 20.)],
+        };
+        let encoded = spilled.encode();
+        assert_eq!(encoded.len(), 7 * 8);
+        assert_eq!(Spilled::decode(&encoded), Some(spilled));
+        assert_eq!(Spilled::decode(&encoded[..6 * 8]), None);
+        assert_eq!(Spilled::decode(&encoded[..7 * 8 - 1]), None);
+    }
+
+    #[test]
+    fn test_spill_store() {
+        let (clock, mock) = Clock::mock();
+        mock.increment(Duration::from_secs(100));
+        let config = BudgetingConfig::new(
+            Duration::from_secs(10),
+            Duration::from_secs(5),
+            Duration::from_secs(1),
+            10.,
+        )
+        .with_clock(clock.clone());
+        let config = Arc::new(config);
+        let path = std::env::temp_dir().join(format!("pb-spill-{}", std::process::id()));
+        let store = SpillStore::create(&path, Duration::from_secs(2)).unwrap();
+        let compact = || {
+            for partition in 0..store.num_partitions() {
+                block_on(store.compact(partition, clock.now()));
+            }
+        };
+        let spending = |project_id: u64| {
+            let mut spending = None;
+            let mut total = |tracker: &dyn BudgetTracker| {
+                let buckets = tracker.bucket_spending(clock.now());
+                spending = Some(buckets.iter().map(|(_age, spent)| spent).sum::<f64>());
+            };
+            block_on(store.get(project_id.into(), &mut total));
+            spending
+        };
+
+        let mut insert = || config.new_tracker();
+        for (project_id, spent) in [(1, 20.), (2, 100.)] {
+            let mut record = |tracker: &mut dyn BudgetTracker| {
+                tracker.record(spent);
+            };
+            assert!(block_on(store.update(
+                project_id.into(),
+                Some(&mut insert),
+                &mut record
+            )));
+        }
+        compact();
+        assert_eq!(store.spilled(), 0);
+
+        // the idle project is spilled, but the blocked one is kept in memory
+        mock.increment(Duration::from_secs(3));
+        compact();
+        assert_eq!(store.spilled(), 1);
+        assert_eq!(block_on(store.len()), 2);
+        assert_eq!(block_on(project_ids(&store)), [2.into()]);
+        assert_eq!(spending(1), Some(20.));
+        assert_eq!(store.spilled(), 1);
+
+        // updating the project restores it
+        let mut record = |tracker: &mut dyn BudgetTracker| {
+            assert_eq!(
+                tracker.report(clock.now()).first_seen + Duration::from_secs(3),
+                clock.now()
+            );
+            tracker.record(10.);
+        };
+        assert!(block_on(store.update(1.into(), None, &mut record)));
+        assert_eq!(store.spilled(), 0);
+        assert_eq!(spending(1), Some(30.));
+
+        mock.increment(Duration::from_secs(3));
+        compact();
+        assert_eq!(store.spilled(), 1);
+        assert!(block_on(store.remove(1.into(), &|_tracker| false)).is_none());
+        assert_eq!(store.spilled(), 1);
+        assert!(block_on(store.remove(1.into(), &|_tracker| true)).is_some());
+        assert_eq!(store.spilled(), 0);
+        assert_eq!(spending(1), None);
+
+        // stale projects are removed from disk
+        let mut record = |tracker: &mut dyn BudgetTracker| {
+            tracker.record(10.);
+        };
+        assert!(block_on(store.update(
+            3.into(),
+            Some(&mut insert),
+            &mut record
+        )));
+        mock.increment(Duration::from_secs(3));
+        compact();
+        assert_eq!(store.spilled(), 1);
+        mock.increment(Duration::from_secs(10));
+        compact();
+        assert_eq!(store.spilled(), 0);
+        assert_eq!(block_on(store.len()), 1);
+
+        block_on(store.clear());
+        assert!(block_on(store.is_empty()));
+        assert_eq!(store.io_errors(), 0);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_restore() {
+        let (clock, mock) = Clock::mock();
+        mock.increment(Duration::from_secs(100));
+        let config = BudgetingConfig::new(
+            Duration::from_secs(10),
+            Duration::from_secs(5),
+            Duration::from_secs(1),
+            10.,
+        )
+        .with_clock(clock.clone());
+        let config = Arc::new(config);
+        let path = std::env::temp_dir().join(format!("pb-spill-concurrent-{}", std::process::id()));
+        let store = Arc::new(SpillStore::create(&path, Duration::from_secs(2)).unwrap());
+
+        let mut insert = || config.new_tracker();
+        let mut record = |tracker: &mut dyn BudgetTracker| {
+            tracker.record(20.);
+        };
+        assert!(store.update(1.into(), Some(&mut insert), &mut record).await);
+        mock.increment(Duration::from_secs(3));
+        for partition in 0..store.num_partitions() {
+            store.compact(partition, clock.now()).await;
+        }
+        assert_eq!(store.spilled(), 1);
+
+        // all the concurrent updates wait for the project to be restored
+        let updates: Vec<_> = (0..16)
+            .map(|_| {
+                let store = store.clone();
+                tokio::spawn(async move {
+                    let mut record = |tracker: &mut dyn BudgetTracker| {
+                        tracker.record(1.);
+                    };
+                    store.update(1.into(), None, &mut record).await
+                })
+            })
+            .collect();
+        for update in updates {
+            assert!(update.await.unwrap());
+        }
+        assert_eq!(store.spilled(), 0);
+        let mut spending = 0.;
+        let mut total = |tracker: &dyn BudgetTracker| {
+            spending = (tracker.bucket_spending(clock.now()).iter())
+                .map(|(_age, spent)| spent)
+                .sum();
+        };
+        assert!(store.get(1.into(), &mut total).await);
+        assert_eq!(spending, 36.);
+        assert_eq!(store.io_errors(), 0);
+
+        drop(store);
+        std::fs::remove_file(path).unwrap();
+    }
+}
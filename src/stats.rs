@@ -1,15 +1,83 @@
 use std::collections::VecDeque;
+use std::fmt;
 use std::sync::Arc;
 use std::time::Duration;
 
 use quanta::Instant;
 
-use crate::config::BudgetingConfig;
+use crate::config::{saturating_add, BudgetingConfig};
+use crate::decision::DecisionReason;
+use crate::tracker::{BudgetTracker, SourceLog, TransitionLog};
+
+/// An error that can happen when recording a refund.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RefundError {
+    /// The config does not allow refunds.
+    NotAllowed,
+    /// The refunded amount is negative or not finite.
+    InvalidAmount,
+}
+
+impl fmt::Display for RefundError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotAllowed => f.write_str("refunds are not allowed for this config"),
+            Self::InvalidAmount => f.write_str("refunds need to be positive and finite"),
+        }
+    }
+}
+
+impl std::error::Error for RefundError {}
+
+/// A report of the current state of a [`ProjectStats`].
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct ProjectReport {
+    /// Whether the project exceeded its budget the last time it was checked.
+    pub exceeds_budget: bool,
+    /// The spent budget within the current window, averaged *per-second*.
+    pub spent_budget: f64,
+    /// The budget assigned to the project.
+    pub budget: f64,
+    /// The remaining time until the "exceeded" state is allowed to change again.
+    pub backoff_remaining: Option<Duration>,
+    /// How much more the project may spend within the current window before exceeding the budget.
+    ///
+    /// Contrary to `spent_budget`, this is a total, and not averaged per-second.
+    /// Keep in mind that a project might still be blocked while in backoff.
+    pub remaining_budget: f64,
+    /// How long the project has been exceeding its budget, if it currently does.
+    pub blocked_for: Option<Duration>,
+    /// The number of times the "exceeded" state changed within the last hour.
+    ///
+    /// Projects whose state changes often are hovering around their budget,
+    /// which hints at a badly tuned config.
+    pub transitions: usize,
+    /// The time at which the "exceeded" state last changed, if it changed within the last hour.
+    pub last_transition: Option<Instant>,
+    /// The time at which the project was first seen.
+    pub first_seen: Instant,
+    /// The time at which spending (or a refund) was last recorded for the project.
+    pub last_updated: Instant,
+    /// The most recent distinct sources of the recorded spending, most recent first.
+    ///
+    /// Sources are optionally given when recording spending, and tell apart the producers
+    /// that generated the spending of a blocked project.
+    pub recent_sources: Vec<Arc<str>>,
+}
 
 /// Per-project (per-anything, really) budget tracking.
 ///
 /// This allows the recorded budget to be recorded, and allows checking whether
 /// the total budget (within the configured time window) has been exceeded.
+///
+/// This can be used standalone, without a [`Service`](crate::Service), in which case
+/// the [`BudgetingConfig`] should be created with a custom [`Clock`](quanta::Clock) via
+/// [`BudgetingConfig::with_clock`].
+/// Keep in mind that the time is based on [`Clock::recent`](quanta::Clock::recent),
+/// which needs to be updated regularly using [`quanta::set_recent`] or a [`quanta::Upkeep`] thread.
+/// Configs with a `bucket_size` below 500ms read the precise time instead, as the recent time
+/// is only updated every 500ms by a [`Service`](crate::Service).
 #[derive(Debug)]
 pub struct ProjectStats {
     /// Configuration that governs the budgeting and bucketing.
@@ -21,8 +89,26 @@ pub struct ProjectStats {
     /// The deadline after which a projects state can change, to avoid rapid flip-flopping.
     backoff_deadline: Option<Instant>,
 
+    /// The time at which this project started exceeding its budget, if it currently does.
+    blocked_since: Option<Instant>,
+
+    /// The recent changes of the "exceeded" state.
+    transitions: TransitionLog,
+
+    /// The recent sources of the recorded spending.
+    sources: SourceLog,
+
     /// The buckets that are used to keep track of the spent budget.
     budget_buckets: VecDeque<(Instant, f64)>,
+
+    /// The larger buckets keeping track of the spent budget within the slow-burn window, if configured.
+    slow_buckets: VecDeque<(Instant, f64)>,
+
+    /// The time at which these stats were created.
+    first_seen: Instant,
+
+    /// The time at which spending or a refund was last recorded.
+    last_updated: Instant,
 }
 
 impl ProjectStats {
@@ -30,17 +116,64 @@ impl ProjectStats {
     pub fn new(config: Arc<BudgetingConfig>) -> Self {
         // One extra bucket may temporarily exist when spending is recorded.
         let budget_buckets = VecDeque::with_capacity(config.num_buckets + 1);
+        let slow_buckets = match config.slow_burn {
+            Some(_) => VecDeque::with_capacity(config.num_buckets + 1),
+            None => VecDeque::new(),
+        };
+        let now = config.now();
         Self {
             config,
             exceeds_budget: false,
             backoff_deadline: None,
+            blocked_since: None,
+            transitions: TransitionLog::default(),
+            sources: SourceLog::default(),
             budget_buckets,
+            slow_buckets,
+            first_seen: now,
+            last_updated: now,
+        }
+    }
+
+    /// Creates stats holding the given `spending`, as returned by [`BudgetTracker::bucket_spending`] at `now`.
+    ///
+    /// The spending was recorded in buckets of the given `bucket_size`, and is split or merged proportionally
+    /// if the config uses a different one. A slow-burn window only sees the given spending.
+    /// Apart from that, the stats start out fresh, without any backoff, and are checked at `now` right away.
+    #[cfg(feature = "service")]
+    pub(crate) fn with_spending(
+        config: Arc<BudgetingConfig>,
+        spending: &[(Duration, f64)],
+        bucket_size: Duration,
+        first_seen: Instant,
+        now: Instant,
+    ) -> Self {
+        // The buckets are kept newest first.
+        let recorded: VecDeque<_> = (spending.iter().rev())
+            .filter(|(_age, spent)| *spent > 0.)
+            .map(|&(age, spent)| (now - age, spent))
+            .collect();
+
+        let mut stats = Self::new(config.clone());
+        stats.budget_buckets = match bucket_size == config.bucket_size {
+            true => recorded.clone(),
+            false => rebucket(&config, &recorded, bucket_size, config.bucket_size, now),
+        };
+        stats.budget_buckets.truncate(config.num_buckets);
+        if let Some(slow_size) = config.slow_bucket_size() {
+            stats.slow_buckets = rebucket(&config, &recorded, bucket_size, slow_size, now);
+            stats.slow_buckets.truncate(config.num_buckets);
         }
+        stats.first_seen = first_seen;
+        stats.last_updated = now;
+
+        stats.check_budget(now, config.truncated_now(now));
+        stats
     }
 
     /// Checks whether this project exceeds its budgets.
     pub fn exceeds_budget(&mut self) -> bool {
-        let now = self.config.now();
+        let now = self.now();
         let truncated_now = self.config.truncated_now(now);
         self.check_budget(now, truncated_now)
     }
@@ -48,9 +181,13 @@ impl ProjectStats {
     /// Records spent budget.
     ///
     /// This will also update internal state when checking.
+    /// Negative spending is ignored, use [`ProjectStats::record_refund`] instead.
     pub fn record_spending(&mut self, spent: f64) -> bool {
-        let now = self.config.now();
+        // `max` also turns `NaN` into `0`.
+        let spent = spent.max(0.);
+        let now = self.now();
         let truncated_now = self.config.truncated_now(now);
+        self.last_updated = now;
 
         match self.budget_buckets.front_mut() {
             Some(latest) if latest.0 >= truncated_now => latest.1 += spent,
@@ -61,23 +198,254 @@ impl ProjectStats {
             self.budget_buckets.pop_back();
         }
 
+        if let Some(bucket_size) = self.config.slow_bucket_size() {
+            let truncated_now = self.config.truncated_to(now, bucket_size);
+            match self.slow_buckets.front_mut() {
+                Some(latest) if latest.0 >= truncated_now => latest.1 += spent,
+                _ => self.slow_buckets.push_front((truncated_now, spent)),
+            }
+            if self.slow_buckets.len() > self.config.num_buckets {
+                self.slow_buckets.pop_back();
+            }
+        }
+
+        self.check_budget(now, truncated_now)
+    }
+
+    /// Refunds previously spent budget, for example when spending was accidentally double-counted.
+    ///
+    /// The refund is subtracted from the most recent bucket, which will not go below zero.
+    /// This only works if the config [allows refunds](BudgetingConfig::allow_refunds).
+    pub fn record_refund(&mut self, refunded: f64) -> Result<bool, RefundError> {
+        if !self.config.allow_refunds {
+            return Err(RefundError::NotAllowed);
+        }
+        if !(refunded.is_finite() && refunded >= 0.) {
+            return Err(RefundError::InvalidAmount);
+        }
+
+        Ok(self.release_spending(refunded, None))
+    }
+
+    /// Releases previously spent budget, which was recorded at `recorded_at`, or most recently.
+    ///
+    /// This is subtracted from the bucket which was current at `recorded_at`, which will not go below zero.
+    /// Nothing is released if that bucket has since been dropped, as its spending is no longer counted.
+    fn release_spending(&mut self, released: f64, recorded_at: Option<Instant>) -> bool {
+        let now = self.now();
+        let truncated_now = self.config.truncated_now(now);
+        self.last_updated = now;
+
+        for buckets in [&mut self.budget_buckets, &mut self.slow_buckets] {
+            // The buckets are kept newest first.
+            let bucket = match recorded_at {
+                Some(recorded_at) => buckets.iter_mut().find(|b| b.0 <= recorded_at),
+                None => buckets.front_mut(),
+            };
+            if let Some(bucket) = bucket {
+                bucket.1 = (bucket.1 - released).max(0.);
+            }
+        }
+
         self.check_budget(now, truncated_now)
     }
 
+    /// Checks whether recording the `spent` budget would exceed the budget, without recording it.
+    ///
+    /// While in backoff, this is the current "exceeded" state, as recording can't change it.
+    pub fn would_exceed(&self, spent: f64) -> bool {
+        self.would_exceed_at(spent, self.now())
+    }
+
+    /// Returns the spent budget within the current window, averaged *per-second*.
+    pub fn spent_budget(&self) -> f64 {
+        self.spend_rate(self.now())
+    }
+
+    /// Returns a [`ProjectReport`] describing the current state.
+    ///
+    /// In contrast to [`ProjectStats::exceeds_budget`], this does not update the "exceeded" state.
+    pub fn report(&self) -> ProjectReport {
+        self.report_at(self.now())
+    }
+
+    /// Returns the current time, which never goes backwards for this project.
+    ///
+    /// The recent time of the [`Clock`](quanta::Clock) is updated periodically, and concurrent callers
+    /// might observe slightly different, or even stale, times. Clamping the time to the last update
+    /// makes sure that spending is never sorted into an older bucket, and checks never go back in time.
+    fn now(&self) -> Instant {
+        self.config.now().max(self.last_updated)
+    }
+
+    /// Returns a [`ProjectReport`] describing the state at the given `now`.
+    fn report_at(&self, now: Instant) -> ProjectReport {
+        let now = now.max(self.last_updated);
+        let backoff_remaining = self
+            .backoff_deadline
+            .filter(|deadline| *deadline > now)
+            .map(|deadline| deadline - now);
+
+        let truncated_now = self.config.truncated_now(now);
+        let spent_budget = self.calculate_spent_budget(now, truncated_now);
+        let window = self.adjusted_time_window(now, truncated_now);
+        let ramp_fraction = self.ramp_fraction(now);
+        let budget = self.config.budget * ramp_fraction;
+        let mut remaining_budget = ((budget - spent_budget) * window.as_secs_f64()).max(0.);
+        // Both windows need to be exceeded, so the project may spend up to the larger remainder.
+        if let (Some(slow_burn), Some((slow_spent, slow_window))) =
+            (&self.config.slow_burn, self.slow_burn_spend(now))
+        {
+            let slow_budget = slow_burn.budget * ramp_fraction;
+            let slow_remaining = slow_budget * slow_window.as_secs_f64() - slow_spent;
+            remaining_budget = remaining_budget.max(slow_remaining);
+        }
+
+        ProjectReport {
+            exceeds_budget: self.exceeds_budget,
+            spent_budget,
+            budget,
+            backoff_remaining,
+            remaining_budget,
+            blocked_for: self
+                .blocked_since
+                .map(|since| now.saturating_duration_since(since)),
+            transitions: self.transitions.count(now),
+            last_transition: self.transitions.last(now),
+            recent_sources: self.sources.recent(),
+            first_seen: self.first_seen,
+            last_updated: self.last_updated,
+        }
+    }
+
+    /// Checks whether recording the `spent` budget at the given `now` would exceed the budget.
+    fn would_exceed_at(&self, spent: f64, now: Instant) -> bool {
+        let now = now.max(self.last_updated);
+        if self.backoff_deadline.is_some_and(|deadline| deadline > now) {
+            return self.exceeds_budget;
+        }
+
+        let truncated_now = self.config.truncated_now(now);
+        let window = self.adjusted_time_window(now, truncated_now);
+        let spent_budget =
+            self.calculate_spent_budget(now, truncated_now) + spent.max(0.) / window.as_secs_f64();
+        spent_budget > self.budget_at(now) && self.exceeds_slow_burn(spent.max(0.), now)
+    }
+
+    /// Returns why the project is in its current "exceeded" state at the given `now`.
+    fn decision_reason_at(&self, now: Instant) -> DecisionReason {
+        let now = now.max(self.last_updated);
+        let truncated_now = self.config.truncated_now(now);
+        let spent_budget = self.calculate_spent_budget(now, truncated_now);
+        let exceeds_at = |ramp_fraction: f64| {
+            spent_budget > self.config.budget * ramp_fraction
+                && self.exceeds_slow_burn_at(0., now, ramp_fraction)
+        };
+
+        let ramp_fraction = self.ramp_fraction(now);
+        let in_backoff = self.backoff_deadline.is_some_and(|deadline| deadline > now);
+        if in_backoff && exceeds_at(ramp_fraction) != self.exceeds_budget {
+            DecisionReason::Backoff
+        } else if !self.exceeds_budget {
+            DecisionReason::UnderBudget
+        } else if ramp_fraction < 1. && !exceeds_at(1.) {
+            DecisionReason::WarmingUp
+        } else {
+            DecisionReason::OverBudget
+        }
+    }
+
+    /// Returns the fraction of the budget the project gets at the given `now`, see [`RampUp`].
+    ///
+    /// [`RampUp`]: crate::RampUp
+    fn ramp_fraction(&self, now: Instant) -> f64 {
+        match &self.config.ramp_up {
+            Some(ramp_up) => ramp_up.fraction(now.saturating_duration_since(self.first_seen)),
+            None => 1.,
+        }
+    }
+
+    /// Returns the budget of the project at the given `now`, which is reduced for new projects.
+    fn budget_at(&self, now: Instant) -> f64 {
+        self.config.budget * self.ramp_fraction(now)
+    }
+
+    /// Returns the total spent budget within the slow-burn window, along with its real length.
+    fn slow_burn_spend(&self, now: Instant) -> Option<(f64, Duration)> {
+        let slow_burn = self.config.slow_burn.as_ref()?;
+        let bucket_size = self.config.slow_bucket_size()?;
+        let truncated_now = self.config.truncated_to(now, bucket_size);
+
+        let earliest_time = truncated_now.checked_sub(slow_burn.budgeting_window);
+        let total_spent_budget = self
+            .slow_buckets
+            .iter()
+            .filter_map(|b| earliest_time.is_none_or(|t| b.0 >= t).then_some(b.1))
+            .sum();
+        let window = adjusted_window(slow_burn.budgeting_window, bucket_size, now - truncated_now);
+        Some((total_spent_budget, window))
+    }
+
+    /// Checks whether spending `spent` in addition would exceed the slow-burn window.
+    ///
+    /// Without a slow-burn window, only the regular window is relevant, so this is always `true`.
+    fn exceeds_slow_burn(&self, spent: f64, now: Instant) -> bool {
+        self.exceeds_slow_burn_at(spent, now, self.ramp_fraction(now))
+    }
+
+    /// Checks whether spending `spent` in addition would exceed the given fraction of the slow-burn budget.
+    fn exceeds_slow_burn_at(&self, spent: f64, now: Instant, ramp_fraction: f64) -> bool {
+        match (&self.config.slow_burn, self.slow_burn_spend(now)) {
+            (Some(slow_burn), Some((slow_spent, window))) => {
+                (slow_spent + spent) / window.as_secs_f64() > slow_burn.budget * ramp_fraction
+            }
+            _ => true,
+        }
+    }
+
     /// Checks whether all of the buckets are outside the current `budgeting_window`.
     ///
     /// This means that these stats can be cleaned up.
+    /// Projects exceeding their budget are kept for an additional `grace_period`.
     pub fn is_stale(&self, now: Instant) -> bool {
+        let grace_period = if self.exceeds_budget {
+            self.config.grace_period
+        } else {
+            Duration::ZERO
+        };
+
         let truncated_now = self.config.truncated_now(now);
         if let Some(deadline) = self.backoff_deadline {
             // we are in backoff, so no cleanup should happen
-            if deadline > now {
+            if saturating_add(deadline, grace_period) > now {
                 return false;
             }
         }
 
-        let earliest_time = truncated_now - self.config.budgeting_window;
-        self.budget_buckets.iter().all(|b| b.0 < earliest_time)
+        let Some(earliest_time) =
+            truncated_now.checked_sub(self.config.budgeting_window + grace_period)
+        else {
+            return false;
+        };
+        if let Some(slow_burn) = &self.config.slow_burn {
+            // The spending within the slow-burn window needs to be kept around as well.
+            let Some(earliest_time) =
+                truncated_now.checked_sub(slow_burn.budgeting_window + grace_period)
+            else {
+                return false;
+            };
+            if self.slow_buckets.iter().any(|b| b.0 >= earliest_time) {
+                return false;
+            }
+        }
+        // Stats that were only just created, like pre-warmed ones, are kept for a whole window as well.
+        self.last_updated < earliest_time && self.budget_buckets.iter().all(|b| b.0 < earliest_time)
+    }
+
+    /// Returns the spent budget at the given `now`, averaged *per-second*.
+    fn spend_rate(&self, now: Instant) -> f64 {
+        let truncated_now = self.config.truncated_now(now);
+        self.calculate_spent_budget(now, truncated_now)
     }
 
     /// Checks whether this project exceeds its allotted budget.
@@ -91,20 +459,22 @@ impl ProjectStats {
             self.backoff_deadline = None;
         }
 
-        let spent_budget = self.spent_budget(now, truncated_now);
+        let spent_budget = self.calculate_spent_budget(now, truncated_now);
 
-        let exceeds_budget = spent_budget > self.config.budget;
+        let exceeds_budget = spent_budget > self.budget_at(now) && self.exceeds_slow_burn(0., now);
 
         if self.exceeds_budget != exceeds_budget {
             self.exceeds_budget = exceeds_budget;
-            self.backoff_deadline = Some(now + self.config.backoff_duration);
+            self.backoff_deadline = Some(saturating_add(now, self.config.backoff_duration));
+            self.blocked_since = exceeds_budget.then_some(now);
+            self.transitions.record(now);
         }
 
         exceeds_budget
     }
 
     /// Returns the spent budget, averaged *per-second*.
-    fn spent_budget(&self, now: Instant, truncated_now: Instant) -> f64 {
+    fn calculate_spent_budget(&self, now: Instant, truncated_now: Instant) -> f64 {
         let earliest_time = truncated_now - self.config.budgeting_window;
         let total_spent_budget: f64 = self
             .budget_buckets
@@ -115,17 +485,182 @@ impl ProjectStats {
         // The configured budget is meant as a per-second budget.
         // To calculate that, we want to divide by the real passed time,
         // to avoid any artifacts resulting from the bucketing as much as possible.
-        let adjustment = now - truncated_now;
-        let adjusted_time_window = if adjustment == Duration::ZERO {
-            // If `adjustment` is `0`, the `budgeting_window` is already exactly correct.
-            self.config.budgeting_window
+        let adjusted_time_window = self.adjusted_time_window(now, truncated_now);
+
+        total_spent_budget / adjusted_time_window.as_secs_f64()
+    }
+
+    /// Returns the real time covered by the current window, accounting for an incomplete bucket.
+    fn adjusted_time_window(&self, now: Instant, truncated_now: Instant) -> Duration {
+        adjusted_window(
+            self.config.budgeting_window,
+            self.config.bucket_size,
+            now - truncated_now,
+        )
+    }
+}
+
+/// Returns the real time covered by a `window`, given how far the current bucket is in already.
+fn adjusted_window(window: Duration, bucket_size: Duration, adjustment: Duration) -> Duration {
+    if adjustment == Duration::ZERO {
+        // If `adjustment` is `0`, the `window` is already exactly correct.
+        window
+    } else {
+        // If `adjustment` is not `0`, we have started a new, incomplete bucket.
+        // We subtract that bucket's size and add the adjustment instead.
+        window.saturating_sub(bucket_size) + adjustment
+    }
+}
+
+/// Splits the spending of the given `buckets` (newest first) into buckets of the `new_size`.
+///
+/// The spending of each bucket is split proportionally to how much of it each new bucket covers,
+/// assuming it was spent evenly. The newest bucket is only covered up until `now`.
+fn rebucket(
+    config: &BudgetingConfig,
+    buckets: &VecDeque<(Instant, f64)>,
+    old_size: Duration,
+    new_size: Duration,
+    now: Instant,
+) -> VecDeque<(Instant, f64)> {
+    let mut rebucketed: VecDeque<(Instant, f64)> = VecDeque::with_capacity(config.num_buckets + 1);
+    // Going from the oldest bucket, the new buckets are pushed to the front in order.
+    for (idx, &(start, spent)) in buckets.iter().enumerate().rev() {
+        let end = if idx == 0 {
+            (start + old_size).min(now)
         } else {
-            // If `adjustment` is not `0`, we have started a new, incomplete bucket.
-            // We subtract that bucket's size and add the adjustment instead.
-            self.config.budgeting_window - self.config.bucket_size + adjustment
+            start + old_size
         };
+        let covered = end.saturating_duration_since(start);
 
-        total_spent_budget / adjusted_time_window.as_secs_f64()
+        let mut new_start = config.truncated_to(start, new_size);
+        loop {
+            let new_end = new_start + new_size;
+            let share = if covered.is_zero() {
+                1.
+            } else {
+                let overlap = new_end
+                    .min(end)
+                    .saturating_duration_since(start.max(new_start));
+                overlap.as_secs_f64() / covered.as_secs_f64()
+            };
+            match rebucketed.front_mut() {
+                Some(latest) if latest.0 == new_start => latest.1 += spent * share,
+                _ => rebucketed.push_front((new_start, spent * share)),
+            }
+            if new_end >= end {
+                break;
+            }
+            new_start = new_end;
+        }
+    }
+    rebucketed
+}
+
+/// The default, sliding window [`BudgetTracker`].
+impl BudgetTracker for ProjectStats {
+    fn record(&mut self, spent: f64) -> bool {
+        self.record_spending(spent)
+    }
+
+    fn record_source(&mut self, source: &str) {
+        self.sources.record(source);
+    }
+
+    fn refund(&mut self, refunded: f64) -> Result<bool, RefundError> {
+        self.record_refund(refunded)
+    }
+
+    fn release(&mut self, released: f64, recorded_at: Instant) -> bool {
+        self.release_spending(released, Some(recorded_at))
+    }
+
+    fn check(&mut self) -> bool {
+        self.exceeds_budget()
+    }
+
+    fn cached_check(&self) -> bool {
+        self.exceeds_budget
+    }
+
+    fn decision_reason(&self, now: Instant) -> DecisionReason {
+        self.decision_reason_at(now)
+    }
+
+    fn would_exceed(&self, spent: f64, now: Instant) -> bool {
+        self.would_exceed_at(spent, now)
+    }
+
+    fn is_stale(&self, now: Instant) -> bool {
+        ProjectStats::is_stale(self, now)
+    }
+
+    fn memory_usage(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + (self.budget_buckets.capacity() + self.slow_buckets.capacity())
+                * std::mem::size_of::<(Instant, f64)>()
+            + self.transitions.memory_usage()
+            + self.sources.memory_usage()
+    }
+
+    fn report(&self, now: Instant) -> ProjectReport {
+        self.report_at(now)
+    }
+
+    fn bucket_spending(&self, now: Instant) -> Vec<(Duration, f64)> {
+        let truncated_now = self.config.truncated_now(now);
+        let bucket_size = self.config.bucket_size;
+        (0..self.config.num_buckets.max(1) as u32)
+            .rev()
+            .map(|idx| {
+                let start = truncated_now - bucket_size * idx;
+                let spent = (self.budget_buckets.iter())
+                    .filter(|b| b.0 >= start && b.0 < start + bucket_size)
+                    // an empty `sum` of floats is `-0.0`
+                    .fold(0., |spent, b| spent + b.1);
+                (now.saturating_duration_since(start), spent)
+            })
+            .collect()
+    }
+
+    /// The windows and the backoff resume where they were before the skipped time.
+    /// The [`first_seen`](ProjectReport::first_seen) time and the recent transitions are kept as-is.
+    fn skip_time(&mut self, skipped: Duration) {
+        for bucket in self.budget_buckets.iter_mut().chain(&mut self.slow_buckets) {
+            bucket.0 += skipped;
+        }
+        if let Some(deadline) = &mut self.backoff_deadline {
+            *deadline = saturating_add(*deadline, skipped);
+        }
+        if let Some(since) = &mut self.blocked_since {
+            *since += skipped;
+        }
+        self.last_updated += skipped;
+    }
+
+    fn config(&self) -> &Arc<BudgetingConfig> {
+        &self.config
+    }
+
+    /// If the bucket size changes, the recorded spending is re-bucketed into the new bucket size,
+    /// so that the project keeps its accounting. The same applies to the slow-burn buckets.
+    /// Buckets that exceed the `num_buckets` of the new config are discarded.
+    fn set_config(&mut self, config: Arc<BudgetingConfig>) {
+        let now = config.now().max(self.last_updated);
+        if config.bucket_size != self.config.bucket_size {
+            let (old_size, new_size) = (self.config.bucket_size, config.bucket_size);
+            self.budget_buckets = rebucket(&config, &self.budget_buckets, old_size, new_size, now);
+        }
+        self.budget_buckets.truncate(config.num_buckets);
+        match (self.config.slow_bucket_size(), config.slow_bucket_size()) {
+            (Some(old_size), Some(new_size)) if old_size != new_size => {
+                self.slow_buckets = rebucket(&config, &self.slow_buckets, old_size, new_size, now);
+            }
+            (_, None) => self.slow_buckets.clear(),
+            _ => {}
+        }
+        self.slow_buckets.truncate(config.num_buckets);
+        self.config = config;
     }
 }
 
@@ -189,6 +724,488 @@ mod tests {
         assert!(stats.is_stale(timer.now()));
     }
 
+    #[test]
+    fn test_would_exceed() {
+        let (clock, mock) = Clock::mock();
+        mock.increment(Duration::from_secs(100));
+        let timer = Timer::new(clock);
+
+        let config = BudgetingConfig::new(
+            Duration::from_secs(10),
+            Duration::from_secs(5),
+            Duration::from_secs(1),
+            20.,
+        )
+        .with_timer(timer);
+
+        let mut stats = ProjectStats::new(Arc::new(config));
+        stats.record_spending(50.);
+
+        // 50 out of 100 were spent so far
+        assert!(!stats.would_exceed(50.));
+        assert!(stats.would_exceed(51.));
+        assert!(!stats.exceeds_budget());
+    }
+
+    #[test]
+    fn test_report() {
+        let (clock, mock) = Clock::mock();
+        mock.increment(Duration::from_secs(100));
+        let timer = Timer::new(clock);
+
+        let config = BudgetingConfig::new(
+            Duration::from_secs(10),
+            Duration::from_secs(5),
+            Duration::from_secs(1),
+            20.,
+        )
+        .with_timer(timer.clone());
+
+        let first_seen = timer.now();
+        let mut stats = ProjectStats::new(Arc::new(config));
+
+        stats.record_spending(50.);
+        mock.increment(Duration::from_millis(1500));
+        stats.record_spending(25.);
+        mock.increment(Duration::from_millis(750));
+
+        assert!(stats.record_spending(40.));
+        let last_updated = timer.now();
+        assert_eq!(stats.report().blocked_for, Some(Duration::ZERO));
+
+        mock.increment(Duration::from_secs(6));
+        assert_eq!(stats.report().blocked_for, Some(Duration::from_secs(6)));
+
+        // the backoff deadline has passed, we are unblocked
+        mock.increment(Duration::from_secs(5));
+        assert!(!stats.exceeds_budget());
+
+        let report = stats.report();
+        assert!(!report.exceeds_budget);
+        assert_eq!(report.spent_budget, 0.);
+        // the current bucket is only a quarter second in
+        assert_eq!(report.remaining_budget, 20. * 4.25);
+        assert_eq!(report.backoff_remaining, Some(Duration::from_secs(10)));
+        assert_eq!(report.blocked_for, None);
+        assert_eq!(report.transitions, 2);
+        // checking the budget does not count as an update
+        assert_eq!(report.first_seen, first_seen);
+        assert_eq!(report.last_updated, last_updated);
+    }
+
+    #[test]
+    fn test_new_stats_are_not_stale() {
+        let (clock, mock) = Clock::mock();
+        mock.increment(Duration::from_secs(100));
+        let timer = Timer::new(clock);
+
+        let config = BudgetingConfig::new(
+            Duration::from_secs(10),
+            Duration::from_secs(5),
+            Duration::from_secs(1),
+            20.,
+        )
+        .with_timer(timer.clone());
+        let stats = ProjectStats::new(Arc::new(config));
+
+        // stats without any spending are kept for one window after they were created
+        assert!(!stats.is_stale(timer.now()));
+        mock.increment(Duration::from_secs(6));
+        assert!(stats.is_stale(timer.now()));
+    }
+
+    #[test]
+    fn test_bucket_spending() {
+        let (clock, mock) = Clock::mock();
+        mock.increment(Duration::from_secs(100));
+        let timer = Timer::new(clock);
+
+        let config = BudgetingConfig::new(
+            Duration::from_secs(10),
+            Duration::from_secs(3),
+            Duration::from_secs(1),
+            20.,
+        )
+        .with_timer(timer.clone());
+        let mut stats = ProjectStats::new(Arc::new(config));
+
+        stats.record_spending(10.);
+        mock.increment(Duration::from_millis(1500));
+        stats.record_spending(5.);
+        stats.record_spending(5.);
+
+        let spending = stats.bucket_spending(timer.now());
+        let ms = Duration::from_millis;
+        assert_eq!(spending, [(ms(2500), 0.), (ms(1500), 10.), (ms(500), 10.)]);
+
+        // old buckets move out of the window
+        mock.increment(Duration::from_secs(2));
+        let spending = stats.bucket_spending(timer.now());
+        assert_eq!(spending, [(ms(2500), 10.), (ms(1500), 0.), (ms(500), 0.)]);
+    }
+
+    #[test]
+    fn test_refunds() {
+        let (clock, mock) = Clock::mock();
+        mock.increment(Duration::from_secs(100));
+        let timer = Timer::new(clock);
+
+        let config = BudgetingConfig::new(
+            Duration::from_secs(10),
+            Duration::from_secs(5),
+            Duration::from_secs(1),
+            20.,
+        )
+        .with_timer(timer.clone());
+        let mut stats = ProjectStats::new(Arc::new(config));
+
+        stats.record_spending(50.);
+        // negative spending is ignored
+        stats.record_spending(-50.);
+        assert_eq!(stats.spent_budget(), 50. / 5.);
+        assert_eq!(stats.report().remaining_budget, 100. - 50.);
+        assert_eq!(stats.record_refund(10.), Err(RefundError::NotAllowed));
+
+        let config = BudgetingConfig::new(
+            Duration::from_secs(10),
+            Duration::from_secs(5),
+            Duration::from_secs(1),
+            20.,
+        )
+        .with_allow_refunds(true)
+        .with_timer(timer.clone());
+        BudgetTracker::set_config(&mut stats, Arc::new(config));
+
+        assert_eq!(stats.record_refund(-10.), Err(RefundError::InvalidAmount));
+        assert_eq!(
+            stats.record_refund(f64::NAN),
+            Err(RefundError::InvalidAmount)
+        );
+
+        assert_eq!(stats.record_refund(10.), Ok(false));
+        assert_eq!(stats.spent_budget(), 40. / 5.);
+
+        // refunds are clamped at zero
+        assert_eq!(stats.record_refund(100.), Ok(false));
+        assert_eq!(stats.spent_budget(), 0.);
+    }
+
+    #[test]
+    fn test_release() {
+        let (clock, mock) = Clock::mock();
+        mock.increment(Duration::from_secs(100));
+        let timer = Timer::new(clock);
+
+        let config = BudgetingConfig::new(
+            Duration::from_secs(10),
+            Duration::from_secs(5),
+            Duration::from_secs(1),
+            20.,
+        )
+        .with_timer(timer.clone());
+        let mut stats = ProjectStats::new(Arc::new(config));
+
+        let recorded_at = timer.now();
+        stats.record_spending(60.);
+        mock.increment(Duration::from_secs(2));
+        stats.record_spending(30.);
+
+        // the budget is released from the bucket it was recorded in, even without refunds
+        assert!(!BudgetTracker::release(&mut stats, 50., recorded_at));
+        assert_eq!(stats.spent_budget(), 40. / 5.);
+
+        // nothing is released once that bucket was dropped
+        for _ in 0..5 {
+            mock.increment(Duration::from_secs(1));
+            stats.record_spending(10.);
+        }
+        assert!(!BudgetTracker::release(&mut stats, 10., recorded_at));
+        assert_eq!(stats.spent_budget(), 50. / 5.);
+    }
+
+    #[test]
+    fn test_rebucketing() {
+        let (clock, mock) = Clock::mock();
+        mock.increment(Duration::from_secs(100));
+        let timer = Timer::new(clock);
+
+        let config = |bucket_size| {
+            let config = BudgetingConfig::new(
+                Duration::from_secs(10),
+                Duration::from_secs(20),
+                Duration::from_secs(bucket_size),
+                100.,
+            )
+            .with_timer(timer.clone());
+            Arc::new(config)
+        };
+        let mut stats = ProjectStats::new(config(10));
+        stats.record_spending(100.);
+        mock.increment(Duration::from_secs(14));
+        stats.record_spending(60.);
+        assert_eq!(stats.budget_buckets.len(), 2);
+
+        // the older bucket is split evenly, the newer one only up until now
+        BudgetTracker::set_config(&mut stats, config(2));
+        let buckets: Vec<_> = stats.budget_buckets.iter().map(|b| b.1).collect();
+        assert_eq!(buckets, [30., 30., 20., 20., 20., 20., 20.]);
+        assert_eq!(stats.spent_budget(), 160. / 20.);
+
+        // merging buckets keeps the total spending
+        BudgetTracker::set_config(&mut stats, config(20));
+        let buckets: Vec<_> = stats.budget_buckets.iter().map(|b| b.1).collect();
+        assert_eq!(buckets, [160.]);
+    }
+
+    #[test]
+    #[cfg(feature = "service")]
+    fn test_with_spending() {
+        let (clock, mock) = Clock::mock();
+        mock.increment(Duration::from_secs(100));
+        let timer = Timer::new(clock);
+
+        let config = |bucket_ms, budget| {
+            let config = BudgetingConfig::new(
+                Duration::from_secs(10),
+                Duration::from_secs(4),
+                Duration::from_millis(bucket_ms),
+                budget,
+            )
+            .with_timer(timer.clone());
+            Arc::new(config)
+        };
+        let mut stats = ProjectStats::new(config(1_000, 20.));
+        stats.record_spending(20.);
+        mock.increment(Duration::from_millis(1500));
+        stats.record_spending(20.);
+        let now = timer.now();
+        let spending = stats.bucket_spending(now);
+        let (first_seen, second) = (stats.first_seen, Duration::from_secs(1));
+
+        // the same spending exceeds a lower budget
+        let simulated =
+            ProjectStats::with_spending(config(1_000, 5.), &spending, second, first_seen, now);
+        let report = simulated.report_at(now);
+        assert!(report.exceeds_budget);
+        assert_eq!(report.spent_budget, 40. / 3.5);
+        assert_eq!(report.backoff_remaining, Some(Duration::from_secs(10)));
+        assert!(!stats.exceeds_budget());
+
+        // the spending is split into smaller buckets, keeping its total
+        let simulated =
+            ProjectStats::with_spending(config(500, 20.), &spending, second, first_seen, now);
+        let buckets: Vec<_> = simulated.budget_buckets.iter().map(|b| b.1).collect();
+        assert_eq!(buckets, [20., 10., 10.]);
+        assert!(!simulated.report_at(now).exceeds_budget);
+    }
+
+    #[test]
+    fn test_slow_burn() {
+        let (clock, mock) = Clock::mock();
+        mock.increment(Duration::from_secs(1_000));
+        let timer = Timer::new(clock);
+
+        // 10 per second within 10 seconds, and 2 per second within 100 seconds
+        let config = BudgetingConfig::new(
+            Duration::from_secs(1),
+            Duration::from_secs(10),
+            Duration::from_secs(1),
+            10.,
+        )
+        .with_slow_burn(Duration::from_secs(100), 2.)
+        .with_timer(timer.clone());
+        let mut stats = ProjectStats::new(Arc::new(config));
+
+        // a short spike only exceeds the regular window
+        assert!(!stats.record_spending(150.));
+        assert!(!stats.would_exceed(50.));
+        assert!(stats.would_exceed(51.));
+        assert_eq!(stats.report().remaining_budget, 200. - 150.);
+
+        // sustained spending exceeds both windows
+        for _ in 0..4 {
+            mock.increment(Duration::from_secs(10));
+            stats.record_spending(150.);
+        }
+        assert!(stats.exceeds_budget());
+
+        // the project is unblocked once the regular window calms down
+        mock.increment(Duration::from_secs(20));
+        assert!(!stats.exceeds_budget());
+
+        // the slow-burn window keeps the project around
+        mock.increment(Duration::from_secs(20));
+        assert!(!stats.is_stale(timer.now()));
+        mock.increment(Duration::from_secs(100));
+        assert!(stats.is_stale(timer.now()));
+    }
+
+    #[test]
+    fn test_stale_recent_time() {
+        let (clock, mock) = Clock::mock();
+        mock.increment(Duration::from_millis(1_000_500));
+        let timer = Timer::new(clock);
+
+        let config = BudgetingConfig::new(
+            Duration::from_secs(1),
+            Duration::from_secs(10),
+            Duration::from_secs(1),
+            10.,
+        )
+        .with_timer(timer.clone());
+        let mut stats = ProjectStats::new(Arc::new(config));
+        assert!(!stats.record_spending(50.));
+        let last_updated = stats.report().last_updated;
+
+        // another caller observes a stale recent time, which is two buckets behind
+        mock.decrement(Duration::from_secs(2));
+        assert!(!stats.would_exceed(50.));
+        assert!(stats.would_exceed(60.));
+        assert!(stats.record_spending(60.));
+        assert_eq!(stats.budget_buckets.len(), 1);
+        assert_eq!(stats.report().last_updated, last_updated);
+        assert!(stats.exceeds_budget());
+
+        // once the time catches up, the spending is still in the same bucket
+        mock.increment(Duration::from_secs(2));
+        stats.record_spending(0.);
+        assert_eq!(stats.budget_buckets.len(), 1);
+        assert_eq!(stats.report().last_updated, last_updated);
+    }
+
+    #[test]
+    fn test_ramp_up() {
+        let (clock, mock) = Clock::mock();
+        mock.increment(Duration::from_secs(1_000));
+        let timer = Timer::new(clock);
+
+        // 10 per second within 10 seconds, ramping up from 1 per second within 100 seconds
+        let config = BudgetingConfig::new(
+            Duration::from_secs(1),
+            Duration::from_secs(10),
+            Duration::from_secs(1),
+            10.,
+        )
+        .with_ramp_up(Duration::from_secs(100), 0.1)
+        .with_timer(timer.clone());
+        let config = Arc::new(config);
+
+        let mut stats = ProjectStats::new(config.clone());
+        assert_eq!(stats.report().budget, 1.);
+        assert!(!stats.record_spending(5.));
+        assert!(stats.would_exceed(10.));
+        assert!(stats.record_spending(10.));
+
+        // half way through, the project gets 55% of its budget
+        mock.increment(Duration::from_secs(50));
+        let mut stats = ProjectStats::new(config.clone());
+        mock.increment(Duration::from_secs(50));
+        assert_eq!(stats.report().budget, 5.5);
+        assert!(!stats.record_spending(50.));
+        assert!(stats.record_spending(10.));
+
+        // an established project gets the full budget
+        mock.increment(Duration::from_secs(50));
+        let mut stats = ProjectStats::new(config);
+        mock.increment(Duration::from_secs(100));
+        assert_eq!(stats.report().budget, 10.);
+        assert!(!stats.record_spending(90.));
+    }
+
+    #[test]
+    fn test_decision_reason() {
+        let (clock, mock) = Clock::mock();
+        mock.increment(Duration::from_secs(1_000));
+        let timer = Timer::new(clock);
+
+        let config = BudgetingConfig::new(
+            Duration::from_secs(60),
+            Duration::from_secs(10),
+            Duration::from_secs(1),
+            10.,
+        )
+        .with_ramp_up(Duration::from_secs(100), 0.1)
+        .with_timer(timer.clone());
+        let config = Arc::new(config);
+
+        let mut stats = ProjectStats::new(config.clone());
+        assert!(!stats.record_spending(5.));
+        assert_eq!(
+            stats.decision_reason(config.now()),
+            DecisionReason::UnderBudget
+        );
+        // the project would be well within its full budget
+        assert!(stats.record_spending(10.));
+        assert_eq!(
+            stats.decision_reason(config.now()),
+            DecisionReason::WarmingUp
+        );
+
+        // the spending left the window, but the project is still in backoff
+        mock.increment(Duration::from_secs(20));
+        assert!(stats.exceeds_budget());
+        assert_eq!(stats.decision_reason(config.now()), DecisionReason::Backoff);
+
+        mock.increment(Duration::from_secs(100));
+        let mut stats = ProjectStats::new(config.clone());
+        mock.increment(Duration::from_secs(100));
+        assert!(stats.record_spending(200.));
+        assert_eq!(
+            stats.decision_reason(config.now()),
+            DecisionReason::OverBudget
+        );
+    }
+
+    #[test]
+    fn test_grace_period() {
+        let (clock, mock) = Clock::mock();
+        mock.increment(Duration::from_secs(100));
+        let timer = Timer::new(clock);
+
+        let config = BudgetingConfig::new(
+            Duration::from_secs(10),
+            Duration::from_secs(5),
+            Duration::from_secs(1),
+            20.,
+        )
+        .with_grace_period(Duration::from_secs(30))
+        .with_timer(timer.clone());
+
+        let mut blocked = ProjectStats::new(Arc::new(config));
+        assert!(blocked.record_spending(1_000.));
+
+        // both the window and the backoff have passed, but we are within the grace period
+        mock.increment(Duration::from_secs(20));
+        assert!(!blocked.is_stale(timer.now()));
+
+        mock.increment(Duration::from_secs(25));
+        assert!(blocked.is_stale(timer.now()));
+    }
+
+    #[test]
+    fn test_overflowing_backoff() {
+        let (clock, mock) = Clock::mock();
+        mock.increment(Duration::from_secs(100));
+        let timer = Timer::new(clock);
+
+        let config = BudgetingConfig::new(
+            Duration::from_secs(18_446_744_000),
+            Duration::from_secs(5),
+            Duration::from_secs(1),
+            20.,
+        )
+        .with_grace_period(Duration::MAX)
+        .with_timer(timer.clone());
+
+        // the backoff deadline saturates instead of overflowing the clock
+        let mut stats = ProjectStats::new(Arc::new(config));
+        assert!(stats.record_spending(1_000.));
+        mock.increment(Duration::from_secs(3600));
+        assert!(stats.exceeds_budget());
+        assert!(!stats.is_stale(timer.now()));
+        stats.skip_time(Duration::from_secs(60));
+    }
+
     #[test]
     fn test_adjusted_budget() {
         let (clock, mock) = Clock::mock();
@@ -213,7 +1230,7 @@ mod tests {
             if i > 50 {
                 let now = timer.now();
                 let truncated_now = stats.config.truncated_now(now);
-                let spent_budget = stats.spent_budget(now, truncated_now);
+                let spent_budget = stats.calculate_spent_budget(now, truncated_now);
 
                 // we are spending 100 per second, but on a higher resolution.
                 // but we expect the rounding that we do to still properly arrive
@@ -0,0 +1,266 @@
+use std::fmt;
+use std::future::{ready, Future};
+use std::pin::Pin;
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use quanta::Instant;
+
+use crate::tracker::BudgetTracker;
+use crate::{BudgetingConfig, ProjectKey};
+
+/// The boxed future returned by the methods of a [`StateStore`].
+pub type StoreFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Stores the [`BudgetTracker`]s of all the projects of a single config, keyed by [`ProjectKey`].
+///
+/// The default is the in-memory [`MemoryStore`]. With the `spill` feature, the `SpillStore` additionally
+/// moves idle projects to disk.
+/// Other backends can be used per config via
+/// [`Service::try_add_config_with_store`](crate::Service::try_add_config_with_store).
+///
+/// All accesses return a [`StoreFuture`], so that backends can do network or disk I/O without blocking.
+/// The async methods of the [`Service`](crate::Service), like
+/// [`Service::try_exceeds_budget_async`](crate::Service::try_exceeds_budget_async) or
+/// [`Service::replace_config_async`](crate::Service::replace_config_async), await the store outside of
+/// any locks of the service, while its synchronous methods and the background maintenance thread block on it.
+///
+/// The store is accessed concurrently from many threads, and has to do its own locking.
+/// The callbacks passed to the store must not access the same store again, as that might deadlock.
+pub trait StateStore: fmt::Debug + Send + Sync {
+    /// Calls `f` with the tracker of the project, if it exists.
+    ///
+    /// Resolves to whether the project exists.
+    fn get<'a>(
+        &'a self,
+        project_id: ProjectKey,
+        f: &'a mut (dyn FnMut(&dyn BudgetTracker) + Send),
+    ) -> StoreFuture<'a, bool>;
+
+    /// Calls `f` with the tracker of the project, locked for modification.
+    ///
+    /// If the project does not exist, a new tracker is created via `insert` first.
+    /// Resolves to whether `f` was called, which is not the case if the project does not exist,
+    /// and no `insert` was given.
+    fn update<'a>(
+        &'a self,
+        project_id: ProjectKey,
+        insert: Option<&'a mut (dyn FnMut() -> Box<dyn BudgetTracker> + Send)>,
+        f: &'a mut (dyn FnMut(&mut dyn BudgetTracker) + Send),
+    ) -> StoreFuture<'a, bool>;
+
+    /// Inserts the tracker of a project, and resolves to the tracker it replaced.
+    fn insert(
+        &self,
+        project_id: ProjectKey,
+        tracker: Box<dyn BudgetTracker>,
+    ) -> StoreFuture<'_, Option<Box<dyn BudgetTracker>>>;
+
+    /// Removes the tracker of a project, if `predicate` returns `true` for it.
+    fn remove<'a>(
+        &'a self,
+        project_id: ProjectKey,
+        predicate: &'a (dyn Fn(&dyn BudgetTracker) -> bool + Sync),
+    ) -> StoreFuture<'a, Option<Box<dyn BudgetTracker>>>;
+
+    /// Returns the number of partitions that can be [scanned](StateStore::scan) independently.
+    ///
+    /// The maintenance scans the partitions in parallel.
+    fn num_partitions(&self) -> usize {
+        1
+    }
+
+    /// Calls `f` with every project of the given partition.
+    fn scan<'a>(
+        &'a self,
+        partition: usize,
+        f: &'a mut (dyn FnMut(ProjectKey, &dyn BudgetTracker) + Send),
+    ) -> StoreFuture<'a, ()>;
+
+    /// Resolves to the number of projects in the store.
+    fn len(&self) -> StoreFuture<'_, usize>;
+
+    /// Resolves to whether the store contains no projects at all.
+    fn is_empty(&self) -> StoreFuture<'_, bool> {
+        Box::pin(async move { self.len().await == 0 })
+    }
+
+    /// Removes all the projects from the store.
+    fn clear(&self) -> StoreFuture<'_, ()>;
+
+    /// Compacts the given partition, right after the maintenance [scanned](StateStore::scan) it at `now`.
+    ///
+    /// This allows stores to move idle projects out of memory, and does nothing by default.
+    fn compact(&self, _partition: usize, _now: Instant) -> StoreFuture<'_, ()> {
+        Box::pin(ready(()))
+    }
+
+    /// Sets the config of the projects which are kept outside of a [`BudgetTracker`].
+    ///
+    /// This is called when the config of the store is replaced, before the trackers are updated,
+    /// and does nothing by default.
+    fn set_config(&self, _config: Arc<BudgetingConfig>) -> StoreFuture<'_, ()> {
+        Box::pin(ready(()))
+    }
+}
+
+/// The default [`StateStore`], which keeps all trackers in memory.
+///
+/// This is a sharded concurrent map, so that many threads can access different projects in parallel.
+/// Each shard is a separate partition for the maintenance. All of its futures are ready right away.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    projects: DashMap<ProjectKey, Box<dyn BudgetTracker>>,
+}
+
+impl StateStore for MemoryStore {
+    fn get<'a>(
+        &'a self,
+        project_id: ProjectKey,
+        f: &'a mut (dyn FnMut(&dyn BudgetTracker) + Send),
+    ) -> StoreFuture<'a, bool> {
+        let exists = match self.projects.get(&project_id) {
+            Some(tracker) => {
+                f(tracker.as_ref());
+                true
+            }
+            None => false,
+        };
+        Box::pin(ready(exists))
+    }
+
+    fn update<'a>(
+        &'a self,
+        project_id: ProjectKey,
+        insert: Option<&'a mut (dyn FnMut() -> Box<dyn BudgetTracker> + Send)>,
+        f: &'a mut (dyn FnMut(&mut dyn BudgetTracker) + Send),
+    ) -> StoreFuture<'a, bool> {
+        // The project usually exists already, in which case we can avoid the more expensive `entry`.
+        if let Some(mut tracker) = self.projects.get_mut(&project_id) {
+            f(tracker.as_mut());
+            return Box::pin(ready(true));
+        }
+        let Some(insert) = insert else {
+            return Box::pin(ready(false));
+        };
+        let mut tracker = self.projects.entry(project_id).or_insert_with(insert);
+        f(tracker.as_mut());
+        Box::pin(ready(true))
+    }
+
+    fn insert(
+        &self,
+        project_id: ProjectKey,
+        tracker: Box<dyn BudgetTracker>,
+    ) -> StoreFuture<'_, Option<Box<dyn BudgetTracker>>> {
+        Box::pin(ready(self.projects.insert(project_id, tracker)))
+    }
+
+    fn remove<'a>(
+        &'a self,
+        project_id: ProjectKey,
+        predicate: &'a (dyn Fn(&dyn BudgetTracker) -> bool + Sync),
+    ) -> StoreFuture<'a, Option<Box<dyn BudgetTracker>>> {
+        let removed = self
+            .projects
+            .remove_if(&project_id, |_k, tracker| predicate(tracker.as_ref()))
+            .map(|(_project_id, tracker)| tracker);
+        Box::pin(ready(removed))
+    }
+
+    fn num_partitions(&self) -> usize {
+        self.projects.shards().len()
+    }
+
+    fn scan<'a>(
+        &'a self,
+        partition: usize,
+        f: &'a mut (dyn FnMut(ProjectKey, &dyn BudgetTracker) + Send),
+    ) -> StoreFuture<'a, ()> {
+        let shard = self.projects.shards()[partition].read();
+        for (project_id, tracker) in shard.iter() {
+            f(*project_id, tracker.get().as_ref());
+        }
+        Box::pin(ready(()))
+    }
+
+    fn len(&self) -> StoreFuture<'_, usize> {
+        Box::pin(ready(self.projects.len()))
+    }
+
+    fn clear(&self) -> StoreFuture<'_, ()> {
+        self.projects.clear();
+        Box::pin(ready(()))
+    }
+}
+
+/// Returns the keys of all the projects in the store.
+pub(crate) async fn project_ids(store: &dyn StateStore) -> Vec<ProjectKey> {
+    let mut project_ids = Vec::with_capacity(store.len().await);
+    for partition in 0..store.num_partitions() {
+        let mut collect = |project_id, _tracker: &dyn BudgetTracker| project_ids.push(project_id);
+        store.scan(partition, &mut collect).await;
+    }
+    project_ids
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use pollster::block_on;
+    use quanta::Clock;
+
+    use crate::config::BudgetingConfig;
+
+    use super::*;
+
+    #[test]
+    fn test_memory_store() {
+        let (clock, mock) = Clock::mock();
+        mock.increment(Duration::from_secs(100));
+        let config = BudgetingConfig::new(
+            Duration::from_secs(10),
+            Duration::from_secs(5),
+            Duration::from_secs(1),
+            10.,
+        )
+        .with_clock(clock);
+        let config = Arc::new(config);
+        let store = MemoryStore::default();
+
+        assert!(!block_on(store.update(1.into(), None, &mut |tracker| {
+            tracker.record(1.);
+        })));
+        assert!(block_on(store.is_empty()));
+
+        let mut insert = || config.new_tracker();
+        for project_id in [1, 2] {
+            let mut record = |tracker: &mut dyn BudgetTracker| {
+                tracker.record(100.);
+            };
+            assert!(block_on(store.update(
+                project_id.into(),
+                Some(&mut insert),
+                &mut record
+            )));
+        }
+        let mut exceeds_budget = false;
+        let mut check = |tracker: &dyn BudgetTracker| exceeds_budget = tracker.cached_check();
+        assert!(block_on(store.get(1.into(), &mut check)));
+        assert!(exceeds_budget);
+        assert!(!block_on(
+            store.get(3.into(), &mut |_tracker| unreachable!())
+        ));
+
+        let not_blocked = |tracker: &dyn BudgetTracker| !tracker.cached_check();
+        assert!(block_on(store.remove(1.into(), &not_blocked)).is_none());
+        let blocked = |tracker: &dyn BudgetTracker| tracker.cached_check();
+        assert!(block_on(store.remove(1.into(), &blocked)).is_some());
+        assert_eq!(block_on(project_ids(&store)), [2.into()]);
+
+        block_on(store.clear());
+        assert_eq!(block_on(store.len()), 0);
+    }
+}
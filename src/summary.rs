@@ -0,0 +1,384 @@
+use std::time::Duration;
+
+use quanta::Instant;
+use serde::Serialize;
+
+use crate::history::SpendRateHistogram;
+use crate::tracker::BudgetTracker;
+use crate::ProjectKey;
+
+/// The maximum number of [`FlipFlopper`]s reported per config.
+pub const MAX_FLIP_FLOPPERS: usize = 10;
+
+/// Aggregated spending of all the projects tracked for a single config.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct SpendSummary {
+    /// The total spend rate (per-second) across all the tracked projects.
+    pub spend_rate: f64,
+
+    /// The number of projects that are currently being tracked.
+    pub tracked_projects: usize,
+
+    /// The number of tracked projects which are exceeding their budget.
+    pub blocked_projects: usize,
+
+    /// The total budget (per-second) across all the tracked projects.
+    pub capacity: f64,
+
+    /// The number of budget checks which answered that a project does not exceed its budget.
+    ///
+    /// This counts all the checks since the config was registered, and is not lagging behind
+    /// like the rest of the summary.
+    pub allowed_decisions: u64,
+
+    /// The number of budget checks which answered that a project exceeds its budget,
+    /// which is the traffic shed by the config. See [`allowed_decisions`](Self::allowed_decisions).
+    pub blocked_decisions: u64,
+
+    /// The distribution of how long the blocked projects have been exceeding their budget.
+    #[serde(skip)]
+    pub blocked_durations: BlockedDurations,
+
+    /// The projects whose "exceeded" state changed most often within the last hour, most frequent first.
+    ///
+    /// Only projects that changed their state at least twice are considered, up to [`MAX_FLIP_FLOPPERS`].
+    #[serde(skip)]
+    pub flip_floppers: Vec<FlipFlopper>,
+
+    /// The distribution of the spend rates of the tracked projects.
+    #[serde(skip)]
+    pub spend_rates: SpendRateHistogram,
+
+    /// The distribution of the budget utilization of the tracked projects.
+    #[serde(skip)]
+    pub utilizations: Utilizations,
+
+    /// The number of projects whose "exceeded" state changed since the previous maintenance pass.
+    ///
+    /// This includes blocked projects which were cleaned up, and thus unblocked.
+    #[serde(skip)]
+    pub state_changes: usize,
+}
+
+/// A project whose "exceeded" state changed repeatedly, see [`SpendSummary::flip_floppers`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct FlipFlopper {
+    /// The key of the project.
+    pub project_id: ProjectKey,
+    /// The number of changes of the "exceeded" state within the last hour.
+    pub transitions: usize,
+}
+
+impl SpendSummary {
+    /// Adds the project tracked by the given [`BudgetTracker`] to this summary.
+    ///
+    /// State changes are counted if they happened after the given `since`.
+    pub(crate) fn add_project(
+        &mut self,
+        project_id: ProjectKey,
+        tracker: &dyn BudgetTracker,
+        now: Instant,
+        since: Option<Instant>,
+    ) {
+        let report = tracker.report(now);
+        if let (Some(last_transition), Some(since)) = (report.last_transition, since) {
+            if last_transition > since {
+                self.state_changes += 1;
+            }
+        }
+        self.spend_rate += report.spent_budget;
+        self.spend_rates.observe(report.spent_budget);
+        if report.budget > 0. {
+            self.utilizations
+                .observe(report.spent_budget / report.budget);
+        }
+        self.tracked_projects += 1;
+        self.capacity += report.budget;
+        if report.exceeds_budget {
+            self.blocked_projects += 1;
+        }
+        if let Some(blocked_for) = report.blocked_for {
+            self.blocked_durations.observe(blocked_for);
+        }
+        if report.transitions >= 2 {
+            self.add_flip_floppers(&[FlipFlopper {
+                project_id,
+                transitions: report.transitions,
+            }]);
+        }
+    }
+
+    /// Adds all the projects of the `other` summary to this one.
+    pub(crate) fn merge(&mut self, other: &SpendSummary) {
+        self.spend_rate += other.spend_rate;
+        self.tracked_projects += other.tracked_projects;
+        self.blocked_projects += other.blocked_projects;
+        self.capacity += other.capacity;
+        self.blocked_durations.merge(&other.blocked_durations);
+        self.add_flip_floppers(&other.flip_floppers);
+        self.spend_rates.merge(&other.spend_rates);
+        self.utilizations.merge(&other.utilizations);
+        self.state_changes += other.state_changes;
+    }
+
+    /// Adds the given projects to the [`flip_floppers`](Self::flip_floppers), keeping only the top ones.
+    fn add_flip_floppers(&mut self, flip_floppers: &[FlipFlopper]) {
+        if flip_floppers.is_empty() {
+            return;
+        }
+        self.flip_floppers.extend_from_slice(flip_floppers);
+        self.flip_floppers
+            .sort_unstable_by_key(|f| (std::cmp::Reverse(f.transitions), f.project_id));
+        self.flip_floppers.truncate(MAX_FLIP_FLOPPERS);
+    }
+
+    /// Returns the ratio of the total spend rate to the total [`capacity`](Self::capacity).
+    ///
+    /// A pressure approaching `1` means that the tracked projects together are close to exhausting
+    /// their budgets, even if no single project is blocked yet.
+    pub fn pressure(&self) -> f64 {
+        if self.capacity > 0. {
+            self.spend_rate / self.capacity
+        } else {
+            0.
+        }
+    }
+}
+
+/// The upper bounds (in seconds) of the buckets of [`BlockedDurations`].
+pub const BLOCKED_DURATION_BUCKETS: [f64; 8] = [
+    10.,
+    60.,
+    300.,
+    900.,
+    3600.,
+    6. * 3600.,
+    24. * 3600.,
+    7. * 24. * 3600.,
+];
+
+/// A histogram of how long projects have been exceeding their budget.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BlockedDurations {
+    /// The number of projects per bucket of [`BLOCKED_DURATION_BUCKETS`].
+    ///
+    /// These are not cumulative, and projects exceeding the last bound are only part of `count`.
+    pub buckets: [usize; BLOCKED_DURATION_BUCKETS.len()],
+    /// The total number of projects.
+    pub count: usize,
+    /// The sum of all the durations, in seconds.
+    pub sum: f64,
+}
+
+impl BlockedDurations {
+    /// Adds a project which has been blocked for the given duration.
+    pub(crate) fn observe(&mut self, blocked_for: Duration) {
+        let secs = blocked_for.as_secs_f64();
+        if let Some(idx) = BLOCKED_DURATION_BUCKETS.iter().position(|le| secs <= *le) {
+            self.buckets[idx] += 1;
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+
+    /// Adds all the projects of the `other` histogram to this one.
+    pub(crate) fn merge(&mut self, other: &BlockedDurations) {
+        for (bucket, other) in self.buckets.iter_mut().zip(other.buckets) {
+            *bucket += other;
+        }
+        self.count += other.count;
+        self.sum += other.sum;
+    }
+
+    /// Returns the cumulative bucket counts, paired with their upper bound, as used by Prometheus.
+    pub fn cumulative_buckets(&self) -> impl Iterator<Item = (f64, usize)> + '_ {
+        BLOCKED_DURATION_BUCKETS
+            .iter()
+            .zip(self.buckets.iter().scan(0, |total, count| {
+                *total += count;
+                Some(*total)
+            }))
+            .map(|(le, count)| (*le, count))
+    }
+}
+
+/// The number of buckets of [`Utilizations`], each covering 1% of the budget, up to 200%.
+const UTILIZATION_BUCKETS: usize = 200;
+
+/// The quantiles of [`Utilizations`] that are reported as metrics.
+pub const UTILIZATION_QUANTILES: [f64; 3] = [0.5, 0.9, 0.99];
+
+/// A histogram of the budget utilization of projects, which is their spend rate relative to their budget.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Utilizations {
+    /// The number of projects per 1% of utilization, up to 200%.
+    buckets: [usize; UTILIZATION_BUCKETS],
+    /// The number of projects exceeding the last bucket.
+    overflow: usize,
+    /// The highest utilization of any project.
+    max: f64,
+}
+
+impl Default for Utilizations {
+    fn default() -> Self {
+        Self {
+            buckets: [0; UTILIZATION_BUCKETS],
+            overflow: 0,
+            max: 0.,
+        }
+    }
+}
+
+impl Utilizations {
+    /// Adds a project with the given utilization, where `1` means it spends exactly its budget.
+    pub(crate) fn observe(&mut self, utilization: f64) {
+        // `max` also turns `NaN` into `0`.
+        let utilization = utilization.max(0.);
+        let idx = (utilization * 100.).ceil() as usize;
+        match self.buckets.get_mut(idx.saturating_sub(1)) {
+            Some(bucket) => *bucket += 1,
+            None => self.overflow += 1,
+        }
+        self.max = self.max.max(utilization);
+    }
+
+    /// Adds all the projects of the `other` histogram to this one.
+    pub(crate) fn merge(&mut self, other: &Utilizations) {
+        for (bucket, other) in self.buckets.iter_mut().zip(other.buckets) {
+            *bucket += other;
+        }
+        self.overflow += other.overflow;
+        self.max = self.max.max(other.max);
+    }
+
+    /// Returns the total number of projects.
+    pub fn count(&self) -> usize {
+        self.buckets.iter().sum::<usize>() + self.overflow
+    }
+
+    /// Returns the utilization below which the given `quantile` of the projects are,
+    /// or [`None`] if there are no projects.
+    ///
+    /// The result is rounded up to the next percent, and utilizations above 200% are reported
+    /// as the highest utilization of any project.
+    pub fn quantile(&self, quantile: f64) -> Option<f64> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = ((quantile * count as f64).ceil() as usize).clamp(1, count);
+        let mut seen = 0;
+        for (idx, bucket) in self.buckets.iter().enumerate() {
+            seen += bucket;
+            if seen >= rank {
+                let upper_bound = (idx + 1) as f64 / 100.;
+                return Some(upper_bound.min(self.max));
+            }
+        }
+        Some(self.max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use quanta::Clock;
+
+    use crate::config::{BudgetingConfig, Timer};
+    use crate::ProjectStats;
+
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let (clock, mock) = Clock::mock();
+        mock.increment(Duration::from_secs(100));
+        let timer = Timer::new(clock);
+
+        let config = BudgetingConfig::new(
+            Duration::from_secs(10),
+            Duration::from_secs(5),
+            Duration::from_secs(1),
+            10.,
+        )
+        .with_timer(timer.clone());
+        let config = Arc::new(config);
+
+        let mut within_budget = ProjectStats::new(config.clone());
+        within_budget.record_spending(5.);
+        let mut exceeding = ProjectStats::new(config);
+        exceeding.record_spending(100.);
+
+        let mut summary = SpendSummary::default();
+        let since = timer.now() - Duration::from_secs(1);
+        summary.add_project(1.into(), &within_budget, timer.now(), Some(since));
+        summary.add_project(2.into(), &exceeding, timer.now(), Some(since));
+
+        assert_eq!(summary.tracked_projects, 2);
+        assert_eq!(summary.blocked_projects, 1);
+        assert_eq!(summary.spend_rate, 105. / 5.);
+        assert_eq!(summary.capacity, 20.);
+        assert_eq!(summary.pressure(), 21. / 20.);
+        assert_eq!(SpendSummary::default().pressure(), 0.);
+        assert_eq!(summary.blocked_durations.count, 1);
+        assert_eq!(summary.utilizations.quantile(0.5), Some(0.1));
+        assert_eq!(summary.utilizations.quantile(0.99), Some(2.));
+
+        mock.increment(Duration::from_secs(90));
+        let mut later = SpendSummary::default();
+        later.add_project(2.into(), &exceeding, timer.now(), Some(timer.now()));
+        summary.merge(&later);
+        assert_eq!(summary.blocked_durations.count, 2);
+        // the state only changed before the later summary
+        assert_eq!(summary.state_changes, 1);
+        assert_eq!(summary.blocked_durations.sum, 90.);
+        let buckets: Vec<_> = summary.blocked_durations.cumulative_buckets().collect();
+        assert_eq!(&buckets[..3], [(10., 1), (60., 1), (300., 2)]);
+    }
+
+    #[test]
+    fn test_utilizations() {
+        let mut utilizations = Utilizations::default();
+        assert_eq!(utilizations.quantile(0.5), None);
+
+        for percent in 1..=100 {
+            utilizations.observe(percent as f64 / 100.);
+        }
+        assert_eq!(utilizations.quantile(0.5), Some(0.5));
+        assert_eq!(utilizations.quantile(0.9), Some(0.9));
+        assert_eq!(utilizations.quantile(0.99), Some(0.99));
+
+        let mut other = Utilizations::default();
+        other.observe(5.);
+        other.observe(f64::NAN);
+        utilizations.merge(&other);
+        assert_eq!(utilizations.count(), 102);
+        assert_eq!(utilizations.quantile(0.), Some(0.01));
+        // the project above 200% is reported with its actual utilization
+        assert_eq!(utilizations.quantile(1.), Some(5.));
+    }
+
+    #[test]
+    fn test_flip_floppers() {
+        let flip_flopper = |project_id: u64, transitions| FlipFlopper {
+            project_id: project_id.into(),
+            transitions,
+        };
+        let mut summary = SpendSummary::default();
+        let projects: Vec<_> = (0..MAX_FLIP_FLOPPERS as u64)
+            .map(|project_id| flip_flopper(project_id, 2))
+            .collect();
+        summary.add_flip_floppers(&projects);
+
+        let mut other = SpendSummary::default();
+        other.add_flip_floppers(&[flip_flopper(100, 5), flip_flopper(101, 3)]);
+        summary.merge(&other);
+
+        assert_eq!(summary.flip_floppers.len(), MAX_FLIP_FLOPPERS);
+        assert_eq!(summary.flip_floppers[0], flip_flopper(100, 5));
+        assert_eq!(summary.flip_floppers[1], flip_flopper(101, 3));
+        assert_eq!(summary.flip_floppers[2], flip_flopper(0, 2));
+    }
+}
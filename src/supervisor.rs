@@ -0,0 +1,155 @@
+//! Supervises all the long-running components of the server, and fails fast if any of them stops.
+//!
+//! Components, like the listeners of each transport and the pollers of external config sources,
+//! are expected to run forever. Instead of limping along partially functional once one of them stopped,
+//! the whole process shuts down, and the failed component is logged, so that it is restarted cleanly.
+
+use std::fmt;
+use std::future::Future;
+use std::sync::mpsc::{self, Receiver, Sender};
+
+/// A component that stopped, along with the reason it stopped.
+#[derive(Debug, PartialEq)]
+pub struct Failure {
+    /// The name of the component, like "HTTP server on `0.0.0.0:4433`".
+    pub component: String,
+    /// Why the component stopped.
+    pub error: String,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} failed: {}", self.component, self.error)
+    }
+}
+
+/// Waits for the first of the supervised components to stop.
+#[derive(Debug)]
+pub struct Supervisor {
+    receiver: Receiver<Failure>,
+    handle: SupervisorHandle,
+}
+
+/// Starts components supervised by a [`Supervisor`], on any runtime or thread.
+#[derive(Clone, Debug)]
+pub struct SupervisorHandle {
+    sender: Sender<Failure>,
+}
+
+impl Supervisor {
+    /// Creates a [`Supervisor`] without any components.
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        Self {
+            receiver,
+            handle: SupervisorHandle { sender },
+        }
+    }
+
+    /// Returns a handle to start supervised components with.
+    pub fn handle(&self) -> SupervisorHandle {
+        self.handle.clone()
+    }
+
+    /// Blocks until the first component stops, and returns why it stopped.
+    pub fn wait(self) -> Failure {
+        let Self { receiver, handle } = self;
+        // Without its own sender, this returns once all the components are gone.
+        drop(handle);
+        receiver.recv().unwrap_or_else(|_| Failure {
+            component: "supervisor".into(),
+            error: "no components are running".into(),
+        })
+    }
+}
+
+impl SupervisorHandle {
+    /// Spawns the `component` as a task on the current tokio runtime.
+    ///
+    /// The component is considered failed once it returns, with or without an error, or panics.
+    pub fn spawn<F, E>(&self, name: String, component: F)
+    where
+        F: Future<Output = Result<(), E>> + Send + 'static,
+        E: fmt::Display + Send + 'static,
+    {
+        let sender = self.sender.clone();
+        let task = tokio::spawn(component);
+        tokio::spawn(async move {
+            let error = match task.await {
+                Ok(Ok(())) => "stopped unexpectedly".into(),
+                Ok(Err(err)) => err.to_string(),
+                Err(err) => err.to_string(),
+            };
+            let _ = sender.send(Failure {
+                component: name,
+                error,
+            });
+        });
+    }
+
+    /// Runs the `component` on a new thread.
+    ///
+    /// Just like with [`SupervisorHandle::spawn`], the component is considered failed once it returns.
+    /// Panics are not caught, as the [panic hook](crate::panics) exits the process before they unwind.
+    pub fn spawn_thread<F, E>(&self, name: String, component: F)
+    where
+        F: FnOnce() -> Result<(), E> + Send + 'static,
+        E: fmt::Display + 'static,
+    {
+        let sender = self.sender.clone();
+        std::thread::spawn(move || {
+            let error = match component() {
+                Ok(()) => "stopped unexpectedly".into(),
+                Err(err) => err.to_string(),
+            };
+            let _ = sender.send(Failure {
+                component: name,
+                error,
+            });
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::runtime::Builder;
+
+    use super::*;
+
+    #[test]
+    fn test_supervisor() {
+        let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+        let supervisor = Supervisor::new();
+        let handle = supervisor.handle();
+        let guard = runtime.enter();
+        handle.spawn("forever".into(), async {
+            std::future::pending::<()>().await;
+            Ok::<_, String>(())
+        });
+        handle.spawn("failing".into(), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            Err("address in use")
+        });
+        drop(guard);
+        std::thread::spawn(move || runtime.block_on(std::future::pending::<()>()));
+        assert_eq!(
+            supervisor.wait(),
+            Failure {
+                component: "failing".into(),
+                error: "address in use".into(),
+            }
+        );
+
+        let supervisor = Supervisor::new();
+        let handle = supervisor.handle();
+        handle.spawn_thread("runtime 0".into(), || Err("too many open files"));
+        drop(handle);
+        let failure = supervisor.wait();
+        assert_eq!(failure.to_string(), "runtime 0 failed: too many open files");
+
+        let supervisor = Supervisor::new();
+        assert_eq!(supervisor.wait().component, "supervisor");
+    }
+}
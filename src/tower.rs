@@ -0,0 +1,125 @@
+//! Exposes the [`Service`] as a [`tower_service::Service`], to compose it with tower middlewares.
+
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use crate::{ConfigError, Decision, ProjectKey, Service, StoreFuture};
+
+/// A request to the budgeting decisions of a shared [`Service`].
+///
+/// The service decides in-process, so it is always ready, and its futures only wait for the [`StateStore`](crate::StateStore)
+/// of the config, which resolves immediately for the default [`MemoryStore`](crate::MemoryStore).
+/// This makes it cheap to wrap in standard middlewares like timeouts, retries or metrics,
+/// or to mount it directly into an axum or tonic stack:
+///
+/// ```
+/// use std::sync::Arc;
+///
+/// use peanutbutter::{BudgetRequest, Service};
+/// use tower_service::Service as _;
+///
+/// let mut service = Arc::new(Service::new());
+/// let request = BudgetRequest::ExceedsBudget { config: "test".into(), project: 1.into() };
+/// // the config is not registered
+/// assert!(pollster::block_on(service.call(request)).is_err());
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub enum BudgetRequest {
+    /// Checks whether the project exceeds its budget, see [`Service::try_exceeds_budget_by_key`].
+    ExceedsBudget { config: String, project: ProjectKey },
+    /// Records spent budget for the project, see [`Service::try_record_spending_by_key`].
+    RecordSpending {
+        config: String,
+        project: ProjectKey,
+        spent: f64,
+    },
+}
+
+impl BudgetRequest {
+    /// Returns the name of the config the request is decided with.
+    pub fn config(&self) -> &str {
+        match self {
+            Self::ExceedsBudget { config, .. } | Self::RecordSpending { config, .. } => config,
+        }
+    }
+}
+
+/// Decides [`BudgetRequest`]s, failing with a [`ConfigError`] for unknown configs.
+impl tower_service::Service<BudgetRequest> for Arc<Service> {
+    type Response = Decision;
+    type Error = ConfigError;
+    type Future = StoreFuture<'static, Result<Decision, ConfigError>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), ConfigError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: BudgetRequest) -> Self::Future {
+        let service = self.clone();
+        Box::pin(async move {
+            match request {
+                BudgetRequest::ExceedsBudget { config, project } => {
+                    (service.try_exceeds_budget_by_key_async(&config, project)).await
+                }
+                BudgetRequest::RecordSpending {
+                    config,
+                    project,
+                    spent,
+                } => (service.try_record_spending_by_key_async(&config, project, spent)).await,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::task::Waker;
+    use std::time::Duration;
+
+    use pollster::block_on;
+    use tower_service::Service as _;
+
+    use super::*;
+    use crate::{BudgetingConfig, DecisionReason};
+
+    #[test]
+    fn test_budget_request() {
+        let mut service = Arc::new(Service::new());
+        let config = BudgetingConfig::new(
+            Duration::from_secs(60),
+            Duration::from_secs(10),
+            Duration::from_secs(1),
+            10.,
+        );
+        service.try_add_config("test", config).unwrap();
+
+        let mut cx = Context::from_waker(Waker::noop());
+        assert_eq!(service.poll_ready(&mut cx), Poll::Ready(Ok(())));
+
+        let uuid = ProjectKey::parse_uuid("67e55044-10b1-426f-9247-bb680e5fe0c8").unwrap();
+        let record = BudgetRequest::RecordSpending {
+            config: "test".into(),
+            project: uuid,
+            spent: 1000.,
+        };
+        assert_eq!(record.config(), "test");
+        let decision = block_on(service.call(record)).unwrap();
+        assert!(decision.exceeds_budget);
+
+        let check = |project| BudgetRequest::ExceedsBudget {
+            config: "test".into(),
+            project,
+        };
+        let decision = block_on(service.call(check(uuid))).unwrap();
+        assert!(decision.exceeds_budget);
+        assert_eq!(decision.reason, DecisionReason::OverBudget);
+        let decision = block_on(service.call(check(1.into()))).unwrap();
+        assert!(!decision.exceeds_budget);
+
+        let unknown = BudgetRequest::ExceedsBudget {
+            config: "unknown".into(),
+            project: 1.into(),
+        };
+        assert!(block_on(service.call(unknown)).is_err());
+    }
+}
@@ -0,0 +1,256 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use quanta::Instant;
+
+use crate::config::BudgetingConfig;
+use crate::decision::{Decision, DecisionReason};
+use crate::stats::{ProjectReport, RefundError};
+
+/// Tracks the budget of a single project, according to some accounting strategy.
+///
+/// The [`Service`](crate::Service) creates a tracker for each project, using the
+/// [`AccountingStrategy`](crate::AccountingStrategy) of the project's [`BudgetingConfig`].
+/// The default strategy is the sliding window implemented by [`ProjectStats`](crate::ProjectStats).
+pub trait BudgetTracker: fmt::Debug + Send + Sync {
+    /// Records spent budget, and returns whether the project exceeds its budget.
+    fn record(&mut self, spent: f64) -> bool;
+
+    /// Acquires budget which is held until it is released, and returns whether the project exceeds its budget.
+    ///
+    /// This is used for reservations and acquisitions, which are released via [`BudgetTracker::release`]
+    /// when they are committed, canceled or expire. By default, this is the same as [`BudgetTracker::record`].
+    fn acquire(&mut self, acquired: f64) -> bool {
+        self.record(acquired)
+    }
+
+    /// Remembers the `source` of recorded spending, like the producer that recorded it.
+    ///
+    /// The recent sources are only used for debugging, see [`ProjectReport::recent_sources`].
+    /// Strategies do not remember any sources by default.
+    fn record_source(&mut self, source: &str) {
+        let _ = source;
+    }
+
+    /// Refunds previously spent budget, and returns whether the project exceeds its budget.
+    ///
+    /// Strategies do not support refunds by default.
+    fn refund(&mut self, refunded: f64) -> Result<bool, RefundError> {
+        let _ = refunded;
+        Err(RefundError::NotAllowed)
+    }
+
+    /// Releases previously reserved budget, which was recorded at `recorded_at`,
+    /// and returns whether the project exceeds its budget.
+    ///
+    /// In contrast to [`BudgetTracker::refund`], this does not depend on refunds being allowed,
+    /// and strategies with buckets release the budget from the bucket it was recorded in.
+    /// By default, this is a refund, and strategies that do not support refunds do not release anything.
+    fn release(&mut self, released: f64, recorded_at: Instant) -> bool {
+        let _ = recorded_at;
+        self.refund(released).unwrap_or_else(|_| self.check())
+    }
+
+    /// Checks whether the project exceeds its budget.
+    fn check(&mut self) -> bool;
+
+    /// Returns whether the project exceeded its budget when it was last checked or spending was recorded.
+    ///
+    /// In contrast to [`BudgetTracker::check`], this does not re-evaluate the spending at all.
+    fn cached_check(&self) -> bool;
+
+    /// Returns why the project is in its current "exceeded" state at the given `now`.
+    ///
+    /// This is meant to be called right after [`BudgetTracker::check`] or [`BudgetTracker::record`],
+    /// and it does not modify the tracked state. By default, the reason follows from the cached state alone.
+    fn decision_reason(&self, now: Instant) -> DecisionReason {
+        let _ = now;
+        Decision::from_exceeds_budget(self.cached_check()).reason
+    }
+
+    /// Checks whether recording the `spent` budget at the given `now` would exceed the budget.
+    ///
+    /// This does not modify the tracked state.
+    fn would_exceed(&self, spent: f64, now: Instant) -> bool;
+
+    /// Checks whether the tracked state is no longer needed, and can be cleaned up.
+    fn is_stale(&self, now: Instant) -> bool;
+
+    /// Returns a [`ProjectReport`] describing the state at the given `now`.
+    ///
+    /// In contrast to [`BudgetTracker::check`], this does not update the "exceeded" state.
+    fn report(&self, now: Instant) -> ProjectReport;
+
+    /// Returns the spending of each bucket within the current window at the given `now`, oldest first.
+    ///
+    /// Each bucket is given as the time since its start, along with its total (not per-second) spending.
+    /// Buckets without any spending are included as well. Strategies without buckets return none by default.
+    fn bucket_spending(&self, now: Instant) -> Vec<(Duration, f64)> {
+        let _ = now;
+        Vec::new()
+    }
+
+    /// Returns the approximate number of bytes of memory used by this tracker.
+    ///
+    /// By default, this only accounts for the tracker itself, and not for any heap allocations it owns.
+    fn memory_usage(&self) -> usize {
+        std::mem::size_of_val(self)
+    }
+
+    /// Shifts the tracked state forward by the `skipped` time, as if that time had not passed at all.
+    ///
+    /// This is used when the clock jumped forward, for example after a suspended VM resumed.
+    /// The tracked state is kept as-is by default, so that it expires according to the jumped clock.
+    fn skip_time(&mut self, skipped: Duration) {
+        let _ = skipped;
+    }
+
+    /// Returns the [`BudgetingConfig`] governing this tracker.
+    fn config(&self) -> &Arc<BudgetingConfig>;
+
+    /// Replaces the [`BudgetingConfig`] governing this tracker, keeping the tracked state.
+    fn set_config(&mut self, config: Arc<BudgetingConfig>);
+}
+
+/// The rolling window within which changes of the "exceeded" state are counted.
+pub(crate) const TRANSITION_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// The maximum number of changes of the "exceeded" state that are remembered per project.
+const MAX_TRANSITIONS: usize = 256;
+
+/// The maximum number of distinct sources of spending that are remembered per project.
+const MAX_SOURCES: usize = 4;
+
+/// The maximum length of a remembered source, in bytes. Longer sources are truncated.
+const MAX_SOURCE_LEN: usize = 64;
+
+/// The most recent distinct sources of spending of a project, see [`BudgetTracker::record_source`].
+///
+/// This does not allocate for projects which never record a source.
+#[derive(Debug, Default)]
+pub(crate) struct SourceLog(Vec<Arc<str>>);
+
+impl SourceLog {
+    /// Records the given `source` as the most recent one.
+    pub fn record(&mut self, source: &str) {
+        let mut len = source.len().min(MAX_SOURCE_LEN);
+        while !source.is_char_boundary(len) {
+            len -= 1;
+        }
+        let source = &source[..len];
+
+        let source = match self.0.iter().position(|recent| **recent == *source) {
+            Some(index) => self.0.remove(index),
+            None => {
+                if self.0.len() >= MAX_SOURCES {
+                    self.0.remove(0);
+                }
+                source.into()
+            }
+        };
+        self.0.push(source);
+    }
+
+    /// Returns the recorded sources, most recent first.
+    pub fn recent(&self) -> Vec<Arc<str>> {
+        self.0.iter().rev().cloned().collect()
+    }
+
+    /// Returns the number of bytes of heap memory used by this log.
+    pub fn memory_usage(&self) -> usize {
+        let sources = self.0.iter().map(|source| source.len()).sum::<usize>();
+        self.0.capacity() * std::mem::size_of::<Arc<str>>() + sources
+    }
+}
+
+/// The times at which the "exceeded" state of a project changed within the [`TRANSITION_WINDOW`].
+///
+/// This does not allocate for projects which never change their state.
+#[derive(Debug, Default)]
+pub(crate) struct TransitionLog(VecDeque<Instant>);
+
+impl TransitionLog {
+    /// Records a change of the "exceeded" state at the given `now`.
+    pub fn record(&mut self, now: Instant) {
+        while self
+            .0
+            .front()
+            .is_some_and(|time| now.saturating_duration_since(*time) > TRANSITION_WINDOW)
+            || self.0.len() >= MAX_TRANSITIONS
+        {
+            self.0.pop_front();
+        }
+        self.0.push_back(now);
+    }
+
+    /// Returns the number of changes within the [`TRANSITION_WINDOW`] before the given `now`.
+    pub fn count(&self, now: Instant) -> usize {
+        self.0
+            .iter()
+            .filter(|time| now.saturating_duration_since(**time) <= TRANSITION_WINDOW)
+            .count()
+    }
+
+    /// Returns the time of the most recent change, if there was one within the [`TRANSITION_WINDOW`].
+    pub fn last(&self, now: Instant) -> Option<Instant> {
+        self.0
+            .back()
+            .copied()
+            .filter(|time| now.saturating_duration_since(*time) <= TRANSITION_WINDOW)
+    }
+
+    /// Returns the number of bytes of heap memory used by this log.
+    pub fn memory_usage(&self) -> usize {
+        self.0.capacity() * std::mem::size_of::<Instant>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use quanta::Clock;
+
+    use super::*;
+
+    #[test]
+    fn test_transition_log() {
+        let (clock, mock) = Clock::mock();
+        mock.increment(Duration::from_secs(100));
+        let mut log = TransitionLog::default();
+        assert_eq!(log.memory_usage(), 0);
+
+        log.record(clock.now());
+        mock.increment(Duration::from_secs(30 * 60));
+        log.record(clock.now());
+        assert_eq!(log.count(clock.now()), 2);
+
+        // the first transition leaves the window
+        mock.increment(Duration::from_secs(31 * 60));
+        assert_eq!(log.count(clock.now()), 1);
+        assert!(log.last(clock.now()).is_some());
+
+        for _ in 0..MAX_TRANSITIONS * 2 {
+            log.record(clock.now());
+        }
+        assert_eq!(log.count(clock.now()), MAX_TRANSITIONS);
+    }
+
+    #[test]
+    fn test_source_log() {
+        let mut log = SourceLog::default();
+        assert_eq!(log.memory_usage(), 0);
+        assert!(log.recent().is_empty());
+
+        for source in ["a", "b", "c", "a", "d", "e"] {
+            log.record(source);
+        }
+        // "b" is the least recent source, "a" moved to the front when it was recorded again
+        let recent: Vec<_> = log.recent().iter().map(|s| s.to_string()).collect();
+        assert_eq!(recent, ["e", "d", "a", "c"]);
+
+        log.record(&"ü".repeat(MAX_SOURCE_LEN));
+        assert_eq!(log.recent()[0].len(), MAX_SOURCE_LEN);
+        assert_eq!(log.recent()[0].chars().count(), MAX_SOURCE_LEN / 2);
+    }
+}
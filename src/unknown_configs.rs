@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use quanta::Instant;
+
+use crate::metrics::Counter;
+
+/// The maximum number of distinct unknown config names that are tracked individually.
+const MAX_UNKNOWN_CONFIGS: usize = 100;
+
+/// The minimum interval between two warnings about the same unknown config name.
+const WARNING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Keeps track of requests for config names which are not registered.
+#[derive(Debug, Default)]
+pub(crate) struct UnknownConfigs {
+    /// The total number of requests for unknown configs.
+    pub total: Counter,
+    /// Per-name request counters, bounded to [`MAX_UNKNOWN_CONFIGS`] names.
+    names: Mutex<HashMap<String, UnknownConfig>>,
+}
+
+/// The requests for a single unknown config name.
+#[derive(Debug)]
+struct UnknownConfig {
+    /// The total number of requests.
+    requests: u64,
+    /// The number of requests since the last warning.
+    requests_since_warning: u64,
+    /// The time of the last warning.
+    last_warning: Option<Instant>,
+}
+
+impl UnknownConfigs {
+    /// Records a request for the unknown config `name`.
+    ///
+    /// Returns the number of requests since the last warning if a warning should be emitted now.
+    /// This is rate limited to one warning per [`WARNING_INTERVAL`] for each name.
+    pub fn record(&self, name: &str, now: Instant) -> Option<u64> {
+        self.total.add(1);
+
+        let mut names = self.names.lock().unwrap();
+        // Beyond the limit, requests for new names are only counted in `total`, and never warned about.
+        if names.len() >= MAX_UNKNOWN_CONFIGS && !names.contains_key(name) {
+            return None;
+        }
+        let unknown = names.entry(name.into()).or_insert(UnknownConfig {
+            requests: 0,
+            requests_since_warning: 0,
+            last_warning: None,
+        });
+
+        unknown.requests += 1;
+        unknown.requests_since_warning += 1;
+
+        let should_warn = unknown
+            .last_warning
+            .is_none_or(|last| now.saturating_duration_since(last) >= WARNING_INTERVAL);
+        if !should_warn {
+            return None;
+        }
+
+        unknown.last_warning = Some(now);
+        Some(std::mem::take(&mut unknown.requests_since_warning))
+    }
+
+    /// Returns the total number of requests per unknown config name.
+    pub fn requests(&self) -> Vec<(String, u64)> {
+        let names = self.names.lock().unwrap();
+        let mut requests: Vec<_> = names
+            .iter()
+            .map(|(name, unknown)| (name.clone(), unknown.requests))
+            .collect();
+        requests.sort();
+        requests
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use quanta::Clock;
+
+    use super::*;
+
+    #[test]
+    fn test_unknown_configs() {
+        let (clock, mock) = Clock::mock();
+        let unknown = UnknownConfigs::default();
+
+        assert_eq!(unknown.record("foo", clock.now()), Some(1));
+        assert_eq!(unknown.record("foo", clock.now()), None);
+        assert_eq!(unknown.record("bar", clock.now()), Some(1));
+
+        mock.increment(WARNING_INTERVAL);
+        assert_eq!(unknown.record("foo", clock.now()), Some(2));
+
+        for i in 0..MAX_UNKNOWN_CONFIGS {
+            unknown.record(&i.to_string(), clock.now());
+        }
+
+        assert_eq!(unknown.total.get(), 4 + MAX_UNKNOWN_CONFIGS as u64);
+        let requests = unknown.requests();
+        assert_eq!(requests.len(), MAX_UNKNOWN_CONFIGS);
+        assert!(requests.contains(&("foo".into(), 3)));
+    }
+}